@@ -0,0 +1,61 @@
+//! The SWO stream in async code, as a `Stream` of `Bytes` on tokio.
+//!
+//! ```text
+//! cargo run --example async_stream --features tokio [BIT_RATE]
+//! ```
+//!
+//! Captures at `BIT_RATE` (3000000 by default) from the first LPC-Link2
+//! running its usual firmware, or from made-up data if `LPC_CAT_SYNTHETIC`
+//! is set, for a few seconds, printing how much arrives.
+
+use std::future::poll_fn;
+use std::pin::Pin;
+use std::time::{Duration, Instant};
+
+use futures_core::Stream;
+use lpc_cat::async_capture::SwoStream;
+use lpc_cat::select::Selector;
+use lpc_cat::synthetic::{self, Synthetic};
+use lpc_cat::{capture, firmware, Error};
+
+async fn capture(rate: u32) -> Result<(), Error> {
+    let mut stream = match std::env::var_os("LPC_CAT_SYNTHETIC") {
+        Some(_) => SwoStream::new(
+            Synthetic::new(synthetic::Config::default()),
+            capture::Config::default(),
+        ),
+        None => {
+            SwoStream::open(
+                firmware::NXP_VID,
+                0x0090,
+                Selector::default(),
+                rate,
+            )
+            .await?
+        }
+    };
+
+    // `StreamExt::next` would do, from the `futures` crate.
+    let started = Instant::now();
+    while started.elapsed() < Duration::from_secs(3) {
+        let next = poll_fn(|cx| Pin::new(&mut stream).poll_next(cx)).await;
+        match next {
+            Some(data) => println!("{} bytes", data?.len()),
+            None => break,
+        }
+    }
+    print!("{}", stream.stop().await?);
+    Ok(())
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let rate = match std::env::args().nth(1) {
+        Some(rate) => rate.parse()?,
+        None => 3_000_000,
+    };
+    // Capture blocks one of the runtime's blocking threads, not the thread
+    // running tasks, so a single-threaded runtime is enough.
+    let runtime = tokio::runtime::Builder::new_current_thread().build()?;
+    runtime.block_on(capture(rate))?;
+    Ok(())
+}
@@ -0,0 +1,64 @@
+//! The least there is to capturing: the SWO stream as an iterator of chunks,
+//! as they arrive from the probe.
+//!
+//! ```text
+//! cargo run --example chunks [BIT_RATE]
+//! ```
+//!
+//! Captures at `BIT_RATE` (3000000 by default) from the first probe found,
+//! or from made-up data if `LPC_CAT_SYNTHETIC` is set, and prints the
+//! length and first few bytes of each of the first 20 chunks.
+
+use lpc_cat::embed::Capture;
+use lpc_cat::select::Selector;
+use lpc_cat::{synthetic, Error};
+
+/// Chunks of a capture, until it stops.
+struct Chunks {
+    capture: Capture,
+    buffer: Vec<u8>,
+}
+
+impl Iterator for Chunks {
+    type Item = Result<Vec<u8>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            // Chunks are about a capture buffer's worth at most, so with a
+            // buffer that big, a read is a chunk (or, rarely, part of one).
+            match self.capture.read(&mut self.buffer, None) {
+                Ok(Some(0)) => continue,
+                Ok(Some(n)) => return Some(Ok(self.buffer[..n].to_vec())),
+                Ok(None) => return None,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+fn main() -> Result<(), Error> {
+    let rate = match std::env::args().nth(1) {
+        Some(rate) => rate.parse().expect("bit rate should be a number"),
+        None => 3_000_000,
+    };
+    let mut capture = match std::env::var_os("LPC_CAT_SYNTHETIC") {
+        Some(_) => Capture::synthetic(synthetic::Config::default()),
+        None => Capture::find(&Selector::default())?,
+    };
+    capture.set_bit_rate(rate, false)?;
+    capture.start(64)?;
+
+    let mut chunks = Chunks {
+        capture,
+        buffer: vec![0; lpc_cat::CAPTURE_BUFFER_SIZE],
+    };
+    for chunk in chunks.by_ref().take(20) {
+        let chunk = chunk?;
+        let head = &chunk[..chunk.len().min(8)];
+        println!("{:5} bytes: {:02x?}...", chunk.len(), head);
+    }
+    // Dropping the capture would stop it too, but this way we get the
+    // statistics.
+    eprint!("{}", chunks.capture.stop()?);
+    Ok(())
+}
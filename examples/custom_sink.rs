@@ -0,0 +1,128 @@
+//! Doing something of your own with the stream as it arrives, by
+//! implementing `Sink` and handing it to `capture::run`.
+//!
+//! ```text
+//! cargo run --example custom_sink [BIT_RATE]
+//! ```
+//!
+//! Captures at `BIT_RATE` (3000000 by default) from the first probe found,
+//! or from made-up data if `LPC_CAT_SYNTHETIC` is set, until Ctrl-C, and
+//! prints the lines of text the target writes to ITM stimulus port 0, with
+//! how long each took to arrive. Lines cut short by lost data are marked as
+//! such.
+
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+use lpc_cat::capture::{self, Fragment, Gap, Sink, Source};
+use lpc_cat::select::Selector;
+use lpc_cat::synthetic::{self, Synthetic};
+use lpc_cat::{firmware, itm, protocol, Handle};
+
+/// Sink printing lines of text from stimulus port 0, timed from their first
+/// byte.
+struct Lines {
+    itm: itm::Decoder,
+    line: Vec<u8>,
+    /// When the first byte of `line` arrived.
+    started: Option<Instant>,
+}
+
+impl Lines {
+    fn print(&mut self, note: &str) {
+        let took = self.started.take().map_or(0, |t| t.elapsed().as_micros());
+        let text = String::from_utf8_lossy(&self.line);
+        println!("{:>8} us  {}{}", took, text.trim_end(), note);
+        self.line.clear();
+    }
+}
+
+impl Sink for Lines {
+    /// Gets the captured bytes, in order. The default `fragment` passes on
+    /// to this; override it to also hear where data sat in the probe's
+    /// buffer, and when it arrived.
+    fn data(&mut self, bytes: &[u8]) -> io::Result<()> {
+        let mut text = vec![];
+        self.itm.feed(bytes, |packet| {
+            if let itm::Packet::Instrumentation { port: 0, payload } = packet {
+                text.extend_from_slice(&payload);
+            }
+        });
+        for b in text {
+            self.started.get_or_insert_with(Instant::now);
+            self.line.push(b);
+            if b == b'\n' {
+                self.print("");
+            }
+        }
+        Ok(())
+    }
+
+    fn fragment(&mut self, info: &Fragment, bytes: &[u8]) -> io::Result<()> {
+        // Time lines from when the probe's data reached us, rather than
+        // from when we got round to it.
+        if self.line.is_empty() && !bytes.is_empty() {
+            self.started = Some(info.received);
+        }
+        self.data(bytes)
+    }
+
+    /// Hears about lost data, and why (the default `lost` calls `gap`).
+    fn lost(&mut self, gap: &Gap) -> io::Result<()> {
+        // Don't take what follows as the rest of a half-decoded packet.
+        self.itm.reset();
+        if !self.line.is_empty() {
+            self.print(&format!(" [cut short: {}]", gap.cause));
+        }
+        Ok(())
+    }
+
+    fn bit_rate(&mut self, rate: u32) -> io::Result<()> {
+        eprintln!("capturing at {} bits/s", rate);
+        Ok(())
+    }
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let rate = match std::env::args().nth(1) {
+        Some(rate) => rate.parse()?,
+        None => 3_000_000,
+    };
+    let source: Box<dyn Source> = match std::env::var_os("LPC_CAT_SYNTHETIC") {
+        Some(_) => Box::new(Synthetic::new(synthetic::Config {
+            pattern: synthetic::Pattern::Itm,
+            ..synthetic::Config::default()
+        })),
+        None => {
+            let (vid, pid) = firmware::find(
+                &hidapi::HidApi::new().map_err(lpc_cat::Error::from)?,
+                &Selector::default(),
+            )?;
+            let handle = Handle::open(vid, pid, &Selector::default())?;
+            handle.ohai(protocol::MODE_UART)?;
+            handle.init_uart()?;
+            handle.set_bit_rate(rate)?;
+            Box::new(handle)
+        }
+    };
+
+    // Capture runs until this is set, finishing what it has in hand first.
+    let stop = Arc::new(AtomicBool::new(false));
+    let flag = Arc::clone(&stop);
+    ctrlc::set_handler(move || flag.store(true, Ordering::Relaxed))?;
+    let config = capture::Config {
+        stop: Some(stop),
+        ..capture::Config::default()
+    };
+
+    let mut lines = Lines {
+        itm: itm::Decoder::new(),
+        line: vec![],
+        started: None,
+    };
+    let stats = capture::run(source, &config, &mut lines)?;
+    eprint!("{}", stats);
+    Ok(())
+}
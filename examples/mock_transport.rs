@@ -0,0 +1,164 @@
+//! Testing code built on `Handle` without a probe, by giving it a stand-in
+//! `Transport` that answers commands from a script.
+//!
+//! ```text
+//! cargo run --example mock_transport
+//! ```
+//!
+//! The stand-in answers the setup commands as a probe would, then hands out
+//! scripted data in response to polls. The check at the end is what a test
+//! of your own code would look like: capture through the real `Handle` and
+//! `capture::run`, and compare what comes out with what went in. The same
+//! check runs as part of `cargo test`.
+
+use std::collections::VecDeque;
+use std::convert::TryInto;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use lpc_cat::capture::{self, PollStrategy, Sink};
+use lpc_cat::transport::Transport;
+use lpc_cat::{protocol, Error, Handle};
+
+/// A pretend probe.
+struct MockProbe {
+    /// Shared, so it can be looked at once the `Handle` has the probe.
+    state: Arc<Mutex<State>>,
+    /// Set once the script has run out, to end capture.
+    done: Arc<AtomicBool>,
+}
+
+#[derive(Default)]
+struct State {
+    /// Data to hand out, a chunk per poll.
+    script: VecDeque<Vec<u8>>,
+    /// How full the pretend capture buffer is.
+    fill: u16,
+    /// The response to the last command, waiting to be read.
+    response: Vec<u8>,
+    /// Every command received, for checking afterwards.
+    commands: Vec<Vec<u8>>,
+}
+
+impl Transport for MockProbe {
+    fn write(&self, data: &[u8]) -> Result<(), Error> {
+        let mut state = self.state.lock().unwrap();
+        state.commands.push(data.to_vec());
+        state.response = match data {
+            [protocol::OHAI, _] => vec![protocol::OHAI, protocol::OHAI_ACK],
+            [protocol::INIT_UART] => {
+                let mut r = vec![protocol::INIT_UART, 0, 0, 0, 0];
+                r.extend_from_slice(&6_000_000u32.to_le_bytes());
+                r
+            }
+            // Agree to any rate asked for.
+            [protocol::SET_BIT_RATE, rate @ ..] => {
+                let mut r = vec![protocol::SET_BIT_RATE];
+                r.extend_from_slice(rate);
+                r
+            }
+            [protocol::POLL] => {
+                let mut r = vec![protocol::RESPONSE_INCREMENTAL, 1];
+                match state.script.pop_front() {
+                    Some(chunk) => {
+                        let start = state.fill;
+                        state.fill += chunk.len() as u16;
+                        r.extend_from_slice(&protocol::pack_fill_levels(
+                            start, state.fill,
+                        ));
+                        r.extend_from_slice(&chunk);
+                    }
+                    None => {
+                        // Nothing new: zero fill levels.
+                        r.extend_from_slice(&[0; 3]);
+                        self.done.store(true, Ordering::Relaxed);
+                    }
+                }
+                r
+            }
+            _ => panic!("unexpected command {:02x?}", data),
+        };
+        Ok(())
+    }
+
+    fn read(
+        &self,
+        buffer: &mut [u8],
+        _timeout: Option<Duration>,
+    ) -> Result<usize, Error> {
+        let state = self.state.lock().unwrap();
+        let n = state.response.len();
+        buffer[..n].copy_from_slice(&state.response);
+        Ok(n)
+    }
+
+    fn serial(&self) -> Result<Option<String>, Error> {
+        Ok(Some("MOCK".to_string()))
+    }
+}
+
+/// Sink collecting everything captured.
+#[derive(Default)]
+struct Collect(Vec<u8>);
+
+impl Sink for Collect {
+    fn data(&mut self, bytes: &[u8]) -> std::io::Result<()> {
+        self.0.extend_from_slice(bytes);
+        Ok(())
+    }
+}
+
+/// Captures a script through the stand-in, checks that what comes out is
+/// what went in, and returns it along with the number of commands sent.
+fn capture_from_script() -> Result<(Vec<u8>, usize), Error> {
+    // An empty chunk is a poll with nothing new.
+    let script = [b"hello, ".to_vec(), vec![], b"world\n".to_vec()];
+    let done = Arc::new(AtomicBool::new(false));
+    let state = Arc::new(Mutex::new(State {
+        script: script.iter().cloned().collect(),
+        ..State::default()
+    }));
+    let probe = MockProbe {
+        state: Arc::clone(&state),
+        done: Arc::clone(&done),
+    };
+
+    let handle = Handle::from_transport(Box::new(probe));
+    handle.ohai(protocol::MODE_UART)?;
+    handle.init_uart()?;
+    assert_eq!(handle.set_bit_rate(3_000_000)?, 3_000_000);
+
+    let config = capture::Config {
+        stop: Some(done),
+        poll: PollStrategy::Fixed(Duration::from_millis(1)),
+        // Keep capture's messages about how it's going off stderr.
+        observer: Some(capture::Observer::new(|_| ())),
+        ..capture::Config::default()
+    };
+    let mut out = Collect::default();
+    let stats = capture::run(handle, &config, &mut out)?;
+
+    assert_eq!(out.0, script.concat());
+    assert_eq!(stats.bytes, out.0.len() as u64);
+    assert_eq!(stats.gaps, 0);
+    let commands = &state.lock().unwrap().commands;
+    let rate = &commands[2][1..];
+    assert_eq!(u32::from_le_bytes(rate.try_into().unwrap()), 3_000_000);
+    Ok((out.0, commands.len()))
+}
+
+fn main() -> Result<(), Error> {
+    let (out, commands) = capture_from_script()?;
+    println!(
+        "captured {:?} in {} commands",
+        String::from_utf8_lossy(&out),
+        commands
+    );
+    Ok(())
+}
+
+#[test]
+fn script_round_trip() -> Result<(), Error> {
+    capture_from_script().map(|_| ())
+}
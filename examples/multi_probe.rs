@@ -0,0 +1,132 @@
+//! The bones of a daemon capturing from every probe on the machine: one
+//! thread per probe, picking up probes plugged in later, riding out probes
+//! being unplugged and plugged back in, and stopping cleanly on Ctrl-C.
+//!
+//! ```text
+//! cargo run --example multi_probe DIR [BIT_RATE [SELECT]]
+//! ```
+//!
+//! Each probe's stream is written to `DIR/<serial>.swo`, at `BIT_RATE`
+//! (3000000 by default). Probes without serial numbers can't be told apart,
+//! so they're skipped. To leave some probes to other programs, `SELECT`
+//! picks out the ones to capture from, in the syntax of `lpc-cat --select`
+//! (see `select::Expr`), e.g. `'port=1-3.*'`.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use lpc_cat::capture::{self, Reopenable, Stats};
+use lpc_cat::interfaces::Function;
+use lpc_cat::select::{self, Selector};
+use lpc_cat::{firmware, protocol, Error, Handle};
+
+/// How often to look for new probes.
+const SCAN_INTERVAL: Duration = Duration::from_secs(2);
+
+/// How often to try to get back in touch with a probe that went away.
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Opens the probe with the given serial number, and sets it up.
+fn open(vid: u16, pid: u16, serial: &str, rate: u32) -> Result<Handle, Error> {
+    let selector = Selector {
+        serial: Some(serial.to_string()),
+        ..Selector::default()
+    };
+    let handle = Handle::open(vid, pid, &selector)?;
+    handle.ohai(protocol::MODE_UART)?;
+    handle.init_uart()?;
+    handle.set_bit_rate(rate)?;
+    Ok(handle)
+}
+
+/// Captures from one probe to `path` until `stop` is set.
+fn capture(
+    (vid, pid): (u16, u16),
+    serial: String,
+    rate: u32,
+    path: PathBuf,
+    stop: Arc<AtomicBool>,
+) -> Result<Stats, Error> {
+    let handle = open(vid, pid, &serial, rate)?;
+    let source = Reopenable::new(handle, move || open(vid, pid, &serial, rate));
+    let config = capture::Config {
+        reconnect: Some(RECONNECT_INTERVAL),
+        stop: Some(stop),
+        ..capture::Config::default()
+    };
+    let mut out = File::create(path)?;
+    capture::run(source, &config, &mut out)
+}
+
+/// Returns the serial numbers of the probes offering SWO capture that
+/// `selector` picks out.
+fn scan(ids: (u16, u16), selector: &Selector) -> Result<Vec<String>, Error> {
+    let api = hidapi::HidApi::new()?;
+    Ok(select::enumerate(&api, ids.0, ids.1, selector)
+        .into_iter()
+        .filter(|p| p.hid_path(Function::Trace).is_some())
+        .filter_map(|p| p.serial)
+        .collect())
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let mut args = std::env::args().skip(1);
+    let dir = PathBuf::from(args.next().ok_or("usage: multi_probe DIR")?);
+    let rate = match args.next() {
+        Some(rate) => rate.parse()?,
+        None => 3_000_000,
+    };
+    let selector = Selector {
+        expr: args.next().map(|s| s.parse()).transpose()?,
+        ..Selector::default()
+    };
+    std::fs::create_dir_all(&dir)?;
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let flag = Arc::clone(&stop);
+    ctrlc::set_handler(move || flag.store(true, Ordering::Relaxed))?;
+
+    // All the probes are taken to be running the same firmware.
+    let ids = firmware::find(&hidapi::HidApi::new()?, &selector)?;
+    let mut captures: HashMap<String, JoinHandle<Result<Stats, Error>>> =
+        HashMap::new();
+    while !stop.load(Ordering::Relaxed) {
+        for serial in scan(ids, &selector)? {
+            if captures.contains_key(&serial) {
+                continue;
+            }
+            eprintln!("probe {}: capturing", serial);
+            let path = dir.join(format!("{}.swo", serial));
+            let (name, stop) = (serial.clone(), Arc::clone(&stop));
+            let thread =
+                thread::spawn(move || capture(ids, name, rate, path, stop));
+            captures.insert(serial, thread);
+        }
+        // A probe whose capture failed is tried again on the next scan.
+        let finished: Vec<String> = captures
+            .iter()
+            .filter(|(_, thread)| thread.is_finished())
+            .map(|(serial, _)| serial.clone())
+            .collect();
+        for serial in finished {
+            let thread = captures.remove(&serial).unwrap();
+            if let Err(e) = thread.join().expect("capture thread panicked") {
+                eprintln!("probe {}: {}", serial, e);
+            }
+        }
+        thread::sleep(SCAN_INTERVAL);
+    }
+
+    for (serial, thread) in captures {
+        match thread.join().expect("capture thread panicked") {
+            Ok(stats) => eprint!("probe {}:\n{}", serial, stats),
+            Err(e) => eprintln!("probe {}: {}", serial, e),
+        }
+    }
+    Ok(())
+}
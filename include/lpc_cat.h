@@ -0,0 +1,77 @@
+/*
+ * C interface to lpc-cat's capture engine, built with `--features ffi`.
+ * See src/ffi.rs for the details of each function.
+ *
+ * A capture is opened, given a bit rate, and started, in that order. Once
+ * started, the probe is polled on a background thread, and captured data
+ * queues up for lpc_cat_read. If the queue fills because the caller isn't
+ * reading fast enough, data is dropped and counted in lpc_cat_get_stats.
+ *
+ * Functions return LPC_CAT_OK (0) or a positive status on success, and a
+ * negative LPC_CAT_ERR_* code on failure; lpc_cat_last_error then says what
+ * went wrong. A handle may be used from any thread, but only from one at a
+ * time.
+ */
+
+#ifndef LPC_CAT_H
+#define LPC_CAT_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define LPC_CAT_OK 0
+/* From lpc_cat_read: capture is over, and everything has been read. */
+#define LPC_CAT_ENDED 1
+#define LPC_CAT_ERR_INVALID (-1)
+#define LPC_CAT_ERR_DEVICE_NOT_FOUND (-2)
+#define LPC_CAT_ERR_USB (-3)
+#define LPC_CAT_ERR_PROTOCOL (-4)
+#define LPC_CAT_ERR_BIT_RATE (-5)
+#define LPC_CAT_ERR_UNSUPPORTED (-6)
+#define LPC_CAT_ERR_IO (-7)
+#define LPC_CAT_ERR_TARGET (-8)
+/* Something went wrong inside the library; only close the handle now. */
+#define LPC_CAT_ERR_PANIC (-9)
+
+typedef struct lpc_cat lpc_cat;
+
+typedef struct {
+    uint64_t bytes;
+    uint64_t probe_gaps;
+    uint64_t host_drops;
+    uint64_t host_dropped_bytes;
+    uint64_t queue_high_water;
+    uint64_t reconnects;
+    uint64_t flushes;
+    double duration; /* seconds */
+} lpc_cat_stats;
+
+/* vid and pid both 0 means the first probe with trace-capable firmware;
+ * serial may be NULL. */
+int lpc_cat_open(uint16_t vid, uint16_t pid, const char *serial,
+                 lpc_cat **out);
+/* pattern is "counter" (or NULL) or "itm"; rate is a bit rate. */
+int lpc_cat_open_synthetic(const char *pattern, uint64_t rate, lpc_cat **out);
+/* actual may be NULL. */
+int lpc_cat_set_bit_rate(lpc_cat *handle, uint32_t rate, int approximate,
+                         uint32_t *actual);
+int lpc_cat_start(lpc_cat *handle, size_t queue_depth);
+/* timeout_ms < 0 waits forever; *read is 0 on timeout. */
+int lpc_cat_read(lpc_cat *handle, uint8_t *buffer, size_t len,
+                 int32_t timeout_ms, size_t *read);
+int lpc_cat_get_stats(lpc_cat *handle, lpc_cat_stats *stats);
+/* stats may be NULL. */
+int lpc_cat_stop(lpc_cat *handle, lpc_cat_stats *stats);
+void lpc_cat_close(lpc_cat *handle);
+/* Returns the full length of the message, which is truncated to fit. */
+size_t lpc_cat_last_error(char *buffer, size_t len);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
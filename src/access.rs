@@ -0,0 +1,112 @@
+//! Access control for network servers: which peers may connect, and what
+//! they must say to be let in.
+
+use std::io::{self, Read};
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use crate::framing;
+
+/// Who may use a server. The default allows everyone.
+#[derive(Clone, Debug, Default)]
+pub struct Policy {
+    /// Networks clients may connect from. If empty, any address may connect.
+    pub allow: Vec<IpNet>,
+    /// Secret clients must present (see `framing` for how) before they get
+    /// any data.
+    pub token: Option<String>,
+}
+
+impl Policy {
+    /// Checks whether a client at `ip` may connect at all.
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        self.allow.is_empty() || self.allow.iter().any(|n| n.contains(ip))
+    }
+
+    /// Reads a client's auth message from `stream`, if we require one, and
+    /// checks it. The caller should arrange for reads to time out.
+    pub fn authenticate(&self, stream: impl Read) -> io::Result<()> {
+        let token = match &self.token {
+            Some(t) => t,
+            None => return Ok(()),
+        };
+        let presented = framing::read_auth(stream)?;
+        if constant_time_eq(presented.as_bytes(), token.as_bytes()) {
+            Ok(())
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "wrong token",
+            ))
+        }
+    }
+}
+
+/// Compares secrets without leaking where they first differ through timing.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len()
+        && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// An IP network in CIDR notation, like `10.0.0.0/8` or `fe80::/10`. A bare
+/// address means just that address.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct IpNet {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl IpNet {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // Treat IPv4 clients connecting to an IPv6 socket as IPv4.
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+            v4 => v4,
+        };
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                prefix_eq(&net.octets(), &ip.octets(), self.prefix)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                prefix_eq(&net.octets(), &ip.octets(), self.prefix)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Checks whether the first `bits` bits of `a` and `b` are equal.
+fn prefix_eq(a: &[u8], b: &[u8], bits: u8) -> bool {
+    let bits = usize::from(bits);
+    let (whole, partial) = (bits / 8, bits % 8);
+    if a[..whole] != b[..whole] {
+        return false;
+    }
+    if partial == 0 {
+        return true;
+    }
+    let mask = !0u8 << (8 - partial);
+    a[whole] & mask == b[whole] & mask
+}
+
+impl FromStr for IpNet {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bad = || format!("can't parse `{}` as an IP network", s);
+        let (addr, prefix) = match s.split_once('/') {
+            Some((a, p)) => (a, Some(p)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| bad())?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(p) => p.parse::<u8>().map_err(|_| bad())?,
+            None => max,
+        };
+        if prefix > max {
+            return Err(bad());
+        }
+        Ok(IpNet { addr, prefix })
+    }
+}
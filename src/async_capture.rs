@@ -0,0 +1,211 @@
+//! The SWO stream for async code running on tokio, as a `Stream` of `Bytes`
+//! or through `AsyncRead`.
+//!
+//! The probe is still polled by `capture::run`, with its blocking hidapi
+//! calls, on one of tokio's blocking threads (see `spawn_blocking`). Data
+//! reaches the async side through a bounded channel; if it isn't consumed
+//! quickly enough, the channel fills, then `capture::run`'s own queue, and
+//! data is dropped as it would be for a stalled sink (see `stats`).
+
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use bytes::Bytes;
+use futures_core::Stream;
+use tokio::io::{AsyncRead, ReadBuf};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+use crate::capture::{self, Progress, Sink, Source, Stats};
+use crate::protocol;
+use crate::select::Selector;
+use crate::{Error, Handle};
+
+/// Chunks (of up to about 1 KiB) that can wait between the blocking side
+/// and the async side.
+const CHANNEL_DEPTH: usize = 64;
+
+/// A capture in progress, yielding the reassembled SWO stream.
+///
+/// Capture stops when this is dropped, or when the stream ends; if it ends
+/// because of an error, the stream yields that error last.
+pub struct SwoStream {
+    rx: mpsc::Receiver<Bytes>,
+    stop: Arc<AtomicBool>,
+    progress: Arc<Progress>,
+    /// The blocking task running capture, until it's been waited for.
+    task: Option<JoinHandle<Result<Stats, Error>>>,
+    /// Data received but not yet read through `AsyncRead`.
+    pending: Bytes,
+}
+
+impl SwoStream {
+    /// Starts capturing from `source`, which should already be set up (see
+    /// `open` for probes). `config.stop` and `config.progress` are replaced.
+    ///
+    /// # Panics
+    ///
+    /// If called outside a tokio runtime.
+    pub fn new(source: impl Source + 'static, config: capture::Config) -> Self {
+        let (tx, rx) = mpsc::channel(CHANNEL_DEPTH);
+        let stop = Arc::new(AtomicBool::new(false));
+        let progress = Arc::new(Progress::default());
+        let config = capture::Config {
+            stop: Some(Arc::clone(&stop)),
+            progress: Some(Arc::clone(&progress)),
+            ..config
+        };
+        let task = tokio::task::spawn_blocking(move || {
+            capture::run(source, &config, &mut Forward(tx))
+        });
+        Self {
+            rx,
+            stop,
+            progress,
+            task: Some(task),
+            pending: Bytes::new(),
+        }
+    }
+
+    /// Opens the probe with the given vid/pid that satisfies `selector`,
+    /// sets it up for UART capture at `bit_rate`, and starts capturing with
+    /// the default options.
+    pub async fn open(
+        vid: u16,
+        pid: u16,
+        selector: Selector,
+        bit_rate: u32,
+    ) -> Result<Self, Error> {
+        let handle = tokio::task::spawn_blocking(move || {
+            let handle = Handle::open(vid, pid, &selector)?;
+            handle.ohai(protocol::MODE_UART)?;
+            handle.init_uart()?;
+            let actual = handle.set_bit_rate(bit_rate)?;
+            if actual != bit_rate {
+                return Err(Error::UnachievableBitRate {
+                    requested: bit_rate,
+                    closest: actual,
+                });
+            }
+            Ok(handle)
+        })
+        .await
+        .unwrap_or_else(|e| std::panic::resume_unwind(e.into_panic()))?;
+        Ok(Self::new(handle, capture::Config::default()))
+    }
+
+    /// Returns statistics about the capture so far.
+    pub fn stats(&self) -> Stats {
+        self.progress.snapshot()
+    }
+
+    /// Stops capture, discarding anything not yet received, and returns the
+    /// final statistics, or the error that stopped capture early.
+    pub async fn stop(mut self) -> Result<Stats, Error> {
+        self.stop.store(true, Ordering::Relaxed);
+        self.rx.close();
+        let result = match self.task.take() {
+            Some(task) => task
+                .await
+                .unwrap_or_else(|e| std::panic::resume_unwind(e.into_panic())),
+            None => return Ok(self.stats()),
+        };
+        match result {
+            // That'll be us hanging up.
+            Err(Error::Io(e)) if capture::is_closed(&e) => Ok(self.stats()),
+            r => r,
+        }
+    }
+
+    /// Once the channel has closed, waits for capture to finish, and turns
+    /// how it went into the end of the stream.
+    fn poll_end(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Bytes, Error>>> {
+        let task = match &mut self.task {
+            Some(task) => task,
+            None => return Poll::Ready(None),
+        };
+        let result = match Pin::new(task).poll(cx) {
+            Poll::Ready(r) => {
+                r.unwrap_or_else(|e| std::panic::resume_unwind(e.into_panic()))
+            }
+            Poll::Pending => return Poll::Pending,
+        };
+        self.task = None;
+        match result {
+            Ok(_) => Poll::Ready(None),
+            Err(Error::Io(e)) if capture::is_closed(&e) => Poll::Ready(None),
+            Err(e) => Poll::Ready(Some(Err(e))),
+        }
+    }
+}
+
+impl Stream for SwoStream {
+    type Item = Result<Bytes, Error>;
+
+    fn poll_next(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        match this.rx.poll_recv(cx) {
+            Poll::Ready(Some(data)) => Poll::Ready(Some(Ok(data))),
+            Poll::Ready(None) => this.poll_end(cx),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl AsyncRead for SwoStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.pending.is_empty() {
+            match Pin::new(&mut *this).poll_next(cx) {
+                Poll::Ready(Some(Ok(data))) => this.pending = data,
+                Poll::Ready(Some(Err(e))) => {
+                    return Poll::Ready(Err(match e {
+                        Error::Io(e) => e,
+                        e => io::Error::other(e),
+                    }))
+                }
+                // End of file.
+                Poll::Ready(None) => return Poll::Ready(Ok(())),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        let n = buf.remaining().min(this.pending.len());
+        buf.put_slice(&this.pending.split_to(n));
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for SwoStream {
+    fn drop(&mut self) {
+        // The capture task notices the channel closing too, but not until it
+        // has something to send.
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Sink handing captured data to the async side.
+struct Forward(mpsc::Sender<Bytes>);
+
+impl Sink for Forward {
+    fn data(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.0
+            .blocking_send(Bytes::copy_from_slice(bytes))
+            .map_err(|_| {
+                io::Error::new(io::ErrorKind::BrokenPipe, "stream dropped")
+            })
+    }
+}
@@ -0,0 +1,1278 @@
+//! Reassembling the SWO byte stream from the probe's poll responses.
+//!
+//! Polling happens on a dedicated thread, which hands data to the sink
+//! through a bounded queue. This way a sink that's briefly slow (say, stdout
+//! piped into a pager) doesn't stop us from polling, which would let the
+//! probe's buffer wrap. If the sink is slow for long enough to fill the
+//! queue, we drop data on the host side and report it as such, distinct from
+//! data lost by the probe.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread::{self, sleep};
+use std::time::{Duration, Instant};
+
+use crate::protocol::{self, PollResult};
+use crate::realtime::Scheduling;
+use crate::shutdown::{self, Shutdown, Stage};
+use crate::timestamp::Clock;
+use crate::{Error, Handle};
+
+/// Consumer of a reassembled SWO stream.
+///
+/// Anything that implements `Write` is a `Sink` that ignores gaps.
+pub trait Sink {
+    /// Handles newly captured bytes.
+    fn data(&mut self, bytes: &[u8]) -> io::Result<()>;
+
+    /// Handles newly captured bytes, along with where they came from. Sinks
+    /// that don't care can leave this alone, and just implement `data`.
+    fn fragment(&mut self, info: &Fragment, bytes: &[u8]) -> io::Result<()> {
+        let _ = info;
+        self.data(bytes)
+    }
+
+    /// Notes that data may have been lost between the previous call to
+    /// `data` and the next one. Sinks that decode framed data should use this
+    /// to resynchronize.
+    fn gap(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Notes a gap, as `gap` does, along with what's known about it. Sinks
+    /// that don't care why data was lost can leave this alone, and just
+    /// implement `gap`.
+    fn lost(&mut self, gap: &Gap) -> io::Result<()> {
+        let _ = gap;
+        self.gap()
+    }
+
+    /// Notes the bit rate the probe is capturing at, when capture starts and
+    /// whenever it changes (say, after reconnecting to the probe).
+    fn bit_rate(&mut self, rate: u32) -> io::Result<()> {
+        let _ = rate;
+        Ok(())
+    }
+
+    /// Handles the fill levels the probe reported in response to a poll.
+    /// These only arrive if `Config::fill_levels` is set.
+    fn fill_levels(&mut self, levels: &FillLevels) -> io::Result<()> {
+        let _ = levels;
+        Ok(())
+    }
+
+    /// Notes the wall clock time that goes with the `Instant`s in
+    /// `Fragment`s from here on, when it isn't simply the host's, as when
+    /// replaying a recording. Sinks that show when data arrived should use
+    /// this rather than asking the system.
+    fn clock(&mut self, clock: Clock) -> io::Result<()> {
+        let _ = clock;
+        Ok(())
+    }
+}
+
+impl<W: Write> Sink for W {
+    fn data(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.write_all(bytes)
+    }
+}
+
+/// Something that can be polled for captured data the way the probe can.
+pub trait Source: Send {
+    /// Asks for an update on the capture buffer. See `Handle::poll`.
+    fn poll<'a>(
+        &mut self,
+        buffer: &'a mut [u8],
+    ) -> Result<(u8, PollResult<'a>), Error>;
+
+    /// Tries to get back in touch after `poll` fails, e.g. because the probe
+    /// was unplugged. On success, polling starts over with a fresh capture
+    /// buffer. Sources that can't do this return `Error::Unsupported`.
+    fn reconnect(&mut self) -> Result<(), Error> {
+        Err(Error::Unsupported("reconnecting"))
+    }
+
+    /// Returns the SWO bit rate being captured, if known.
+    fn bit_rate(&self) -> Option<u32> {
+        None
+    }
+
+    /// Returns how big a buffer `poll` needs. See `Handle::response_size`.
+    fn response_size(&self) -> usize {
+        protocol::PACKET_SIZE
+    }
+}
+
+impl Source for Handle {
+    fn poll<'a>(
+        &mut self,
+        buffer: &'a mut [u8],
+    ) -> Result<(u8, PollResult<'a>), Error> {
+        Handle::poll(self, buffer)
+    }
+
+    fn bit_rate(&self) -> Option<u32> {
+        Handle::bit_rate(self)
+    }
+
+    fn response_size(&self) -> usize {
+        Handle::response_size(self)
+    }
+}
+
+impl<S: Source + ?Sized> Source for Box<S> {
+    fn poll<'a>(
+        &mut self,
+        buffer: &'a mut [u8],
+    ) -> Result<(u8, PollResult<'a>), Error> {
+        (**self).poll(buffer)
+    }
+
+    fn reconnect(&mut self) -> Result<(), Error> {
+        (**self).reconnect()
+    }
+
+    fn bit_rate(&self) -> Option<u32> {
+        (**self).bit_rate()
+    }
+
+    fn response_size(&self) -> usize {
+        (**self).response_size()
+    }
+}
+
+/// A probe that can be reopened if it goes away, using a function that finds
+/// it again and sets it up.
+pub struct Reopenable<F> {
+    handle: Option<Handle>,
+    open: F,
+}
+
+impl<F> Reopenable<F>
+where
+    F: FnMut() -> Result<Handle, Error> + Send,
+{
+    /// Wraps `handle`, which should already be set up for capture.
+    pub fn new(handle: Handle, open: F) -> Self {
+        Self {
+            handle: Some(handle),
+            open,
+        }
+    }
+}
+
+impl<F> Source for Reopenable<F>
+where
+    F: FnMut() -> Result<Handle, Error> + Send,
+{
+    fn poll<'a>(
+        &mut self,
+        buffer: &'a mut [u8],
+    ) -> Result<(u8, PollResult<'a>), Error> {
+        match &self.handle {
+            Some(handle) => handle.poll(buffer),
+            None => Err(Error::DeviceNotFound),
+        }
+    }
+
+    fn reconnect(&mut self) -> Result<(), Error> {
+        // Let go of the old device before looking for the new one.
+        self.handle = None;
+        self.handle = Some((self.open)()?);
+        Ok(())
+    }
+
+    fn bit_rate(&self) -> Option<u32> {
+        self.handle.as_ref().and_then(Handle::bit_rate)
+    }
+
+    fn response_size(&self) -> usize {
+        self.handle
+            .as_ref()
+            .map_or(protocol::PACKET_SIZE, Handle::response_size)
+    }
+}
+
+/// Sink that passes everything on to several others.
+///
+/// If one of them fails because whatever was reading from it went away (say,
+/// stdout was piped into `head`), it's dropped, and the rest carry on. Once
+/// there's only one left, its errors are passed on as usual.
+pub struct Tee(pub Vec<Box<dyn Sink>>);
+
+impl Tee {
+    fn each(
+        &mut self,
+        mut f: impl FnMut(&mut dyn Sink) -> io::Result<()>,
+    ) -> io::Result<()> {
+        let mut i = 0;
+        while i < self.0.len() {
+            match f(&mut *self.0[i]) {
+                Err(e) if is_closed(&e) && self.0.len() > 1 => {
+                    log::warn!("an output was closed; carrying on without it");
+                    self.0.remove(i);
+                }
+                Err(e) => return Err(e),
+                Ok(()) => i += 1,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Sink for Tee {
+    fn data(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.each(|sink| sink.data(bytes))
+    }
+
+    fn fragment(&mut self, info: &Fragment, bytes: &[u8]) -> io::Result<()> {
+        self.each(|sink| sink.fragment(info, bytes))
+    }
+
+    fn gap(&mut self) -> io::Result<()> {
+        self.each(|sink| sink.gap())
+    }
+
+    fn lost(&mut self, gap: &Gap) -> io::Result<()> {
+        self.each(|sink| sink.lost(gap))
+    }
+
+    fn bit_rate(&mut self, rate: u32) -> io::Result<()> {
+        self.each(|sink| sink.bit_rate(rate))
+    }
+
+    fn fill_levels(&mut self, levels: &FillLevels) -> io::Result<()> {
+        self.each(|sink| sink.fill_levels(levels))
+    }
+
+    fn clock(&mut self, clock: Clock) -> io::Result<()> {
+        self.each(|sink| sink.clock(clock))
+    }
+}
+
+/// Checks whether an error from a sink means that nobody is reading its
+/// output any more.
+pub fn is_closed(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::BrokenPipe
+}
+
+/// Where a chunk of captured data came from.
+#[derive(Copy, Clone, Debug)]
+pub struct Fragment {
+    /// When we received it from the probe.
+    pub received: Instant,
+    /// Capture epoch reported by the probe.
+    pub epoch: u8,
+    /// Where the data sat in the probe's capture buffer: from `start`
+    /// (inclusive) to `end` (exclusive).
+    pub start: u16,
+    pub end: u16,
+}
+
+/// A point in the stream where data may have been lost.
+#[derive(Copy, Clone, Debug)]
+pub struct Gap {
+    /// When we noticed.
+    pub noticed: Instant,
+    /// Our best guess at why.
+    pub cause: GapCause,
+}
+
+/// Why data was lost, as far as we can tell from the host. Each of these
+/// also has a `Status`, with more detail, and a counter in `Stats`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum GapCause {
+    /// The probe's buffer filled up, and started over, more than once
+    /// between polls.
+    Overflow,
+    /// An incremental response didn't follow on from the last one.
+    SyncLost,
+    /// USB communication failed, and we lost whatever the probe captured
+    /// until we reconnected.
+    Disconnected,
+    /// The sink couldn't keep up, so we dropped data on the host.
+    HostDrop,
+}
+
+impl GapCause {
+    /// Returns a short name for the cause, as used in telemetry.
+    pub fn name(self) -> &'static str {
+        match self {
+            GapCause::Overflow => "overflow",
+            GapCause::SyncLost => "sync_lost",
+            GapCause::Disconnected => "disconnected",
+            GapCause::HostDrop => "host_drop",
+        }
+    }
+}
+
+impl fmt::Display for GapCause {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            GapCause::Overflow => "probe buffer overflow",
+            GapCause::SyncLost => "lost sync with the probe",
+            GapCause::Disconnected => "probe disconnected",
+            GapCause::HostDrop => "host couldn't keep up",
+        })
+    }
+}
+
+/// The fill levels of the probe's capture buffer, as given in an incremental
+/// poll response: how far it had been filled at the previous poll, and at
+/// this one. Both are zero when there was nothing new.
+///
+/// These are exactly what the probe sent, even if they don't make sense;
+/// they're meant for studying how the probe's firmware manages its buffer.
+#[derive(Copy, Clone, Debug)]
+pub struct FillLevels {
+    /// When we received them from the probe.
+    pub received: Instant,
+    /// Capture epoch reported by the probe.
+    pub epoch: u8,
+    pub start: u16,
+    pub end: u16,
+}
+
+/// Capture options.
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// Number of chunks (of up to about 1 KiB each) that can be waiting
+    /// between the polling thread and the sink before we start dropping data.
+    pub queue_depth: usize,
+    /// How long to wait between polls of the probe.
+    pub poll: PollStrategy,
+    /// If USB communication fails, try to reconnect this often, rather than
+    /// giving up.
+    pub reconnect: Option<Duration>,
+    /// If given, capture stops soon after this is set, as if the source had
+    /// run dry.
+    pub stop: Option<Arc<AtomicBool>>,
+    /// Pass the fill levels from every incremental poll response to the
+    /// sink. These aren't queued if the sink is falling behind.
+    pub fill_levels: bool,
+    /// Where to report on how capture is going. By default, problems are
+    /// described on stderr.
+    pub observer: Option<Observer>,
+    /// How often to report throughput to the observer, if at all.
+    pub throughput_interval: Option<Duration>,
+    /// Counters to keep up to date as capture goes on, so that someone else
+    /// can see how it's going, or how far it got if it ends abnormally.
+    pub progress: Option<Arc<Progress>>,
+    /// How to schedule the polling thread, to keep it from being held up
+    /// on a busy machine. If this can't be done, we say so and carry on.
+    pub scheduling: Scheduling,
+    /// Shutdown to go through the first stages of once capture stops, for
+    /// whoever goes on to the rest. By default, capture has its own.
+    pub shutdown: Option<Shutdown>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            queue_depth: 4096,
+            poll: PollStrategy::Fixed(Duration::from_millis(10)),
+            reconnect: None,
+            stop: None,
+            fill_levels: false,
+            observer: None,
+            throughput_interval: None,
+            progress: None,
+            scheduling: Scheduling::default(),
+            shutdown: None,
+        }
+    }
+}
+
+/// Something that happened during capture, as reported to an `Observer`.
+#[derive(Clone, Debug)]
+pub enum Status {
+    /// Capture started, at this bit rate if it's known.
+    Started { bit_rate: Option<u32> },
+    /// We received `bytes` from the probe over the last `interval`. `recent`
+    /// says what the sink got over each of `RECENT_SPANS`.
+    Throughput {
+        bytes: u64,
+        interval: Duration,
+        recent: Vec<Recent>,
+    },
+    /// An incremental response didn't follow on from the last one, so data
+    /// may have been lost before `offset` in the probe's buffer.
+    SyncLost { epoch: u8, offset: u16 },
+    /// The probe's buffer filled up, and started over, more than once
+    /// between polls, so data has been lost from epochs before `epoch`.
+    Overflow { epoch: u8 },
+    /// The sink couldn't keep up, so we're dropping data.
+    HostDrop,
+    /// USB communication failed; we'll try to reconnect.
+    Disconnected { error: String },
+    /// We got the probe back, capturing at this bit rate if it's known.
+    Reconnected { bit_rate: Option<u32> },
+    /// The bit rate changed (after reconnecting).
+    BitRateChanged { old: u32, new: u32 },
+    /// Capture has ended without error.
+    Ended(Stats),
+    /// Capture was cut short by an error, a panic, or being told to exit
+    /// at once, having got as far as `stats`. This is never reported by
+    /// `run`, which doesn't know about most of these; it's for whoever
+    /// notices, to pass on to the observer.
+    Aborted { reason: String, stats: Stats },
+}
+
+impl Status {
+    /// Checks whether this is worth telling someone watching stderr about.
+    fn is_problem(&self) -> bool {
+        !matches!(
+            self,
+            Status::Started { .. }
+                | Status::Throughput { .. }
+                | Status::Ended(_)
+        )
+    }
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Status::Started {
+                bit_rate: Some(rate),
+            } => write!(f, "capture started at {} bit/s", rate),
+            Status::Started { bit_rate: None } => write!(f, "capture started"),
+            Status::Throughput {
+                bytes, interval, ..
+            } => {
+                write!(f, "received {} bytes in {:?}", bytes, interval)
+            }
+            Status::SyncLost { epoch, offset } => write!(
+                f,
+                "lost stream sync at {:02x}:{:03x}, data may be lost",
+                epoch, offset
+            ),
+            Status::Overflow { epoch } => write!(
+                f,
+                "lost stream sync at {:02x}:000, data may be lost",
+                epoch
+            ),
+            Status::HostDrop => write!(
+                f,
+                "output can't keep up; dropping data on the host side (this \
+                 is not a probe overflow)"
+            ),
+            Status::Disconnected { error } => write!(
+                f,
+                "lost contact with probe ({}); waiting for it to come back",
+                error
+            ),
+            Status::Reconnected { .. } => write!(
+                f,
+                "reconnected to probe; data sent while it was gone is lost"
+            ),
+            Status::BitRateChanged { old, new } => {
+                write!(f, "probe now captures at {} bit/s (was {})", new, old)
+            }
+            Status::Ended(stats) => {
+                write!(f, "capture ended after {} bytes", stats.bytes)
+            }
+            Status::Aborted { reason, stats } => write!(
+                f,
+                "capture aborted after {} bytes: {}",
+                stats.bytes, reason
+            ),
+        }
+    }
+}
+
+/// Receives `Status` updates as capture goes on. This is called from the
+/// thread that polls the probe, so it shouldn't take long.
+#[derive(Clone)]
+pub struct Observer(Arc<dyn Fn(&Status) + Send + Sync>);
+
+impl Observer {
+    pub fn new(f: impl Fn(&Status) + Send + Sync + 'static) -> Self {
+        Self(Arc::new(f))
+    }
+
+    /// Passes on `status`.
+    pub fn notify(&self, status: &Status) {
+        (self.0)(status)
+    }
+}
+
+impl fmt::Debug for Observer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Observer")
+    }
+}
+
+/// Reports `status` to `observer`, or describes it on stderr if there's no
+/// observer and it's a problem.
+fn report(observer: Option<&Observer>, status: Status) {
+    match observer {
+        Some(o) => (o.0)(&status),
+        None if status.is_problem() => eprintln!("{}", status),
+        None => (),
+    }
+}
+
+/// How long to wait between polls of the probe.
+///
+/// We never wait after a poll that returned data, since more is likely to be
+/// waiting; these govern how long to wait after a poll that came back empty.
+#[derive(Copy, Clone, Debug)]
+pub enum PollStrategy {
+    /// Always wait the same amount of time.
+    Fixed(Duration),
+    /// Start out waiting `initial`. Each consecutive empty poll doubles the
+    /// wait, up to `max`; data arriving resets it. Data arriving in large
+    /// fragments, which suggests the probe's buffer is close to wrapping,
+    /// halves the wait we reset to, down to `min`.
+    Adaptive {
+        initial: Duration,
+        min: Duration,
+        max: Duration,
+    },
+}
+
+/// Fragments at least this large count as "near full" for the purposes of
+/// adaptive polling. The probe's buffer holds 1022 bytes.
+const NEAR_FULL: usize = 768;
+
+/// Tracks the state of a `PollStrategy`.
+struct Pacer {
+    strategy: PollStrategy,
+    /// What to wait after an empty poll that follows data.
+    base: Duration,
+    /// What to wait after the next empty poll.
+    current: Duration,
+}
+
+impl Pacer {
+    fn new(strategy: PollStrategy) -> Self {
+        let base = match strategy {
+            PollStrategy::Fixed(d) => d,
+            PollStrategy::Adaptive { initial, .. } => initial,
+        };
+        log::debug!("polling strategy: {:?}", strategy);
+        Self {
+            strategy,
+            base,
+            current: base,
+        }
+    }
+
+    /// Records the size of the data returned by a poll (`None` if it was
+    /// empty), and returns how long to wait before polling again.
+    fn after_poll(&mut self, fragment: Option<usize>) -> Duration {
+        let (min, max) = match self.strategy {
+            PollStrategy::Fixed(d) => {
+                return if fragment.is_some() {
+                    Duration::from_millis(0)
+                } else {
+                    d
+                };
+            }
+            PollStrategy::Adaptive { min, max, .. } => (min, max),
+        };
+
+        match fragment {
+            None => {
+                let wait = self.current;
+                let next = (self.current * 2).min(max);
+                if next != self.current {
+                    log::debug!(
+                        "poll came back empty, backing off to {:?}",
+                        next
+                    );
+                    self.current = next;
+                }
+                wait
+            }
+            Some(n) => {
+                if n >= NEAR_FULL && self.base > min {
+                    self.base = (self.base / 2).max(min);
+                    log::debug!(
+                        "got {} bytes in one poll, shortening interval to {:?}",
+                        n,
+                        self.base
+                    );
+                }
+                self.current = self.base;
+                Duration::from_millis(0)
+            }
+        }
+    }
+}
+
+/// Counters describing how a capture went.
+#[derive(Clone, Debug, Default)]
+pub struct Stats {
+    /// Bytes delivered to the sink.
+    pub bytes: u64,
+    /// Number of times the probe lost data.
+    pub probe_gaps: u64,
+    /// How many of `probe_gaps` were because the probe's buffer overflowed
+    /// between polls.
+    pub overflows: u64,
+    /// Number of times we dropped data because the sink couldn't keep up.
+    pub host_drops: u64,
+    /// Bytes we dropped because the sink couldn't keep up.
+    pub host_dropped_bytes: u64,
+    /// Largest number of chunks that were ever waiting for the sink.
+    pub queue_high_water: usize,
+    /// Number of times we reconnected to the probe.
+    pub reconnects: u64,
+    /// Number of times the probe's buffer filled up and it sent the whole
+    /// thing.
+    pub flushes: u64,
+    /// Number of gaps passed on to the sink, whatever their cause.
+    pub gaps: u64,
+    /// How long capture ran for.
+    pub duration: Duration,
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let secs = self.duration.as_secs_f64();
+        let rate = if secs > 0.0 {
+            self.bytes as f64 / secs
+        } else {
+            0.0
+        };
+        writeln!(f, "session:            {}", crate::session::id())?;
+        writeln!(f, "bytes captured:     {}", self.bytes)?;
+        writeln!(f, "duration:           {:.1} s", secs)?;
+        writeln!(f, "average throughput: {:.0} bytes/s", rate)?;
+        writeln!(f, "buffer flushes:     {}", self.flushes)?;
+        writeln!(f, "gaps in output:     {}", self.gaps)?;
+        writeln!(f, "sync losses:        {}", self.probe_gaps)?;
+        writeln!(f, "probe overflows:    {}", self.overflows)?;
+        writeln!(
+            f,
+            "host drops:         {} bytes in {} episodes",
+            self.host_dropped_bytes, self.host_drops
+        )?;
+        writeln!(f, "reconnects:         {}", self.reconnects)
+    }
+}
+
+/// The spans of time that live statistics are usually given for, as rates
+/// over the last second, ten seconds and minute.
+pub const RECENT_SPANS: [Duration; 3] = [
+    Duration::from_secs(1),
+    Duration::from_secs(10),
+    Duration::from_secs(60),
+];
+
+/// How finely `Progress` keeps track of recent history.
+const TICK: Duration = Duration::from_millis(100);
+
+/// How many `TICK`s of history `Progress` keeps.
+const HISTORY_TICKS: u64 = 600;
+
+/// What happened over a recent span of a capture in progress, from
+/// `Progress::recent`, for showing how capture is going now rather than
+/// over its whole life.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Recent {
+    /// How far back this goes, as asked for.
+    pub span: Duration,
+    /// How much of `span` capture has been going for, which is less than
+    /// `span` early on.
+    pub covered: Duration,
+    /// Bytes passed to the sink.
+    pub bytes: u64,
+    /// Gaps in what was passed to the sink.
+    pub gaps: u64,
+}
+
+impl Recent {
+    /// Returns the average rate data was passed to the sink, in bytes per
+    /// second.
+    pub fn rate(&self) -> f64 {
+        match self.covered.as_secs_f64() {
+            secs if secs > 0.0 => self.bytes as f64 / secs,
+            _ => 0.0,
+        }
+    }
+}
+
+/// `Stats` for a capture in progress, updated as it goes.
+#[derive(Debug, Default)]
+pub struct Progress {
+    started: OnceLock<Instant>,
+    ended: AtomicBool,
+    bytes: AtomicU64,
+    probe_gaps: AtomicU64,
+    overflows: AtomicU64,
+    host_drops: AtomicU64,
+    host_dropped_bytes: AtomicU64,
+    queue_high_water: AtomicUsize,
+    reconnects: AtomicU64,
+    flushes: AtomicU64,
+    gaps: AtomicU64,
+    /// Bytes and gaps in each `TICK` since capture started, for `recent`.
+    history: Mutex<VecDeque<Tick>>,
+}
+
+/// Counts for one `TICK` of a capture.
+#[derive(Debug)]
+struct Tick {
+    /// How many `TICK`s into the capture this one is.
+    index: u64,
+    bytes: u64,
+    gaps: u64,
+}
+
+impl Progress {
+    /// Returns the statistics so far. This takes no locks, so it's safe to
+    /// call from anywhere, including a panic hook.
+    pub fn snapshot(&self) -> Stats {
+        let get = |n: &AtomicU64| n.load(Ordering::Relaxed);
+        Stats {
+            bytes: get(&self.bytes),
+            probe_gaps: get(&self.probe_gaps),
+            overflows: get(&self.overflows),
+            host_drops: get(&self.host_drops),
+            host_dropped_bytes: get(&self.host_dropped_bytes),
+            queue_high_water: self.queue_high_water.load(Ordering::Relaxed),
+            reconnects: get(&self.reconnects),
+            flushes: get(&self.flushes),
+            gaps: get(&self.gaps),
+            duration: self
+                .started
+                .get()
+                .map_or(Duration::ZERO, Instant::elapsed),
+        }
+    }
+
+    /// Returns what happened over the last `span` of the capture so far,
+    /// up to a minute. Unlike `snapshot`, this takes a lock, but it still
+    /// works after capture panicked with the lock held.
+    pub fn recent(&self, span: Duration) -> Recent {
+        let span = span.min(TICK * HISTORY_TICKS as u32);
+        let elapsed =
+            self.started.get().map_or(Duration::ZERO, Instant::elapsed);
+        let now = Self::tick(elapsed);
+        // The current tick is only partly over, so whole ticks cover a little
+        // less than `span`; `covered` says how much.
+        let first = (now + 1).saturating_sub(Self::tick(span));
+        let mut recent = Recent {
+            span,
+            covered: elapsed.saturating_sub(TICK * first as u32).min(span),
+            bytes: 0,
+            gaps: 0,
+        };
+        let history = self.history.lock().unwrap_or_else(|e| e.into_inner());
+        for tick in history.iter().filter(|t| t.index >= first) {
+            recent.bytes += tick.bytes;
+            recent.gaps += tick.gaps;
+        }
+        recent
+    }
+
+    /// Checks whether capture has started.
+    pub fn started(&self) -> bool {
+        self.started.get().is_some()
+    }
+
+    /// Checks whether capture ended without error.
+    pub fn ended(&self) -> bool {
+        self.ended.load(Ordering::Relaxed)
+    }
+
+    fn add(counter: &AtomicU64, n: u64) {
+        counter.fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// Counts `bytes` and `gaps` passed to the sink, both in the totals and
+    /// in recent history.
+    fn passed(&self, bytes: u64, gaps: u64) {
+        Self::add(&self.bytes, bytes);
+        Self::add(&self.gaps, gaps);
+        let index = match self.started.get() {
+            Some(started) => Self::tick(started.elapsed()),
+            None => return,
+        };
+        let mut history =
+            self.history.lock().unwrap_or_else(|e| e.into_inner());
+        match history.back_mut() {
+            Some(tick) if tick.index == index => {
+                tick.bytes += bytes;
+                tick.gaps += gaps;
+            }
+            _ => history.push_back(Tick { index, bytes, gaps }),
+        }
+        while history
+            .front()
+            .is_some_and(|t| t.index + HISTORY_TICKS <= index)
+        {
+            history.pop_front();
+        }
+    }
+
+    /// Returns which `TICK` of the capture `elapsed` falls in.
+    fn tick(elapsed: Duration) -> u64 {
+        (elapsed.as_nanos() / TICK.as_nanos()) as u64
+    }
+}
+
+enum Event {
+    Data(Fragment, Vec<u8>),
+    Gap(Gap),
+    BitRate(u32),
+    FillLevels(FillLevels),
+}
+
+/// Polls the probe (or other `source`) until something fails, or until
+/// asked to stop (see `Config::stop`), feeding the reassembled SWO stream to
+/// `sink`. Returns statistics about the capture if it ended without error.
+///
+/// `source` is polled from a thread of its own. If that thread doesn't stop
+/// within `Stage::Polling`'s timeout, it's left behind with `source`, which
+/// may then outlive this call, and `Error::State` is returned.
+pub fn run(
+    mut source: impl Source + 'static,
+    config: &Config,
+    sink: &mut (impl Sink + ?Sized),
+) -> Result<Stats, Error> {
+    let progress = config.progress.clone().unwrap_or_default();
+    let started = *progress.started.get_or_init(Instant::now);
+    let (tx, rx) = sync_channel(config.queue_depth);
+    let queued = Arc::new(AtomicUsize::new(0));
+
+    let reader_queued = Arc::clone(&queued);
+    let reader_progress = Arc::clone(&progress);
+    let pacer = Pacer::new(config.poll);
+    let reconnect = config.reconnect;
+    let stop = config.stop.clone();
+    let fill_levels = config.fill_levels;
+    let observer = config.observer.clone();
+    let throughput = config
+        .throughput_interval
+        .map(|interval| Throughput::new(started, interval));
+    let scheduling = config.scheduling;
+    let shutdown = config.shutdown.clone().unwrap_or_default();
+    let reader_shutdown = shutdown.clone();
+    let reader = thread::spawn(move || {
+        if let Err(e) = scheduling.apply() {
+            log::warn!("can't set up scheduling of polling thread: {}", e);
+        }
+        let mut reader = Reader {
+            tx,
+            queued: reader_queued,
+            stats: reader_progress,
+            dropping: false,
+            stop,
+            fill_levels,
+            observer,
+            throughput,
+        };
+        let result = reader.poll_loop(&mut source, pacer, reconnect);
+        reader_shutdown.stage(Stage::Polling);
+        log::debug!(
+            "stopped polling with {} chunks queued",
+            reader.queued.load(Ordering::Relaxed)
+        );
+        drop(source);
+        reader_shutdown.stage(Stage::Draining);
+        // This closes the queue, so the sink gets the rest and is done.
+        drop(reader);
+        result
+    });
+
+    let write_result = drain(rx, &queued, &progress, sink);
+    // If the sink failed, the reader will notice the queue is gone and stop,
+    // unless it's stuck talking to the probe.
+    let deadline = Instant::now() + Stage::Polling.timeout();
+    let read_result = match shutdown::join_by(reader, deadline) {
+        Ok(result) => result.expect("polling thread panicked"),
+        Err(_) => {
+            log::warn!("polling thread didn't stop, leaving it behind");
+            Err(Error::State("polling thread didn't stop"))
+        }
+    };
+    let stats = progress.snapshot();
+
+    log::info!(
+        "capture ended: {} bytes, {} probe gaps, {} bytes dropped by host in \
+         {} episodes, queue high water {}/{}, {} reconnects",
+        stats.bytes,
+        stats.probe_gaps,
+        stats.host_dropped_bytes,
+        stats.host_drops,
+        stats.queue_high_water,
+        config.queue_depth,
+        stats.reconnects,
+    );
+
+    if config.shutdown.is_none() {
+        shutdown.finish();
+    }
+    write_result?;
+    read_result?;
+    progress.ended.store(true, Ordering::Relaxed);
+    report(config.observer.as_ref(), Status::Ended(stats.clone()));
+    Ok(stats)
+}
+
+/// Keeps track of throughput, to report it every so often.
+struct Throughput {
+    interval: Duration,
+    since: Instant,
+    bytes: u64,
+}
+
+impl Throughput {
+    fn new(since: Instant, interval: Duration) -> Self {
+        Self {
+            interval,
+            since,
+            bytes: 0,
+        }
+    }
+
+    /// Counts `bytes` of new data received.
+    fn add(&mut self, bytes: usize) {
+        self.bytes += bytes as u64;
+    }
+
+    /// Returns a report if it's time for one, with recent history from
+    /// `progress`.
+    fn due(&mut self, progress: &Progress) -> Option<Status> {
+        let now = Instant::now();
+        let interval = now.duration_since(self.since);
+        if interval < self.interval {
+            return None;
+        }
+        let status = Status::Throughput {
+            bytes: self.bytes,
+            interval,
+            recent: RECENT_SPANS.iter().map(|&s| progress.recent(s)).collect(),
+        };
+        self.since = now;
+        self.bytes = 0;
+        Some(status)
+    }
+}
+
+fn drain(
+    rx: Receiver<Event>,
+    queued: &AtomicUsize,
+    progress: &Progress,
+    sink: &mut (impl Sink + ?Sized),
+) -> Result<(), Error> {
+    for event in rx {
+        queued.fetch_sub(1, Ordering::Relaxed);
+        match event {
+            Event::Data(info, data) => {
+                sink.fragment(&info, &data)?;
+                progress.passed(data.len() as u64, 0);
+            }
+            Event::Gap(gap) => {
+                sink.lost(&gap)?;
+                progress.passed(0, 1);
+            }
+            Event::BitRate(rate) => sink.bit_rate(rate)?,
+            Event::FillLevels(levels) => sink.fill_levels(&levels)?,
+        }
+    }
+    Ok(())
+}
+
+struct Reader {
+    tx: SyncSender<Event>,
+    queued: Arc<AtomicUsize>,
+    stats: Arc<Progress>,
+    /// Whether we're currently throwing data away because the queue is full.
+    dropping: bool,
+    stop: Option<Arc<AtomicBool>>,
+    /// Whether to pass on fill levels.
+    fill_levels: bool,
+    observer: Option<Observer>,
+    throughput: Option<Throughput>,
+}
+
+impl Reader {
+    fn poll_loop(
+        &mut self,
+        source: &mut impl Source,
+        mut pacer: Pacer,
+        reconnect: Option<Duration>,
+    ) -> Result<(), Error> {
+        let mut buffer = vec![0; source.response_size()];
+        let mut last: Option<(u8, u16)> = None;
+
+        let mut bit_rate = source.bit_rate();
+        self.report(Status::Started { bit_rate });
+        if let Some(rate) = bit_rate {
+            if !self.send(Event::BitRate(rate)) {
+                return Ok(());
+            }
+        }
+
+        while !self.stopping() {
+            let (epoch, result) = match source.poll(&mut buffer) {
+                Ok(r) => r,
+                Err(e) if e.is_disconnect() => {
+                    let interval = match reconnect {
+                        Some(i) => i,
+                        None => return Err(e),
+                    };
+                    self.report(Status::Disconnected {
+                        error: e.to_string(),
+                    });
+                    if !self.send(Self::gap(GapCause::Disconnected)) {
+                        return Ok(());
+                    }
+                    self.reconnect(source, interval)?;
+                    // Perhaps not the same probe, or the same firmware.
+                    buffer.resize(source.response_size(), 0);
+                    let new_rate = source.bit_rate();
+                    self.report(Status::Reconnected { bit_rate: new_rate });
+                    if new_rate != bit_rate {
+                        // Only possible if the bit rate doesn't have to be
+                        // exact; otherwise setup would have failed.
+                        if let (Some(old), Some(new)) = (bit_rate, new_rate) {
+                            self.report(Status::BitRateChanged { old, new });
+                        }
+                        if let Some(rate) = new_rate {
+                            if !self.send(Event::BitRate(rate)) {
+                                return Ok(());
+                            }
+                        }
+                        bit_rate = new_rate;
+                    }
+                    last = None;
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            let received = Instant::now();
+            let info = |start, end| Fragment {
+                received,
+                epoch,
+                start,
+                end,
+            };
+            let levels = match &result {
+                PollResult::Empty => Some((0, 0)),
+                PollResult::Incremental { start, end, .. } => {
+                    Some((*start, *end))
+                }
+                PollResult::Total(_) => None,
+            };
+            if let Some((start, end)) = levels {
+                let levels = FillLevels {
+                    received,
+                    epoch,
+                    start,
+                    end,
+                };
+                if !self.send_fill_levels(levels) {
+                    return Ok(());
+                }
+            }
+            let wait = pacer.after_poll(match &result {
+                PollResult::Empty => None,
+                PollResult::Incremental { fragment, .. } => {
+                    Some(fragment.len())
+                }
+                PollResult::Total(packet) => Some(packet.len()),
+            });
+            let due = match &mut self.throughput {
+                Some(throughput) => throughput.due(&self.stats),
+                None => None,
+            };
+            if let Some(status) = due {
+                self.report(status);
+            }
+            let sent = match result {
+                PollResult::Empty => {
+                    // Try back in a bit.
+                    sleep(wait);
+                    true
+                }
+                PollResult::Incremental {
+                    start,
+                    end,
+                    fragment,
+                } => {
+                    let continuous = if let Some((last_epoch, last_end)) = last
+                    {
+                        last_epoch == epoch && last_end == start
+                    } else {
+                        // If this is the first data we've seen, we'll accept
+                        // it unconditionally.
+                        true
+                    };
+
+                    last = Some((epoch, end));
+                    if !continuous {
+                        self.report(Status::SyncLost {
+                            epoch,
+                            offset: start,
+                        });
+                        Progress::add(&self.stats.probe_gaps, 1);
+                        self.send(Self::gap(GapCause::SyncLost))
+                            && self.send(Event::Data(
+                                info(start, end),
+                                fragment.to_vec(),
+                            ))
+                    } else {
+                        self.send(Event::Data(
+                            info(start, end),
+                            fragment.to_vec(),
+                        ))
+                    }
+                }
+                PollResult::Total(packet) => {
+                    Progress::add(&self.stats.flushes, 1);
+                    let sent = if let Some((last_epoch, last_end)) = last {
+                        if epoch == last_epoch {
+                            // We need to collect the tail of the data for this
+                            // epoch from the end of the packet buffer.
+                            let tail = &packet[usize::from(last_end)..];
+                            self.send(Event::Data(
+                                info(last_end, packet.len() as u16),
+                                tail.to_vec(),
+                            ))
+                        } else {
+                            self.report(Status::Overflow { epoch });
+                            Progress::add(&self.stats.probe_gaps, 1);
+                            Progress::add(&self.stats.overflows, 1);
+                            self.send(Self::gap(GapCause::Overflow))
+                        }
+                    } else {
+                        // This is kind of a boring first packet, but ok.
+                        self.send(Event::Data(
+                            info(0, packet.len() as u16),
+                            packet.to_vec(),
+                        ))
+                    };
+                    last = Some((epoch.wrapping_add(1), 0));
+                    sent
+                }
+            };
+            if !sent {
+                // The sink has gone away; it'll report why.
+                return Ok(());
+            }
+        }
+        Ok(())
+    }
+
+    /// Tries to reconnect to `source` every `interval` until it works.
+    fn reconnect(
+        &mut self,
+        source: &mut impl Source,
+        interval: Duration,
+    ) -> Result<(), Error> {
+        loop {
+            sleep(interval);
+            if self.stopping() {
+                return Ok(());
+            }
+            match source.reconnect() {
+                Ok(()) => break,
+                Err(e @ Error::Unsupported(_)) => return Err(e),
+                // The probe's back, but won't capture at the rate we were
+                // using, and waiting won't change its mind.
+                Err(e @ Error::UnachievableBitRate { .. }) => return Err(e),
+                Err(e) => log::debug!("reconnect failed: {}", e),
+            }
+        }
+        Progress::add(&self.stats.reconnects, 1);
+        Ok(())
+    }
+
+    /// Makes a gap event, noticed now.
+    fn gap(cause: GapCause) -> Event {
+        Event::Gap(Gap {
+            noticed: Instant::now(),
+            cause,
+        })
+    }
+
+    fn report(&self, status: Status) {
+        report(self.observer.as_ref(), status);
+    }
+
+    fn stopping(&self) -> bool {
+        self.stop
+            .as_ref()
+            .is_some_and(|s| s.load(Ordering::Relaxed))
+    }
+
+    /// Queues `event` for the sink, dropping it if the queue is full. Returns
+    /// `false` if the sink has stopped listening.
+    fn send(&mut self, event: Event) -> bool {
+        if let (Event::Data(_, data), Some(t)) = (&event, &mut self.throughput)
+        {
+            t.add(data.len());
+        }
+        if self.dropping {
+            // Once we've dropped data, the sink has to hear about the gap
+            // before anything else.
+            match self.try_send(Self::gap(GapCause::HostDrop)) {
+                Ok(()) => self.dropping = false,
+                Err(TrySendError::Full(_)) => {
+                    self.drop_event(&event);
+                    return true;
+                }
+                Err(TrySendError::Disconnected(_)) => return false,
+            }
+        }
+
+        match self.try_send(event) {
+            Ok(()) => true,
+            Err(TrySendError::Full(event)) => {
+                self.report(Status::HostDrop);
+                Progress::add(&self.stats.host_drops, 1);
+                self.dropping = true;
+                self.drop_event(&event);
+                true
+            }
+            Err(TrySendError::Disconnected(_)) => false,
+        }
+    }
+
+    /// Queues fill levels for the sink, if it wants them. Unlike data, these
+    /// are quietly skipped if the queue is full, or if we're already dropping
+    /// data, so that they never cause a gap. Returns `false` if the sink has
+    /// stopped listening.
+    fn send_fill_levels(&mut self, levels: FillLevels) -> bool {
+        if !self.fill_levels || self.dropping {
+            return true;
+        }
+        !matches!(
+            self.try_send(Event::FillLevels(levels)),
+            Err(TrySendError::Disconnected(_))
+        )
+    }
+
+    fn drop_event(&mut self, event: &Event) {
+        if let Event::Data(_, data) = event {
+            Progress::add(&self.stats.host_dropped_bytes, data.len() as u64);
+        }
+    }
+
+    /// Sends `event`, keeping count of how many events are queued.
+    fn try_send(&mut self, event: Event) -> Result<(), TrySendError<Event>> {
+        // Count the event before sending it, or the sink could take it off
+        // the queue (and the count) first.
+        let n = self.queued.fetch_add(1, Ordering::Relaxed) + 1;
+        match self.tx.try_send(event) {
+            Ok(()) => {
+                self.stats.queue_high_water.fetch_max(n, Ordering::Relaxed);
+                Ok(())
+            }
+            Err(e) => {
+                self.queued.fetch_sub(1, Ordering::Relaxed);
+                Err(e)
+            }
+        }
+    }
+}
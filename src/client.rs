@@ -0,0 +1,203 @@
+//! Client for `lpc-cat serve --framed`, for tools that want to consume a
+//! capture over the network.
+//!
+//! This is only available with the `client` feature.
+
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::thread::sleep;
+use std::time::Duration;
+
+use crate::framing::{self, Attach, FrameReader};
+
+pub use crate::framing::Attach as Start;
+
+/// Default time to wait between attempts to reconnect.
+const DEFAULT_RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A run of data received from the server.
+#[derive(Clone, Debug)]
+pub struct Chunk {
+    /// Offset of the first byte of `data` in the server's stream.
+    pub offset: u64,
+    pub data: Vec<u8>,
+    /// The probe lost data just before this chunk.
+    pub probe_gap: bool,
+    /// Number of bytes we expected to receive before this chunk but didn't,
+    /// because they were lost between the server and us (including while we
+    /// were reconnecting).
+    pub bytes_lost: u64,
+}
+
+/// Connection to a framing `lpc-cat` server that transparently reconnects
+/// and picks up where it left off.
+pub struct Client {
+    addr: String,
+    /// Where to start if we haven't received anything yet.
+    start: Attach,
+    /// Token to present to servers that require one.
+    token: Option<String>,
+    reconnect_interval: Option<Duration>,
+    reader: Option<FrameReader<TcpStream>>,
+    /// Offset of the next byte we expect.
+    next_offset: Option<u64>,
+}
+
+impl Client {
+    /// Connects to the server at `addr`, starting with live data.
+    pub fn connect(addr: &str) -> io::Result<Self> {
+        Self::connect_at(addr, Start::Live)
+    }
+
+    /// Connects to the server at `addr`, asking to start at a particular
+    /// point in the stream. How far back we can go depends on how much
+    /// history the server keeps.
+    pub fn connect_at(addr: &str, start: Start) -> io::Result<Self> {
+        Self::open(addr, start, None)
+    }
+
+    /// Like `connect_at`, but for servers that require a token.
+    pub fn connect_with_token(
+        addr: &str,
+        start: Start,
+        token: &str,
+    ) -> io::Result<Self> {
+        Self::open(addr, start, Some(token.to_string()))
+    }
+
+    fn open(
+        addr: &str,
+        start: Start,
+        token: Option<String>,
+    ) -> io::Result<Self> {
+        if start == Start::Status {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "use `client::status` to ask for the server's status",
+            ));
+        }
+        let mut client = Self {
+            addr: addr.to_string(),
+            start,
+            token,
+            reconnect_interval: Some(DEFAULT_RECONNECT_INTERVAL),
+            reader: None,
+            next_offset: match start {
+                Start::Resume(offset) => Some(offset),
+                _ => None,
+            },
+        };
+        client.attach()?;
+        Ok(client)
+    }
+
+    /// Sets how long to wait between attempts to reconnect after the
+    /// connection fails. `None` disables reconnection, so that errors are
+    /// returned from `next_chunk` instead.
+    pub fn set_reconnect_interval(&mut self, interval: Option<Duration>) {
+        self.reconnect_interval = interval;
+    }
+
+    /// Offset of the next byte we expect to receive, if we've received
+    /// anything yet.
+    pub fn offset(&self) -> Option<u64> {
+        self.next_offset
+    }
+
+    /// Receives the next chunk of data, blocking until one arrives.
+    ///
+    /// Returns `Ok(None)` if the server closed the connection and
+    /// reconnection is disabled.
+    pub fn next_chunk(&mut self) -> io::Result<Option<Chunk>> {
+        loop {
+            let result = match &mut self.reader {
+                Some(reader) => reader.next_frame(),
+                None => Ok(None),
+            };
+            let failure = match result {
+                Ok(Some(mut frame)) => {
+                    let end = frame.offset + frame.payload.len() as u64;
+                    let bytes_lost = match self.next_offset {
+                        Some(expected) if end <= expected => {
+                            // We've seen all of this before.
+                            continue;
+                        }
+                        Some(expected) if frame.offset < expected => {
+                            // Or some of it, anyway.
+                            let seen = (expected - frame.offset) as usize;
+                            frame.payload.drain(..seen);
+                            frame.offset = expected;
+                            0
+                        }
+                        Some(expected) => frame.offset - expected,
+                        None => 0,
+                    };
+                    self.next_offset = Some(end);
+                    return Ok(Some(Chunk {
+                        offset: frame.offset,
+                        data: frame.payload,
+                        probe_gap: frame.probe_gap,
+                        bytes_lost,
+                    }));
+                }
+                Ok(None) => None,
+                Err(e) => Some(e),
+            };
+
+            self.reader = None;
+            let interval = match (self.reconnect_interval, failure) {
+                (Some(interval), failure) => {
+                    if let Some(e) = failure {
+                        log::warn!("connection to {} failed: {}", self.addr, e);
+                    }
+                    interval
+                }
+                (None, Some(e)) => return Err(e),
+                (None, None) => return Ok(None),
+            };
+
+            while self.reader.is_none() {
+                sleep(interval);
+                if let Err(e) = self.attach() {
+                    log::debug!("reconnecting to {}: {}", self.addr, e);
+                }
+            }
+            log::info!("reconnected to {}", self.addr);
+        }
+    }
+
+    fn attach(&mut self) -> io::Result<()> {
+        let mut stream = TcpStream::connect(&self.addr)?;
+        if let Some(token) = &self.token {
+            stream.write_all(&framing::encode_auth(token)?)?;
+        }
+        let req = match self.next_offset {
+            Some(offset) => Attach::Resume(offset),
+            None => self.start,
+        };
+        stream.write_all(&req.encode())?;
+        self.reader = Some(FrameReader::new(stream));
+        Ok(())
+    }
+}
+
+/// Asks the server at `addr` how capture is going, presenting `token` if
+/// it requires one. Returns the line of JSON described in the `framing`
+/// module, without the newline.
+pub fn status(addr: &str, token: Option<&str>) -> io::Result<String> {
+    let mut stream = TcpStream::connect(addr)?;
+    if let Some(token) = token {
+        stream.write_all(&framing::encode_auth(token)?)?;
+    }
+    stream.write_all(&Attach::Status.encode())?;
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line)?;
+    if !line.ends_with('\n') {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "server hung up without sending its status",
+        ));
+    }
+    line.pop();
+    Ok(line)
+}
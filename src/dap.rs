@@ -0,0 +1,234 @@
+//! Just enough of CMSIS-DAP to poke at the target's memory through the
+//! probe's debug port, so we can set the target up to emit SWO.
+//!
+//! This speaks CMSIS-DAP v1 (HID) over SWD to the first MEM-AP, which is
+//! where Cortex-M parts keep their system memory map. It's not a general
+//! debugger: there's no support for JTAG, multi-drop SWD, or other APs.
+
+use std::convert::TryInto;
+
+use crate::interfaces::Function;
+use crate::select::{self, Selector};
+use crate::Error;
+
+/// CMSIS-DAP command numbers.
+mod cmd {
+    pub const CONNECT: u8 = 0x02;
+    pub const DISCONNECT: u8 = 0x03;
+    pub const TRANSFER_CONFIGURE: u8 = 0x04;
+    pub const TRANSFER: u8 = 0x05;
+    pub const SWJ_CLOCK: u8 = 0x11;
+    pub const SWJ_SEQUENCE: u8 = 0x12;
+    pub const SWD_CONFIGURE: u8 = 0x13;
+}
+
+/// `DAP_Connect` port number for SWD.
+const PORT_SWD: u8 = 1;
+/// SWD clock to use, in Hz. Slow enough for long wires and sleepy targets;
+/// we only move a handful of words.
+const SWD_CLOCK: u32 = 1_000_000;
+
+/// Debug port registers, by address.
+mod dp {
+    pub const DPIDR: u8 = 0x0;
+    pub const ABORT: u8 = 0x0;
+    pub const CTRL_STAT: u8 = 0x4;
+    pub const SELECT: u8 = 0x8;
+}
+
+/// MEM-AP registers, by address within bank 0.
+mod ap {
+    pub const CSW: u8 = 0x0;
+    pub const TAR: u8 = 0x4;
+    pub const DRW: u8 = 0xC;
+}
+
+/// `CTRL/STAT` power-up requests and acknowledgements for the system and
+/// debug domains.
+const POWER_UP_REQ: u32 = 0x5000_0000;
+const POWER_UP_ACK: u32 = 0xA000_0000;
+/// `ABORT` bits clearing all sticky errors.
+const CLEAR_ERRORS: u32 = 0x1E;
+/// `CSW` for single 32-bit accesses with no address increment, as a
+/// privileged debugger.
+const CSW_WORD: u32 = 0x2300_0002;
+
+/// `DAP_Transfer` request bits.
+const REQ_AP: u8 = 1 << 0;
+const REQ_READ: u8 = 1 << 1;
+/// `DAP_Transfer` acknowledgement for success.
+const ACK_OK: u8 = 1;
+
+/// An open connection to the CMSIS-DAP interface of an LPC-Link2, attached
+/// to the target over SWD.
+pub struct Dap(hidapi::HidDevice);
+
+impl Dap {
+    /// Opens the CMSIS-DAP interface of the probe with the given vid/pid that
+    /// satisfies `selector`, and connects to the target.
+    ///
+    /// If several devices match, the first one will be chosen.
+    pub fn open(
+        vid: u16,
+        pid: u16,
+        selector: &Selector,
+    ) -> Result<Self, Error> {
+        let api = hidapi::HidApi::new()?;
+
+        let path = select::enumerate(&api, vid, pid, selector)
+            .iter()
+            .find_map(|p| p.hid_path(Function::CmsisDap).map(String::from))
+            .ok_or(Error::DeviceNotFound)?;
+
+        log::info!("found CMSIS-DAP interface at {}", path);
+
+        // Paths come from C strings in the first place, so can't contain NUL.
+        let device = api.open_path(&std::ffi::CString::new(path).unwrap())?;
+
+        let dap = Self(device);
+        dap.connect()?;
+        Ok(dap)
+    }
+
+    /// Sends a command and returns the response, less the echoed command
+    /// byte.
+    fn command(&self, request: &[u8]) -> Result<Vec<u8>, Error> {
+        // Unlike the trace interface, commands here include zero, which
+        // hidapi would take as a report number. So give it one.
+        let mut packet = Vec::with_capacity(request.len() + 1);
+        packet.push(0);
+        packet.extend_from_slice(request);
+        self.0.write(&packet)?;
+
+        let mut response = vec![0; 1024];
+        let n = self.0.read(&mut response)?;
+        response.truncate(n);
+
+        if response.first() != Some(&request[0]) {
+            return Err(Error::Protocol {
+                command: request[0],
+                reason: "response is for a different command",
+            });
+        }
+        response.remove(0);
+        Ok(response)
+    }
+
+    /// Sends a command whose response is just a status byte.
+    fn simple_command(&self, request: &[u8]) -> Result<(), Error> {
+        match self.command(request)?.first() {
+            Some(0) => Ok(()),
+            _ => Err(Error::Protocol {
+                command: request[0],
+                reason: "command failed",
+            }),
+        }
+    }
+
+    /// Switches the debug port to SWD and powers up the debug domain.
+    fn connect(&self) -> Result<(), Error> {
+        match self.command(&[cmd::CONNECT, PORT_SWD])?.first() {
+            Some(&PORT_SWD) => (),
+            _ => return Err(Error::Unsupported("SWD")),
+        }
+        let mut clock = [cmd::SWJ_CLOCK, 0, 0, 0, 0];
+        clock[1..].copy_from_slice(&SWD_CLOCK.to_le_bytes());
+        self.simple_command(&clock)?;
+        // No idle cycles, and generous retry counts for WAIT responses.
+        self.simple_command(&[cmd::TRANSFER_CONFIGURE, 0, 0xFF, 0, 0, 0])?;
+        self.simple_command(&[cmd::SWD_CONFIGURE, 0])?;
+
+        // Line reset, the JTAG-to-SWD switch sequence, another line reset,
+        // and some idle cycles.
+        const RESET: [u8; 7] = [0xFF; 7];
+        self.swj_sequence(56, &RESET)?;
+        self.swj_sequence(16, &0xE79E_u16.to_le_bytes())?;
+        self.swj_sequence(56, &RESET)?;
+        self.swj_sequence(8, &[0])?;
+
+        // Reading DPIDR is required to leave the reset state.
+        let idr = self.read_dp(dp::DPIDR)?;
+        log::info!("target DPIDR: {:#010x}", idr);
+
+        self.write_dp(dp::ABORT, CLEAR_ERRORS)?;
+        self.write_dp(dp::SELECT, 0)?;
+        self.write_dp(dp::CTRL_STAT, POWER_UP_REQ)?;
+        for _ in 0..100 {
+            if self.read_dp(dp::CTRL_STAT)? & POWER_UP_ACK == POWER_UP_ACK {
+                return self.write_ap(ap::CSW, CSW_WORD);
+            }
+        }
+        Err(Error::Target("debug domain didn't power up"))
+    }
+
+    fn swj_sequence(&self, bits: u8, data: &[u8]) -> Result<(), Error> {
+        let mut request = vec![cmd::SWJ_SEQUENCE, bits];
+        request.extend_from_slice(data);
+        self.simple_command(&request)
+    }
+
+    /// Performs a single SWD transfer, returning the data read (or zero, for
+    /// writes).
+    fn transfer(&self, request: u8, data: u32) -> Result<u32, Error> {
+        let mut packet = vec![cmd::TRANSFER, 0, 1, request];
+        if request & REQ_READ == 0 {
+            packet.extend_from_slice(&data.to_le_bytes());
+        }
+        let response = self.command(&packet)?;
+        match response.get(..2) {
+            Some([1, ACK_OK]) => (),
+            Some([_, ack]) => {
+                log::debug!("SWD transfer {:#04x} got ack {:#x}", request, ack);
+                return Err(Error::Target("target didn't acknowledge access"));
+            }
+            _ => {
+                return Err(Error::Protocol {
+                    command: cmd::TRANSFER,
+                    reason: "truncated response",
+                })
+            }
+        }
+        if request & REQ_READ == 0 {
+            return Ok(0);
+        }
+        response
+            .get(2..6)
+            .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+            .ok_or(Error::Protocol {
+                command: cmd::TRANSFER,
+                reason: "truncated response",
+            })
+    }
+
+    fn read_dp(&self, addr: u8) -> Result<u32, Error> {
+        self.transfer(REQ_READ | addr, 0)
+    }
+
+    fn write_dp(&self, addr: u8, value: u32) -> Result<(), Error> {
+        self.transfer(addr, value).map(drop)
+    }
+
+    fn write_ap(&self, addr: u8, value: u32) -> Result<(), Error> {
+        self.transfer(REQ_AP | addr, value).map(drop)
+    }
+
+    /// Reads a word of target memory.
+    pub fn read_word(&self, addr: u32) -> Result<u32, Error> {
+        self.write_ap(ap::TAR, addr)?;
+        // The probe takes care of AP reads being posted.
+        self.transfer(REQ_AP | REQ_READ | ap::DRW, 0)
+    }
+
+    /// Writes a word of target memory.
+    pub fn write_word(&self, addr: u32, value: u32) -> Result<(), Error> {
+        self.write_ap(ap::TAR, addr)?;
+        self.write_ap(ap::DRW, value)
+    }
+}
+
+impl Drop for Dap {
+    fn drop(&mut self) {
+        // Leaves the target running; this just releases the debug port.
+        let _ = self.command(&[cmd::DISCONNECT]);
+    }
+}
@@ -0,0 +1,222 @@
+//! Decoding defmt log frames carried over an ITM stimulus port.
+//!
+//! This is only available with the `defmt` feature.
+
+use std::error::Error;
+use std::io::{self, Write};
+use std::time::{Instant, SystemTime};
+
+use defmt_decoder::{DecodeError, Encoding, Frame, Table};
+
+use crate::capture::{Fragment, Sink};
+use crate::itm;
+use crate::timestamp::{self, Clock, Correlator};
+
+/// Sink that decodes ITM, extracts one stimulus port, and decodes the defmt
+/// frames carried on it, printing log lines to `out`.
+pub struct DefmtSink<W> {
+    frames: Frames,
+    itm: itm::Decoder,
+    port: u8,
+    color: bool,
+    /// How to time log lines, if they're to be timestamped.
+    timestamps: Option<Correlator>,
+    out: W,
+}
+
+impl<W: Write> DefmtSink<W> {
+    /// Loads the defmt table from the contents of the target's ELF file.
+    ///
+    /// If `color` is set, log lines will be colored by level using ANSI
+    /// escape sequences.
+    pub fn new(
+        elf: &[u8],
+        port: u8,
+        color: bool,
+        out: W,
+    ) -> Result<Self, Box<dyn Error>> {
+        let table = Table::parse(elf)
+            .map_err(|e| format!("can't read defmt table from ELF: {}", e))?
+            .ok_or("ELF doesn't contain defmt data")?;
+        Ok(Self {
+            frames: Frames::new(table),
+            itm: itm::Decoder::new(),
+            port,
+            color,
+            timestamps: None,
+            out,
+        })
+    }
+
+    /// Starts each log line with the wall clock time the end of its frame
+    /// was sent, as for `ports::Console::set_timestamps`.
+    pub fn set_timestamps(&mut self, frequency: Option<u32>) {
+        self.timestamps = Some(Correlator::new(frequency));
+    }
+
+    /// Decodes `bytes`, which arrived at `received`, and prints any log
+    /// lines they complete.
+    fn decode(&mut self, received: Instant, bytes: &[u8]) -> io::Result<()> {
+        let port = self.port;
+        let mut packets = vec![];
+        self.itm.feed(bytes, |packet| match packet {
+            itm::Packet::Instrumentation { port: p, .. } if p == port => {
+                packets.push(packet)
+            }
+            itm::Packet::LocalTimestamp { .. } => packets.push(packet),
+            _ => (),
+        });
+        let times = self.timestamps.as_mut().map(|t| {
+            t.arrived(received);
+            t.times(&packets)
+        });
+        for (i, packet) in packets.into_iter().enumerate() {
+            if let itm::Packet::Instrumentation { payload, .. } = packet {
+                self.frames.received(&payload);
+                // Frames are printed as soon as they're complete, so that
+                // each gets the time of the packet that completed it.
+                self.drain(times.as_ref().map(|t| t[i]))?;
+            }
+        }
+        self.out.flush()
+    }
+
+    /// Prints the frames decoded so far, as sent at `time` if known.
+    fn drain(&mut self, time: Option<SystemTime>) -> io::Result<()> {
+        let (out, color) = (&mut self.out, self.color);
+        self.frames.decode(|frame| match frame {
+            Ok(frame) => print(out, color, time, &frame),
+            Err(_) => {
+                log::warn!("malformed defmt frame");
+                Ok(())
+            }
+        })
+    }
+}
+
+/// Prints `frame` to `out` as a log line, as sent at `time` if known.
+fn print<W: Write>(
+    out: &mut W,
+    color: bool,
+    time: Option<SystemTime>,
+    frame: &Frame<'_>,
+) -> io::Result<()> {
+    let level = frame.level().map(|l| l.as_str());
+    let (start, end) = match (color, level) {
+        (true, Some("trace")) => ("\x1b[2m", "\x1b[0m"),
+        (true, Some("debug")) => ("\x1b[37m", "\x1b[0m"),
+        (true, Some("info")) => ("\x1b[32m", "\x1b[0m"),
+        (true, Some("warn")) => ("\x1b[33m", "\x1b[0m"),
+        (true, Some("error")) => ("\x1b[31m", "\x1b[0m"),
+        _ => ("", ""),
+    };
+    if let Some(time) = time {
+        write!(out, "{} ", timestamp::format(time))?;
+    }
+    if let Some(ts) = frame.display_timestamp() {
+        write!(out, "{} ", ts)?;
+    }
+    writeln!(
+        out,
+        "{}{:<5}{} {}",
+        start,
+        level.unwrap_or("print").to_uppercase(),
+        end,
+        frame.display_message(),
+    )
+}
+
+/// Splits a stream into defmt frames and decodes them, owning the table they
+/// refer to. (defmt-decoder's own stream decoders borrow the table, which
+/// would make `DefmtSink` borrow it too.)
+struct Frames {
+    table: Table,
+    /// Bytes received but not yet decoded.
+    pending: Vec<u8>,
+}
+
+impl Frames {
+    fn new(table: Table) -> Self {
+        Self {
+            table,
+            pending: vec![],
+        }
+    }
+
+    fn received(&mut self, bytes: &[u8]) {
+        self.pending.extend_from_slice(bytes);
+    }
+
+    /// Drops anything half-received.
+    fn reset(&mut self) {
+        self.pending.clear();
+    }
+
+    /// Decodes the frames completed so far, passing each to `f`, or
+    /// `DecodeError::Malformed` in place of one that can't be decoded.
+    fn decode<F>(&mut self, mut f: F) -> io::Result<()>
+    where
+        F: FnMut(Result<Frame<'_>, DecodeError>) -> io::Result<()>,
+    {
+        match self.table.encoding() {
+            Encoding::Raw => loop {
+                match self.table.decode(&self.pending) {
+                    Ok((frame, len)) => {
+                        f(Ok(frame))?;
+                        self.pending.drain(..len);
+                    }
+                    Err(DecodeError::UnexpectedEof) => return Ok(()),
+                    Err(DecodeError::Malformed) => {
+                        // Without framing, we've lost our place for good.
+                        self.pending.clear();
+                        return f(Err(DecodeError::Malformed));
+                    }
+                }
+            },
+            _ => {
+                // Frames end with a zero byte, so they can be cut out here
+                // and each handed to a fresh stream decoder, which undoes
+                // the encoding.
+                while let Some(end) = self.pending.iter().position(|&b| b == 0)
+                {
+                    let frame: Vec<u8> = self.pending.drain(..=end).collect();
+                    if frame.len() == 1 {
+                        // Just padding between frames.
+                        continue;
+                    }
+                    let mut decoder = self.table.new_stream_decoder();
+                    decoder.received(&frame);
+                    f(decoder.decode())?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl<W: Write> Sink for DefmtSink<W> {
+    fn data(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.decode(Instant::now(), bytes)
+    }
+
+    fn fragment(&mut self, info: &Fragment, bytes: &[u8]) -> io::Result<()> {
+        self.decode(info.received, bytes)
+    }
+
+    fn gap(&mut self) -> io::Result<()> {
+        // Anything half-decoded is garbage now.
+        self.itm.reset();
+        self.frames.reset();
+        if let Some(timestamps) = &mut self.timestamps {
+            timestamps.gap();
+        }
+        writeln!(self.out, "(data lost)")
+    }
+
+    fn clock(&mut self, clock: Clock) -> io::Result<()> {
+        if let Some(timestamps) = &mut self.timestamps {
+            timestamps.set_clock(clock);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,86 @@
+//! Splitting an ITM stream up by stimulus port, so that each port's data can
+//! go to its own file.
+
+use std::io::{self, BufWriter, Write};
+
+use crate::capture::{is_closed, Sink};
+use crate::itm;
+
+/// Sink that decodes ITM and writes the payload of each stimulus port of
+/// interest to its own output. Everything else is discarded.
+///
+/// As with `capture::Tee`, an output whose reader goes away is dropped, as
+/// long as there are others.
+pub struct Demux {
+    itm: itm::Decoder,
+    outputs: Vec<(u8, BufWriter<Box<dyn Write>>)>,
+}
+
+impl Demux {
+    pub fn new() -> Self {
+        Self {
+            itm: itm::Decoder::new(),
+            outputs: vec![],
+        }
+    }
+
+    /// Sends the payload of stimulus port `port` to `out`, replacing any
+    /// output previously set for that port.
+    pub fn add_port(&mut self, port: u8, out: impl Write + 'static) {
+        let out = BufWriter::new(Box::new(out) as Box<dyn Write>);
+        match self.outputs.iter_mut().find(|(p, _)| *p == port) {
+            Some(entry) => entry.1 = out,
+            None => self.outputs.push((port, out)),
+        }
+    }
+}
+
+impl Default for Demux {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Sink for Demux {
+    fn data(&mut self, bytes: &[u8]) -> io::Result<()> {
+        let outputs = &mut self.outputs;
+        let mut failed: Vec<(u8, io::Error)> = vec![];
+        self.itm.feed(bytes, |packet| {
+            if let itm::Packet::Instrumentation { port, payload } = packet {
+                if failed.iter().any(|(p, _)| *p == port) {
+                    return;
+                }
+                if let Some((_, out)) =
+                    outputs.iter_mut().find(|(p, _)| *p == port)
+                {
+                    if let Err(e) = out.write_all(&payload) {
+                        failed.push((port, e));
+                    }
+                }
+            }
+        });
+        // Whoever's reading (say, through a FIFO) wants data promptly.
+        for (port, out) in &mut self.outputs {
+            if failed.iter().all(|(p, _)| p != port) {
+                if let Err(e) = out.flush() {
+                    failed.push((*port, e));
+                }
+            }
+        }
+
+        for (port, e) in failed {
+            if !is_closed(&e) || self.outputs.len() == 1 {
+                return Err(e);
+            }
+            log::warn!("output for port {} was closed; carrying on", port);
+            self.outputs.retain(|(p, _)| *p != port);
+        }
+        Ok(())
+    }
+
+    fn gap(&mut self) -> io::Result<()> {
+        // Anything half-decoded is garbage now.
+        self.itm.reset();
+        Ok(())
+    }
+}
@@ -0,0 +1,258 @@
+//! Digests of the captured stream, so that copies of it made downstream (by
+//! `--output`, a recording, or whatever stdout was piped into) can be checked
+//! against what was captured, bit for bit.
+//!
+//! The digest covers the raw stream, as `cat` writes it with no decoding or
+//! `--format`: every byte captured, in order, with nothing marking gaps.
+
+use std::io::{self, Write};
+
+use crate::capture::Sink;
+
+/// Digest algorithms.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Algorithm {
+    Sha256,
+}
+
+impl Algorithm {
+    /// Names of the algorithms, as accepted by `from_str`.
+    pub const NAMES: &'static [&'static str] = &["sha256"];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Algorithm::Sha256 => "sha256",
+        }
+    }
+}
+
+impl std::str::FromStr for Algorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sha256" => Ok(Algorithm::Sha256),
+            _ => Err(format!("unknown digest `{}`", s)),
+        }
+    }
+}
+
+/// SHA-256 round constants.
+const K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1,
+    0x923f82a4, 0xab1c5ed5, 0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3,
+    0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174, 0xe49b69c1, 0xefbe4786,
+    0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147,
+    0x06ca6351, 0x14292967, 0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13,
+    0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85, 0xa2bfe8a1, 0xa81a664b,
+    0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a,
+    0x5b9cca4f, 0x682e6ff3, 0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208,
+    0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+/// Incremental SHA-256 computation.
+#[derive(Clone)]
+pub struct Sha256 {
+    state: [u32; 8],
+    /// Input not yet making up a whole block.
+    block: [u8; 64],
+    /// Bytes of input so far.
+    len: u64,
+}
+
+impl Sha256 {
+    pub fn new() -> Self {
+        Self {
+            state: [
+                0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f,
+                0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+            ],
+            block: [0; 64],
+            len: 0,
+        }
+    }
+
+    pub fn update(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            let used = (self.len % 64) as usize;
+            let n = data.len().min(64 - used);
+            self.block[used..used + n].copy_from_slice(&data[..n]);
+            self.len += n as u64;
+            data = &data[n..];
+            if used + n == 64 {
+                let block = self.block;
+                self.compress(&block);
+            }
+        }
+    }
+
+    pub fn finish(&self) -> [u8; 32] {
+        let mut last = self.clone();
+        let bits = self.len * 8;
+        last.update(&[0x80]);
+        while last.len % 64 != 56 {
+            last.update(&[0]);
+        }
+        last.update(&bits.to_be_bytes());
+        let mut digest = [0; 32];
+        for (out, word) in digest.chunks_exact_mut(4).zip(last.state) {
+            out.copy_from_slice(&word.to_be_bytes());
+        }
+        digest
+    }
+
+    fn compress(&mut self, block: &[u8; 64]) {
+        let mut w = [0u32; 64];
+        for (w, bytes) in w.iter_mut().zip(block.chunks_exact(4)) {
+            *w = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7)
+                ^ w[i - 15].rotate_right(18)
+                ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17)
+                ^ w[i - 2].rotate_right(19)
+                ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16]
+                .wrapping_add(s0)
+                .wrapping_add(w[i - 7])
+                .wrapping_add(s1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] =
+            self.state;
+        for i in 0..64 {
+            let s1 =
+                e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let ch = (e & f) ^ (!e & g);
+            let t1 = h
+                .wrapping_add(s1)
+                .wrapping_add(ch)
+                .wrapping_add(K[i])
+                .wrapping_add(w[i]);
+            let s0 =
+                a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let maj = (a & b) ^ (a & c) ^ (b & c);
+            let t2 = s0.wrapping_add(maj);
+            h = g;
+            g = f;
+            f = e;
+            e = d.wrapping_add(t1);
+            d = c;
+            c = b;
+            b = a;
+            a = t1.wrapping_add(t2);
+        }
+        for (s, v) in self.state.iter_mut().zip([a, b, c, d, e, f, g, h]) {
+            *s = s.wrapping_add(v);
+        }
+    }
+}
+
+impl Default for Sha256 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Sink that digests the stream, and when dropped writes the digest to
+/// `out` as `sha256sum` would for stdin (`<hex>  -`), so that `sha256sum -c`
+/// can check a copy fed to it on stdin.
+pub struct Digest<W: Write> {
+    sha256: Sha256,
+    out: W,
+}
+
+impl<W: Write> Digest<W> {
+    pub fn new(algorithm: Algorithm, out: W) -> Self {
+        match algorithm {
+            Algorithm::Sha256 => Self {
+                sha256: Sha256::new(),
+                out,
+            },
+        }
+    }
+
+    /// Returns the digest of the stream so far, in hex.
+    pub fn hex(&self) -> String {
+        self.sha256
+            .finish()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+}
+
+impl<W: Write> Sink for Digest<W> {
+    fn data(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.sha256.update(bytes);
+        Ok(())
+    }
+}
+
+impl<W: Write> Drop for Digest<W> {
+    fn drop(&mut self) {
+        let line = format!("{}  -\n", self.hex());
+        self.out.write_all(line.as_bytes()).ok();
+        self.out.flush().ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(sha: &Sha256) -> String {
+        sha.finish().iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    fn sha256(data: &[u8]) -> String {
+        let mut sha = Sha256::new();
+        sha.update(data);
+        hex(&sha)
+    }
+
+    #[test]
+    fn known_answers() {
+        let cases: &[(&[u8], &str)] = &[
+            (
+                b"",
+                "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+            ),
+            (
+                b"abc",
+                "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad",
+            ),
+            // The longest input whose length still fits in the last block,
+            // and the shortest that needs another.
+            (
+                &[b'a'; 55],
+                "9f4390f8d30c2dd92ec9f095b65e2b9ae9b0a925a5258e241c9f1e910f734318",
+            ),
+            (
+                &[b'a'; 56],
+                "b35439a4ac6f0948b6d6f9e3c6af0f5f590ce20f1bde7090ef7970686ec6738a",
+            ),
+            (
+                &[b'a'; 1000],
+                "41edece42d63e8d9bf515a9ba6932e1c20cbc9f5a5d134645adb5db1b9737ea3",
+            ),
+        ];
+        for (data, expected) in cases {
+            assert_eq!(sha256(data), *expected, "{} bytes", data.len());
+        }
+    }
+
+    #[test]
+    fn split_updates() {
+        let data: Vec<u8> = (0..1000u32).map(|i| i as u8).collect();
+        let whole = sha256(&data);
+        for &split in &[1, 55, 63, 64, 65, 999] {
+            let mut sha = Sha256::new();
+            sha.update(&data[..split]);
+            sha.update(&data[split..]);
+            assert_eq!(hex(&sha), whole, "split at {}", split);
+        }
+    }
+}
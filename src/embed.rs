@@ -0,0 +1,235 @@
+//! Driving a capture from inside another program, rather than through the
+//! `lpc-cat` binary. This is what the C (`ffi`) and Python (`python`)
+//! bindings wrap.
+//!
+//! A `Capture` is opened, given a bit rate, and started, in that order. Once
+//! it's started, the probe is polled on a background thread, and what it
+//! captures waits in a queue until `read`. The queue holds `queue_depth`
+//! chunks of up to about 1 KiB; if the reader falls behind for long enough
+//! to fill it, data is dropped (and counted in `stats`), just as `lpc-cat`
+//! drops data when its output stalls. A reader that can't keep up with the
+//! probe in bursts should ask for a deeper queue.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::capture::{self, Progress, Sink, Source, Stats};
+use crate::firmware;
+use crate::protocol;
+use crate::select::Selector;
+use crate::synthetic::{self, Synthetic};
+use crate::{Error, Handle};
+
+/// A capture, from setup to the end.
+pub struct Capture {
+    state: State,
+    progress: Arc<Progress>,
+    /// Data received but not yet read.
+    pending: Vec<u8>,
+    /// How much of `pending` has been read.
+    taken: usize,
+}
+
+enum State {
+    /// Opened, but not yet started.
+    Ready(Origin),
+    Running(Running),
+    /// Capture has stopped, and there's nothing left to read.
+    Ended,
+}
+
+/// Where the data comes from.
+enum Origin {
+    Probe(Handle),
+    Synthetic(Box<Synthetic>),
+}
+
+struct Running {
+    rx: Receiver<Vec<u8>>,
+    stop: Arc<AtomicBool>,
+    thread: JoinHandle<Result<Stats, Error>>,
+}
+
+impl Capture {
+    /// Opens the probe with the given vid/pid that satisfies `selector`, and
+    /// sets it up for UART capture.
+    pub fn open(
+        vid: u16,
+        pid: u16,
+        selector: &Selector,
+    ) -> Result<Self, Error> {
+        let handle = Handle::open(vid, pid, selector)?;
+        handle.ohai(protocol::MODE_UART)?;
+        handle.init_uart()?;
+        Ok(Self::from_origin(Origin::Probe(handle)))
+    }
+
+    /// Opens the first probe that satisfies `selector` and is running
+    /// firmware with a trace interface (see `firmware::find`).
+    pub fn find(selector: &Selector) -> Result<Self, Error> {
+        let (vid, pid) = firmware::find(&hidapi::HidApi::new()?, selector)?;
+        Self::open(vid, pid, selector)
+    }
+
+    /// Makes up data instead of capturing it, for testing without hardware.
+    /// See `synthetic`.
+    pub fn synthetic(config: synthetic::Config) -> Self {
+        Self::from_origin(Origin::Synthetic(Box::new(Synthetic::new(config))))
+    }
+
+    fn from_origin(origin: Origin) -> Self {
+        Self {
+            state: State::Ready(origin),
+            progress: Arc::default(),
+            pending: vec![],
+            taken: 0,
+        }
+    }
+
+    /// Sets the bit rate to capture at, before capture starts, and returns
+    /// the rate the probe agreed to. Fails with `UnachievableBitRate` if
+    /// that isn't `rate`, unless `approximate` is set.
+    ///
+    /// Synthetic captures ignore this, and keep the rate they were made
+    /// with.
+    pub fn set_bit_rate(
+        &mut self,
+        rate: u32,
+        approximate: bool,
+    ) -> Result<u32, Error> {
+        let handle = match &self.state {
+            State::Ready(Origin::Probe(handle)) => handle,
+            State::Ready(Origin::Synthetic(_)) => return Ok(rate),
+            _ => return Err(Error::State("capture has already started")),
+        };
+        let actual = handle.set_bit_rate(rate)?;
+        if actual != rate && !approximate {
+            return Err(Error::UnachievableBitRate {
+                requested: rate,
+                closest: actual,
+            });
+        }
+        Ok(actual)
+    }
+
+    /// Starts polling the probe in the background, queueing up to
+    /// `queue_depth` chunks for `read`.
+    pub fn start(&mut self, queue_depth: usize) -> Result<(), Error> {
+        let source: Box<dyn Source> =
+            match std::mem::replace(&mut self.state, State::Ended) {
+                State::Ready(Origin::Probe(handle)) => Box::new(handle),
+                State::Ready(Origin::Synthetic(s)) => s,
+                other => {
+                    self.state = other;
+                    return Err(Error::State("capture has already started"));
+                }
+            };
+        let (tx, rx) = sync_channel(queue_depth);
+        let stop = Arc::new(AtomicBool::new(false));
+        let config = capture::Config {
+            queue_depth,
+            stop: Some(Arc::clone(&stop)),
+            progress: Some(Arc::clone(&self.progress)),
+            // Problems are counted in `stats`, and the caller may have other
+            // uses for stderr.
+            observer: Some(capture::Observer::new(|_| ())),
+            ..capture::Config::default()
+        };
+        let thread = thread::spawn(move || {
+            let mut sink = Forward(tx);
+            capture::run(source, &config, &mut sink)
+        });
+        self.state = State::Running(Running { rx, stop, thread });
+        Ok(())
+    }
+
+    /// Reads captured data into `buffer`, waiting up to `timeout` (or
+    /// forever, if `None`) for some to arrive. Returns the number of bytes
+    /// read, which is 0 if none arrived in time, or `None` once capture has
+    /// stopped and everything it captured has been read.
+    ///
+    /// If capture stopped because of an error, that's returned once all the
+    /// data has been read.
+    pub fn read(
+        &mut self,
+        buffer: &mut [u8],
+        timeout: Option<Duration>,
+    ) -> Result<Option<usize>, Error> {
+        if self.taken == self.pending.len() {
+            let running = match &self.state {
+                State::Running(running) => running,
+                State::Ready(_) => {
+                    return Err(Error::State("capture hasn't started yet"))
+                }
+                State::Ended => return Ok(None),
+            };
+            let received = match timeout {
+                Some(timeout) => running.rx.recv_timeout(timeout),
+                None => running
+                    .rx
+                    .recv()
+                    .map_err(|_| RecvTimeoutError::Disconnected),
+            };
+            self.pending = match received {
+                Ok(data) => data,
+                Err(RecvTimeoutError::Timeout) => return Ok(Some(0)),
+                Err(RecvTimeoutError::Disconnected) => {
+                    // Capture stopped; find out why.
+                    self.stop()?;
+                    return Ok(None);
+                }
+            };
+            self.taken = 0;
+        }
+        let n = buffer.len().min(self.pending.len() - self.taken);
+        buffer[..n].copy_from_slice(&self.pending[self.taken..][..n]);
+        self.taken += n;
+        Ok(Some(n))
+    }
+
+    /// Returns statistics about the capture so far.
+    pub fn stats(&self) -> Stats {
+        self.progress.snapshot()
+    }
+
+    /// Stops capture, discarding anything not yet read, and returns the
+    /// final statistics, or the error that stopped capture early.
+    pub fn stop(&mut self) -> Result<Stats, Error> {
+        self.pending.clear();
+        self.taken = 0;
+        match std::mem::replace(&mut self.state, State::Ended) {
+            State::Running(running) => {
+                running.stop.store(true, Ordering::Relaxed);
+                drop(running.rx);
+                match running.thread.join().expect("capture thread panicked") {
+                    // That'll be us hanging up.
+                    Err(Error::Io(e)) if capture::is_closed(&e) => {
+                        Ok(self.stats())
+                    }
+                    r => r,
+                }
+            }
+            _ => Ok(self.stats()),
+        }
+    }
+}
+
+impl Drop for Capture {
+    fn drop(&mut self) {
+        let _ = self.stop();
+    }
+}
+
+/// Sink handing captured data to the reader.
+struct Forward(std::sync::mpsc::SyncSender<Vec<u8>>);
+
+impl Sink for Forward {
+    fn data(&mut self, bytes: &[u8]) -> std::io::Result<()> {
+        self.0.send(bytes.to_vec()).map_err(|_| {
+            std::io::Error::new(std::io::ErrorKind::BrokenPipe, "reader gone")
+        })
+    }
+}
@@ -0,0 +1,65 @@
+//! Character encodings for text from firmware that doesn't send UTF-8, so
+//! that it can be shown as text (see `ports::Console::set_encoding`). Raw
+//! output is left as the target sent it.
+
+use std::borrow::Cow;
+
+/// How the target encodes text.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum Encoding {
+    #[default]
+    Utf8,
+    /// ISO 8859-1, whose code points are Unicode's first 256.
+    Latin1,
+    /// The original IBM PC character set, with box drawing characters and so
+    /// on above 0x7f. Bytes below 0x20 are taken as control characters, as
+    /// they usually are in a stream of text, rather than as CP437's symbols.
+    Cp437,
+}
+
+impl Encoding {
+    /// Names of the encodings, as accepted by `from_str`.
+    pub const NAMES: &'static [&'static str] = &["utf-8", "latin1", "cp437"];
+
+    /// Converts `bytes` to a string. Invalid UTF-8 is replaced with U+FFFD;
+    /// every byte means something in the other encodings.
+    pub fn decode(self, bytes: &[u8]) -> Cow<'_, str> {
+        match self {
+            Encoding::Utf8 => String::from_utf8_lossy(bytes),
+            Encoding::Latin1 => bytes.iter().map(|&b| char::from(b)).collect(),
+            Encoding::Cp437 => bytes
+                .iter()
+                .map(|&b| match b {
+                    0x80..=0xff => CP437_HIGH[usize::from(b - 0x80)],
+                    _ => char::from(b),
+                })
+                .collect(),
+        }
+    }
+}
+
+impl std::str::FromStr for Encoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "utf-8" | "utf8" => Ok(Encoding::Utf8),
+            "latin1" | "latin-1" | "iso-8859-1" => Ok(Encoding::Latin1),
+            "cp437" | "ibm437" => Ok(Encoding::Cp437),
+            _ => Err(format!("unknown encoding `{}`", s)),
+        }
+    }
+}
+
+/// CP437 characters 0x80 to 0xff.
+#[rustfmt::skip]
+const CP437_HIGH: [char; 128] = [
+    'Ç', 'ü', 'é', 'â', 'ä', 'à', 'å', 'ç', 'ê', 'ë', 'è', 'ï', 'î', 'ì', 'Ä', 'Å',
+    'É', 'æ', 'Æ', 'ô', 'ö', 'ò', 'û', 'ù', 'ÿ', 'Ö', 'Ü', '¢', '£', '¥', '₧', 'ƒ',
+    'á', 'í', 'ó', 'ú', 'ñ', 'Ñ', 'ª', 'º', '¿', '⌐', '¬', '½', '¼', '¡', '«', '»',
+    '░', '▒', '▓', '│', '┤', '╡', '╢', '╖', '╕', '╣', '║', '╗', '╝', '╜', '╛', '┐',
+    '└', '┴', '┬', '├', '─', '┼', '╞', '╟', '╚', '╔', '╩', '╦', '╠', '═', '╬', '╧',
+    '╨', '╤', '╥', '╙', '╘', '╒', '╓', '╫', '╪', '┘', '┌', '█', '▄', '▌', '▐', '▀',
+    'α', 'ß', 'Γ', 'π', 'Σ', 'σ', 'µ', 'τ', 'Φ', 'Θ', 'Ω', 'δ', '∞', 'φ', 'ε', '∩',
+    '≡', '±', '≥', '≤', '⌠', '⌡', '÷', '≈', '°', '∙', '·', '√', 'ⁿ', '²', '■', '\u{a0}',
+];
@@ -0,0 +1,141 @@
+//! Errors reported by this crate.
+
+use std::fmt;
+use std::io;
+
+/// Things that can go wrong talking to a probe.
+#[derive(Debug)]
+pub enum Error {
+    /// No attached probe matched the criteria given.
+    DeviceNotFound,
+    /// The only matching probe is running firmware that can't capture SWO.
+    /// See `firmware::Support::NoTrace`.
+    Firmware {
+        state: &'static str,
+        advice: &'static str,
+    },
+    /// Communication with the probe over USB failed.
+    Hid(hidapi::HidError),
+    /// Communication with the probe through libusb failed.
+    #[cfg(feature = "bulk")]
+    Usb(rusb::Error),
+    /// The probe sent a response we don't understand, in reply to `command`.
+    Protocol { command: u8, reason: &'static str },
+    /// The probe sent a response to `command` that doesn't hang together,
+    /// which is included in full as `report`.
+    Malformed {
+        command: u8,
+        reason: &'static str,
+        report: Vec<u8>,
+    },
+    /// The probe can't capture at the requested bit rate; the closest it can
+    /// manage is `closest`.
+    UnachievableBitRate { requested: u32, closest: u32 },
+    /// The probe doesn't appear to support something we asked for.
+    Unsupported(&'static str),
+    /// Setting up the target through the probe's debug port failed.
+    Target(&'static str),
+    /// A capture was asked to do something out of order, e.g. to change bit
+    /// rate once started (see `embed::Capture`).
+    State(&'static str),
+    /// Writing captured data somewhere failed.
+    Io(io::Error),
+}
+
+impl Error {
+    /// Whether this is a failure talking to the probe over USB, through
+    /// either transport, which waiting for the probe to come back might fix.
+    pub fn is_disconnect(&self) -> bool {
+        match self {
+            Error::Hid(_) => true,
+            #[cfg(feature = "bulk")]
+            Error::Usb(_) => true,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::DeviceNotFound => write!(f, "can't find matching device"),
+            Error::Firmware { state, advice } => {
+                write!(f, "probe is {}; {}", state, advice)
+            }
+            Error::Hid(e) => write!(f, "USB communication failed: {}", e),
+            #[cfg(feature = "bulk")]
+            Error::Usb(e) => write!(f, "USB communication failed: {}", e),
+            Error::Protocol { command, reason } => write!(
+                f,
+                "protocol violation in response to command {:02x}: {}",
+                command, reason
+            ),
+            Error::Malformed {
+                command,
+                reason,
+                report,
+            } => {
+                write!(
+                    f,
+                    "malformed response to command {:02x}: {} (got {} bytes:",
+                    command,
+                    reason,
+                    report.len()
+                )?;
+                // The interesting part is at the start; the rest is usually
+                // padding or data.
+                for b in report.iter().take(16) {
+                    write!(f, " {:02x}", b)?;
+                }
+                if report.len() > 16 {
+                    write!(f, " ...")?;
+                }
+                write!(f, ")")
+            }
+            Error::UnachievableBitRate { requested, closest } => write!(
+                f,
+                "can't achieve bit rate {} (closest: {})",
+                requested, closest
+            ),
+            Error::Unsupported(what) => {
+                write!(f, "probe doesn't support {}", what)
+            }
+            Error::Target(reason) => {
+                write!(f, "can't set up target: {}", reason)
+            }
+            Error::State(why) => write!(f, "{}", why),
+            Error::Io(e) => write!(f, "I/O error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Hid(e) => Some(e),
+            #[cfg(feature = "bulk")]
+            Error::Usb(e) => Some(e),
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<hidapi::HidError> for Error {
+    fn from(e: hidapi::HidError) -> Self {
+        Error::Hid(e)
+    }
+}
+
+#[cfg(feature = "bulk")]
+impl From<rusb::Error> for Error {
+    fn from(e: rusb::Error) -> Self {
+        Error::Usb(e)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
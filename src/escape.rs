@@ -0,0 +1,232 @@
+//! Optional in-band encoding for the raw output stream, so that a consumer
+//! that can only read stdout can still tell where data was lost, and when it
+//! arrived.
+//!
+//! Captured bytes are passed through unchanged, except for an escape byte
+//! (0xDB by default, but configurable) which introduces a marker:
+//!
+//! ```text
+//! escape, 0x00           = a literal escape byte in the data
+//! escape, 0x01           = gap: data may have been lost here
+//! escape, 0x02, 9, ...   = fragment: the following data was received at
+//!                          once; followed by the capture epoch (u8) and the
+//!                          time it was received, in microseconds since the
+//!                          output started (u64 little endian)
+//! ```
+//!
+//! Markers other than 0x00 and 0x01 are followed by a byte giving the length
+//! of the rest of the marker, so that decoders can skip markers they don't
+//! understand.
+
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
+
+use crate::capture::{Fragment, Sink};
+
+pub const DEFAULT_ESCAPE: u8 = 0xDB;
+
+const LITERAL: u8 = 0x00;
+const GAP: u8 = 0x01;
+const FRAGMENT: u8 = 0x02;
+const FRAGMENT_LEN: u8 = 9;
+
+/// Sink that writes the stream with markers escaped into it.
+pub struct Encoder<W: Write> {
+    inner: W,
+    escape: u8,
+    started: Instant,
+}
+
+impl<W: Write> Encoder<W> {
+    pub fn new(inner: W, escape: u8) -> Self {
+        Self {
+            inner,
+            escape,
+            started: Instant::now(),
+        }
+    }
+
+    /// Gives back the underlying writer.
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W: Write> Sink for Encoder<W> {
+    fn data(&mut self, bytes: &[u8]) -> io::Result<()> {
+        let escape = self.escape;
+        for chunk in bytes.split_inclusive(|&b| b == escape) {
+            self.inner.write_all(chunk)?;
+            if chunk.last() == Some(&escape) {
+                self.inner.write_all(&[LITERAL])?;
+            }
+        }
+        Ok(())
+    }
+
+    fn fragment(&mut self, info: &Fragment, bytes: &[u8]) -> io::Result<()> {
+        let time = info.received.saturating_duration_since(self.started);
+        let mut marker = [0; 12];
+        marker[..4].copy_from_slice(&[
+            self.escape,
+            FRAGMENT,
+            FRAGMENT_LEN,
+            info.epoch,
+        ]);
+        marker[4..].copy_from_slice(&(time.as_micros() as u64).to_le_bytes());
+        self.inner.write_all(&marker)?;
+        self.data(bytes)
+    }
+
+    fn gap(&mut self) -> io::Result<()> {
+        self.inner.write_all(&[self.escape, GAP])
+    }
+}
+
+/// Something found in an escaped stream.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Event {
+    /// A byte of captured data.
+    Data(u8),
+    /// Data may have been lost here.
+    Gap,
+    /// The data that follows was received at once, `time` after the output
+    /// started.
+    Fragment { epoch: u8, time: Duration },
+}
+
+/// Streaming decoder for escaped streams.
+pub struct Decoder {
+    escape: u8,
+    state: State,
+}
+
+#[derive(Copy, Clone)]
+enum State {
+    Data,
+    /// Just saw the escape byte.
+    Escaped,
+    /// Saw a marker code, and waiting for its length.
+    Length(u8),
+    /// Collecting a marker's contents.
+    Marker {
+        code: u8,
+        len: u8,
+        got: u8,
+        buf: [u8; FRAGMENT_LEN as usize],
+    },
+}
+
+impl Decoder {
+    pub fn new(escape: u8) -> Self {
+        Self {
+            escape,
+            state: State::Data,
+        }
+    }
+
+    /// Decodes `bytes`, calling `f` with each event.
+    pub fn feed(&mut self, bytes: &[u8], mut f: impl FnMut(Event)) {
+        for &b in bytes {
+            if let Some(e) = self.push(b) {
+                f(e);
+            }
+        }
+    }
+
+    /// Decodes `bytes`, appending just the captured data to `out`.
+    pub fn strip(&mut self, bytes: &[u8], out: &mut Vec<u8>) {
+        self.feed(bytes, |e| {
+            if let Event::Data(b) = e {
+                out.push(b);
+            }
+        })
+    }
+
+    /// Decodes a single byte, returning an event if it completes one.
+    pub fn push(&mut self, b: u8) -> Option<Event> {
+        match self.state {
+            State::Data if b == self.escape => {
+                self.state = State::Escaped;
+                None
+            }
+            State::Data => Some(Event::Data(b)),
+            State::Escaped => {
+                self.state = State::Data;
+                match b {
+                    LITERAL => Some(Event::Data(self.escape)),
+                    GAP => Some(Event::Gap),
+                    code => {
+                        self.state = State::Length(code);
+                        None
+                    }
+                }
+            }
+            State::Length(code) => {
+                self.state = State::Marker {
+                    code,
+                    len: b,
+                    got: 0,
+                    buf: [0; FRAGMENT_LEN as usize],
+                };
+                self.finish_marker()
+            }
+            State::Marker {
+                code,
+                len,
+                got,
+                mut buf,
+            } => {
+                if let Some(slot) = buf.get_mut(usize::from(got)) {
+                    *slot = b;
+                }
+                self.state = State::Marker {
+                    code,
+                    len,
+                    got: got + 1,
+                    buf,
+                };
+                self.finish_marker()
+            }
+        }
+    }
+
+    /// Returns the event for the current marker if it's complete.
+    fn finish_marker(&mut self) -> Option<Event> {
+        let (code, len, got, buf) = match self.state {
+            State::Marker {
+                code,
+                len,
+                got,
+                buf,
+            } => (code, len, got, buf),
+            _ => return None,
+        };
+        if got < len {
+            return None;
+        }
+        self.state = State::Data;
+        match code {
+            FRAGMENT if len == FRAGMENT_LEN => {
+                let mut time = [0; 8];
+                time.copy_from_slice(&buf[1..]);
+                Some(Event::Fragment {
+                    epoch: buf[0],
+                    time: Duration::from_micros(u64::from_le_bytes(time)),
+                })
+            }
+            _ => {
+                log::debug!("skipping unknown marker {:#04x}", code);
+                None
+            }
+        }
+    }
+}
+
+/// Removes the markers from an escaped stream, leaving just the captured
+/// data.
+pub fn strip(bytes: &[u8], escape: u8) -> Vec<u8> {
+    let mut out = Vec::with_capacity(bytes.len());
+    Decoder::new(escape).strip(bytes, &mut out);
+    out
+}
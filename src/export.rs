@@ -0,0 +1,556 @@
+//! Exporting decoded ITM and DWT packets with their timing, for tools that
+//! analyze traces rather than logs: Trace Compass and babeltrace, which read
+//! Common Trace Format (CTF), and waveform viewers such as GTKWave, which
+//! read VCD.
+//!
+//! Timing comes from the target's timestamp packets. ITM sends these after
+//! the packets they relate to, so packets are held back until the next
+//! timestamp arrives, and then given its time. Local timestamps count cycles
+//! of the timestamp clock since the last one; if the stream has none, global
+//! timestamps are used instead. A stream without either puts everything at
+//! time zero. To bound memory, packets that have waited for a timestamp for
+//! too long are given the last time seen.
+//!
+//! Times are in cycles of the timestamp clock. Given its frequency, the VCD
+//! timescale and the CTF clock are set so that viewers show real time;
+//! otherwise, a cycle is shown as a nanosecond.
+//!
+//! A CTF trace is a directory holding a `metadata` file, which describes the
+//! events (see there for their fields), and a `stream` file. VCD has a
+//! variable for each of the 32 stimulus ports, holding the last value
+//! written to it, and for:
+//!
+//! - `exception`: the exception number being handled, 0 in thread mode;
+//! - `pc`: the last PC sample, or `x` if the core was asleep;
+//! - `counter_wrap`: the last DWT event counter wrap packet's flags;
+//! - `hw<N>`: the last data trace packet with discriminator N;
+//! - `overflow` and `gap`: events marking lost data, on the target and in
+//!   the capture respectively.
+
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use crate::capture::Sink;
+use crate::itm;
+
+/// Packets held back waiting for a timestamp before they're given the last
+/// time seen instead.
+const MAX_PENDING: usize = 4096;
+
+/// Bits of the global timestamp carried by its low packet.
+const GLOBAL_LOW_BITS: u32 = 26;
+
+/// DWT discriminators.
+const COUNTER_WRAP: u8 = 0;
+const EXCEPTION_TRACE: u8 = 1;
+const PC_SAMPLE: u8 = 2;
+const DATA_TRACE: std::ops::RangeInclusive<u8> = 8..=23;
+
+/// Something worth exporting.
+enum Event {
+    Stimulus {
+        port: u8,
+        payload: itm::Payload,
+    },
+    /// `function` is 1 for entry, 2 for exit and 3 for return.
+    Exception {
+        number: u16,
+        function: u8,
+    },
+    /// `None` if the core was asleep.
+    PcSample(Option<u32>),
+    CounterWrap(u8),
+    DataTrace {
+        discriminator: u8,
+        payload: itm::Payload,
+    },
+    Overflow,
+    Gap,
+}
+
+/// Writes events in some format.
+trait Writer {
+    /// Writes `event`, which happened at `time` cycles. Times never go
+    /// backwards.
+    fn event(&mut self, time: u64, event: &Event) -> io::Result<()>;
+
+    fn flush(&mut self) -> io::Result<()>;
+}
+
+/// Sink that decodes ITM, and exports the packets of interest with their
+/// timing.
+pub struct Export {
+    itm: itm::Decoder,
+    writer: Box<dyn Writer>,
+    /// Cycles since the start of the trace.
+    now: u64,
+    /// Whether the stream has local timestamps, so global ones are ignored.
+    local: bool,
+    /// Bits 47:26 of the last global timestamp, in place, and bits 25:0.
+    global_high: u64,
+    global_low: u32,
+    /// Events waiting for a timestamp.
+    pending: Vec<Event>,
+}
+
+impl Export {
+    /// Exports to `out` as VCD. `clock` is the frequency of the timestamp
+    /// clock in Hz, if known.
+    pub fn vcd<W>(out: W, clock: Option<u32>) -> io::Result<Self>
+    where
+        W: Write + 'static,
+    {
+        Ok(Self::new(Box::new(Vcd::new(out, clock)?)))
+    }
+
+    /// Exports to a CTF trace in `dir`, which is created if need be. `clock`
+    /// is the frequency of the timestamp clock in Hz, if known.
+    pub fn ctf(dir: &Path, clock: Option<u32>) -> io::Result<Self> {
+        Ok(Self::new(Box::new(Ctf::new(dir, clock)?)))
+    }
+
+    fn new(writer: Box<dyn Writer>) -> Self {
+        Self {
+            itm: itm::Decoder::new(),
+            writer,
+            now: 0,
+            local: false,
+            global_high: 0,
+            global_low: 0,
+            pending: vec![],
+        }
+    }
+
+    fn packet(&mut self, packet: itm::Packet) -> io::Result<()> {
+        use itm::Packet;
+
+        let event = match packet {
+            Packet::LocalTimestamp { delta, .. } => {
+                self.local = true;
+                self.now += u64::from(delta);
+                return self.release();
+            }
+            Packet::GlobalTimestamp { .. } if self.local => return Ok(()),
+            Packet::GlobalTimestamp { value, high: false } => {
+                let value = value & ((1 << GLOBAL_LOW_BITS) - 1);
+                // The low bits wrapped before the next high packet came.
+                if value < self.global_low {
+                    self.global_high += 1 << GLOBAL_LOW_BITS;
+                }
+                self.global_low = value;
+                self.global_time();
+                return self.release();
+            }
+            Packet::GlobalTimestamp { value, high: true } => {
+                self.global_high = u64::from(value) << GLOBAL_LOW_BITS;
+                self.global_time();
+                return Ok(());
+            }
+            Packet::Instrumentation { port, payload } => {
+                Event::Stimulus { port, payload }
+            }
+            Packet::Hardware {
+                discriminator,
+                payload,
+            } => match discriminator {
+                COUNTER_WRAP => {
+                    Event::CounterWrap(payload.first().copied().unwrap_or(0))
+                }
+                EXCEPTION_TRACE => {
+                    let value = payload.value();
+                    Event::Exception {
+                        number: (value & 0x1ff) as u16,
+                        function: ((value >> 12) & 3) as u8,
+                    }
+                }
+                PC_SAMPLE if payload.len() == 4 => {
+                    Event::PcSample(Some(payload.value()))
+                }
+                PC_SAMPLE => Event::PcSample(None),
+                d if DATA_TRACE.contains(&d) => Event::DataTrace {
+                    discriminator,
+                    payload,
+                },
+                _ => return Ok(()),
+            },
+            Packet::Overflow => Event::Overflow,
+            Packet::Sync | Packet::Extension { .. } => return Ok(()),
+        };
+        self.pending.push(event);
+        if self.pending.len() >= MAX_PENDING {
+            self.release()?;
+        }
+        Ok(())
+    }
+
+    /// Moves time on to the last global timestamp, unless that would take
+    /// it backwards.
+    fn global_time(&mut self) {
+        let time = self.global_high | u64::from(self.global_low);
+        self.now = self.now.max(time);
+    }
+
+    /// Writes out the pending events at the current time.
+    fn release(&mut self) -> io::Result<()> {
+        for event in self.pending.drain(..) {
+            self.writer.event(self.now, &event)?;
+        }
+        Ok(())
+    }
+}
+
+impl Sink for Export {
+    fn data(&mut self, bytes: &[u8]) -> io::Result<()> {
+        let mut packets = vec![];
+        self.itm.feed(bytes, |p| packets.push(p));
+        for packet in packets {
+            self.packet(packet)?;
+        }
+        self.writer.flush()
+    }
+
+    fn gap(&mut self) -> io::Result<()> {
+        // Anything half-decoded is garbage now, and whatever timestamp the
+        // pending events were waiting for may have been lost.
+        self.itm.reset();
+        self.release()?;
+        self.writer.event(self.now, &Event::Gap)?;
+        self.writer.flush()
+    }
+}
+
+impl Drop for Export {
+    fn drop(&mut self) {
+        self.release().ok();
+        self.writer.flush().ok();
+    }
+}
+
+/// Number of ITM stimulus ports.
+const PORTS: u8 = 32;
+
+/// Writes VCD. Variables are numbered in the order they're declared: the
+/// ports, then the rest.
+struct Vcd<W: Write> {
+    out: W,
+    /// Frequency of the timestamp clock in Hz, if known.
+    clock: Option<u32>,
+    /// Time of the last value change written, in output units.
+    last_time: Option<u64>,
+}
+
+/// Variables after the ports, with their widths.
+const VCD_EXCEPTION: usize = PORTS as usize;
+const VCD_PC: usize = VCD_EXCEPTION + 1;
+const VCD_COUNTER_WRAP: usize = VCD_PC + 1;
+const VCD_OVERFLOW: usize = VCD_COUNTER_WRAP + 1;
+const VCD_GAP: usize = VCD_OVERFLOW + 1;
+const VCD_DATA_TRACE: usize = VCD_GAP + 1;
+
+impl<W: Write> Vcd<W> {
+    fn new(mut out: W, clock: Option<u32>) -> io::Result<Self> {
+        writeln!(out, "$version lpc-cat {} $end", env!("CARGO_PKG_VERSION"))?;
+        if clock.is_none() {
+            writeln!(
+                out,
+                "$comment timestamp clock unknown; times are in cycles $end"
+            )?;
+        }
+        writeln!(out, "$timescale 1ns $end")?;
+        writeln!(out, "$scope module itm $end")?;
+        for port in 0..PORTS {
+            var(
+                &mut out,
+                "wire",
+                32,
+                usize::from(port),
+                &format!("port{}", port),
+            )?;
+        }
+        var(&mut out, "wire", 9, VCD_EXCEPTION, "exception")?;
+        var(&mut out, "wire", 32, VCD_PC, "pc")?;
+        var(&mut out, "wire", 8, VCD_COUNTER_WRAP, "counter_wrap")?;
+        var(&mut out, "event", 1, VCD_OVERFLOW, "overflow")?;
+        var(&mut out, "event", 1, VCD_GAP, "gap")?;
+        for d in DATA_TRACE {
+            let index = VCD_DATA_TRACE + usize::from(d - DATA_TRACE.start());
+            var(&mut out, "wire", 32, index, &format!("hw{}", d))?;
+        }
+        writeln!(out, "$upscope $end")?;
+        writeln!(out, "$enddefinitions $end")?;
+        Ok(Self {
+            out,
+            clock,
+            last_time: None,
+        })
+    }
+
+    /// Writes a vector value change for variable `index`, or `x` if there's
+    /// no value.
+    fn vector(&mut self, index: usize, value: Option<u32>) -> io::Result<()> {
+        match value {
+            Some(v) => writeln!(self.out, "b{:b} {}", v, vcd_id(index)),
+            None => writeln!(self.out, "bx {}", vcd_id(index)),
+        }
+    }
+}
+
+impl<W: Write> Writer for Vcd<W> {
+    fn event(&mut self, time: u64, event: &Event) -> io::Result<()> {
+        let time = match self.clock {
+            Some(hz) => {
+                (u128::from(time) * 1_000_000_000 / u128::from(hz)) as u64
+            }
+            None => time,
+        };
+        if self.last_time != Some(time) {
+            writeln!(self.out, "#{}", time)?;
+            self.last_time = Some(time);
+        }
+        match *event {
+            Event::Stimulus { port, payload } => {
+                self.vector(usize::from(port), Some(payload.value()))
+            }
+            // Exit is followed by a return, which says where to.
+            Event::Exception { function: 2, .. } => Ok(()),
+            Event::Exception { number, .. } => {
+                self.vector(VCD_EXCEPTION, Some(u32::from(number)))
+            }
+            Event::PcSample(pc) => self.vector(VCD_PC, pc),
+            Event::CounterWrap(flags) => {
+                self.vector(VCD_COUNTER_WRAP, Some(u32::from(flags)))
+            }
+            Event::DataTrace {
+                discriminator,
+                payload,
+            } => {
+                let index = VCD_DATA_TRACE
+                    + usize::from(discriminator - DATA_TRACE.start());
+                self.vector(index, Some(payload.value()))
+            }
+            Event::Overflow => writeln!(self.out, "1{}", vcd_id(VCD_OVERFLOW)),
+            Event::Gap => writeln!(self.out, "1{}", vcd_id(VCD_GAP)),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/// Declares a VCD variable.
+fn var(
+    out: &mut impl Write,
+    kind: &str,
+    width: u32,
+    index: usize,
+    name: &str,
+) -> io::Result<()> {
+    writeln!(
+        out,
+        "$var {} {} {} {} $end",
+        kind,
+        width,
+        vcd_id(index),
+        name
+    )
+}
+
+/// Returns the identifier code for VCD variable `index`, using the
+/// printable ASCII characters as digits.
+fn vcd_id(mut index: usize) -> String {
+    const FIRST: u8 = b'!';
+    const DIGITS: usize = (b'~' - b'!' + 1) as usize;
+    let mut id = String::new();
+    loop {
+        id.push(char::from(FIRST + (index % DIGITS) as u8));
+        index /= DIGITS;
+        if index == 0 {
+            return id;
+        }
+        index -= 1;
+    }
+}
+
+/// CTF event IDs, as declared in the metadata.
+const CTF_STIMULUS: u8 = 0;
+const CTF_EXCEPTION: u8 = 1;
+const CTF_PC_SAMPLE: u8 = 2;
+const CTF_COUNTER_WRAP: u8 = 3;
+const CTF_DATA_TRACE: u8 = 4;
+const CTF_OVERFLOW: u8 = 5;
+const CTF_GAP: u8 = 6;
+
+const CTF_MAGIC: u32 = 0xC1FC_1FC1;
+
+/// TSDL describing the stream, with the clock frequency to fill in.
+const CTF_METADATA: &str = r#"/* CTF 1.8 */
+
+typealias integer { size = 8; align = 8; signed = false; } := uint8_t;
+typealias integer { size = 16; align = 8; signed = false; } := uint16_t;
+typealias integer { size = 32; align = 8; signed = false; } := uint32_t;
+typealias integer { size = 64; align = 8; signed = false; } := uint64_t;
+
+trace {
+    major = 1;
+    minor = 8;
+    byte_order = le;
+    packet.header := struct {
+        uint32_t magic;
+    };
+};
+
+env {
+    tracer_name = "lpc-cat";
+};
+
+clock {
+    name = itm;
+    description = "ITM timestamp clock";
+    freq = @FREQ@;
+};
+
+typealias integer {
+    size = 64; align = 8; signed = false;
+    map = clock.itm.value;
+} := itm_clock_t;
+
+stream {
+    event.header := struct {
+        uint8_t id;
+        itm_clock_t timestamp;
+    };
+};
+
+/* Data written by software to an ITM stimulus port. */
+event {
+    name = "stimulus";
+    id = 0;
+    fields := struct {
+        uint8_t port;
+        uint8_t size;
+        uint32_t value;
+    };
+};
+
+/* function: 1 = entered, 2 = exited, 3 = returned to. */
+event {
+    name = "exception";
+    id = 1;
+    fields := struct {
+        uint16_t number;
+        uint8_t function;
+    };
+};
+
+/* PC sampled by the DWT; sleeping is 1 if the core was asleep. */
+event {
+    name = "pc_sample";
+    id = 2;
+    fields := struct {
+        uint32_t pc;
+        uint8_t sleeping;
+    };
+};
+
+/* A DWT event counter wrapped; flags has a bit for each counter. */
+event {
+    name = "counter_wrap";
+    id = 3;
+    fields := struct {
+        uint8_t flags;
+    };
+};
+
+/* DWT data trace packet; see the ARMv7-M ARM for discriminators. */
+event {
+    name = "data_trace";
+    id = 4;
+    fields := struct {
+        uint8_t discriminator;
+        uint8_t size;
+        uint32_t value;
+    };
+};
+
+/* The target's ITM FIFO overflowed, and data was lost. */
+event {
+    name = "overflow";
+    id = 5;
+};
+
+/* Data may have been lost in capture. */
+event {
+    name = "gap";
+    id = 6;
+};
+"#;
+
+/// Writes a CTF trace, as a single packet that takes up the whole stream
+/// file.
+struct Ctf {
+    stream: BufWriter<File>,
+}
+
+impl Ctf {
+    fn new(dir: &Path, clock: Option<u32>) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let freq = clock.map_or(1_000_000_000, u64::from);
+        fs::write(
+            dir.join("metadata"),
+            CTF_METADATA.replace("@FREQ@", &freq.to_string()),
+        )?;
+        let mut stream = BufWriter::new(File::create(dir.join("stream"))?);
+        stream.write_all(&CTF_MAGIC.to_le_bytes())?;
+        Ok(Self { stream })
+    }
+}
+
+impl Writer for Ctf {
+    fn event(&mut self, time: u64, event: &Event) -> io::Result<()> {
+        let mut record = Vec::with_capacity(16);
+        let mut header = |id: u8| {
+            record.push(id);
+            record.extend_from_slice(&time.to_le_bytes());
+        };
+        match *event {
+            Event::Stimulus { port, payload } => {
+                header(CTF_STIMULUS);
+                record.push(port);
+                record.push(payload.len() as u8);
+                record.extend_from_slice(&payload.value().to_le_bytes());
+            }
+            Event::Exception { number, function } => {
+                header(CTF_EXCEPTION);
+                record.extend_from_slice(&number.to_le_bytes());
+                record.push(function);
+            }
+            Event::PcSample(pc) => {
+                header(CTF_PC_SAMPLE);
+                record.extend_from_slice(&pc.unwrap_or(0).to_le_bytes());
+                record.push(pc.is_none().into());
+            }
+            Event::CounterWrap(flags) => {
+                header(CTF_COUNTER_WRAP);
+                record.push(flags);
+            }
+            Event::DataTrace {
+                discriminator,
+                payload,
+            } => {
+                header(CTF_DATA_TRACE);
+                record.push(discriminator);
+                record.push(payload.len() as u8);
+                record.extend_from_slice(&payload.value().to_le_bytes());
+            }
+            Event::Overflow => header(CTF_OVERFLOW),
+            Event::Gap => header(CTF_GAP),
+        }
+        self.stream.write_all(&record)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
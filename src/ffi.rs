@@ -0,0 +1,391 @@
+//! C bindings for `embed::Capture`, declared in `include/lpc_cat.h`.
+//!
+//! Every function returns `LPC_CAT_OK` (0) or a positive status on success,
+//! and one of the negative `LPC_CAT_ERR_*` codes on failure, in which case
+//! `lpc_cat_last_error` describes what went wrong. Handles may be used from
+//! any thread, but only from one at a time.
+
+use std::cell::RefCell;
+use std::convert::TryFrom;
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int};
+use std::panic::{self, AssertUnwindSafe};
+use std::time::Duration;
+
+use crate::capture::Stats;
+use crate::embed::Capture;
+use crate::select::Selector;
+use crate::{synthetic, Error};
+
+pub const LPC_CAT_OK: c_int = 0;
+/// From `lpc_cat_read`: capture is over, and everything has been read.
+pub const LPC_CAT_ENDED: c_int = 1;
+pub const LPC_CAT_ERR_INVALID: c_int = -1;
+pub const LPC_CAT_ERR_DEVICE_NOT_FOUND: c_int = -2;
+pub const LPC_CAT_ERR_USB: c_int = -3;
+pub const LPC_CAT_ERR_PROTOCOL: c_int = -4;
+pub const LPC_CAT_ERR_BIT_RATE: c_int = -5;
+pub const LPC_CAT_ERR_UNSUPPORTED: c_int = -6;
+pub const LPC_CAT_ERR_IO: c_int = -7;
+pub const LPC_CAT_ERR_TARGET: c_int = -8;
+/// Something went wrong inside the library; the handle shouldn't be used
+/// again, other than to close it.
+pub const LPC_CAT_ERR_PANIC: c_int = -9;
+
+/// Returns the `LPC_CAT_ERR_*` code for `e`.
+pub fn code(e: &Error) -> c_int {
+    match e {
+        Error::DeviceNotFound | Error::Firmware { .. } => {
+            LPC_CAT_ERR_DEVICE_NOT_FOUND
+        }
+        Error::Hid(_) => LPC_CAT_ERR_USB,
+        #[cfg(feature = "bulk")]
+        Error::Usb(_) => LPC_CAT_ERR_USB,
+        Error::Protocol { .. } | Error::Malformed { .. } => {
+            LPC_CAT_ERR_PROTOCOL
+        }
+        Error::UnachievableBitRate { .. } => LPC_CAT_ERR_BIT_RATE,
+        Error::Unsupported(_) => LPC_CAT_ERR_UNSUPPORTED,
+        Error::Target(_) => LPC_CAT_ERR_TARGET,
+        Error::State(_) => LPC_CAT_ERR_INVALID,
+        Error::Io(_) => LPC_CAT_ERR_IO,
+    }
+}
+
+/// Capture statistics, as in `capture::Stats`.
+#[repr(C)]
+pub struct LpcCatStats {
+    pub bytes: u64,
+    pub probe_gaps: u64,
+    pub host_drops: u64,
+    pub host_dropped_bytes: u64,
+    pub queue_high_water: u64,
+    pub reconnects: u64,
+    pub flushes: u64,
+    /// In seconds.
+    pub duration: f64,
+}
+
+impl From<Stats> for LpcCatStats {
+    fn from(s: Stats) -> Self {
+        Self {
+            bytes: s.bytes,
+            probe_gaps: s.probe_gaps,
+            host_drops: s.host_drops,
+            host_dropped_bytes: s.host_dropped_bytes,
+            queue_high_water: s.queue_high_water as u64,
+            reconnects: s.reconnects,
+            flushes: s.flushes,
+            duration: s.duration.as_secs_f64(),
+        }
+    }
+}
+
+/// Why a call failed, other than with an `Error`.
+enum Failure {
+    Error(Error),
+    Invalid(&'static str),
+}
+
+impl From<Error> for Failure {
+    fn from(e: Error) -> Self {
+        Failure::Error(e)
+    }
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> =
+        const { RefCell::new(None) };
+}
+
+fn set_last_error(message: String) {
+    // Messages are ours, or from libraries that don't put NULs in them.
+    let message = CString::new(message.replace('\0', " ")).unwrap();
+    LAST_ERROR.with(|e| *e.borrow_mut() = Some(message));
+}
+
+/// Runs `f`, turning its result (or a panic) into a status code.
+fn call(f: impl FnOnce() -> Result<c_int, Failure>) -> c_int {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(status)) => status,
+        Ok(Err(Failure::Error(e))) => {
+            set_last_error(e.to_string());
+            code(&e)
+        }
+        Ok(Err(Failure::Invalid(why))) => {
+            set_last_error(why.to_string());
+            LPC_CAT_ERR_INVALID
+        }
+        Err(_) => {
+            set_last_error("lpc-cat panicked".to_string());
+            LPC_CAT_ERR_PANIC
+        }
+    }
+}
+
+/// Reads an optional C string argument.
+///
+/// # Safety
+///
+/// `s` must be null or point to a NUL-terminated string.
+unsafe fn optional_str<'a>(
+    s: *const c_char,
+    what: &'static str,
+) -> Result<Option<&'a str>, Failure> {
+    if s.is_null() {
+        return Ok(None);
+    }
+    CStr::from_ptr(s)
+        .to_str()
+        .map(Some)
+        .map_err(|_| Failure::Invalid(what))
+}
+
+/// # Safety
+///
+/// `handle` must be null or have come from `lpc_cat_open*`, and not have
+/// been closed.
+unsafe fn capture<'a>(
+    handle: *mut Capture,
+) -> Result<&'a mut Capture, Failure> {
+    handle.as_mut().ok_or(Failure::Invalid("null handle"))
+}
+
+/// Opens the probe with the given vid/pid and serial number (which may be
+/// null to take the first). If `vid` and `pid` are both 0, the first probe
+/// running firmware with a trace interface is used (see `firmware::find`).
+/// On success, stores a handle in `*out`, to be closed with
+/// `lpc_cat_close`.
+///
+/// # Safety
+///
+/// `serial` must be null or point to a NUL-terminated string, and `out` must
+/// be valid for writing a pointer.
+#[no_mangle]
+pub unsafe extern "C" fn lpc_cat_open(
+    vid: u16,
+    pid: u16,
+    serial: *const c_char,
+    out: *mut *mut Capture,
+) -> c_int {
+    call(|| {
+        if out.is_null() {
+            return Err(Failure::Invalid("null output pointer"));
+        }
+        let selector = Selector {
+            serial: optional_str(serial, "serial isn't UTF-8")?
+                .map(str::to_string),
+            ..Selector::default()
+        };
+        let capture = if vid == 0 && pid == 0 {
+            Capture::find(&selector)?
+        } else {
+            Capture::open(vid, pid, &selector)?
+        };
+        *out = Box::into_raw(Box::new(capture));
+        Ok(LPC_CAT_OK)
+    })
+}
+
+/// Like `lpc_cat_open`, but makes up data instead of capturing it, for
+/// testing without hardware. `pattern` is `"counter"` or `"itm"` (see
+/// `synthetic::Pattern`), or null for the former, and `rate` is the bit
+/// rate to simulate.
+///
+/// # Safety
+///
+/// As for `lpc_cat_open`, with `pattern` in place of `serial`.
+#[no_mangle]
+pub unsafe extern "C" fn lpc_cat_open_synthetic(
+    pattern: *const c_char,
+    rate: u64,
+    out: *mut *mut Capture,
+) -> c_int {
+    call(|| {
+        if out.is_null() {
+            return Err(Failure::Invalid("null output pointer"));
+        }
+        if rate == 0 {
+            return Err(Failure::Invalid("synthetic rate must be nonzero"));
+        }
+        let pattern = match optional_str(pattern, "pattern isn't UTF-8")? {
+            Some(p) => p
+                .parse()
+                .map_err(|_| Failure::Invalid("unknown synthetic pattern"))?,
+            None => synthetic::Pattern::Counter,
+        };
+        let capture = Capture::synthetic(synthetic::Config { pattern, rate });
+        *out = Box::into_raw(Box::new(capture));
+        Ok(LPC_CAT_OK)
+    })
+}
+
+/// Sets the bit rate to capture at, before `lpc_cat_start`. If `actual` isn't
+/// null, the rate the probe agreed to is stored there. Unless `approximate`
+/// is nonzero, fails with `LPC_CAT_ERR_BIT_RATE` if that isn't `rate`.
+///
+/// # Safety
+///
+/// `handle` must be open, and `actual` null or valid for writing.
+#[no_mangle]
+pub unsafe extern "C" fn lpc_cat_set_bit_rate(
+    handle: *mut Capture,
+    rate: u32,
+    approximate: c_int,
+    actual: *mut u32,
+) -> c_int {
+    call(|| {
+        let agreed = capture(handle)?.set_bit_rate(rate, approximate != 0);
+        // Report the closest rate even if it wasn't good enough.
+        let closest = match &agreed {
+            Ok(r) => Some(*r),
+            Err(Error::UnachievableBitRate { closest, .. }) => Some(*closest),
+            Err(_) => None,
+        };
+        if let (Some(r), false) = (closest, actual.is_null()) {
+            *actual = r;
+        }
+        agreed?;
+        Ok(LPC_CAT_OK)
+    })
+}
+
+/// Starts capture in the background, queueing up to `queue_depth` chunks (of
+/// up to about 1 KiB) for `lpc_cat_read` before dropping data.
+///
+/// # Safety
+///
+/// `handle` must be open.
+#[no_mangle]
+pub unsafe extern "C" fn lpc_cat_start(
+    handle: *mut Capture,
+    queue_depth: usize,
+) -> c_int {
+    call(|| {
+        if queue_depth == 0 {
+            return Err(Failure::Invalid("queue depth must be nonzero"));
+        }
+        capture(handle)?.start(queue_depth)?;
+        Ok(LPC_CAT_OK)
+    })
+}
+
+/// Reads up to `len` bytes of captured data into `buffer`, waiting up to
+/// `timeout_ms` milliseconds (or forever, if negative) for some to arrive,
+/// and stores the number read in `*read` (0 if none arrived in time).
+/// Returns `LPC_CAT_ENDED` once capture has stopped and everything has been
+/// read, or the error that stopped it.
+///
+/// # Safety
+///
+/// `handle` must be open, `buffer` valid for writing `len` bytes, and `read`
+/// valid for writing.
+#[no_mangle]
+pub unsafe extern "C" fn lpc_cat_read(
+    handle: *mut Capture,
+    buffer: *mut u8,
+    len: usize,
+    timeout_ms: i32,
+    read: *mut usize,
+) -> c_int {
+    call(|| {
+        if buffer.is_null() || read.is_null() {
+            return Err(Failure::Invalid("null buffer"));
+        }
+        let buffer = std::slice::from_raw_parts_mut(buffer, len);
+        let timeout = u64::try_from(timeout_ms).ok().map(Duration::from_millis);
+        *read = 0;
+        match capture(handle)?.read(buffer, timeout)? {
+            Some(n) => {
+                *read = n;
+                Ok(LPC_CAT_OK)
+            }
+            None => Ok(LPC_CAT_ENDED),
+        }
+    })
+}
+
+/// Stores statistics about the capture so far in `*stats`.
+///
+/// # Safety
+///
+/// `handle` must be open, and `stats` valid for writing.
+#[no_mangle]
+pub unsafe extern "C" fn lpc_cat_get_stats(
+    handle: *mut Capture,
+    stats: *mut LpcCatStats,
+) -> c_int {
+    call(|| {
+        if stats.is_null() {
+            return Err(Failure::Invalid("null stats pointer"));
+        }
+        *stats = capture(handle)?.stats().into();
+        Ok(LPC_CAT_OK)
+    })
+}
+
+/// Stops capture, discarding anything not yet read. If `stats` isn't null,
+/// the final statistics are stored there. Fails with the error that stopped
+/// capture early, if one did.
+///
+/// # Safety
+///
+/// `handle` must be open, and `stats` null or valid for writing.
+#[no_mangle]
+pub unsafe extern "C" fn lpc_cat_stop(
+    handle: *mut Capture,
+    stats: *mut LpcCatStats,
+) -> c_int {
+    call(|| {
+        let capture = capture(handle)?;
+        let result = capture.stop();
+        if !stats.is_null() {
+            *stats = capture.stats().into();
+        }
+        result?;
+        Ok(LPC_CAT_OK)
+    })
+}
+
+/// Stops capture if need be, and frees `handle`. Null is ignored.
+///
+/// # Safety
+///
+/// `handle` must be null, or open; it's closed afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn lpc_cat_close(handle: *mut Capture) {
+    if !handle.is_null() {
+        call(|| {
+            drop(Box::from_raw(handle));
+            Ok(LPC_CAT_OK)
+        });
+    }
+}
+
+/// Copies a description of the last error on this thread into `buffer`, as
+/// a NUL-terminated string truncated to fit in `len` bytes. Returns the
+/// length of the whole description, without the NUL, or 0 if there's been
+/// no error.
+///
+/// # Safety
+///
+/// `buffer` must be null (if `len` is 0) or valid for writing `len` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn lpc_cat_last_error(
+    buffer: *mut c_char,
+    len: usize,
+) -> usize {
+    LAST_ERROR.with(|e| {
+        let e = e.borrow();
+        let message = match e.as_ref() {
+            Some(m) => m.as_bytes(),
+            None => return 0,
+        };
+        if !buffer.is_null() && len > 0 {
+            let n = message.len().min(len - 1);
+            std::ptr::copy_nonoverlapping(message.as_ptr().cast(), buffer, n);
+            *buffer.add(n) = 0;
+        }
+        message.len()
+    })
+}
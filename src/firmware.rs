@@ -0,0 +1,206 @@
+//! Recognizing what firmware a probe is running, so that we can find a
+//! trace-capable probe without being told its USB IDs, and say something
+//! useful when the only probe around can't capture.
+//!
+//! The LPC-Link2 can run several firmware images, each of which enumerates
+//! differently. Only the CMSIS-DAP image described in the README is known
+//! to have the trace interface this crate speaks to; other NXP images are
+//! used if their descriptors name a trace interface. Images that certainly
+//! can't capture (the boot ROM's DFU mode, SEGGER's J-Link firmware) are
+//! recognized so that we can say so. Entries other than the README's image
+//! come from vendor documentation rather than our own captures, and
+//! corrections are welcome.
+
+use std::fs;
+use std::path::Path;
+
+use crate::interfaces::Function;
+use crate::select::{self, Selector};
+use crate::Error;
+
+/// NXP's USB vendor ID, which its firmware images share.
+pub const NXP_VID: u16 = 0x1fc9;
+
+/// A firmware image we know about.
+#[derive(Debug)]
+pub struct Variant {
+    pub vid: u16,
+    pub pid: u16,
+    pub name: &'static str,
+    pub support: Support,
+}
+
+/// What a firmware image can do for us.
+#[derive(Debug)]
+pub enum Support {
+    /// It has a trace interface, at the interface numbers in `layout` when
+    /// its descriptors don't say.
+    Trace { layout: &'static [(i32, Function)] },
+    /// It can't capture SWO. `state` describes the probe (as in "probe is
+    /// `state`"), and `advice` what to do about it.
+    NoTrace {
+        state: &'static str,
+        advice: &'static str,
+    },
+}
+
+/// Interface numbers used by the firmware described in the README.
+const CMSIS_DAP_LAYOUT: &[(i32, Function)] =
+    &[(1, Function::CmsisDap), (4, Function::Trace)];
+
+const BOOT_FIRST: &str = "boot a trace-capable firmware first, e.g. the \
+                          CMSIS-DAP image, with LPCScrypt";
+
+/// Firmware we recognize, most useful first.
+pub const KNOWN: &[Variant] = &[
+    Variant {
+        vid: NXP_VID,
+        pid: 0x0090,
+        name: "LPC-Link2 CMSIS-DAP",
+        support: Support::Trace {
+            layout: CMSIS_DAP_LAYOUT,
+        },
+    },
+    Variant {
+        vid: NXP_VID,
+        pid: 0x000c,
+        name: "LPC43xx boot ROM (DFU)",
+        support: Support::NoTrace {
+            state: "in DFU mode",
+            advice: BOOT_FIRST,
+        },
+    },
+    Variant {
+        vid: 0x1366,
+        pid: 0x0101,
+        name: "SEGGER J-Link",
+        support: Support::NoTrace {
+            state: "running SEGGER J-Link firmware",
+            advice: BOOT_FIRST,
+        },
+    },
+    Variant {
+        vid: 0x1366,
+        pid: 0x0105,
+        name: "SEGGER J-Link (with VCOM)",
+        support: Support::NoTrace {
+            state: "running SEGGER J-Link firmware",
+            advice: BOOT_FIRST,
+        },
+    },
+];
+
+/// Looks up the firmware with the given USB IDs.
+pub fn identify(vid: u16, pid: u16) -> Option<&'static Variant> {
+    KNOWN.iter().find(|v| v.vid == vid && v.pid == pid)
+}
+
+/// Returns the interface layout to assume for a probe with the given USB
+/// IDs when its descriptors can't be read. Probes we don't recognize are
+/// assumed to be laid out like the README's.
+pub fn layout(vid: u16, pid: u16) -> &'static [(i32, Function)] {
+    match identify(vid, pid) {
+        Some(Variant {
+            support: Support::Trace { layout },
+            ..
+        }) => layout,
+        Some(_) => &[],
+        None => CMSIS_DAP_LAYOUT,
+    }
+}
+
+/// Returns the USB IDs a probe might be found at: those of every image we
+/// know, most useful first, then those of any other NXP image attached.
+pub fn candidates(api: &hidapi::HidApi) -> Vec<(u16, u16)> {
+    let mut candidates: Vec<(u16, u16)> =
+        KNOWN.iter().map(|v| (v.vid, v.pid)).collect();
+    for d in api.device_list() {
+        let ids = (d.vendor_id(), d.product_id());
+        if ids.0 == NXP_VID && !candidates.contains(&ids) {
+            candidates.push(ids);
+        }
+    }
+    candidates
+}
+
+/// Finds the USB IDs of an attached probe that matches `selector` and can
+/// capture SWO: one running a trace-capable image we know, or failing that,
+/// another NXP image whose descriptors name a trace interface.
+///
+/// If there's no such probe, but there is one running firmware that can't
+/// capture, says so in `Error::Firmware`. Probes with no HID interfaces are
+/// only found on Linux, where sysfs lists them.
+pub fn find(
+    api: &hidapi::HidApi,
+    selector: &Selector,
+) -> Result<(u16, u16), Error> {
+    for (vid, pid) in candidates(api) {
+        let variant = identify(vid, pid);
+        let found =
+            select::enumerate(api, vid, pid, selector).iter().any(|p| {
+                p.interfaces.iter().any(|i| {
+                    // Unknown images have to say they have a trace interface,
+                    // rather than it being assumed from the layout.
+                    i.function == Function::Trace
+                        && i.hid_path.is_some()
+                        && (variant.is_some() || i.name.is_some())
+                })
+            });
+        if found {
+            match variant {
+                Some(v) => log::info!("found probe running {}", v.name),
+                None => log::info!(
+                    "found probe with a trace interface at {:04x}:{:04x}",
+                    vid,
+                    pid
+                ),
+            }
+            return Ok((vid, pid));
+        }
+    }
+
+    for (vid, pid, serial, usb_port) in attached_usb() {
+        if selector.serial.is_some() && selector.serial != serial {
+            continue;
+        }
+        if selector.usb_port.is_some() && selector.usb_port != Some(usb_port) {
+            continue;
+        }
+        if let Some(Variant {
+            support: Support::NoTrace { state, advice },
+            ..
+        }) = identify(vid, pid)
+        {
+            return Err(Error::Firmware { state, advice });
+        }
+    }
+    Err(Error::DeviceNotFound)
+}
+
+/// Lists attached USB devices as `(vid, pid, serial, port path)`, from
+/// sysfs. Returns nothing if that isn't available.
+fn attached_usb() -> Vec<(u16, u16, Option<String>, String)> {
+    let entries = match fs::read_dir("/sys/bus/usb/devices") {
+        Ok(e) => e,
+        Err(_) => return vec![],
+    };
+    let read = |dir: &Path, attr: &str| -> Option<String> {
+        Some(fs::read_to_string(dir.join(attr)).ok()?.trim().to_string())
+    };
+    let hex = |s: Option<String>| u16::from_str_radix(&s?, 16).ok();
+    entries
+        .filter_map(Result::ok)
+        .filter_map(|e| {
+            let port = e.file_name().into_string().ok()?;
+            // Interfaces have a `:config.interface` suffix; root hubs are
+            // `usbN`.
+            if port.contains(':') || !port.contains('-') {
+                return None;
+            }
+            let dir = e.path();
+            let vid = hex(read(&dir, "idVendor"))?;
+            let pid = hex(read(&dir, "idProduct"))?;
+            Some((vid, pid, read(&dir, "serial"), port))
+        })
+        .collect()
+}
@@ -0,0 +1,335 @@
+//! Encodings for the captured stream that are friendlier than raw binary,
+//! either to people (hexdump, base64) or to other tools (pcapng).
+
+use std::convert::TryFrom;
+use std::io::{self, Write};
+use std::str::FromStr;
+use std::time::{Instant, UNIX_EPOCH};
+
+use crate::capture::{Fragment, Sink};
+use crate::timestamp::Clock;
+
+/// How to write the captured stream.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// The bytes as captured.
+    Raw,
+    /// Offset, hex and ASCII, 16 bytes to a line, like `hexdump -C`.
+    Hexdump,
+    /// Base64, 76 characters to a line.
+    Base64,
+    /// A pcapng capture file, with a packet for each piece of data received
+    /// from the probe.
+    Pcapng,
+}
+
+impl Format {
+    /// Names accepted by `from_str`.
+    pub const NAMES: &'static [&'static str] =
+        &["raw", "hexdump", "base64", "pcapng"];
+
+    /// Returns a sink that writes the stream to `out` in this format.
+    pub fn sink<W>(self, out: W) -> io::Result<Box<dyn Sink>>
+    where
+        W: Write + 'static,
+    {
+        Ok(match self {
+            Format::Raw => Box::new(out),
+            Format::Hexdump => Box::new(Hexdump::new(out)),
+            Format::Base64 => Box::new(Base64::new(out)),
+            Format::Pcapng => Box::new(Pcapng::new(out)?),
+        })
+    }
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "raw" => Ok(Format::Raw),
+            "hexdump" => Ok(Format::Hexdump),
+            "base64" => Ok(Format::Base64),
+            "pcapng" => Ok(Format::Pcapng),
+            _ => Err(format!("unknown format `{}`", s)),
+        }
+    }
+}
+
+/// Sink that writes a hexdump of the stream. Bytes are held back until
+/// there's a full line of them, or a gap, which is marked in the output.
+pub struct Hexdump<W: Write> {
+    inner: W,
+    offset: u64,
+    line: Vec<u8>,
+}
+
+const HEXDUMP_WIDTH: usize = 16;
+
+impl<W: Write> Hexdump<W> {
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            offset: 0,
+            line: Vec::with_capacity(HEXDUMP_WIDTH),
+        }
+    }
+
+    fn write_line(&mut self) -> io::Result<()> {
+        if self.line.is_empty() {
+            return Ok(());
+        }
+        let mut text = format!("{:08x} ", self.offset);
+        for i in 0..HEXDUMP_WIDTH {
+            if i % 8 == 0 {
+                text.push(' ');
+            }
+            match self.line.get(i) {
+                Some(b) => text.push_str(&format!("{:02x} ", b)),
+                None => text.push_str("   "),
+            }
+        }
+        text.push_str(" |");
+        text.extend(self.line.iter().map(|&b| {
+            if b.is_ascii_graphic() || b == b' ' {
+                char::from(b)
+            } else {
+                '.'
+            }
+        }));
+        text.push_str("|\n");
+        self.inner.write_all(text.as_bytes())?;
+        self.offset += self.line.len() as u64;
+        self.line.clear();
+        Ok(())
+    }
+}
+
+impl<W: Write> Sink for Hexdump<W> {
+    fn data(&mut self, mut bytes: &[u8]) -> io::Result<()> {
+        while !bytes.is_empty() {
+            let n = (HEXDUMP_WIDTH - self.line.len()).min(bytes.len());
+            self.line.extend_from_slice(&bytes[..n]);
+            bytes = &bytes[n..];
+            if self.line.len() == HEXDUMP_WIDTH {
+                self.write_line()?;
+            }
+        }
+        self.inner.flush()
+    }
+
+    fn gap(&mut self) -> io::Result<()> {
+        self.write_line()?;
+        self.inner
+            .write_all(b"-- data may have been lost here --\n")?;
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> Drop for Hexdump<W> {
+    fn drop(&mut self) {
+        self.write_line().ok();
+        self.inner.flush().ok();
+    }
+}
+
+/// Sink that writes the stream as base64. Each line can be decoded on its
+/// own: bytes are held back until there's a full line of them, or a gap,
+/// which ends the line early (with padding if need be).
+pub struct Base64<W: Write> {
+    inner: W,
+    line: Vec<u8>,
+}
+
+/// Bytes encoded on each full line; 76 characters of output.
+const BASE64_LINE: usize = 57;
+
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+impl<W: Write> Base64<W> {
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            line: Vec::with_capacity(BASE64_LINE),
+        }
+    }
+
+    fn write_line(&mut self) -> io::Result<()> {
+        if self.line.is_empty() {
+            return Ok(());
+        }
+        let mut text = Vec::with_capacity(BASE64_LINE / 3 * 4 + 1);
+        for group in self.line.chunks(3) {
+            let mut bytes = [0; 3];
+            bytes[..group.len()].copy_from_slice(group);
+            let n = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+            for i in 0..4 {
+                if i <= group.len() {
+                    let index = (n >> (18 - 6 * i)) & 0x3f;
+                    text.push(BASE64_ALPHABET[index as usize]);
+                } else {
+                    text.push(b'=');
+                }
+            }
+        }
+        text.push(b'\n');
+        self.line.clear();
+        self.inner.write_all(&text)
+    }
+}
+
+impl<W: Write> Sink for Base64<W> {
+    fn data(&mut self, mut bytes: &[u8]) -> io::Result<()> {
+        while !bytes.is_empty() {
+            let n = (BASE64_LINE - self.line.len()).min(bytes.len());
+            self.line.extend_from_slice(&bytes[..n]);
+            bytes = &bytes[n..];
+            if self.line.len() == BASE64_LINE {
+                self.write_line()?;
+            }
+        }
+        self.inner.flush()
+    }
+
+    fn gap(&mut self) -> io::Result<()> {
+        self.write_line()?;
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> Drop for Base64<W> {
+    fn drop(&mut self) {
+        self.write_line().ok();
+        self.inner.flush().ok();
+    }
+}
+
+/// Sink that writes a pcapng capture file, with an enhanced packet block for
+/// each fragment of data, timestamped with when it was received. The link
+/// type is `LINKTYPE_USER0`, since SWO doesn't have one of its own. A packet
+/// that follows a gap carries a comment saying so.
+pub struct Pcapng<W: Write> {
+    inner: W,
+    /// For turning the times data was received into wall clock time.
+    clock: Clock,
+    after_gap: bool,
+}
+
+const PCAPNG_SECTION_HEADER: u32 = 0x0A0D_0D0A;
+const PCAPNG_INTERFACE_DESCRIPTION: u32 = 0x0000_0001;
+const PCAPNG_ENHANCED_PACKET: u32 = 0x0000_0006;
+const PCAPNG_BYTE_ORDER_MAGIC: u32 = 0x1A2B_3C4D;
+const PCAPNG_OPT_END: u16 = 0;
+const PCAPNG_OPT_COMMENT: u16 = 1;
+const PCAPNG_IF_NAME: u16 = 2;
+const LINKTYPE_USER0: u16 = 147;
+
+impl<W: Write> Pcapng<W> {
+    /// Starts a capture file, writing its header to `inner`.
+    pub fn new(inner: W) -> io::Result<Self> {
+        let mut pcapng = Self {
+            inner,
+            clock: Clock::now(),
+            after_gap: false,
+        };
+
+        let mut shb = vec![];
+        shb.extend_from_slice(&PCAPNG_BYTE_ORDER_MAGIC.to_le_bytes());
+        shb.extend_from_slice(&1u16.to_le_bytes());
+        shb.extend_from_slice(&0u16.to_le_bytes());
+        // Section length not given.
+        shb.extend_from_slice(&(-1i64).to_le_bytes());
+        pcapng.block(PCAPNG_SECTION_HEADER, &shb)?;
+
+        let mut idb = vec![];
+        idb.extend_from_slice(&LINKTYPE_USER0.to_le_bytes());
+        idb.extend_from_slice(&0u16.to_le_bytes());
+        // No snapshot length limit.
+        idb.extend_from_slice(&0u32.to_le_bytes());
+        option(&mut idb, PCAPNG_IF_NAME, b"swo");
+        option(&mut idb, PCAPNG_OPT_END, &[]);
+        pcapng.block(PCAPNG_INTERFACE_DESCRIPTION, &idb)?;
+        pcapng.inner.flush()?;
+        Ok(pcapng)
+    }
+
+    fn block(&mut self, kind: u32, body: &[u8]) -> io::Result<()> {
+        let len = u32::try_from(12 + body.len())
+            .map_err(|_| invalid("block too long"))?;
+        self.inner.write_all(&kind.to_le_bytes())?;
+        self.inner.write_all(&len.to_le_bytes())?;
+        self.inner.write_all(body)?;
+        self.inner.write_all(&len.to_le_bytes())
+    }
+
+    fn packet(&mut self, received: Instant, bytes: &[u8]) -> io::Result<()> {
+        // Microseconds, the default resolution.
+        let time = self
+            .clock
+            .wall(received)
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |t| t.as_micros() as u64);
+        let len = u32::try_from(bytes.len())
+            .map_err(|_| invalid("packet too long"))?;
+
+        let mut epb = Vec::with_capacity(bytes.len() + 64);
+        epb.extend_from_slice(&0u32.to_le_bytes());
+        epb.extend_from_slice(&((time >> 32) as u32).to_le_bytes());
+        epb.extend_from_slice(&(time as u32).to_le_bytes());
+        epb.extend_from_slice(&len.to_le_bytes());
+        epb.extend_from_slice(&len.to_le_bytes());
+        epb.extend_from_slice(bytes);
+        pad(&mut epb);
+        if self.after_gap {
+            option(
+                &mut epb,
+                PCAPNG_OPT_COMMENT,
+                b"data may have been lost before this packet",
+            );
+            option(&mut epb, PCAPNG_OPT_END, &[]);
+            self.after_gap = false;
+        }
+        self.block(PCAPNG_ENHANCED_PACKET, &epb)?;
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> Sink for Pcapng<W> {
+    fn data(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.packet(Instant::now(), bytes)
+    }
+
+    fn fragment(&mut self, info: &Fragment, bytes: &[u8]) -> io::Result<()> {
+        self.packet(info.received, bytes)
+    }
+
+    fn gap(&mut self) -> io::Result<()> {
+        self.after_gap = true;
+        Ok(())
+    }
+
+    fn clock(&mut self, clock: Clock) -> io::Result<()> {
+        self.clock = clock;
+        Ok(())
+    }
+}
+
+/// Appends a pcapng option to `block`.
+fn option(block: &mut Vec<u8>, code: u16, value: &[u8]) {
+    block.extend_from_slice(&code.to_le_bytes());
+    block.extend_from_slice(&(value.len() as u16).to_le_bytes());
+    block.extend_from_slice(value);
+    pad(block);
+}
+
+/// Pads `block` to a multiple of 4 bytes, as pcapng requires.
+fn pad(block: &mut Vec<u8>) {
+    while !block.len().is_multiple_of(4) {
+        block.push(0);
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
@@ -0,0 +1,386 @@
+//! Optional framing for network streams, so that a remote consumer can tell
+//! data lost on the way to it apart from data lost by the probe.
+//!
+//! Each frame is laid out as follows (all integers little endian):
+//!
+//! ```text
+//! byte[0]    = 0xA5 magic
+//! byte[1]    = flags; bit 0 set means the probe lost data just before this
+//!              frame's payload
+//! byte[2:3]  = u16 payload length
+//! byte[4:7]  = u32 sequence number, incremented for every frame the server
+//!              produces, whether or not a particular client receives it
+//! byte[8:15] = u64 offset of the payload's first byte in the server's stream
+//! byte[16+]  = payload
+//! then       = u32 CRC-32 (IEEE) of everything from the magic byte through
+//!              the end of the payload
+//! ```
+//!
+//! A gap in sequence numbers means the frames were dropped between the server
+//! and the client (e.g. because the client couldn't keep up); the gap flag
+//! reports loss upstream of the server.
+//!
+//! When a client connects to a server using framing, it may first send an
+//! attach request saying where in the stream it wants to start:
+//!
+//! ```text
+//! byte[0]    = 0xA6 magic
+//! byte[1]    = 0 to start with live data, 1 to resume from an offset, 2 to
+//!              start with data captured in the last so many milliseconds,
+//!              3 to ask for the server's status instead of data
+//! byte[2:9]  = u64 offset or milliseconds, depending on byte[1]
+//! ```
+//!
+//! A server asked for its status replies with a single line holding a JSON
+//! object, and then closes the connection:
+//!
+//! ```text
+//! {"bytes":1048576,"gaps":0,"rate_1s":2951,"gaps_1s":0,"rate_10s":2951,
+//!  "gaps_10s":0,"rate_60s":2951,"gaps_60s":0}
+//! ```
+//!
+//! `bytes` and `gaps` count everything since capture started; the other
+//! fields cover the last so many seconds, as in the `throughput` events of
+//! `telemetry::StatusLog`.
+//!
+//! If the server requires a token, clients must send an auth message before
+//! anything else (even if the server isn't using framing):
+//!
+//! ```text
+//! byte[0]    = 0xA7 magic
+//! byte[1]    = token length in bytes
+//! byte[2+]   = token
+//! ```
+//!
+//! The server closes the connection if the token is wrong.
+//!
+//! A server that can't go back as far as requested starts as close to the
+//! requested point as it can; clients can tell from the offset in the first
+//! frame. A server may also start slightly *before* the requested offset, so
+//! clients should discard data they've already seen. If no attach request
+//! arrives promptly, the client gets live data.
+
+use std::convert::TryFrom;
+use std::io::{self, Read};
+use std::time::Duration;
+
+pub const MAGIC: u8 = 0xA5;
+pub const FLAG_PROBE_GAP: u8 = 1 << 0;
+pub const HEADER_LEN: usize = 16;
+pub const TRAILER_LEN: usize = 4;
+
+pub const ATTACH_MAGIC: u8 = 0xA6;
+pub const ATTACH_LEN: usize = 10;
+
+pub const AUTH_MAGIC: u8 = 0xA7;
+
+/// Largest payload that fits in a frame.
+pub const MAX_PAYLOAD: usize = u16::MAX as usize;
+
+/// Appends a frame carrying `payload`, which starts at `offset` in the
+/// stream, to `out`.
+///
+/// # Panics
+///
+/// If `payload` is longer than `MAX_PAYLOAD`.
+pub fn encode(
+    sequence: u32,
+    offset: u64,
+    probe_gap: bool,
+    payload: &[u8],
+    out: &mut Vec<u8>,
+) {
+    assert!(payload.len() <= MAX_PAYLOAD);
+    let start = out.len();
+    out.push(MAGIC);
+    out.push(if probe_gap { FLAG_PROBE_GAP } else { 0 });
+    out.extend_from_slice(&(payload.len() as u16).to_le_bytes());
+    out.extend_from_slice(&sequence.to_le_bytes());
+    out.extend_from_slice(&offset.to_le_bytes());
+    out.extend_from_slice(payload);
+    let crc = crc32(&out[start..]);
+    out.extend_from_slice(&crc.to_le_bytes());
+}
+
+/// Where a client asks to start receiving data.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Attach {
+    /// Start with whatever arrives next.
+    Live,
+    /// Start at this offset in the stream, if the server still has it.
+    Resume(u64),
+    /// Start with data the server received this long ago, if it still has
+    /// it.
+    Since(Duration),
+    /// Don't send data; send the server's status and hang up.
+    Status,
+}
+
+impl Attach {
+    pub fn encode(self) -> [u8; ATTACH_LEN] {
+        let mut req = [0; ATTACH_LEN];
+        req[0] = ATTACH_MAGIC;
+        let (mode, arg) = match self {
+            Attach::Live => (0, 0),
+            Attach::Resume(offset) => (1, offset),
+            Attach::Since(d) => (2, d.as_millis() as u64),
+            Attach::Status => (3, 0),
+        };
+        req[1] = mode;
+        req[2..].copy_from_slice(&arg.to_le_bytes());
+        req
+    }
+
+    pub fn decode(req: &[u8; ATTACH_LEN]) -> io::Result<Self> {
+        if req[0] != ATTACH_MAGIC {
+            return Err(invalid("bad attach request magic"));
+        }
+        let mut arg = [0; 8];
+        arg.copy_from_slice(&req[2..]);
+        let arg = u64::from_le_bytes(arg);
+        match req[1] {
+            0 => Ok(Attach::Live),
+            1 => Ok(Attach::Resume(arg)),
+            2 => Ok(Attach::Since(Duration::from_millis(arg))),
+            3 => Ok(Attach::Status),
+            _ => Err(invalid("unknown attach mode")),
+        }
+    }
+}
+
+/// Encodes an auth message presenting `token`, which must be at most 255
+/// bytes long.
+pub fn encode_auth(token: &str) -> io::Result<Vec<u8>> {
+    let len = u8::try_from(token.len())
+        .map_err(|_| invalid("token longer than 255 bytes"))?;
+    let mut msg = vec![AUTH_MAGIC, len];
+    msg.extend_from_slice(token.as_bytes());
+    Ok(msg)
+}
+
+/// Reads an auth message, returning the token presented.
+pub fn read_auth(mut stream: impl Read) -> io::Result<String> {
+    let mut header = [0; 2];
+    stream.read_exact(&mut header)?;
+    if header[0] != AUTH_MAGIC {
+        return Err(invalid("bad auth message magic"));
+    }
+    let mut token = vec![0; usize::from(header[1])];
+    stream.read_exact(&mut token)?;
+    String::from_utf8(token).map_err(|_| invalid("token isn't UTF-8"))
+}
+
+/// A frame received from the server.
+#[derive(Clone, Debug)]
+pub struct Frame {
+    pub sequence: u32,
+    /// Offset of the first byte of `payload` in the server's stream.
+    pub offset: u64,
+    /// The probe lost data just before this payload.
+    pub probe_gap: bool,
+    /// Number of frames that should have arrived before this one but didn't,
+    /// i.e. were lost between the server and us.
+    pub frames_dropped: u32,
+    pub payload: Vec<u8>,
+}
+
+/// Reads and checks frames from a stream.
+pub struct FrameReader<R> {
+    inner: R,
+    next_sequence: Option<u32>,
+}
+
+impl<R: Read> FrameReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            next_sequence: None,
+        }
+    }
+
+    /// Reads the next frame. Returns `Ok(None)` at a clean end of stream, and
+    /// an error of kind `InvalidData` if a frame is corrupt.
+    pub fn next_frame(&mut self) -> io::Result<Option<Frame>> {
+        let mut header = [0; HEADER_LEN];
+        match self.inner.read_exact(&mut header[..1]) {
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                return Ok(None)
+            }
+            r => r?,
+        }
+        self.inner.read_exact(&mut header[1..])?;
+        if header[0] != MAGIC {
+            return Err(invalid("bad frame magic"));
+        }
+        let len = usize::from(u16::from_le_bytes([header[2], header[3]]));
+        let mut word = [0; 4];
+        word.copy_from_slice(&header[4..8]);
+        let sequence = u32::from_le_bytes(word);
+        let mut dword = [0; 8];
+        dword.copy_from_slice(&header[8..16]);
+        let offset = u64::from_le_bytes(dword);
+
+        let mut rest = vec![0; len + TRAILER_LEN];
+        self.inner.read_exact(&mut rest)?;
+        let mut crc_bytes = [0; TRAILER_LEN];
+        crc_bytes.copy_from_slice(&rest[len..]);
+        rest.truncate(len);
+
+        let mut crc = Crc32::new();
+        crc.update(&header);
+        crc.update(&rest);
+        if crc.finish() != u32::from_le_bytes(crc_bytes) {
+            return Err(invalid("frame CRC mismatch"));
+        }
+
+        let frames_dropped = match self.next_sequence {
+            Some(expected) => sequence.wrapping_sub(expected),
+            None => 0,
+        };
+        self.next_sequence = Some(sequence.wrapping_add(1));
+
+        Ok(Some(Frame {
+            sequence,
+            offset,
+            probe_gap: header[1] & FLAG_PROBE_GAP != 0,
+            frames_dropped,
+            payload: rest,
+        }))
+    }
+
+    /// Gives back the underlying stream.
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Computes the CRC-32 (IEEE 802.3) of `data`.
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = Crc32::new();
+    crc.update(data);
+    crc.finish()
+}
+
+/// Incremental CRC-32 (IEEE 802.3) computation.
+pub struct Crc32(u32);
+
+impl Crc32 {
+    pub fn new() -> Self {
+        Crc32(!0)
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        for &b in data {
+            self.0 ^= u32::from(b);
+            for _ in 0..8 {
+                let mask = (self.0 & 1).wrapping_neg();
+                self.0 = (self.0 >> 1) ^ (0xEDB8_8320 & mask);
+            }
+        }
+    }
+
+    pub fn finish(&self) -> u32 {
+        !self.0
+    }
+}
+
+impl Default for Crc32 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32(b""), 0);
+        let mut crc = Crc32::new();
+        crc.update(b"1234");
+        crc.update(b"56789");
+        assert_eq!(crc.finish(), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut stream = vec![];
+        encode(7, 0, false, b"hello", &mut stream);
+        encode(8, 5, true, b"", &mut stream);
+        // Frames 9 and 10 went to other clients, or nowhere.
+        encode(11, 100, false, &[0xA5; 300], &mut stream);
+        encode(u32::MAX, 400, false, b"x", &mut stream);
+        encode(0, 401, false, b"y", &mut stream);
+
+        let mut reader = FrameReader::new(&stream[..]);
+        let mut next = || reader.next_frame().unwrap().unwrap();
+        let f = next();
+        assert_eq!((f.sequence, f.offset, f.probe_gap), (7, 0, false));
+        assert_eq!((f.frames_dropped, &f.payload[..]), (0, &b"hello"[..]));
+        let f = next();
+        assert_eq!((f.sequence, f.offset, f.probe_gap), (8, 5, true));
+        assert_eq!((f.frames_dropped, f.payload.len()), (0, 0));
+        let f = next();
+        assert_eq!((f.sequence, f.offset, f.frames_dropped), (11, 100, 2));
+        assert_eq!(f.payload, [0xA5; 300]);
+        let f = next();
+        assert_eq!((f.sequence, f.frames_dropped), (u32::MAX, u32::MAX - 12));
+        // Sequence numbers wrap.
+        let f = next();
+        assert_eq!(
+            (f.sequence, f.frames_dropped, &f.payload[..]),
+            (0, 0, &b"y"[..])
+        );
+        assert!(reader.next_frame().unwrap().is_none());
+    }
+
+    #[test]
+    fn corruption() {
+        let mut frame = vec![];
+        encode(1, 2, false, b"payload", &mut frame);
+        for i in 0..frame.len() {
+            let mut bad = frame.clone();
+            bad[i] ^= 0x10;
+            let mut reader = FrameReader::new(&bad[..]);
+            let e = reader.next_frame().unwrap_err();
+            // A longer length runs off the end before the CRC is checked.
+            if !(2..4).contains(&i) {
+                assert_eq!(e.kind(), io::ErrorKind::InvalidData, "byte {}", i);
+            }
+        }
+        // A frame cut short isn't a clean end of stream.
+        let mut reader = FrameReader::new(&frame[..frame.len() - 1]);
+        assert!(reader.next_frame().is_err());
+    }
+
+    #[test]
+    fn attach_round_trip() {
+        for &req in &[
+            Attach::Live,
+            Attach::Resume(0x0123_4567_89ab_cdef),
+            Attach::Since(Duration::from_millis(1500)),
+            Attach::Status,
+        ] {
+            assert_eq!(Attach::decode(&req.encode()).unwrap(), req);
+        }
+        let mut req = Attach::Live.encode();
+        req[1] = 4;
+        assert!(Attach::decode(&req).is_err());
+        req[0] = MAGIC;
+        assert!(Attach::decode(&req).is_err());
+    }
+
+    #[test]
+    fn auth_round_trip() {
+        let msg = encode_auth("sesame").unwrap();
+        assert_eq!(read_auth(&msg[..]).unwrap(), "sesame");
+        assert!(read_auth(&msg[..msg.len() - 1]).is_err());
+        assert!(encode_auth(&"x".repeat(256)).is_err());
+    }
+}
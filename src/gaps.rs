@@ -0,0 +1,136 @@
+//! Policies for what to do when data is lost.
+//!
+//! Decoders that care about framing (ITM, defmt, and so on) throw away
+//! anything half-decoded at a gap, and hunt for the start of the next
+//! message, so that the data either side of it isn't spliced together. Raw
+//! output, though, is just the bytes that arrived, and carries on as if
+//! nothing had happened. `OnGap` lets the user choose instead to mark gaps in
+//! the raw output, to leave decoders be, or to stop altogether.
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+
+use crate::capture::{FillLevels, Fragment, Gap, GapCause, Sink};
+use crate::timestamp::Clock;
+
+/// Marker written into the output by `Policy::Marker` unless told otherwise.
+pub const DEFAULT_MARKER: &[u8] = b"\n-- data may have been lost here --\n";
+
+/// What to do about a gap.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Policy {
+    /// Only warn (which capture does anyway); decoders aren't told, and
+    /// carry on as if the data either side of the gap were continuous.
+    Warn,
+    /// Write these bytes into the output at the gap, and let decoders
+    /// resynchronize.
+    Marker(Vec<u8>),
+    /// Let decoders throw away what they have, and resynchronize.
+    Flush,
+    /// Stop, failing with `DataLost`.
+    Abort,
+}
+
+impl Policy {
+    /// Names of the policies, as accepted by `from_str`.
+    pub const NAMES: &'static [&'static str] =
+        &["warn", "marker", "flush", "abort"];
+}
+
+impl FromStr for Policy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "warn" => Ok(Policy::Warn),
+            "marker" => Ok(Policy::Marker(DEFAULT_MARKER.to_vec())),
+            "flush" => Ok(Policy::Flush),
+            "abort" => Ok(Policy::Abort),
+            _ => Err(format!("unknown gap policy `{}`", s)),
+        }
+    }
+}
+
+/// Capture stopped because data was lost, as asked by `Policy::Abort`.
+#[derive(Copy, Clone, Debug)]
+pub struct DataLost(pub Option<GapCause>);
+
+impl fmt::Display for DataLost {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Some(cause) => write!(f, "data was lost ({})", cause),
+            None => write!(f, "data was lost"),
+        }
+    }
+}
+
+impl Error for DataLost {}
+
+/// Checks whether an error from a sink is an `OnGap` giving up.
+pub fn is_data_lost(e: &io::Error) -> bool {
+    e.get_ref().is_some_and(|e| e.is::<DataLost>())
+}
+
+/// Sink that applies a `Policy` to gaps, and passes everything else on to
+/// `inner` untouched.
+pub struct OnGap {
+    inner: Box<dyn Sink>,
+    policy: Policy,
+}
+
+impl OnGap {
+    pub fn new(inner: Box<dyn Sink>, policy: Policy) -> Self {
+        Self { inner, policy }
+    }
+
+    fn apply(&mut self, gap: Option<&Gap>) -> io::Result<()> {
+        let forward = |inner: &mut Box<dyn Sink>| match gap {
+            Some(gap) => inner.lost(gap),
+            None => inner.gap(),
+        };
+        match &self.policy {
+            Policy::Warn => Ok(()),
+            Policy::Marker(marker) => {
+                self.inner.data(marker)?;
+                forward(&mut self.inner)
+            }
+            Policy::Flush => forward(&mut self.inner),
+            Policy::Abort => {
+                forward(&mut self.inner)?;
+                Err(io::Error::other(DataLost(gap.map(|g| g.cause))))
+            }
+        }
+    }
+}
+
+impl Sink for OnGap {
+    fn data(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.inner.data(bytes)
+    }
+
+    fn fragment(&mut self, info: &Fragment, bytes: &[u8]) -> io::Result<()> {
+        self.inner.fragment(info, bytes)
+    }
+
+    fn gap(&mut self) -> io::Result<()> {
+        self.apply(None)
+    }
+
+    fn lost(&mut self, gap: &Gap) -> io::Result<()> {
+        self.apply(Some(gap))
+    }
+
+    fn bit_rate(&mut self, rate: u32) -> io::Result<()> {
+        self.inner.bit_rate(rate)
+    }
+
+    fn fill_levels(&mut self, levels: &FillLevels) -> io::Result<()> {
+        self.inner.fill_levels(levels)
+    }
+
+    fn clock(&mut self, clock: Clock) -> io::Result<()> {
+        self.inner.clock(clock)
+    }
+}
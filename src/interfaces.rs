@@ -0,0 +1,154 @@
+//! Working out which of a probe's USB interfaces does what.
+//!
+//! The LPC-Link2 firmware exposes several interfaces, only some of which are
+//! HID. Rather than hardcoding interface numbers, which differ between firmware
+//! builds, we identify each interface from its descriptors where the OS lets
+//! us read them, and fall back to the layout of the firmware described in the
+//! README otherwise.
+
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+/// What an interface is for.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Function {
+    /// The CMSIS-DAP debug port.
+    CmsisDap,
+    /// Virtual serial port (CDC ACM), for the target's UART.
+    Vcom,
+    /// The proprietary SWO trace port this crate speaks to.
+    Trace,
+    /// NXP's LPCSIO bridge, for driving the probe's SPI/I2C/GPIO pins.
+    Bridge,
+    /// Something we don't recognize.
+    Unknown,
+}
+
+impl fmt::Display for Function {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Function::CmsisDap => "CMSIS-DAP",
+            Function::Vcom => "VCOM",
+            Function::Trace => "trace",
+            Function::Bridge => "bridge",
+            Function::Unknown => "unknown",
+        })
+    }
+}
+
+/// One USB interface of a probe.
+#[derive(Clone, Debug)]
+pub struct Interface {
+    /// `bInterfaceNumber`.
+    pub number: i32,
+    pub function: Function,
+    /// The interface string descriptor, if we could read it.
+    pub name: Option<String>,
+    /// Path for opening the interface with hidapi, if it's a HID interface.
+    pub hid_path: Option<String>,
+}
+
+/// USB interface classes we care about.
+const CLASS_CDC: u8 = 0x02;
+const CLASS_CDC_DATA: u8 = 0x0a;
+
+/// Describes the interfaces of the probe plugged into `usb_port` (see
+/// `select::usb_port_path`), given the HID interfaces we found on it as
+/// `(interface number, path)`. Interfaces we can't read the descriptors of
+/// are assumed to be as in `layout` (see `firmware::layout`).
+///
+/// Non-HID interfaces are only found if their descriptors can be read, which
+/// is currently only the case on Linux.
+pub fn describe(
+    usb_port: Option<&str>,
+    hid: &[(i32, String)],
+    layout: &[(i32, Function)],
+) -> Vec<Interface> {
+    let mut interfaces = usb_port.map(read_sysfs).unwrap_or_default();
+
+    for (number, path) in hid {
+        match interfaces.iter_mut().find(|i| i.number == *number) {
+            Some(i) => i.hid_path = Some(path.clone()),
+            None => interfaces.push(Interface {
+                number: *number,
+                function: Function::Unknown,
+                name: None,
+                hid_path: Some(path.clone()),
+            }),
+        }
+    }
+
+    // If we know nothing better about an interface, assume the usual layout.
+    for i in &mut interfaces {
+        if i.function == Function::Unknown && i.name.is_none() {
+            i.function = default_function(layout, i.number);
+        }
+    }
+
+    interfaces.sort_by_key(|i| i.number);
+    interfaces
+}
+
+/// Returns the function of interface `number` in `layout`, for when we
+/// can't read its descriptors.
+pub(crate) fn default_function(
+    layout: &[(i32, Function)],
+    number: i32,
+) -> Function {
+    layout
+        .iter()
+        .find(|(n, _)| *n == number)
+        .map_or(Function::Unknown, |(_, f)| *f)
+}
+
+/// Guesses an interface's function from its descriptors.
+pub(crate) fn classify(name: Option<&str>, class: Option<u8>) -> Function {
+    let name = name.map(str::to_uppercase).unwrap_or_default();
+    if name.contains("CMSIS-DAP") {
+        Function::CmsisDap
+    } else if name.contains("DATA PORT") {
+        Function::Trace
+    } else if name.contains("LPCSIO") || name.contains("BRIDGE") {
+        Function::Bridge
+    } else if name.contains("VCOM")
+        || class == Some(CLASS_CDC)
+        || class == Some(CLASS_CDC_DATA)
+    {
+        Function::Vcom
+    } else {
+        Function::Unknown
+    }
+}
+
+/// Reads interface descriptors for the device at `usb_port` from sysfs.
+fn read_sysfs(usb_port: &str) -> Vec<Interface> {
+    let entries = match fs::read_dir("/sys/bus/usb/devices") {
+        Ok(e) => e,
+        Err(_) => return vec![],
+    };
+    let prefix = format!("{}:", usb_port);
+    let read = |dir: &Path, attr: &str| -> Option<String> {
+        Some(fs::read_to_string(dir.join(attr)).ok()?.trim().to_string())
+    };
+
+    entries
+        .filter_map(Result::ok)
+        .filter(|e| e.file_name().to_string_lossy().starts_with(&prefix))
+        .filter_map(|e| {
+            let dir = e.path();
+            let number =
+                i32::from_str_radix(&read(&dir, "bInterfaceNumber")?, 16)
+                    .ok()?;
+            let class = read(&dir, "bInterfaceClass")
+                .and_then(|c| u8::from_str_radix(&c, 16).ok());
+            let name = read(&dir, "interface");
+            Some(Interface {
+                number,
+                function: classify(name.as_deref(), class),
+                name,
+                hid_path: None,
+            })
+        })
+        .collect()
+}
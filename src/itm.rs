@@ -0,0 +1,282 @@
+//! Decoder for the ITM/DWT packet protocol that Cortex-M parts usually send
+//! over SWO, as described in the ARMv7-M Architecture Reference Manual,
+//! appendix D4.
+
+use std::ops::Deref;
+
+/// A decoded ITM packet.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Packet {
+    /// Synchronization packet.
+    Sync,
+    /// The ITM's output FIFO overflowed and data was lost on the target side.
+    Overflow,
+    /// Data written by software to a stimulus port.
+    Instrumentation { port: u8, payload: Payload },
+    /// Data generated by the DWT, identified by its discriminator: 0 is event
+    /// counter wrap, 1 is exception trace, 2 is PC sample, and 8-23 are data
+    /// trace.
+    Hardware { discriminator: u8, payload: Payload },
+    /// Local timestamp: `delta` cycles (of the timestamp clock) since the last
+    /// local timestamp. `relation` is the TC field, which says how the
+    /// timestamp relates to the packet it accompanies (0 means it's exact).
+    LocalTimestamp { delta: u32, relation: u8 },
+    /// Global timestamp, bits 25:0 (`high == false`) or 47:26
+    /// (`high == true`) of the global timestamp counter.
+    GlobalTimestamp { value: u32, high: bool },
+    /// Extension packet, as used for stimulus port page numbers.
+    Extension { value: u32, hardware: bool },
+}
+
+/// Up to four bytes of source packet payload, in the order they appeared on
+/// the wire.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Payload {
+    bytes: [u8; 4],
+    len: u8,
+}
+
+impl Payload {
+    /// Returns the payload as a little-endian integer, as the target wrote it.
+    pub fn value(&self) -> u32 {
+        u32::from_le_bytes(self.bytes)
+    }
+}
+
+impl Deref for Payload {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.bytes[..usize::from(self.len)]
+    }
+}
+
+/// Streaming ITM decoder. Feed it bytes as they arrive; packets come out as
+/// they're completed.
+#[derive(Default)]
+pub struct Decoder {
+    state: State,
+}
+
+#[derive(Copy, Clone, Default)]
+enum State {
+    /// Waiting for the first byte of a packet.
+    #[default]
+    Header,
+    /// Saw this many zero bytes, which may be the start of a sync packet.
+    Zeros(usize),
+    /// Collecting the payload of a source packet.
+    Source {
+        header: u8,
+        payload: Payload,
+        expected: u8,
+    },
+    /// Collecting the 7-bit groups of a packet with continuation bits.
+    Continued {
+        kind: Continued,
+        value: u32,
+        shift: u8,
+        count: u8,
+    },
+}
+
+#[derive(Copy, Clone)]
+enum Continued {
+    LocalTimestamp { relation: u8 },
+    GlobalTimestamp { high: bool },
+    Extension { hardware: bool },
+}
+
+/// A sync packet is at least 47 zero bits followed by a one. In practice it's
+/// five zero bytes and then 0x80.
+const SYNC_ZEROS: usize = 5;
+const SYNC_END: u8 = 0x80;
+const OVERFLOW: u8 = 0x70;
+const GTS1: u8 = 0x94;
+const GTS2: u8 = 0xB4;
+
+impl Decoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Forgets any partially received packet. Call this when there's a gap in
+    /// the input, so that we don't splice unrelated data into a packet.
+    ///
+    /// ITM has no way of finding packet boundaries other than sync packets,
+    /// which many targets don't send periodically, so after a reset we
+    /// optimistically assume the next byte starts a packet. This may produce
+    /// some garbage packets until the stream happens to line up again.
+    pub fn reset(&mut self) {
+        self.state = State::Header;
+    }
+
+    /// Decodes `bytes`, calling `f` with each packet completed.
+    pub fn feed(&mut self, bytes: &[u8], mut f: impl FnMut(Packet)) {
+        for &b in bytes {
+            if let Some(p) = self.push(b) {
+                f(p);
+            }
+        }
+    }
+
+    /// Decodes a single byte, returning a packet if it completes one.
+    pub fn push(&mut self, b: u8) -> Option<Packet> {
+        match self.state {
+            State::Header => self.header(b),
+            State::Zeros(n) => {
+                if b == 0 {
+                    self.state = State::Zeros(n + 1);
+                    None
+                } else if b == SYNC_END && n >= SYNC_ZEROS {
+                    self.state = State::Header;
+                    Some(Packet::Sync)
+                } else {
+                    // Not a valid sync packet; the zeros were (malformed)
+                    // noise, so start over with this byte.
+                    self.header(b)
+                }
+            }
+            State::Source {
+                header,
+                mut payload,
+                expected,
+            } => {
+                payload.bytes[usize::from(payload.len)] = b;
+                payload.len += 1;
+                if payload.len < expected {
+                    self.state = State::Source {
+                        header,
+                        payload,
+                        expected,
+                    };
+                    return None;
+                }
+                self.state = State::Header;
+                let address = header >> 3;
+                Some(if header & 0b100 == 0 {
+                    Packet::Instrumentation {
+                        port: address,
+                        payload,
+                    }
+                } else {
+                    Packet::Hardware {
+                        discriminator: address,
+                        payload,
+                    }
+                })
+            }
+            State::Continued {
+                kind,
+                value,
+                shift,
+                count,
+            } => {
+                let value = value | u32::from(b & 0x7F) << shift;
+                let count = count + 1;
+                // No ITM packet has more than four bytes after the header.
+                if b & 0x80 != 0 && count < 4 {
+                    self.state = State::Continued {
+                        kind,
+                        value,
+                        shift: shift + 7,
+                        count,
+                    };
+                    return None;
+                }
+                self.state = State::Header;
+                Some(match kind {
+                    Continued::LocalTimestamp { relation } => {
+                        Packet::LocalTimestamp {
+                            delta: value,
+                            relation,
+                        }
+                    }
+                    Continued::GlobalTimestamp { high } => {
+                        Packet::GlobalTimestamp { value, high }
+                    }
+                    Continued::Extension { hardware } => {
+                        Packet::Extension { value, hardware }
+                    }
+                })
+            }
+        }
+    }
+
+    fn header(&mut self, b: u8) -> Option<Packet> {
+        self.state = State::Header;
+        if b == 0 {
+            self.state = State::Zeros(1);
+            return None;
+        }
+        if b == OVERFLOW {
+            return Some(Packet::Overflow);
+        }
+        if b & 0b11 != 0 {
+            let expected = match b & 0b11 {
+                0b01 => 1,
+                0b10 => 2,
+                _ => 4,
+            };
+            self.state = State::Source {
+                header: b,
+                payload: Payload {
+                    bytes: [0; 4],
+                    len: 0,
+                },
+                expected,
+            };
+            return None;
+        }
+        if b & 0x0F == 0 {
+            // Local timestamp.
+            if b & 0x80 == 0 {
+                // Format 2: the timestamp is in the header.
+                return Some(Packet::LocalTimestamp {
+                    delta: u32::from(b >> 4),
+                    relation: 0,
+                });
+            }
+            self.state = State::Continued {
+                kind: Continued::LocalTimestamp {
+                    relation: (b >> 4) & 0b11,
+                },
+                value: 0,
+                shift: 0,
+                count: 0,
+            };
+            return None;
+        }
+        if b == GTS1 || b == GTS2 {
+            self.state = State::Continued {
+                kind: Continued::GlobalTimestamp { high: b == GTS2 },
+                value: 0,
+                shift: 0,
+                count: 0,
+            };
+            return None;
+        }
+        if b & 0b1011 == 0b1000 {
+            let low = u32::from((b >> 4) & 0b111);
+            let hardware = b & 0b100 != 0;
+            if b & 0x80 == 0 {
+                return Some(Packet::Extension {
+                    value: low,
+                    hardware,
+                });
+            }
+            // The header supplies the low three bits of the value, and any
+            // following bytes the rest.
+            self.state = State::Continued {
+                kind: Continued::Extension { hardware },
+                value: low,
+                shift: 3,
+                count: 0,
+            };
+            return None;
+        }
+        // Reserved header; skip it.
+        log::debug!("ignoring reserved ITM header {:02x}", b);
+        None
+    }
+}
@@ -0,0 +1,311 @@
+//! Library for talking to the LPC-Link2's SWO trace interface, as described in
+//! the README. The `lpc-cat` binary is a thin command-line wrapper around this.
+
+use std::cell::{Cell, RefCell};
+use std::time::Duration;
+
+pub mod access;
+#[cfg(feature = "tokio")]
+pub mod async_capture;
+pub mod capture;
+#[cfg(feature = "client")]
+pub mod client;
+pub mod dap;
+#[cfg(feature = "defmt")]
+pub mod defmt;
+pub mod demux;
+pub mod digest;
+pub mod embed;
+pub mod encoding;
+mod error;
+pub mod escape;
+pub mod export;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod firmware;
+pub mod format;
+pub mod framing;
+pub mod gaps;
+pub mod interfaces;
+pub mod itm;
+pub mod multi;
+pub mod pace;
+pub mod pause;
+pub mod ports;
+pub mod profile;
+pub mod protocol;
+#[cfg(feature = "python")]
+mod python;
+pub mod realtime;
+pub mod recording;
+pub mod rotate;
+pub mod select;
+pub mod serve;
+pub mod session;
+pub mod shutdown;
+pub mod soak;
+pub mod spool;
+pub mod stat;
+#[cfg(feature = "probe-rs")]
+pub mod swo_access;
+pub mod synthetic;
+pub mod telemetry;
+pub mod timestamp;
+pub mod trace;
+pub mod transport;
+pub mod until;
+#[cfg(feature = "bulk")]
+pub mod usb;
+pub mod version;
+pub mod watch;
+
+pub use error::Error;
+pub use protocol::{parse_poll, PollResult, CAPTURE_BUFFER_SIZE};
+
+use interfaces::Function;
+use select::Selector;
+use transport::Transport;
+
+/// An open connection to the trace interface of an LPC-Link2.
+pub struct Handle {
+    device: Box<dyn Transport>,
+    /// Bit rate the probe last agreed to capture at.
+    bit_rate: Cell<Option<u32>>,
+    /// Where responses to commands other than polls go, allocated once (or
+    /// given by `set_response_buffer`) rather than for every command.
+    response: RefCell<Vec<u8>>,
+}
+
+impl Handle {
+    /// Opens the LPC-Link2 device with the given vid/pid that satisfies
+    /// `selector`.
+    ///
+    /// If several devices match, the first one will be chosen.
+    pub fn open(
+        vid: u16,
+        pid: u16,
+        selector: &Selector,
+    ) -> Result<Self, Error> {
+        Self::open_with(vid, pid, selector, transport::Kind::Hid)
+    }
+
+    /// Like `open`, but talks to the probe through the given transport.
+    pub fn open_with(
+        vid: u16,
+        pid: u16,
+        selector: &Selector,
+        kind: transport::Kind,
+    ) -> Result<Self, Error> {
+        let api = hidapi::HidApi::new()?;
+
+        // Probes are found through hidapi whatever the transport, so that
+        // selectors work the same.
+        let probe = select::enumerate(&api, vid, pid, selector)
+            .into_iter()
+            .find(|p| p.hid_path(Function::Trace).is_some())
+            .ok_or(Error::DeviceNotFound)?;
+        let path = probe.hid_path(Function::Trace).unwrap();
+
+        log::info!("found matching device at {}", path);
+
+        let device: Box<dyn Transport> = match kind {
+            transport::Kind::Hid => {
+                // Paths come from C strings in the first place, so can't
+                // contain NUL.
+                let path = std::ffi::CString::new(path).unwrap();
+                Box::new(api.open_path(&path)?)
+            }
+            #[cfg(feature = "bulk")]
+            transport::Kind::Bulk => Box::new(usb::Usb::open(
+                vid,
+                pid,
+                probe.usb_port.as_deref(),
+                probe.serial.as_deref(),
+            )?),
+            #[cfg(not(feature = "bulk"))]
+            transport::Kind::Bulk => {
+                return Err(Error::Unsupported("libusb in this build"))
+            }
+        };
+
+        Ok(Self::from_transport(device))
+    }
+
+    /// Talks to a probe through `device`, which needn't be a real one: say,
+    /// a stand-in that answers commands from a script, for testing code
+    /// built on `Handle` without hardware (see `examples/mock_transport.rs`).
+    pub fn from_transport(device: Box<dyn Transport>) -> Self {
+        let size = device.report_size().unwrap_or(protocol::PACKET_SIZE);
+        Self {
+            device,
+            bit_rate: Cell::new(None),
+            response: RefCell::new(vec![0; size]),
+        }
+    }
+
+    /// Returns the probe's USB serial number, if it has one.
+    pub fn serial(&self) -> Result<Option<String>, Error> {
+        self.device.serial()
+    }
+
+    /// Returns the size of the packets the probe sends, and so how big a
+    /// buffer `poll` needs: the size of the trace interface's input reports,
+    /// if the transport can find it out, or else `protocol::PACKET_SIZE`.
+    pub fn response_size(&self) -> usize {
+        self.response.borrow().len()
+    }
+
+    /// Has responses to commands other than polls read into `buffer`,
+    /// rather than one allocated by `open`, for callers that look after
+    /// their own memory. The buffer must be at least `response_size` bytes.
+    pub fn set_response_buffer(
+        &mut self,
+        buffer: Vec<u8>,
+    ) -> Result<(), Error> {
+        if buffer.len() < self.response_size() {
+            return Err(Error::State("response buffer is too small"));
+        }
+        self.response = RefCell::new(buffer);
+        Ok(())
+    }
+
+    /// Sends a command and passes the response to `decode`.
+    fn command<T>(
+        &self,
+        request: &[u8],
+        decode: impl FnOnce(&[u8]) -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        self.device.write(request)?;
+
+        let mut response = self.response.borrow_mut();
+        let n = self.device.read(&mut response, None)?;
+        decode(&response[..n])
+    }
+
+    /// Initializes communications with the probe.
+    pub fn ohai(&self, mode: u8) -> Result<(), Error> {
+        self.command(&protocol::encode_ohai(mode), protocol::decode_ohai)
+    }
+
+    /// Sets the probe up to capture Manchester-encoded SWO, which (unlike
+    /// UART) needs no bit rate agreement.
+    ///
+    /// We've never seen the vendor tools do this, so it's a guess (see
+    /// `protocol::MODE_MANCHESTER`). If the probe doesn't accept it, this
+    /// returns `Error::Unsupported`.
+    pub fn init_manchester(&self) -> Result<(), Error> {
+        match self.ohai(protocol::MODE_MANCHESTER) {
+            Err(Error::Protocol { .. }) => {
+                Err(Error::Unsupported("Manchester SWO capture"))
+            }
+            r => r,
+        }
+    }
+
+    /// Does basic UART setup and returns the highest available bit rate.
+    pub fn init_uart(&self) -> Result<u32, Error> {
+        self.command(&protocol::encode_init_uart(), protocol::decode_init_uart)
+    }
+
+    /// Configures the UART for a particular bit rate (in bits per second). The
+    /// probe can't achieve just *any* rate, and will return a nearby achievable
+    /// rate.
+    pub fn set_bit_rate(&self, rate: u32) -> Result<u32, Error> {
+        let rate = self.command(
+            &protocol::encode_set_bit_rate(rate),
+            protocol::decode_set_bit_rate,
+        )?;
+        self.bit_rate.set(Some(rate));
+        Ok(rate)
+    }
+
+    /// Sets the highest bit rate the probe can achieve at or below
+    /// `ceiling`, and returns it. The probe answers each request with a
+    /// nearby rate it can manage, which may be above or below the request;
+    /// requests are lowered by however far the probe overshot until it
+    /// doesn't, giving up with `UnachievableBitRate` after a few tries.
+    pub fn set_bit_rate_up_to(&self, ceiling: u32) -> Result<u32, Error> {
+        const TRIES: usize = 16;
+        let mut request = ceiling;
+        let mut closest = 0;
+        for _ in 0..TRIES {
+            closest = self.set_bit_rate(request)?;
+            if closest <= ceiling {
+                return Ok(closest);
+            }
+            let lower =
+                u64::from(request) * u64::from(ceiling) / u64::from(closest);
+            request = match (lower as u32).checked_sub(1) {
+                Some(0) | None => break,
+                Some(r) => r,
+            };
+        }
+        Err(Error::UnachievableBitRate {
+            requested: ceiling,
+            closest,
+        })
+    }
+
+    /// Returns the bit rate the probe last agreed to in `set_bit_rate`, if
+    /// any.
+    pub fn bit_rate(&self) -> Option<u32> {
+        self.bit_rate.get()
+    }
+
+    /// Sends an arbitrary command, and waits up to `timeout` for a response,
+    /// which is written to `response`. Returns the length of the response, or
+    /// `None` if there wasn't one in time.
+    ///
+    /// This is for exploring the protocol: it's up to the caller to know what
+    /// the command will do.
+    pub fn transact(
+        &self,
+        command: &[u8],
+        response: &mut [u8],
+        timeout: Duration,
+    ) -> Result<Option<usize>, Error> {
+        self.device.write(command)?;
+        match self.device.read(response, Some(timeout))? {
+            0 => Ok(None),
+            n => Ok(Some(n)),
+        }
+    }
+
+    /// Asks the probe for an update on its capture buffer, which is read
+    /// into `buffer`; this must be at least `response_size` bytes.
+    ///
+    /// Returns a tuple of `(epoch, poll_result)`. See `parse_poll`.
+    pub fn poll<'a>(
+        &self,
+        buffer: &'a mut [u8],
+    ) -> Result<(u8, PollResult<'a>), Error> {
+        assert!(buffer.len() >= self.response_size());
+
+        self.device.write(&protocol::encode_poll())?;
+
+        let n = self.device.read(buffer, None)?;
+
+        parse_poll(&mut buffer[..n])
+    }
+}
+
+/// Ways the target can encode SWO.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SwoProtocol {
+    /// NRZ, i.e. UART framing at an agreed bit rate.
+    Uart,
+    /// Manchester encoding, which carries its own clock.
+    Manchester,
+}
+
+impl std::str::FromStr for SwoProtocol {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "uart" => Ok(SwoProtocol::Uart),
+            "manchester" => Ok(SwoProtocol::Manchester),
+            _ => Err(format!("unknown SWO protocol `{}`", s)),
+        }
+    }
+}
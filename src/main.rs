@@ -1,300 +1,2714 @@
 //! `lpc-cat`: reference implementation for reading from the LPC-Link2 SWO
 //! endpoint.
 
-use std::convert::TryInto;
 use std::error::Error;
-use std::io::Write;
-use std::thread::sleep;
-use std::time::Duration;
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
 
 use structopt::StructOpt;
 
+use lpc_cat::capture::Sink;
+use lpc_cat::dap::Dap;
+use lpc_cat::interfaces::Function;
+use lpc_cat::select::{self, Selector};
+use lpc_cat::shutdown::{Shutdown, Stage};
+use lpc_cat::{
+    access, capture, demux, digest, encoding, escape, export, firmware, format,
+    gaps, multi, pace, pause, ports, profile, protocol, realtime, recording,
+    rotate, serve, soak, spool, stat, synthetic, telemetry, trace, transport,
+    until, watch, Handle, SwoProtocol,
+};
+
 /// A tool for extracting SWO trace data from an LPC-Link2.
 ///
 /// Note: this tool will not magically cause your microcontroller to begin
-/// emitting SWO trace data. This is only useful once SWO is configured.
+/// emitting SWO trace data. This is only useful once SWO is configured, either
+/// by the firmware, or through the probe's debug port with `--cpu-freq`.
 ///
 /// Alternatively, you can use this tool to make the LPC-Link2 into the world's
 /// least convenient unidirectional UART.
-#[derive(StructOpt)]
+///
+/// For compatibility with older scripts, if no subcommand is given, `cat` is
+/// assumed.
+#[derive(Debug, StructOpt)]
 #[structopt(max_term_width = 80)]
-struct LpcCat {
-    /// USB Vendor ID of the debug probe, in hex.
-    #[structopt(long, short, default_value = "1fc9")]
-    vid: String,
-    /// USB Product ID of the debug probe, in hex.
-    #[structopt(long, short, default_value = "0090")]
-    pid: String,
+// Parsed once, so there's nothing to gain from boxing the big variants.
+#[allow(clippy::large_enum_variant)]
+enum LpcCat {
+    /// Capture SWO data and write it to stdout.
+    Cat {
+        #[structopt(flatten)]
+        probe: ProbeArgs,
+        #[structopt(flatten)]
+        session: SessionArgs,
+
+        /// Exit before actually extracting any data. This is only useful for
+        /// testing the setup and bitrate code.
+        #[structopt(long)]
+        no_cat: bool,
+
+        #[structopt(flatten)]
+        output: OutputArgs,
+
+        #[structopt(flatten)]
+        stop: StopArgs,
+
+        /// Capture from all attached probes that match the other criteria,
+        /// each on its own thread. Unless `--probe-dir` is given, their data
+        /// is interleaved on stdout in chunks tagged with each probe's serial
+        /// number (see `lpc_cat::multi`).
+        #[structopt(long, conflicts_with = "serial")]
+        all: bool,
+
+        /// Run a soak test: capture until stopped (reconnecting to the probe
+        /// if need be), checking that the stream adds up along the way (see
+        /// `lpc_cat::soak`), and writing statistics to stderr every
+        /// `--checkpoint-interval`. At the end, prints a verdict, and exits
+        /// with an error if the capture lost any data.
+        #[structopt(long, conflicts_with_all = &["all", "no-cat"])]
+        soak: bool,
+
+        /// Seconds between soak test checkpoints [default: 60].
+        #[structopt(long, value_name = "secs", requires = "soak")]
+        checkpoint_interval: Option<u64>,
+
+        /// When capturing from several probes, write each probe's data to
+        /// `<dir>/<serial>.swo` instead of interleaving it on stdout.
+        #[structopt(long, value_name = "dir", parse(from_os_str))]
+        probe_dir: Option<PathBuf>,
+    },
+    /// List attached debug probes.
+    ///
+    /// Prints each probe's serial number, USB port path, and product string,
+    /// and whether it offers the trace interface needed for SWO capture. Any of
+    /// these can be used to pick a probe with the other subcommands.
+    List {
+        #[structopt(flatten)]
+        probe: ProbeArgs,
+
+        /// Also list each probe's USB interfaces and what they're for.
+        #[structopt(long)]
+        verbose: bool,
+    },
+    /// Capture SWO data and write it to a file, along with when and how each
+    /// piece arrived, for later use with `replay`.
+    Record {
+        #[structopt(flatten)]
+        probe: ProbeArgs,
+        #[structopt(flatten)]
+        session: SessionArgs,
+
+        /// File to write the capture into. It will be replaced if it exists.
+        #[structopt(long, short, parse(from_os_str))]
+        output: PathBuf,
+
+        /// Also record the fill levels the probe reports for its capture
+        /// buffer on every poll. This makes recordings much larger when the
+        /// target is quiet.
+        #[structopt(long)]
+        fill_levels: bool,
+
+        #[structopt(flatten)]
+        stop: StopArgs,
+    },
+    /// Play back a recorded capture, processing it as `cat` would.
+    Replay {
+        /// Recording produced by the `record` subcommand.
+        #[structopt(parse(from_os_str))]
+        input: PathBuf,
+
+        /// Play back at this multiple of the original speed, e.g. `1` for
+        /// real time or `10` for ten times faster. By default, plays back as
+        /// fast as possible.
+        #[structopt(long, value_name = "factor")]
+        speed: Option<f64>,
+
+        /// Show the output stored by `cat --record` with the recording,
+        /// rather than decoding the data again.
+        #[structopt(
+            long,
+            conflicts_with_all = &[
+                "defmt", "port-out", "ports", "output", "escape", "format",
+                "fill-levels", "vcd", "ctf", "record",
+            ]
+        )]
+        decoded: bool,
+
+        #[structopt(flatten)]
+        output: OutputArgs,
+    },
+    /// Summarize recordings: how long they are, how much data they hold and
+    /// when it arrived, what was lost and why, and which ITM stimulus ports
+    /// were used.
+    Stat {
+        /// Recordings produced by `record` or `cat --record`.
+        #[structopt(parse(from_os_str), required = true)]
+        inputs: Vec<PathBuf>,
+
+        /// Number of slices of time to show throughput for.
+        #[structopt(long, value_name = "count", default_value = "10")]
+        buckets: usize,
+    },
+    /// Capture SWO data and stream it to TCP clients.
+    ///
+    /// Clients receive the raw SWO byte stream, just like the SWO ports of
+    /// OpenOCD and Orbuculum, so existing viewers can connect directly.
+    Serve {
+        #[structopt(flatten)]
+        probe: ProbeArgs,
+        #[structopt(flatten)]
+        session: SessionArgs,
+
+        /// Address to listen on for TCP connections.
+        #[structopt(
+            long,
+            value_name = "addr:port",
+            default_value = "127.0.0.1:2332"
+        )]
+        listen: String,
+
+        /// Wrap the data in frames with sequence numbers and CRCs, so clients
+        /// can tell data lost by the probe apart from data lost on the way to
+        /// them. Clients can also ask how capture is going, without taking
+        /// the data. The framing is not understood by other SWO tools.
+        #[structopt(long)]
+        framed: bool,
+
+        /// With `--framed`, keep this much recent data (e.g. `4M`) so that
+        /// clients that reconnect can resume where they left off.
+        #[structopt(
+            long,
+            value_name = "bytes",
+            default_value = "0",
+            parse(try_from_str = parse_size)
+        )]
+        history: u64,
+
+        /// Send each client at most this many bytes per second (e.g. `100K`),
+        /// dropping the rest, so that no one client can hog the link.
+        #[structopt(
+            long,
+            value_name = "bytes/s",
+            parse(try_from_str = parse_size)
+        )]
+        max_client_rate: Option<u64>,
+
+        /// Send all clients together at most this many bytes per second.
+        #[structopt(
+            long,
+            value_name = "bytes/s",
+            parse(try_from_str = parse_size)
+        )]
+        max_total_rate: Option<u64>,
+
+        /// Only accept connections from this network (e.g. `10.0.0.0/8` or
+        /// `::1`). May be given more than once; by default, any address may
+        /// connect.
+        #[structopt(long, value_name = "net", number_of_values = 1)]
+        allow: Vec<access::IpNet>,
+
+        /// Require clients to present the token in this file before sending
+        /// them data. Stock SWO viewers can't do this; use `--allow` to
+        /// restrict access for them instead.
+        #[structopt(long, value_name = "path", parse(from_os_str))]
+        token_file: Option<PathBuf>,
+
+        /// Speak TLS, using the certificate chain in this PEM file. Requires
+        /// `--tls-key`.
+        #[structopt(
+            long,
+            value_name = "path",
+            parse(from_os_str),
+            requires = "tls-key"
+        )]
+        tls_cert: Option<PathBuf>,
+
+        /// Private key (PEM) for `--tls-cert`.
+        #[structopt(
+            long,
+            value_name = "path",
+            parse(from_os_str),
+            requires = "tls-cert"
+        )]
+        tls_key: Option<PathBuf>,
+
+        /// Also record everything captured into rotating files in this
+        /// directory (see `replay`), whether or not any clients are
+        /// connected.
+        #[structopt(long, value_name = "dir", parse(from_os_str))]
+        spool: Option<PathBuf>,
+
+        /// Size at which to start a new spool file (e.g. `64M`).
+        #[structopt(
+            long,
+            value_name = "bytes",
+            default_value = "64M",
+            parse(try_from_str = parse_size)
+        )]
+        spool_size: u64,
+
+        /// Number of spool files to keep; the oldest are deleted.
+        #[structopt(long, value_name = "count", default_value = "8")]
+        spool_files: usize,
+    },
+    /// Send raw commands to the trace interface and dump the responses, for
+    /// working out more of the protocol.
+    ///
+    /// Each command is given in hex, e.g. `1fff` or `1f ff`. Commands are
+    /// taken from the command line, or, if there are none, read from stdin
+    /// one per line (ignoring blank lines and lines starting with `#`).
+    ///
+    /// This can put the probe in a state where it needs unplugging to
+    /// recover, and who knows what else. Use with care.
+    Raw {
+        #[structopt(flatten)]
+        probe: ProbeArgs,
+
+        /// How long to wait for each response, in milliseconds.
+        #[structopt(long, value_name = "ms", default_value = "1000")]
+        timeout: u64,
+
+        /// Instead of sending given commands, send each single-byte command
+        /// in turn and report which ones get a response.
+        #[structopt(long, conflicts_with = "commands")]
+        probe_commands: bool,
+
+        /// Command bytes (in hex) to leave out of `--probe-commands`, e.g.
+        /// because they're known to upset the probe. Can be repeated.
+        #[structopt(
+            long,
+            value_name = "byte",
+            number_of_values = 1,
+            parse(try_from_str = parse_hex_byte)
+        )]
+        skip: Vec<u8>,
+
+        /// Commands to send, in hex.
+        commands: Vec<String>,
+    },
+}
+
+/// Options for finding the debug probe.
+#[derive(Clone, Debug, StructOpt)]
+struct ProbeArgs {
+    /// USB Vendor ID of the debug probe, in hex. If neither this nor `--pid`
+    /// is given, the first attached probe running firmware with a trace
+    /// interface is used. [default: detected]
+    #[structopt(long, short)]
+    vid: Option<String>,
+    /// USB Product ID of the debug probe, in hex. [default: detected]
+    #[structopt(long, short)]
+    pid: Option<String>,
     /// Serial number of the particular debug probe to use. This is only
     /// required when more than one identical probe are connected to the system.
-    #[structopt(long, short)]
-    serial: Option<String>,
+    /// With `cat`, this can be given more than once to capture from several
+    /// probes at once.
+    #[structopt(long, short, number_of_values = 1)]
+    serial: Vec<String>,
+    /// Physical USB port path of the debug probe (e.g. `1-3.2`), as shown by
+    /// the `list` subcommand. Unlike serial numbers, this identifies the port
+    /// the probe is plugged into, which is useful when probes get swapped.
+    #[structopt(long, value_name = "path")]
+    usb_port: Option<String>,
+    /// Expression choosing the debug probe by its properties, e.g.
+    /// `serial~a5f* && port=1-3.*`. Terms are `key=glob`, `key~glob`
+    /// (ignoring case), or `key!=glob`, where key is one of `serial`, `port`,
+    /// `path`, `product`, `manufacturer`, `vid`, or `pid`; they can be combined
+    /// with `&&`, `||`, `!`, and parentheses.
+    #[structopt(long, value_name = "expr")]
+    select: Option<select::Expr>,
+    /// How to talk to the probe's trace interface: `hid` reports through the
+    /// OS's HID driver, or `bulk` USB transfers straight to its endpoints
+    /// through libusb, which keeps up with higher bit rates. (`bulk` requires
+    /// the `bulk` feature.)
+    #[structopt(
+        long,
+        value_name = "kind",
+        possible_values = transport::Kind::NAMES,
+        default_value = "hid"
+    )]
+    transport: transport::Kind,
+}
+
+impl ProbeArgs {
+    fn vid_pid(&self) -> Result<(u16, u16), Box<dyn Error>> {
+        if self.vid.is_none() && self.pid.is_none() {
+            let api = hidapi::HidApi::new().map_err(lpc_cat::Error::from)?;
+            return Ok(firmware::find(&api, &self.selector())?);
+        }
+        let vid =
+            u16::from_str_radix(self.vid.as_deref().unwrap_or("1fc9"), 16)
+                .map_err(|_| "can't parse vid as hex")?;
+        let pid =
+            u16::from_str_radix(self.pid.as_deref().unwrap_or("0090"), 16)
+                .map_err(|_| "can't parse pid as hex")?;
+        Ok((vid, pid))
+    }
+
+    fn selector(&self) -> Selector {
+        Selector {
+            serial: self.serial.first().cloned(),
+            usb_port: self.usb_port.clone(),
+            expr: self.select.clone(),
+        }
+    }
+
+    fn open(&self) -> Result<Handle, Box<dyn Error>> {
+        self.check_transport()?;
+        let (vid, pid) = self.vid_pid()?;
+        Ok(Handle::open_with(
+            vid,
+            pid,
+            &self.selector(),
+            self.transport,
+        )?)
+    }
+
+    fn check_transport(&self) -> Result<(), Box<dyn Error>> {
+        if self.transport == transport::Kind::Bulk && !cfg!(feature = "bulk") {
+            return Err("this build of lpc-cat doesn't support the bulk \
+                        transport; rebuild with `--features bulk`"
+                .into());
+        }
+        Ok(())
+    }
+
+    /// Finds the attached probes these options would capture from, without
+    /// opening them: the first that matches, or with `all`, every one. Finds
+    /// nothing if no probe can be found.
+    fn resolve(&self, all: bool) -> Vec<((u16, u16), select::ProbeInfo)> {
+        let ids = match self.vid_pid() {
+            Ok(ids) => ids,
+            Err(_) => return vec![],
+        };
+        let api = match hidapi::HidApi::new() {
+            Ok(api) => api,
+            Err(_) => return vec![],
+        };
+        // With several serial numbers, any probe with one of them will do.
+        let several = self.serial.len() > 1;
+        let mut selector = self.selector();
+        if several {
+            selector.serial = None;
+        }
+        let probes = select::enumerate(&api, ids.0, ids.1, &selector);
+        let mut probes: Vec<_> = probes
+            .into_iter()
+            .filter(|p| p.hid_path(Function::Trace).is_some())
+            .filter(|p| {
+                !several
+                    || matches!(&p.serial, Some(s) if self.serial.contains(s))
+            })
+            .collect();
+        if !all && !several {
+            probes.truncate(1);
+        }
+        probes.into_iter().map(|p| (ids, p)).collect()
+    }
+
+    /// Describes the probes these options would look for.
+    fn describe(&self, all: bool) -> Result<String, Box<dyn Error>> {
+        let mut source = if self.vid.is_none() && self.pid.is_none() {
+            "the first probe running firmware with a trace interface"
+                .to_string()
+        } else {
+            let (vid, pid) = self.vid_pid()?;
+            format!("probe {:04x}:{:04x}", vid, pid)
+        };
+        if all {
+            source.push_str(", every one that matches");
+        }
+        if !self.serial.is_empty() {
+            source.push_str(&format!(", serial {}", self.serial.join(" or ")));
+        }
+        if let Some(port) = &self.usb_port {
+            source.push_str(&format!(", in port {}", port));
+        }
+        if self.select.is_some() {
+            source.push_str(", matching --select");
+        }
+        Ok(source)
+    }
+
+    /// Checks that these options pick out at most one probe.
+    fn single(&self) -> Result<(), Box<dyn Error>> {
+        if self.serial.len() > 1 {
+            return Err("only `cat` can use several probes at once".into());
+        }
+        Ok(())
+    }
+
+    /// Finds the serial numbers of all attached probes with trace interfaces
+    /// that match the other criteria.
+    fn attached_serials(&self) -> Result<Vec<String>, Box<dyn Error>> {
+        let (vid, pid) = self.vid_pid()?;
+        let api = hidapi::HidApi::new().map_err(lpc_cat::Error::from)?;
+        select::enumerate(&api, vid, pid, &self.selector())
+            .into_iter()
+            .filter(|p| p.hid_path(Function::Trace).is_some())
+            .map(|p| match p.serial {
+                Some(serial) => Ok(serial),
+                None => Err(format!(
+                    "probe at {} has no serial number",
+                    p.usb_port.as_deref().unwrap_or("unknown port")
+                )
+                .into()),
+            })
+            .collect()
+    }
+}
+
+/// Options for what to do with captured data.
+#[derive(Debug, StructOpt)]
+struct OutputArgs {
+    /// Decode the stream as ITM, and decode the data on one stimulus port
+    /// (see `--defmt-port`) as defmt log frames, using the tables in this ELF
+    /// file. Log lines are printed instead of raw data. (Requires the `defmt`
+    /// feature.)
+    #[structopt(long, value_name = "elf", parse(from_os_str))]
+    defmt: Option<PathBuf>,
+
+    /// ITM stimulus port that carries defmt frames.
+    #[structopt(long, value_name = "port", default_value = "0")]
+    defmt_port: u8,
+
+    /// Decode the stream as ITM, and count the DWT's PC samples by function,
+    /// using the symbol table in this ELF file; when capture ends, write the
+    /// profile to stdout instead of raw data (see `lpc_cat::profile`).
+    #[structopt(
+        long,
+        value_name = "elf",
+        parse(from_os_str),
+        conflicts_with_all = &["defmt", "ports"]
+    )]
+    profile: Option<PathBuf>,
+
+    /// With `--profile`, write a `table` of the hottest functions (the
+    /// default), or `folded` stacks for flame graph tools.
+    #[structopt(
+        long,
+        value_name = "format",
+        possible_values = profile::Format::NAMES,
+        requires = "profile"
+    )]
+    profile_format: Option<profile::Format>,
+
+    /// Decode the stream as ITM, and write the data sent to stimulus port N
+    /// to a file (or FIFO), or to stdout if the path is `-`. May be given
+    /// more than once, for different ports. Data on other ports is
+    /// discarded.
+    #[structopt(long, value_name = "N=path", number_of_values = 1)]
+    port_out: Vec<PortOut>,
+
+    /// Decode the stream as ITM, and show the data on each stimulus port on
+    /// stdout, as lines of text or as hex, depending on what it looks like
+    /// (see `lpc_cat::ports`). Ports given to `--port-out` go there instead.
+    #[structopt(long, conflicts_with = "defmt")]
+    ports: bool,
+
+    /// With `--ports`, show stimulus port N as `text` or `binary` data,
+    /// rather than guessing. May be given more than once.
+    #[structopt(
+        long,
+        value_name = "N=kind",
+        number_of_values = 1,
+        requires = "ports"
+    )]
+    port_kind: Vec<PortKind>,
+
+    /// With `--ports`, take text to be in this encoding (`utf-8`, the
+    /// default, `latin1` or `cp437`), and convert it to UTF-8 to show it.
+    /// Raw output is left as it was sent.
+    #[structopt(long, value_name = "encoding", requires = "ports")]
+    input_encoding: Option<encoding::Encoding>,
+
+    /// With `--ports`, show ports side by side in this many columns, sized
+    /// to fit the terminal (or `$COLUMNS`), rather than one after the
+    /// other. Ports get columns in the order they first send something.
+    #[structopt(long, value_name = "count", requires = "ports")]
+    columns: Option<usize>,
+
+    /// Write the raw stream to this file instead of stdout, starting a new
+    /// one according to `--rotate-size` and `--rotate-interval`. Each file
+    /// starts with a 20-byte header giving the bit rate and the time it was
+    /// started (see `lpc_cat::rotate`).
+    #[structopt(
+        long,
+        short,
+        value_name = "path",
+        parse(from_os_str),
+        conflicts_with_all =
+            &["defmt", "profile", "ports", "escape", "format"]
+    )]
+    output: Option<PathBuf>,
+
+    /// With `--output`, start a new file once the current one reaches this
+    /// size (e.g. `100M`).
+    #[structopt(
+        long,
+        value_name = "bytes",
+        requires = "output",
+        parse(try_from_str = parse_size)
+    )]
+    rotate_size: Option<u64>,
+
+    /// With `--output`, start a new file once the current one is this many
+    /// seconds old.
+    #[structopt(long, value_name = "secs", requires = "output")]
+    rotate_interval: Option<u64>,
+
+    /// With `--output`, keep this many files, including the current one;
+    /// the oldest are deleted [default: 10].
+    #[structopt(long, value_name = "count", requires = "output")]
+    rotate_count: Option<usize>,
+
+    /// With `--output`, start new files each time the target restarts (say,
+    /// because it was reflashed), named for the boot they're from:
+    /// `trace.bin` becomes `trace-boot1.bin`, `trace-boot2.bin` and so on.
+    /// A restart is taken to be data arriving after `--watch-quiet` of
+    /// silence, or after the probe was reconnected (see `lpc_cat::watch`).
+    /// When capture ends, a table of the boots goes to stderr, with each
+    /// one's reset reason if the firmware printed one.
+    #[structopt(long, requires = "output")]
+    watch: bool,
+
+    /// With `--watch`, how long the stream has to be quiet, in
+    /// milliseconds, before data is taken to be from a new boot. This
+    /// should be longer than the target is ever quiet while running
+    /// [default: 2000].
+    #[structopt(long, value_name = "ms", requires = "watch")]
+    watch_quiet: Option<u64>,
+
+    /// Write raw output to stdout at the rate it came over the wire, rather
+    /// than in bursts as it arrives from the probe, for tools that expect
+    /// the timing of a real UART: bytes go out at the bit rate, at 10 bits
+    /// each, and pauses in the stream are kept. With `replay`, pauses are
+    /// taken from the recording, so use `--speed 1` too (see
+    /// `lpc_cat::pace`).
+    #[structopt(
+        long,
+        conflicts_with_all =
+            &["defmt", "profile", "ports", "escape", "format"]
+    )]
+    pace: bool,
+
+    /// Mark gaps, and when each piece of data arrived, in the raw output by
+    /// escaping them into the stream (see `lpc_cat::escape`). Occurrences of
+    /// the escape byte in the data are themselves escaped.
+    #[structopt(
+        long,
+        conflicts_with_all = &["defmt", "profile", "port-out", "ports"]
+    )]
+    escape: bool,
+
+    /// Escape byte to use with `--escape`, in hex.
+    #[structopt(
+        long,
+        value_name = "byte",
+        requires = "escape",
+        parse(try_from_str = parse_hex_byte)
+    )]
+    escape_byte: Option<u8>,
+
+    /// How to write the captured data to stdout: as is (`raw`, the
+    /// default), as a `hexdump`, as `base64` lines, or as a `pcapng` capture
+    /// file with a packet for each piece of data received.
+    #[structopt(
+        long,
+        value_name = "format",
+        possible_values = format::Format::NAMES,
+        conflicts_with_all =
+            &["defmt", "profile", "port-out", "ports", "escape"]
+    )]
+    format: Option<format::Format>,
+
+    /// What to do when data is lost: only `warn` on stderr, carrying on as
+    /// if nothing happened; write a `marker` into the raw output (see
+    /// `--gap-marker`); `flush` whatever decoders have half-decoded, and
+    /// have them look for the start of the next message (the default); or
+    /// `abort`, exiting with status 10.
+    #[structopt(
+        long,
+        value_name = "policy",
+        possible_values = gaps::Policy::NAMES
+    )]
+    on_gap: Option<gaps::Policy>,
+
+    /// Bytes, in hex, to write into the raw output at each gap with
+    /// `--on-gap marker`, instead of a line saying that data may have been
+    /// lost.
+    #[structopt(long, value_name = "hex")]
+    gap_marker: Option<String>,
+
+    /// Compute a digest of the raw stream (everything captured, as written
+    /// with no decoding or `--format`), and when capture ends, write it to
+    /// stderr, or to `--digest-file`, as `sha256sum` does for stdin; so
+    /// `sha256sum -c FILE < copy` checks a copy of the stream.
+    #[structopt(
+        long,
+        value_name = "algorithm",
+        possible_values = digest::Algorithm::NAMES
+    )]
+    digest: Option<digest::Algorithm>,
+
+    /// With `--digest`, write the digest to this file instead of stderr.
+    #[structopt(
+        long,
+        value_name = "path",
+        parse(from_os_str),
+        requires = "digest"
+    )]
+    digest_file: Option<PathBuf>,
+
+    /// Write the fill levels the probe reports for its capture buffer on
+    /// every poll, along with gaps, to this file (or FIFO) as JSON lines (see
+    /// `lpc_cat::telemetry`). This doesn't change what's written to stdout.
+    #[structopt(long, value_name = "path", parse(from_os_str))]
+    fill_levels: Option<PathBuf>,
+
+    /// Decode the stream as ITM, and export stimulus port writes, DWT
+    /// packets and lost data, with their timing, to this file as VCD for
+    /// waveform viewers (see `lpc_cat::export`). This doesn't change what's
+    /// written to stdout.
+    #[structopt(long, value_name = "path", parse(from_os_str))]
+    vcd: Option<PathBuf>,
+
+    /// Like `--vcd`, but writes a Common Trace Format trace into this
+    /// directory, for Trace Compass or babeltrace.
+    #[structopt(long, value_name = "dir", parse(from_os_str))]
+    ctf: Option<PathBuf>,
+
+    /// Frequency of the target's ITM timestamp clock, in Hz, so that
+    /// `--vcd` and `--ctf` can give times in seconds rather than cycles, and
+    /// `--timestamps` can use ITM local timestamps.
+    #[structopt(long, value_name = "hz")]
+    timestamp_clock: Option<u32>,
+
+    /// With `--ports` or `--defmt`, start each line with the wall clock time
+    /// (in UTC) that it was sent. Given `--timestamp-clock`, lines are timed
+    /// by the target's ITM local timestamps, tied to the host's clock by
+    /// when data arrives; otherwise, by when their data arrived from the
+    /// probe, up to a poll interval after it was sent (see
+    /// `lpc_cat::timestamp`). Recordings always note the wall clock time,
+    /// and replays use the time of the original capture.
+    #[structopt(long)]
+    timestamps: bool,
+
+    /// Let Ctrl-S pause what's shown on stdout, and Ctrl-Q resume it, while
+    /// the capture (and anything being written to files) carries on. Output
+    /// is held in memory while paused, up to 64 MiB. Needs a terminal on
+    /// stdin.
+    #[structopt(long)]
+    pause: bool,
+
+    /// Also record the capture into this file, as with `record`, along with
+    /// what's shown on stdout, so that `replay --decoded` can show it again
+    /// without the ELF file or options used to decode it.
+    #[structopt(long, value_name = "path", parse(from_os_str))]
+    record: Option<PathBuf>,
+}
+
+/// Most output held in memory while the display is paused.
+const PAUSE_LIMIT: usize = 64 << 20;
+
+impl OutputArgs {
+    /// Builds the sink for captured data.
+    fn sink(&self) -> Result<Box<dyn Sink>, Box<dyn Error>> {
+        let mut sinks: Vec<Box<dyn Sink>> = vec![];
+        let mut stdout_used = false;
+        let display = match self.pause {
+            true => Some(pause_keys()?),
+            false => None,
+        };
+        let transcript = self
+            .record
+            .as_ref()
+            .map(|_| recording::Transcript::default());
+        let stdout = || -> Box<dyn Write> {
+            let out: Box<dyn Write> = match &display {
+                Some(display) => Box::new(display.clone()),
+                None => Box::new(std::io::stdout().lock()),
+            };
+            match &transcript {
+                Some(transcript) => Box::new(transcript.tee(out)),
+                None => out,
+            }
+        };
+        if self.timestamps && self.defmt.is_none() && !self.ports {
+            return Err("--timestamps needs --ports or --defmt".into());
+        }
+        if let Some(elf) = &self.defmt {
+            let elf = std::fs::read(elf)?;
+            sinks.push(Box::new(defmt_sink(
+                &elf,
+                self.defmt_port,
+                self.timestamps.then_some(self.timestamp_clock),
+                stdout(),
+            )?));
+            stdout_used = true;
+        }
+        if let Some(elf) = &self.profile {
+            let symbols = profile::Symbols::from_elf(&std::fs::read(elf)?)?;
+            sinks.push(Box::new(profile::Profile::new(
+                symbols,
+                self.profile_format.unwrap_or(profile::Format::Table),
+                stdout(),
+            )));
+            stdout_used = true;
+        }
+        if !self.port_out.is_empty() {
+            let mut demux = demux::Demux::new();
+            let mut ports = vec![];
+            for PortOut { port, path } in &self.port_out {
+                if ports.contains(port) {
+                    return Err(format!(
+                        "--port-out given more than once for port {}",
+                        port
+                    )
+                    .into());
+                }
+                ports.push(*port);
+                if path.as_os_str() == "-" {
+                    if stdout_used {
+                        return Err("only one output can go to stdout".into());
+                    }
+                    stdout_used = true;
+                    demux.add_port(*port, stdout());
+                } else {
+                    demux.add_port(*port, File::create(path)?);
+                }
+            }
+            sinks.push(Box::new(demux));
+        }
+        if self.ports {
+            if stdout_used {
+                return Err("only one output can go to stdout".into());
+            }
+            let mut console = ports::Console::new(stdout());
+            for PortOut { port, .. } in &self.port_out {
+                console.ignore(*port);
+            }
+            for PortKind { port, kind } in &self.port_kind {
+                console.set_kind(*port, *kind);
+            }
+            if let Some(count) = self.columns {
+                console.set_columns(count, terminal_width());
+            }
+            if self.timestamps {
+                console.set_timestamps(self.timestamp_clock);
+            }
+            if let Some(encoding) = self.input_encoding {
+                console.set_encoding(encoding);
+            }
+            sinks.push(Box::new(console));
+        }
+        if let Some(path) = &self.output {
+            let config = rotate::Config {
+                path: path.clone(),
+                size: self.rotate_size,
+                interval: self.rotate_interval.map(Duration::from_secs),
+                count: self.rotate_count.unwrap_or(10),
+            };
+            if self.watch {
+                let quiet =
+                    Duration::from_millis(self.watch_quiet.unwrap_or(2000));
+                let watch = watch::Watch::new(config, quiet);
+                session_state().boots.set(watch.boots()).ok();
+                sinks.push(Box::new(watch));
+            } else {
+                sinks.push(Box::new(rotate::Rotating::new(config)));
+            }
+        }
+        let policy = match (self.on_gap.clone(), &self.gap_marker) {
+            (Some(gaps::Policy::Marker(_)), Some(marker)) => {
+                gaps::Policy::Marker(parse_hex(marker)?)
+            }
+            (_, Some(_)) => {
+                return Err("--gap-marker needs --on-gap marker".into())
+            }
+            (policy, None) => policy.unwrap_or(gaps::Policy::Flush),
+        };
+        if let gaps::Policy::Marker(_) = policy {
+            let formatted =
+                !matches!(self.format, None | Some(format::Format::Raw));
+            if !sinks.is_empty() || self.escape || formatted {
+                return Err(
+                    "--on-gap marker only works with raw output to stdout"
+                        .into(),
+                );
+            }
+        }
+        if self.pace && !sinks.is_empty() {
+            return Err("--pace only works with raw output to stdout".into());
+        }
+        if sinks.is_empty() {
+            let raw: Box<dyn Sink> = if self.escape {
+                Box::new(escape::Encoder::new(
+                    stdout(),
+                    self.escape_byte.unwrap_or(escape::DEFAULT_ESCAPE),
+                ))
+            } else if self.pace {
+                Box::new(pace::Pace::new(stdout()))
+            } else {
+                self.format.unwrap_or(format::Format::Raw).sink(stdout())?
+            };
+            sinks.push(match policy {
+                gaps::Policy::Marker(_) => {
+                    Box::new(gaps::OnGap::new(raw, policy.clone()))
+                }
+                _ => raw,
+            });
+        }
+        // Gaps are still worth noting in files that are only about what
+        // happened during the capture.
+        if policy == gaps::Policy::Warn {
+            let output = match sinks.len() {
+                1 => sinks.pop().unwrap(),
+                _ => Box::new(capture::Tee(std::mem::take(&mut sinks))),
+            };
+            sinks.push(Box::new(gaps::OnGap::new(output, gaps::Policy::Warn)));
+        }
+        if let Some(algorithm) = self.digest {
+            let out: Box<dyn Write> = match &self.digest_file {
+                Some(path) => Box::new(File::create(path)?),
+                None => Box::new(std::io::stderr()),
+            };
+            sinks.push(Box::new(digest::Digest::new(algorithm, out)));
+        }
+        if let Some(path) = &self.fill_levels {
+            sinks
+                .push(Box::new(telemetry::JsonLines::new(File::create(path)?)));
+        }
+        if let Some(path) = &self.vcd {
+            sinks.push(Box::new(export::Export::vcd(
+                BufWriter::new(File::create(path)?),
+                self.timestamp_clock,
+            )?));
+        }
+        if let Some(dir) = &self.ctf {
+            sinks.push(Box::new(export::Export::ctf(
+                dir,
+                self.timestamp_clock,
+            )?));
+        }
+        let sink = match sinks.len() {
+            1 => sinks.pop().unwrap(),
+            _ => Box::new(capture::Tee(sinks)),
+        };
+        let sink: Box<dyn Sink> = match (&self.record, transcript) {
+            (Some(path), Some(transcript)) => {
+                let out = BufWriter::new(File::create(path)?);
+                let writer = recording::Writer::new(out)?;
+                Box::new(recording::Annotated::new(writer, sink, transcript))
+            }
+            _ => sink,
+        };
+        Ok(match policy {
+            gaps::Policy::Abort => Box::new(gaps::OnGap::new(sink, policy)),
+            _ => sink,
+        })
+    }
+}
+
+/// Options for ending a capture on its own (see `lpc_cat::until`).
+#[derive(Debug, StructOpt)]
+struct StopArgs {
+    /// Stop once this many bytes have been captured (e.g. `10M`).
+    #[structopt(
+        long,
+        value_name = "bytes",
+        parse(try_from_str = parse_size)
+    )]
+    max_bytes: Option<u64>,
+
+    /// Stop once capture has gone on this long: a number of seconds, or of
+    /// `ms`, `s`, `m` or `h` (e.g. `30s`).
+    #[structopt(
+        long,
+        value_name = "time",
+        parse(try_from_str = parse_duration)
+    )]
+    duration: Option<Duration>,
+
+    /// Stop once this turns up in the raw stream: text, or bytes in hex
+    /// after `hex:` (e.g. `hex:deadbeef`). If capture stops because of
+    /// `--duration` or `--max-bytes` first, exit with status 11 or 12
+    /// respectively.
+    #[structopt(long, value_name = "pattern")]
+    until_pattern: Option<Pattern>,
+
+    /// Exit with status 10 at the end if any data was lost along the way,
+    /// whatever the cause, for unattended captures that need to tell a
+    /// clean capture from one with holes in it. Unlike `--on-gap abort`,
+    /// this doesn't stop capture at the first gap.
+    #[structopt(long)]
+    fail_on_gaps: bool,
+
+    /// Exit with status 10 at the end if the probe's buffer overflowed more
+    /// than this many times, losing data.
+    #[structopt(long, value_name = "count")]
+    fail_on_overflow: Option<u64>,
+}
+
+impl StopArgs {
+    /// Returns the conditions to stop capture on, if there are any.
+    fn conditions(&self) -> Option<until::Conditions> {
+        let conditions = until::Conditions {
+            max_bytes: self.max_bytes,
+            duration: self.duration,
+            pattern: self.until_pattern.as_ref().map(|p| p.0.clone()),
+        };
+        match (
+            &conditions.max_bytes,
+            &conditions.duration,
+            &conditions.pattern,
+        ) {
+            (None, None, None) => None,
+            _ => Some(conditions),
+        }
+    }
+
+    /// Runs capture, stopping when the conditions say to, and returns which
+    /// one did, if any.
+    fn capture(
+        &self,
+        source: Box<dyn capture::Source>,
+        config: &capture::Config,
+        sink: &mut dyn Sink,
+    ) -> Result<(capture::Stats, Option<until::Reason>), lpc_cat::Error> {
+        let conditions = match self.conditions() {
+            Some(conditions) => conditions,
+            None => return Ok((capture::run(source, config, sink)?, None)),
+        };
+        let mut until = until::Until::new(sink, conditions, stop_flag());
+        let reason = until.reason();
+        let stats = capture::run(source, config, &mut until)?;
+        Ok((stats, reason.get().copied()))
+    }
+
+    /// Fails with `Unhealthy` if capture lost more than `--fail-on-gaps` or
+    /// `--fail-on-overflow` allow, or otherwise with `PatternNotSeen` if
+    /// there was a pattern to wait for and capture stopped for some other
+    /// `reason`.
+    fn check(
+        &self,
+        reason: Option<until::Reason>,
+        stats: &capture::Stats,
+    ) -> Result<(), Box<dyn Error>> {
+        if self.fail_on_gaps && stats.gaps > 0 {
+            return Err(Unhealthy(format!(
+                "{} gaps in the captured data",
+                stats.gaps
+            ))
+            .into());
+        }
+        match self.fail_on_overflow {
+            Some(limit) if stats.overflows > limit => {
+                return Err(Unhealthy(format!(
+                    "the probe's buffer overflowed {} times",
+                    stats.overflows
+                ))
+                .into());
+            }
+            _ => (),
+        }
+        match reason {
+            Some(until::Reason::Pattern) | None => Ok(()),
+            Some(_) if self.until_pattern.is_none() => Ok(()),
+            Some(reason) => Err(PatternNotSeen(reason).into()),
+        }
+    }
+}
+
+/// Bytes to look for in the stream.
+#[derive(Debug)]
+struct Pattern(Vec<u8>);
+
+impl std::str::FromStr for Pattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bytes = match s.strip_prefix("hex:") {
+            Some(hex) => parse_hex(hex)?,
+            None => s.as_bytes().to_vec(),
+        };
+        if bytes.is_empty() {
+            return Err("the pattern is empty".into());
+        }
+        Ok(Pattern(bytes))
+    }
+}
+
+/// Capture stopped for some other reason before `--until-pattern` saw its
+/// pattern.
+#[derive(Debug)]
+struct PatternNotSeen(until::Reason);
+
+impl std::fmt::Display for PatternNotSeen {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let why = match self.0 {
+            until::Reason::Duration => "timed out",
+            _ => "reached --max-bytes",
+        };
+        write!(f, "{} before the pattern turned up", why)
+    }
+}
+
+impl Error for PatternNotSeen {}
+
+/// Capture lost more data than `--fail-on-gaps` or `--fail-on-overflow`
+/// allow, as described.
+#[derive(Debug)]
+struct Unhealthy(String);
+
+impl std::fmt::Display for Unhealthy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "capture lost data: {}", self.0)
+    }
+}
+
+impl Error for Unhealthy {}
+
+/// Destination for the data on one ITM stimulus port.
+#[derive(Debug)]
+struct PortOut {
+    port: u8,
+    path: PathBuf,
+}
+
+impl std::str::FromStr for PortOut {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (port, path) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected N=path, got `{}`", s))?;
+        let port = parse_port(port)?;
+        if path.is_empty() {
+            return Err(format!("no path given for port {}", port));
+        }
+        Ok(PortOut {
+            port,
+            path: PathBuf::from(path),
+        })
+    }
+}
+
+/// What sort of data an ITM stimulus port carries.
+#[derive(Debug)]
+struct PortKind {
+    port: u8,
+    kind: ports::Kind,
+}
+
+impl std::str::FromStr for PortKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (port, kind) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected N=kind, got `{}`", s))?;
+        Ok(PortKind {
+            port: parse_port(port)?,
+            kind: kind.parse()?,
+        })
+    }
+}
+
+/// Returns the width of the terminal we're writing to, in characters, or a
+/// reasonable guess if there isn't one.
+fn terminal_width() -> usize {
+    if let Some(width) =
+        std::env::var("COLUMNS").ok().and_then(|c| c.parse().ok())
+    {
+        return width;
+    }
+    #[cfg(unix)]
+    for fd in [libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+        let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+        // Safety: TIOCGWINSZ only writes to the winsize we pass it.
+        let r = unsafe { libc::ioctl(fd, libc::TIOCGWINSZ, &mut size) };
+        if r == 0 && size.ws_col > 0 {
+            return usize::from(size.ws_col);
+        }
+    }
+    80
+}
+
+/// Returns stdout, paused by Ctrl-S and resumed by Ctrl-Q on stdin. The
+/// terminal stops handling those keys itself (which would block our writes
+/// until resumed) until `restore_terminal` is called.
+#[cfg(unix)]
+fn pause_keys() -> Result<pause::Pausable<std::io::Stdout>, Box<dyn Error>> {
+    const XOFF: u8 = 0x13;
+    const XON: u8 = 0x11;
+
+    let fd = libc::STDIN_FILENO;
+    let mut mode: libc::termios = unsafe { std::mem::zeroed() };
+    // Safety: tcgetattr only writes to the termios we pass it.
+    if unsafe { libc::tcgetattr(fd, &mut mode) } != 0 {
+        return Err("--pause needs a terminal on stdin".into());
+    }
+    let mut raw = mode;
+    // Keep Ctrl-C, but take keys as they come, unechoed, and see Ctrl-S
+    // and Ctrl-Q for ourselves.
+    raw.c_iflag &= !libc::IXON;
+    raw.c_lflag &= !(libc::ICANON | libc::ECHO);
+    raw.c_cc[libc::VMIN] = 1;
+    raw.c_cc[libc::VTIME] = 0;
+    if unsafe { libc::tcsetattr(fd, libc::TCSANOW, &raw) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    SAVED_TERMINAL.set(mode).ok();
+
+    let display = pause::Pausable::new(std::io::stdout(), PAUSE_LIMIT);
+    let keys = display.clone();
+    std::thread::spawn(move || {
+        for key in std::io::stdin().lock().bytes() {
+            let result = match key {
+                Ok(XOFF) if !keys.is_paused() => {
+                    eprintln!("-- paused; Ctrl-Q to resume --");
+                    keys.pause();
+                    Ok(())
+                }
+                Ok(XON) if keys.is_paused() => keys.resume(),
+                Ok(_) => Ok(()),
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result {
+                log::warn!("pausing output: {}", e);
+                break;
+            }
+        }
+    });
+    Ok(display)
+}
+
+#[cfg(not(unix))]
+fn pause_keys() -> Result<pause::Pausable<std::io::Stdout>, Box<dyn Error>> {
+    Err("--pause isn't supported on this platform".into())
+}
+
+/// Terminal settings from before `pause_keys` changed them.
+#[cfg(unix)]
+static SAVED_TERMINAL: OnceLock<libc::termios> = OnceLock::new();
+
+/// Puts the terminal back as `pause_keys` found it, if it changed it.
+fn restore_terminal() {
+    #[cfg(unix)]
+    if let Some(mode) = SAVED_TERMINAL.get() {
+        // Safety: tcsetattr only reads the termios we pass it.
+        unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, mode) };
+    }
+}
+
+fn parse_port(s: &str) -> Result<u8, String> {
+    s.parse::<u8>()
+        .ok()
+        .filter(|&p| p < 32)
+        .ok_or_else(|| format!("`{}` isn't a stimulus port (0-31)", s))
+}
 
+/// Options for setting up a trace session.
+#[derive(Debug, StructOpt)]
+struct SessionArgs {
     /// Allow the LPC-Link2 to choose a bitrate somewhere near the requested
     /// rate. The chosen bitrate will be printed. This is rarely useful.
     #[structopt(long)]
     allow_approx: bool,
 
-    /// Exit before actually extracting any data. This is only useful for
-    /// testing the setup and bitrate code.
+    /// Capture at the highest bit rate the probe can achieve, at or below
+    /// the one given (if any), rather than insisting on it; the rate chosen
+    /// is printed. With `--cpu-freq`, only rates the target's TPIU
+    /// prescaler can produce are considered, and the target is set up to
+    /// match.
+    #[structopt(long, conflicts_with = "allow-approx")]
+    auto: bool,
+
+    /// Print the highest bit rate the probe supports, and exit.
     #[structopt(long)]
-    no_cat: bool,
+    query_max_rate: bool,
+
+    /// Number of chunks (of up to about 1 KiB) of captured data to buffer
+    /// while waiting for output. If output stalls for long enough to fill the
+    /// buffer, data is dropped.
+    #[structopt(long, value_name = "chunks", default_value = "4096")]
+    queue_depth: usize,
+
+    /// Time to wait before polling the probe again after it reports no new
+    /// data, in milliseconds.
+    #[structopt(long, value_name = "ms", default_value = "10")]
+    poll_interval: u64,
+
+    /// Adapt the poll interval to the traffic: back off exponentially (up to
+    /// `--max-poll-interval`) while the target is quiet, and poll more often
+    /// (down to 1 ms) if data arrives fast enough to risk overflowing the
+    /// probe's buffer.
+    #[structopt(long)]
+    adaptive_poll: bool,
+
+    /// Longest time to wait between polls with `--adaptive-poll`, in
+    /// milliseconds.
+    #[structopt(long, value_name = "ms", default_value = "100")]
+    max_poll_interval: u64,
+
+    /// How the target encodes SWO. Manchester support is experimental: we
+    /// don't know for sure that the probe firmware can capture it.
+    #[structopt(
+        long,
+        value_name = "protocol",
+        default_value = "uart",
+        possible_values = &["uart", "manchester"]
+    )]
+    protocol: SwoProtocol,
+
+    /// If the probe disappears (say, because the board browned out), wait
+    /// for it to come back and carry on, rather than exiting.
+    #[structopt(long)]
+    reconnect: bool,
+
+    /// How often to look for the probe with `--reconnect`, in milliseconds.
+    #[structopt(long, value_name = "ms", default_value = "1000")]
+    reconnect_interval: u64,
+
+    /// Run the thread that polls the probe at this real-time (`SCHED_FIFO`)
+    /// priority, so that other work on a loaded machine can't delay polls
+    /// enough to overflow the probe's buffer. Usually needs privileges (e.g.
+    /// `CAP_SYS_NICE` on Linux); if it can't be done, capture carries on
+    /// without it.
+    #[structopt(long, value_name = "priority")]
+    rt_priority: Option<i32>,
+
+    /// Keep the thread that polls the probe on this CPU core (numbered from
+    /// 0). Linux only; elsewhere, capture carries on without it.
+    #[structopt(long, value_name = "core")]
+    pin_core: Option<usize>,
+
+    /// Where to get data: `probe` (the default), or
+    /// `synthetic[:pattern=P,rate=R]` to make up data for testing without
+    /// hardware. Patterns are `counter` (the default) and `itm` (text on
+    /// stimulus port 0); the rate is a bit rate, as for a real target (e.g.
+    /// `2M`).
+    #[structopt(long, value_name = "source", default_value = "probe")]
+    source: SourceArg,
+
+    /// Set the target up to emit SWO before capturing, through the probe's
+    /// debug port, given the frequency of its CPU clock in Hz. This programs
+    /// the Cortex-M trace registers, but can't turn on trace clocks or pins
+    /// that the part gates with vendor-specific registers (see
+    /// `--stm32-dbgmcu`).
+    #[structopt(long, value_name = "hz")]
+    cpu_freq: Option<u32>,
 
-    /// Bitrate of (UART) SWO traffic, in bits per second.
-    bitrate: u32,
+    /// ITM stimulus ports to enable with `--cpu-freq`, as a list of port
+    /// numbers and ranges (e.g. `0,8-15`). All ports are enabled by default.
+    #[structopt(long, value_name = "ports", requires = "cpu-freq")]
+    enable_ports: Option<PortMask>,
+
+    /// With `--cpu-freq`, also enable the trace pins in the `DBGMCU_CR`
+    /// register found on STM32 parts.
+    #[structopt(long, requires = "cpu-freq")]
+    stm32_dbgmcu: bool,
+
+    /// How to report on how capture is going: as messages about problems
+    /// (`text`, the default), or as newline-delimited JSON events (`json`,
+    /// see `lpc_cat::telemetry`) including the bit rate, throughput every
+    /// second (with rates over the last second, ten seconds and minute), and
+    /// a summary at the end.
+    #[structopt(
+        long,
+        value_name = "format",
+        possible_values = &["text", "json"]
+    )]
+    events: Option<EventFormat>,
+
+    /// Write events to this file (or FIFO) instead of stderr.
+    #[structopt(long, value_name = "path", parse(from_os_str))]
+    events_file: Option<PathBuf>,
+
+    /// Print what would be captured from, and every option with defaults
+    /// filled in, then exit without touching USB or creating any files.
+    #[structopt(long)]
+    dry_run: bool,
+
+    /// Bitrate of SWO traffic, in bits per second. Required for UART SWO,
+    /// unless `--auto` is given, when it's the most to allow; for
+    /// Manchester, only used to set up the target with `--cpu-freq`.
+    bitrate: Option<u32>,
 }
 
-fn main() -> Result<(), Box<dyn Error>> {
-    pretty_env_logger::init();
-    let args = LpcCat::from_args();
+/// Set of ITM stimulus ports.
+#[derive(Copy, Clone, Debug)]
+struct PortMask(u32);
+
+impl std::str::FromStr for PortMask {
+    type Err = String;
 
-    let vid = u16::from_str_radix(&args.vid, 16)
-        .map_err(|_| "can't parse vid as hex")?;
-    let pid = u16::from_str_radix(&args.pid, 16)
-        .map_err(|_| "can't parse pid as hex")?;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let port = |p: &str| match p.parse::<u32>() {
+            Ok(n) if n < 32 => Ok(n),
+            _ => Err(format!("bad stimulus port `{}`", p)),
+        };
+        let mut mask = 0;
+        for item in s.split(',') {
+            let (first, last) = match item.split_once('-') {
+                Some((first, last)) => (port(first)?, port(last)?),
+                None => (port(item)?, port(item)?),
+            };
+            if first > last {
+                return Err(format!("bad stimulus port range `{}`", item));
+            }
+            for n in first..=last {
+                mask |= 1 << n;
+            }
+        }
+        Ok(PortMask(mask))
+    }
+}
+
+/// Where to get data from.
+#[derive(Debug)]
+enum SourceArg {
+    Probe,
+    Synthetic(synthetic::Config),
+}
 
-    let handle =
-        Handle::open(vid, pid, args.serial.as_ref().map(|s| s.as_str()))?;
+impl std::str::FromStr for SourceArg {
+    type Err = String;
 
-    // Set up the trace session.
-    const MYSTERIOUS_MODE: u8 = 0xFF;
-    handle.ohai(MYSTERIOUS_MODE)?;
-    handle.init_uart()?;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, options) = match s.split_once(':') {
+            Some((kind, options)) => (kind, Some(options)),
+            None => (s, None),
+        };
+        match (kind, options) {
+            ("probe", None) => Ok(SourceArg::Probe),
+            ("synthetic", options) => {
+                let mut config = synthetic::Config::default();
+                for option in options.into_iter().flat_map(|o| o.split(',')) {
+                    match option.split_once('=') {
+                        Some(("pattern", p)) => config.pattern = p.parse()?,
+                        Some(("rate", r)) => config.rate = parse_size(r)?,
+                        _ => {
+                            return Err(format!(
+                                "unknown synthetic source option `{}`",
+                                option
+                            ))
+                        }
+                    }
+                }
+                if config.rate == 0 {
+                    return Err("synthetic rate must be nonzero".into());
+                }
+                Ok(SourceArg::Synthetic(config))
+            }
+            _ => Err(format!("unknown source `{}`", s)),
+        }
+    }
+}
+
+impl SessionArgs {
+    /// Opens the source of data, setting up the trace session if it's a
+    /// probe.
+    fn open(
+        &self,
+        probe: &ProbeArgs,
+    ) -> Result<Box<dyn capture::Source>, Box<dyn Error>> {
+        match &self.source {
+            SourceArg::Probe => {
+                let setup = self.setup()?;
+                let target = self.target_config();
+                let handle = probe.open()?;
+
+                // Insist on talking to the same probe from here on, even if
+                // others would match.
+                let (vid, pid) = probe.vid_pid()?;
+                let transport = probe.transport;
+                let mut selector = probe.selector();
+                if let Ok(Some(serial)) = handle.serial() {
+                    selector.serial = Some(serial);
+                }
+
+                let rate = setup.start(&handle)?;
+                configure_target(target, rate, vid, pid, &selector)?;
+                if !self.reconnect {
+                    return Ok(Box::new(handle));
+                }
+
+                Ok(Box::new(capture::Reopenable::new(handle, move || {
+                    let handle =
+                        Handle::open_with(vid, pid, &selector, transport)?;
+                    let rate = setup.start(&handle)?;
+                    // The probe going away may well mean the target reset.
+                    configure_target(target, rate, vid, pid, &selector)?;
+                    Ok(handle)
+                })))
+            }
+            SourceArg::Synthetic(config) => {
+                log::info!("using synthetic source: {:?}", config);
+                Ok(Box::new(synthetic::Synthetic::new(*config)))
+            }
+        }
+    }
+
+    /// Works out how to set up the probe.
+    fn setup(&self) -> Result<Setup, Box<dyn Error>> {
+        match (self.protocol, self.bitrate) {
+            (SwoProtocol::Manchester, _) if self.auto => {
+                Err("--auto only applies to UART SWO".into())
+            }
+            (SwoProtocol::Uart, ceiling) if self.auto => Ok(Setup::Auto {
+                ceiling,
+                cpu_freq: self.cpu_freq,
+            }),
+            (SwoProtocol::Manchester, bitrate) => {
+                if self.cpu_freq.is_some() && bitrate.is_none() {
+                    return Err("setting up the target needs a bit rate".into());
+                }
+                if bitrate.is_some() && self.cpu_freq.is_none() {
+                    log::warn!("bit rate isn't used with Manchester SWO");
+                }
+                Ok(Setup::Manchester)
+            }
+            (SwoProtocol::Uart, Some(bitrate)) => Ok(Setup::Uart {
+                bitrate,
+                allow_approx: self.allow_approx,
+            }),
+            (SwoProtocol::Uart, None) => {
+                Err("UART SWO needs a bit rate".into())
+            }
+        }
+    }
+
+    /// Works out how to set up the target, if we're asked to. The bit rate
+    /// is filled in once the probe has agreed to one.
+    fn target_config(&self) -> Option<trace::Config> {
+        Some(trace::Config {
+            cpu_freq: self.cpu_freq?,
+            bitrate: self.bitrate.unwrap_or(0),
+            protocol: self.protocol,
+            ports: self.enable_ports.map_or(!0, |p| p.0),
+            stm32_dbgmcu: self.stm32_dbgmcu,
+        })
+    }
 
-    let actual_rate = handle.set_bit_rate(args.bitrate)?;
-    if actual_rate != args.bitrate {
-        if args.allow_approx {
-            eprintln!(
-                "actual bit rate: {} (requested: {})",
-                actual_rate, args.bitrate
-            );
+    fn capture_config(&self) -> Result<capture::Config, Box<dyn Error>> {
+        let interval = Duration::from_millis(self.poll_interval);
+        let poll = if self.adaptive_poll {
+            capture::PollStrategy::Adaptive {
+                initial: interval,
+                min: Duration::from_millis(1),
+                max: Duration::from_millis(self.max_poll_interval)
+                    .max(interval),
+            }
         } else {
-            log::error!(
-                "can't achieve bit rate {} (closest: {})",
-                args.bitrate,
-                actual_rate
-            );
-            std::process::exit(1);
+            capture::PollStrategy::Fixed(interval)
+        };
+        let mut config = capture::Config {
+            queue_depth: self.queue_depth,
+            poll,
+            reconnect: if self.reconnect {
+                Some(Duration::from_millis(self.reconnect_interval))
+            } else {
+                None
+            },
+            stop: Some(stop_flag()),
+            progress: Some(Arc::clone(&session_state().progress)),
+            shutdown: Some(session_state().shutdown.clone()),
+            scheduling: realtime::Scheduling {
+                priority: self.rt_priority,
+                core: self.pin_core,
+            },
+            ..capture::Config::default()
+        };
+        if self.events.is_some() || self.events_file.is_some() {
+            let out: Box<dyn Write + Send> = match &self.events_file {
+                Some(path) => Box::new(File::create(path)?),
+                None => Box::new(std::io::stderr()),
+            };
+            let observer = match self.events {
+                Some(EventFormat::Json) => {
+                    let log = telemetry::StatusLog::new(out);
+                    capture::Observer::new(move |s| log.write(s))
+                }
+                _ => {
+                    let out = std::sync::Mutex::new(out);
+                    capture::Observer::new(move |s| {
+                        let mut out =
+                            out.lock().unwrap_or_else(|e| e.into_inner());
+                        writeln!(out, "{}", s).ok();
+                    })
+                }
+            };
+            session_state().observer.set(observer.clone()).ok();
+            config.observer = Some(observer);
+            config.throughput_interval = Some(Duration::from_secs(1));
         }
-    } else {
-        log::info!("probe confirms rate: {}", actual_rate);
+        Ok(config)
     }
+}
+
+/// How to report on how capture is going.
+#[derive(Copy, Clone, Debug)]
+enum EventFormat {
+    Text,
+    Json,
+}
 
-    if args.no_cat {
-        return Ok(());
+impl std::str::FromStr for EventFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(EventFormat::Text),
+            "json" => Ok(EventFormat::Json),
+            _ => Err(format!("unknown event format `{}`", s)),
+        }
     }
+}
+
+/// How to set up a probe for capture.
+#[derive(Copy, Clone, Debug)]
+enum Setup {
+    Uart {
+        bitrate: u32,
+        allow_approx: bool,
+    },
+    /// The highest UART bit rate the probe (and the target's TPIU, given the
+    /// frequency of its trace clock) can manage, up to `ceiling`.
+    Auto {
+        ceiling: Option<u32>,
+        cpu_freq: Option<u32>,
+    },
+    Manchester,
+}
 
-    const MAX_PACKET: usize = 1024;
-    const POLL_INTERVAL: Duration = Duration::from_millis(10);
+impl Setup {
+    /// Sets up the trace session on a freshly opened probe. Returns the bit
+    /// rate the probe expects, if it cares.
+    fn start(self, handle: &Handle) -> Result<Option<u32>, lpc_cat::Error> {
+        let (bitrate, allow_approx) = match self {
+            Setup::Uart {
+                bitrate,
+                allow_approx,
+            } => (bitrate, allow_approx),
+            Setup::Auto { ceiling, cpu_freq } => {
+                return Self::negotiate(handle, ceiling, cpu_freq).map(Some)
+            }
+            Setup::Manchester => return handle.init_manchester().map(|_| None),
+        };
 
-    let mut buffer = [0; MAX_PACKET];
-    let mut last: Option<(u8, u16)> = None;
-    let out = std::io::stdout();
-    let mut out = out.lock();
+        handle.ohai(protocol::MODE_UART)?;
+        handle.init_uart()?;
 
-    loop {
-        let (epoch, result) = handle.poll(&mut buffer)?;
-        match result {
-            PollResult::Empty => {
-                // Try back in a bit.
-                sleep(POLL_INTERVAL);
+        let actual_rate = handle.set_bit_rate(bitrate)?;
+        if actual_rate != bitrate {
+            if allow_approx {
+                eprintln!(
+                    "actual bit rate: {} (requested: {})",
+                    actual_rate, bitrate
+                );
+            } else {
+                return Err(lpc_cat::Error::UnachievableBitRate {
+                    requested: bitrate,
+                    closest: actual_rate,
+                });
             }
-            PollResult::Incremental {
-                start,
-                end,
-                fragment,
-            } => {
-                let continuous = if let Some((last_epoch, last_end)) = last {
-                    last_epoch == epoch && last_end == start
-                } else {
-                    // If this is the first data we've seen, we'll accept it
-                    // unconditionally.
-                    true
-                };
+        } else {
+            log::info!("probe confirms rate: {}", actual_rate);
+        }
+        Ok(Some(actual_rate))
+    }
 
-                if !continuous {
-                    eprintln!(
-                        "lost stream sync at {:02x}:{:03x}, data may be lost",
-                        epoch, start
-                    );
-                }
-                out.write_all(fragment)?;
-                last = Some((epoch, end));
-            }
-            PollResult::Total(packet) => {
-                if let Some((last_epoch, last_end)) = last {
-                    if epoch == last_epoch {
-                        // We need to collect the tail of the data for this
-                        // epoch from the end of the packet buffer.
-                        out.write_all(&packet[usize::from(last_end)..])?;
-                    } else {
-                        eprintln!(
-                            "lost stream sync at {:02x}:000, data may be lost",
-                            epoch
+    /// Sets up the probe for `Setup::Auto`, and returns the rate chosen.
+    fn negotiate(
+        handle: &Handle,
+        ceiling: Option<u32>,
+        cpu_freq: Option<u32>,
+    ) -> Result<u32, lpc_cat::Error> {
+        /// TPIU rates to try before giving up.
+        const TRIES: usize = 64;
+        /// How far the probe's rate can be from the target's. UART framing
+        /// copes with a few percent between the two ends.
+        const TOLERANCE: f64 = 0.01;
+
+        handle.ohai(protocol::MODE_UART)?;
+        let max = handle.init_uart()?;
+        let ceiling = ceiling.map_or(max, |c| c.min(max));
+        let rate = match cpu_freq {
+            None => handle.set_bit_rate_up_to(ceiling)?,
+            Some(cpu_freq) => {
+                let mut found = None;
+                let mut closest = 0;
+                for (prescaler, rate) in
+                    trace::rates(cpu_freq, ceiling).take(TRIES)
+                {
+                    closest = handle.set_bit_rate(rate)?;
+                    let error =
+                        (f64::from(closest) / f64::from(rate) - 1.0).abs();
+                    if error <= TOLERANCE {
+                        log::info!(
+                            "target prescaler {} gives {}",
+                            prescaler,
+                            rate
                         );
+                        found = Some(closest);
+                        break;
                     }
-                } else {
-                    // This is kind of a boring first packet, but ok.
-                    out.write_all(packet)?;
                 }
-                last = Some((epoch.wrapping_add(1), 0));
+                found.ok_or(lpc_cat::Error::UnachievableBitRate {
+                    requested: ceiling,
+                    closest,
+                })?
             }
-        }
+        };
+        eprintln!("bit rate: {} (at most {})", rate, ceiling);
+        Ok(rate)
     }
 }
 
-struct Handle(hidapi::HidDevice);
+/// Sets up the target to emit SWO, if `target` says to, at the bit rate the
+/// probe expects (if it cares).
+fn configure_target(
+    target: Option<trace::Config>,
+    probe_rate: Option<u32>,
+    vid: u16,
+    pid: u16,
+    selector: &Selector,
+) -> Result<(), lpc_cat::Error> {
+    let mut target = match target {
+        Some(target) => target,
+        None => return Ok(()),
+    };
+    if let Some(rate) = probe_rate {
+        target.bitrate = rate;
+    }
+    let dap = Dap::open(vid, pid, selector)?;
+    trace::configure(&dap, &target)
+}
 
-impl Handle {
-    /// Opens the LPC-Link2 device with the given vid/pid and optional serial
-    /// number.
-    ///
-    /// If `serial` is `None`, the first matching device will be chosen.
-    pub fn open(
-        vid: u16,
-        pid: u16,
-        serial: Option<&str>,
-    ) -> Result<Self, Box<dyn Error>> {
-        let api = hidapi::HidApi::new()?;
-
-        const TRACE_IF_NO: i32 = 4;
-
-        let device_info = api
-            .device_list()
-            .filter(|d| d.vendor_id() == vid && d.product_id() == pid)
-            .filter(|d| d.interface_number() == TRACE_IF_NO)
-            .filter(|d| {
-                if let Some(serial) = serial {
-                    d.serial_number() == Some(serial)
-                } else {
-                    true
-                }
-            })
-            .next()
-            .ok_or("can't find matching device")?;
+/// Exit statuses, so that scripts can tell what went wrong. (Command line
+/// parsing errors exit with status 1.)
+mod exit {
+    pub const OTHER: i32 = 1;
+    pub const DEVICE_NOT_FOUND: i32 = 2;
+    pub const USB: i32 = 3;
+    pub const PROTOCOL: i32 = 4;
+    pub const BIT_RATE: i32 = 5;
+    pub const IO: i32 = 6;
+    pub const TARGET: i32 = 7;
+    /// Output was piped somewhere that stopped reading.
+    pub const CLOSED: i32 = 8;
+    /// A soak test lost data or found something amiss.
+    pub const SOAK_FAILED: i32 = 9;
+    /// Data was lost, with `--on-gap abort`, `--fail-on-gaps` or
+    /// `--fail-on-overflow`.
+    pub const DATA_LOST: i32 = 10;
+    /// `--duration` ran out, or `--max-bytes` were captured, before
+    /// `--until-pattern` saw its pattern.
+    pub const TIMED_OUT: i32 = 11;
+    pub const BYTE_LIMIT: i32 = 12;
+    /// Asked to stop twice, and didn't wait to finish cleanly. This is what
+    /// shells report for death by SIGINT.
+    pub const INTERRUPTED: i32 = 130;
+}
+
+/// Logger that starts every message with the session ID, so that log lines
+/// can be matched up with the rest of what this run left behind.
+struct SessionLogger(Box<dyn log::Log>);
+
+impl log::Log for SessionLogger {
+    fn enabled(&self, metadata: &log::Metadata<'_>) -> bool {
+        self.0.enabled(metadata)
+    }
 
-        log::info!(
-            "found matching device at {}",
-            device_info.path().to_string_lossy()
+    fn log(&self, record: &log::Record<'_>) {
+        self.0.log(
+            &log::Record::builder()
+                .args(format_args!(
+                    "[{}] {}",
+                    lpc_cat::session::id(),
+                    record.args()
+                ))
+                .metadata(record.metadata().clone())
+                .module_path(record.module_path())
+                .file(record.file())
+                .line(record.line())
+                .build(),
         );
+    }
 
-        let device = device_info.open_device(&api)?;
+    fn flush(&self) {
+        self.0.flush()
+    }
+}
 
-        Ok(Self(device))
+/// Sets up logging as `pretty_env_logger::init` does, but with the session
+/// ID in each message.
+fn init_logging() {
+    let mut builder = pretty_env_logger::formatted_builder();
+    if let Ok(filters) = std::env::var("RUST_LOG") {
+        builder.parse_filters(&filters);
     }
+    let logger = builder.build();
+    log::set_max_level(logger.filter());
+    log::set_boxed_logger(Box::new(SessionLogger(Box::new(logger)))).ok();
+}
 
-    /// Initializes communications with the probe.
-    pub fn ohai(&self, mode: u8) -> Result<(), Box<dyn Error>> {
-        self.0.write(&[0x1f, mode])?;
+fn main() {
+    init_logging();
+    if wants_verbose_version(std::env::args_os()) {
+        print!("{}", lpc_cat::version::info());
+        return;
+    }
+    let args = LpcCat::from_iter(default_to_cat(std::env::args_os()));
 
-        let mut response = [0; 1024];
-        self.0.read(&mut response)?;
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        default_hook(info);
+        report_abort("panicked");
+        restore_terminal();
+    }));
 
-        check_cmd(response[0], 0x1f)?;
-        if response[1] != 0x38 {
-            return Err("unexpected ohai response".into());
+    let result = run(args);
+    restore_terminal();
+    if let Err(e) = result {
+        // Whoever was reading our output has lost interest, which isn't
+        // worth complaining about.
+        let io = match e.downcast_ref::<lpc_cat::Error>() {
+            Some(lpc_cat::Error::Io(e)) => Some(e),
+            _ => e.downcast_ref::<std::io::Error>(),
+        };
+        if io.is_some_and(capture::is_closed) {
+            log::info!("output closed");
+            std::process::exit(exit::CLOSED);
         }
 
-        Ok(())
+        eprintln!("Error: {}", e);
+        report_abort(&e.to_string());
+        let code = match e.downcast_ref::<lpc_cat::Error>() {
+            _ if io.is_some_and(gaps::is_data_lost) => exit::DATA_LOST,
+            Some(lpc_cat::Error::DeviceNotFound)
+            | Some(lpc_cat::Error::Firmware { .. }) => exit::DEVICE_NOT_FOUND,
+            Some(lpc_cat::Error::Hid(_)) => exit::USB,
+            #[cfg(feature = "bulk")]
+            Some(lpc_cat::Error::Usb(_)) => exit::USB,
+            Some(lpc_cat::Error::Protocol { .. }) => exit::PROTOCOL,
+            Some(lpc_cat::Error::Malformed { .. }) => exit::PROTOCOL,
+            Some(lpc_cat::Error::UnachievableBitRate { .. }) => exit::BIT_RATE,
+            Some(lpc_cat::Error::Unsupported(_)) => exit::PROTOCOL,
+            Some(lpc_cat::Error::Target(_)) => exit::TARGET,
+            Some(lpc_cat::Error::State(_)) => exit::OTHER,
+            Some(lpc_cat::Error::Io(_)) => exit::IO,
+            None if e.is::<std::io::Error>() => exit::IO,
+            None if e.is::<SoakFailed>() => exit::SOAK_FAILED,
+            None if e.is::<Unhealthy>() => exit::DATA_LOST,
+            None => match e.downcast_ref::<PatternNotSeen>() {
+                Some(PatternNotSeen(until::Reason::Duration)) => {
+                    exit::TIMED_OUT
+                }
+                Some(_) => exit::BYTE_LIMIT,
+                None => exit::OTHER,
+            },
+        };
+        std::process::exit(code);
     }
+}
 
-    /// Does basic UART setup and returns the highest available bit rate.
-    pub fn init_uart(&self) -> Result<u32, Box<dyn Error>> {
-        self.0.write(&[0x03])?;
+fn run(args: LpcCat) -> Result<(), Box<dyn Error>> {
+    match &args {
+        LpcCat::Cat { .. } | LpcCat::Replay { .. } | LpcCat::Stat { .. } => (),
+        LpcCat::List { probe, .. }
+        | LpcCat::Record { probe, .. }
+        | LpcCat::Serve { probe, .. }
+        | LpcCat::Raw { probe, .. } => probe.single()?,
+    }
+    match &args {
+        LpcCat::Cat { probe, session, .. }
+        | LpcCat::Record { probe, session, .. }
+        | LpcCat::Serve { probe, session, .. }
+            if session.dry_run =>
+        {
+            return dry_run(&args, probe, session);
+        }
+        LpcCat::Cat { probe, session, .. }
+        | LpcCat::Record { probe, session, .. }
+        | LpcCat::Serve { probe, session, .. }
+            if session.query_max_rate =>
+        {
+            probe.single()?;
+            let handle = probe.open()?;
+            handle.ohai(protocol::MODE_UART)?;
+            println!("{}", handle.init_uart()?);
+            return Ok(());
+        }
+        _ => (),
+    }
 
-        let mut response = [0; 1024];
-        self.0.read(&mut response)?;
+    match args {
+        LpcCat::Cat {
+            probe,
+            session,
+            no_cat,
+            output,
+            all,
+            probe_dir,
+            soak,
+            checkpoint_interval,
+            stop,
+        } => {
+            if all || probe.serial.len() > 1 {
+                if soak {
+                    return Err("--soak only works with one probe".into());
+                }
+                if stop.conditions().is_some() {
+                    return Err(
+                        "--max-bytes, --duration and --until-pattern only work \
+                         with one probe"
+                            .into(),
+                    );
+                }
+                if stop.fail_on_gaps || stop.fail_on_overflow.is_some() {
+                    return Err(
+                        "--fail-on-gaps and --fail-on-overflow only work \
+                         with one probe"
+                            .into(),
+                    );
+                }
+                return cat_multi(
+                    &probe,
+                    &session,
+                    &output,
+                    all,
+                    probe_dir.as_deref(),
+                    no_cat,
+                );
+            }
+            if probe_dir.is_some() {
+                return Err(
+                    "--probe-dir only applies when using several probes".into(),
+                );
+            }
+            // Load any ELF before touching the probe, to fail fast.
+            let mut sink = output.sink()?;
+            let source = session.open(&probe)?;
+            if no_cat {
+                return Ok(());
+            }
+            let mut config = capture::Config {
+                fill_levels: output.fill_levels.is_some(),
+                ..session.capture_config()?
+            };
+            if soak {
+                if stop.conditions().is_some() {
+                    return Err("--soak runs until it's stopped".into());
+                }
+                if stop.fail_on_gaps || stop.fail_on_overflow.is_some() {
+                    return Err(
+                        "--soak already fails if anything was lost".into()
+                    );
+                }
+                config.fill_levels = true;
+                config.reconnect = config.reconnect.or(Some(
+                    Duration::from_millis(session.reconnect_interval),
+                ));
+                let interval =
+                    Duration::from_secs(checkpoint_interval.unwrap_or(60));
+                return soak_test(source, &config, sink, interval);
+            }
+            let (stats, reason) = stop.capture(source, &config, &mut *sink)?;
+            let shutdown = &session_state().shutdown;
+            shutdown.stage(Stage::Flushing);
+            drop(sink);
+            std::io::stdout().flush()?;
+            shutdown.stage(Stage::Reports);
+            report_stop(None, &stats);
+            report_boots();
+            shutdown.finish();
+            stop.check(reason, &stats)
+        }
+        LpcCat::List { probe, verbose } => list(&probe, verbose),
+        LpcCat::Record {
+            probe,
+            session,
+            output,
+            fill_levels,
+            stop,
+        } => {
+            let source = session.open(&probe)?;
+            let out = BufWriter::new(File::create(output)?);
+            let mut recording = recording::Writer::new(out)?;
+            let config = capture::Config {
+                fill_levels,
+                ..session.capture_config()?
+            };
+            let (stats, reason) =
+                stop.capture(source, &config, &mut recording)?;
+            let shutdown = &session_state().shutdown;
+            shutdown.stage(Stage::Flushing);
+            recording.into_inner().flush()?;
+            shutdown.stage(Stage::Reports);
+            report_stop(None, &stats);
+            shutdown.finish();
+            stop.check(reason, &stats)
+        }
+        LpcCat::Replay {
+            input,
+            speed,
+            decoded,
+            output,
+        } => {
+            if speed.is_some_and(|s| !s.is_finite() || s <= 0.0) {
+                return Err("--speed must be a positive number".into());
+            }
+            if decoded {
+                let input =
+                    recording::Reader::new(BufReader::new(File::open(input)?))?;
+                let mut out: Box<dyn Write> = match output.pause {
+                    true => Box::new(pause_keys()?),
+                    false => Box::new(std::io::stdout().lock()),
+                };
+                if !recording::replay_decoded(input, speed, &mut out)? {
+                    return Err("recording has no decoded output; it was \
+                                made without `cat --record`"
+                        .into());
+                }
+                return Ok(());
+            }
+            let mut sink = output.sink()?;
+            let input =
+                recording::Reader::new(BufReader::new(File::open(input)?))?;
+            recording::replay(input, speed, &mut *sink)?;
+            drop(sink);
+            report_boots();
+            Ok(())
+        }
+        LpcCat::Stat { inputs, buckets } => {
+            if buckets == 0 {
+                return Err("--buckets must be at least 1".into());
+            }
+            let mut out = std::io::stdout().lock();
+            let mut failed = 0;
+            for (i, input) in inputs.iter().enumerate() {
+                if inputs.len() > 1 {
+                    if i > 0 {
+                        writeln!(out)?;
+                    }
+                    writeln!(out, "{}:", input.display())?;
+                }
+                let summary = File::open(input)
+                    .and_then(|f| recording::Reader::new(BufReader::new(f)))
+                    .and_then(|r| stat::Summary::read(r, buckets));
+                match summary {
+                    Ok(summary) => write!(out, "{}", summary)?,
+                    Err(e) => {
+                        eprintln!("can't read {}: {}", input.display(), e);
+                        failed += 1;
+                    }
+                }
+            }
+            match failed {
+                0 => Ok(()),
+                _ => Err(format!(
+                    "couldn't read {} of {} recordings",
+                    failed,
+                    inputs.len()
+                )
+                .into()),
+            }
+        }
+        LpcCat::Serve {
+            probe,
+            session,
+            listen,
+            framed,
+            history,
+            max_client_rate,
+            max_total_rate,
+            allow,
+            token_file,
+            tls_cert,
+            tls_key,
+            spool,
+            spool_size,
+            spool_files,
+        } => {
+            let token = match token_file {
+                Some(path) => {
+                    let token = std::fs::read_to_string(path)?;
+                    Some(token.trim_end().to_string())
+                }
+                None => None,
+            };
+            #[cfg(not(feature = "tls"))]
+            if tls_cert.is_some() || tls_key.is_some() {
+                return Err("this build of lpc-cat doesn't support TLS; \
+                            rebuild with `--features tls`"
+                    .into());
+            }
+            let config = serve::Config {
+                framing: framed,
+                history_bytes: history as usize,
+                client_rate: max_client_rate,
+                total_rate: max_total_rate,
+                access: access::Policy { allow, token },
+                progress: Some(Arc::clone(&session_state().progress)),
+                #[cfg(feature = "tls")]
+                tls: match (tls_cert, tls_key) {
+                    (Some(cert), Some(key)) => Some(tls_config(&cert, &key)?),
+                    _ => None,
+                },
+            };
+            let spool = match spool {
+                Some(dir) => Some(spool::Spool::new(spool::Config {
+                    dir,
+                    file_size: spool_size,
+                    files: spool_files,
+                })?),
+                None => None,
+            };
+            let source = session.open(&probe)?;
+            let server = serve::Server::bind(&listen, config)?;
+            let closer = server.closer();
+            let mut sink: Box<dyn Sink> = match spool {
+                Some(spool) => Box::new(capture::Tee(vec![
+                    Box::new(server),
+                    Box::new(spool),
+                ])),
+                None => Box::new(server),
+            };
+            let stats =
+                capture::run(source, &session.capture_config()?, &mut *sink)?;
+            let shutdown = &session_state().shutdown;
+            shutdown.stage(Stage::Flushing);
+            drop(sink);
+            let deadline = shutdown.stage(Stage::Servers);
+            closer.close(deadline);
+            shutdown.stage(Stage::Reports);
+            report_stop(None, &stats);
+            shutdown.finish();
+            Ok(())
+        }
+        LpcCat::Raw {
+            probe,
+            timeout,
+            probe_commands,
+            skip,
+            commands,
+        } => {
+            let handle = probe.open()?;
+            let timeout = Duration::from_millis(timeout);
+            if probe_commands {
+                return sweep_commands(&handle, timeout, &skip);
+            }
+            if !commands.is_empty() {
+                for c in &commands {
+                    raw_command(&handle, &parse_hex(c)?, timeout)?;
+                }
+                return Ok(());
+            }
+            for line in std::io::stdin().lock().lines() {
+                let line = line?;
+                let line = line.trim();
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+                // Carry on after typos when used interactively.
+                match parse_hex(line) {
+                    Ok(command) => raw_command(&handle, &command, timeout)?,
+                    Err(e) => eprintln!("{}", e),
+                }
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Returns a flag that's set when we're asked to stop, by SIGINT (Ctrl-C) or
+/// SIGTERM, for captures to watch. Until this is first called, those signals
+/// kill us as usual. A second signal gives up on stopping cleanly.
+fn stop_flag() -> Arc<AtomicBool> {
+    static FLAG: OnceLock<Arc<AtomicBool>> = OnceLock::new();
+    let flag = FLAG.get_or_init(|| {
+        let flag = Arc::new(AtomicBool::new(false));
+        let handler_flag = Arc::clone(&flag);
+        let installed = ctrlc::set_handler(move || {
+            if handler_flag.swap(true, Ordering::Relaxed) {
+                report_abort("interrupted");
+                restore_terminal();
+                std::process::exit(exit::INTERRUPTED);
+            }
+            eprintln!("stopping...");
+        });
+        if let Err(e) = installed {
+            log::warn!("can't stop cleanly on Ctrl-C: {}", e);
+        }
+        flag
+    });
+    Arc::clone(flag)
+}
 
-        check_cmd(response[0], 0x03)?;
+/// What's needed to report on a capture that ends abnormally, from wherever
+/// that's noticed. There's only ever one capture (`cat --all` doesn't track
+/// its captures here).
+struct SessionState {
+    progress: Arc<capture::Progress>,
+    /// Capture goes through the first stages, and we do the rest.
+    shutdown: Shutdown,
+    observer: OnceLock<capture::Observer>,
+    /// The target's boots, with `--watch`.
+    boots: OnceLock<watch::Boots>,
+    /// Whether an abnormal end has been reported already.
+    reported: AtomicBool,
+}
+
+fn session_state() -> &'static SessionState {
+    static STATE: OnceLock<SessionState> = OnceLock::new();
+    STATE.get_or_init(|| SessionState {
+        progress: Arc::default(),
+        shutdown: Shutdown::new(),
+        observer: OnceLock::new(),
+        boots: OnceLock::new(),
+        reported: AtomicBool::new(false),
+    })
+}
+
+/// Prints a summary of a capture that's ending abnormally, for `reason`,
+/// and passes it on to the `--events` observer, if there is one. Does
+/// nothing if capture didn't start, or ended normally, or this has already
+/// been done.
+///
+/// This is called from panic hooks and signal handlers (which `ctrlc` runs
+/// on an ordinary thread), so it mustn't take any locks that whoever
+/// panicked might hold. Observers take their own, but only while writing.
+fn report_abort(reason: &str) {
+    let state = session_state();
+    let progress = &state.progress;
+    if !progress.started()
+        || progress.ended()
+        || state.reported.swap(true, Ordering::Relaxed)
+    {
+        return;
+    }
+    let stats = progress.snapshot();
+    eprint!("capture aborted ({}); up to then:\n{}", reason, stats);
+    if let Some(observer) = state.observer.get() {
+        observer.notify(&capture::Status::Aborted {
+            reason: reason.to_string(),
+            stats,
+        });
+    }
+}
 
-        Ok(u32::from_le_bytes(response[5..9].try_into().unwrap()))
+/// Prints the effective configuration for `--dry-run`: where data would
+/// come from, and then all the options as parsed. Attached probes are looked
+/// up but not opened.
+fn dry_run(
+    args: &LpcCat,
+    probe: &ProbeArgs,
+    session: &SessionArgs,
+) -> Result<(), Box<dyn Error>> {
+    let sources = match session.source {
+        SourceArg::Synthetic(config) => vec![format!(
+            "synthetic data ({:?} pattern at {} bit/s)",
+            config.pattern, config.rate
+        )],
+        SourceArg::Probe => {
+            // Catch what would stop us before opening the probe.
+            session.setup()?;
+            probe.check_transport()?;
+            let transport = match probe.transport {
+                transport::Kind::Hid => "HID reports",
+                transport::Kind::Bulk => "libusb",
+            };
+            let all = matches!(args, LpcCat::Cat { all: true, .. });
+            let found = probe.resolve(all);
+            if found.is_empty() {
+                // Nothing attached, so say what would be looked for.
+                vec![format!("{}, through {}", probe.describe(all)?, transport)]
+            } else {
+                found
+                    .into_iter()
+                    .map(|((vid, pid), p)| {
+                        format!(
+                            "probe {:04x}:{:04x}, serial {}, in port {}, \
+                             through {}",
+                            vid,
+                            pid,
+                            p.serial.as_deref().unwrap_or("unknown"),
+                            p.usb_port.as_deref().unwrap_or("unknown"),
+                            transport
+                        )
+                    })
+                    .collect()
+            }
+        }
+    };
+    let mut out = std::io::stdout().lock();
+    for source in sources {
+        writeln!(out, "would capture from {}", source)?;
     }
+    writeln!(out, "{:#?}", args)?;
+    Ok(())
+}
 
-    /// Configures the UART for a particular bit rate (in bits per second). The
-    /// probe can't achieve just *any* rate, and will return a nearby achievable
-    /// rate.
-    pub fn set_bit_rate(&self, rate: u32) -> Result<u32, Box<dyn Error>> {
-        let mut req = [0x01, 0, 0, 0, 0];
-        req[1..].copy_from_slice(&rate.to_le_bytes());
-        self.0.write(&req)?;
+/// Prints a summary of a capture that was stopped by a signal. There's no
+/// command to tell the probe to stop capturing that we know of; it stops
+/// being polled, and that's that.
+fn report_stop(serial: Option<&str>, stats: &capture::Stats) {
+    if !stop_flag().load(Ordering::Relaxed) {
+        return;
+    }
+    match serial {
+        Some(serial) => eprint!("probe {}:\n{}", serial, stats),
+        None => eprint!("{}", stats),
+    }
+}
 
-        let mut response = [0; 1024];
-        self.0.read(&mut response)?;
+/// Prints the table of the target's boots, with `--watch`.
+fn report_boots() {
+    if let Some(boots) = session_state().boots.get() {
+        if !boots.get().is_empty() {
+            eprint!("{}", boots);
+        }
+    }
+}
 
-        check_cmd(response[0], 0x01)?;
+/// The soak test found problems, which it has already described.
+#[derive(Debug)]
+struct SoakFailed;
 
-        Ok(u32::from_le_bytes(response[1..5].try_into().unwrap()))
+impl std::fmt::Display for SoakFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "soak test failed")
     }
+}
 
-    /// Asks the probe for an update on its capture buffer.
-    ///
-    /// Returns a tuple of `(epoch, poll_result)`.
-    pub fn poll<'a>(
-        &self,
-        buffer: &'a mut [u8],
-    ) -> Result<(u8, PollResult<'a>), Box<dyn Error>> {
-        assert!(buffer.len() >= 1024);
+impl Error for SoakFailed {}
 
-        self.0.write(&[0x02])?;
+/// Captures for `cat --soak`, and gives a verdict at the end.
+fn soak_test(
+    source: Box<dyn capture::Source>,
+    config: &capture::Config,
+    sink: Box<dyn Sink>,
+    interval: Duration,
+) -> Result<(), Box<dyn Error>> {
+    let mut soak = soak::Soak::new(sink, interval);
+    let result = capture::run(source, config, &mut soak);
+    let shutdown = &session_state().shutdown;
+    shutdown.stage(Stage::Flushing);
+    let counts = soak.counts().clone();
+    drop(soak);
+    std::io::stdout().flush()?;
 
-        let n = self.0.read(buffer)?;
+    shutdown.stage(Stage::Reports);
+    if let Ok(stats) = &result {
+        eprint!("{}", stats);
+    }
+    report_boots();
+    let verdict = soak::Verdict::new(&counts, result.as_ref().ok());
+    eprintln!("soak test verdict: {}", verdict);
+    shutdown.finish();
+    result?;
+    if verdict.passed() {
+        Ok(())
+    } else {
+        Err(SoakFailed.into())
+    }
+}
 
-        let response = &mut buffer[..n];
+/// Captures from several probes at once for `cat`, each on its own thread.
+/// A probe failing doesn't stop capture from the others.
+fn cat_multi(
+    probe: &ProbeArgs,
+    session: &SessionArgs,
+    output: &OutputArgs,
+    all: bool,
+    probe_dir: Option<&Path>,
+    no_cat: bool,
+) -> Result<(), Box<dyn Error>> {
+    if output.defmt.is_some()
+        || !output.port_out.is_empty()
+        || output.ports
+        || output.output.is_some()
+        || output.escape
+        || output.format.is_some()
+        || output.fill_levels.is_some()
+        || output.vcd.is_some()
+        || output.ctf.is_some()
+        || output.pause
+        || output.record.is_some()
+    {
+        return Err("output options can't be used with several probes".into());
+    }
+    let serials = if all {
+        probe.attached_serials()?
+    } else {
+        probe.serial.clone()
+    };
+    if serials.is_empty() {
+        return Err(lpc_cat::Error::DeviceNotFound.into());
+    }
+    if let Some(dir) = probe_dir {
+        std::fs::create_dir_all(dir)?;
+    }
 
-        let kind = response[0];
-        let epoch = response[1];
+    let stdout = multi::Shared::new(std::io::stdout());
+    // Each capture keeps its own count, and shuts down on its own.
+    let config = capture::Config {
+        progress: None,
+        shutdown: None,
+        ..session.capture_config()?
+    };
+    let capture = |serial: &str| -> Result<(), Box<dyn Error>> {
+        let mut probe = probe.clone();
+        probe.serial = vec![serial.to_string()];
+        let mut sink: Box<dyn Sink> = match probe_dir {
+            Some(dir) => {
+                Box::new(File::create(dir.join(format!("{}.swo", serial)))?)
+            }
+            None => Box::new(stdout.tagged(serial)?),
+        };
+        let source = session.open(&probe)?;
+        if no_cat {
+            return Ok(());
+        }
+        let stats = capture::run(source, &config, &mut *sink)?;
+        report_stop(Some(serial), &stats);
+        Ok(())
+    };
 
-        match kind {
-            0x04 => {
-                let packed_levels = u32::from(response[2])
-                    | u32::from(response[3]) << 8
-                    | u32::from(response[4]) << 16;
+    let failures = std::thread::scope(|s| {
+        let threads: Vec<_> = serials
+            .iter()
+            .map(|serial| {
+                let capture = &capture;
+                // Errors aren't `Send`, so only their descriptions come back.
+                let thread =
+                    s.spawn(move || capture(serial).map_err(|e| e.to_string()));
+                (serial, thread)
+            })
+            .collect();
+        let mut failures = 0;
+        for (serial, thread) in threads {
+            if let Err(e) = thread.join().expect("capture thread panicked") {
+                eprintln!("probe {}: {}", serial, e);
+                failures += 1;
+            }
+        }
+        failures
+    });
+    if failures > 0 {
+        return Err(format!(
+            "capture failed for {} of {} probes",
+            failures,
+            serials.len()
+        )
+        .into());
+    }
+    Ok(())
+}
 
-                if packed_levels == 0 {
-                    return Ok((epoch, PollResult::Empty));
-                }
-                let start = (packed_levels & 0xFFF) as u16;
-                let end = (packed_levels >> 12) as u16;
+/// Sends one command for `raw`, and dumps the response.
+fn raw_command(
+    handle: &Handle,
+    command: &[u8],
+    timeout: Duration,
+) -> Result<(), Box<dyn Error>> {
+    let mut out = std::io::stdout().lock();
+    writeln!(out, "> {}", hex_bytes(command))?;
+    let mut response = vec![0; handle.response_size()];
+    let start = Instant::now();
+    let n = handle.transact(command, &mut response, timeout)?;
+    let elapsed = start.elapsed();
+    match n {
+        None => writeln!(out, "< no response after {:?}", elapsed)?,
+        Some(n) => {
+            // Responses are padded, usually with zeros, which aren't worth
+            // printing.
+            let response = &response[..n];
+            let len =
+                response.iter().rposition(|&b| b != 0).map_or(0, |i| i + 1);
+            writeln!(
+                out,
+                "< {} bytes ({} before trailing zeros) after {:?}",
+                n, len, elapsed
+            )?;
+            for (i, line) in response[..len].chunks(16).enumerate() {
+                writeln!(out, "  {:04x}  {}", i * 16, hex_bytes(line))?;
+            }
+        }
+    }
+    Ok(())
+}
 
-                if end < start {
-                    return Err("invalid fill levels!".into());
-                }
-                let n = usize::from(end - start);
-                Ok((
-                    epoch,
-                    PollResult::Incremental {
-                        start,
-                        end,
-                        fragment: &mut response[5..5 + n],
-                    },
-                ))
+/// Sends each single-byte command to the probe, for `raw --probe-commands`,
+/// and summarizes which ones got a response.
+fn sweep_commands(
+    handle: &Handle,
+    timeout: Duration,
+    skip: &[u8],
+) -> Result<(), Box<dyn Error>> {
+    let mut response = vec![0; handle.response_size()];
+    let mut answered = vec![];
+    for command in 0..=u8::MAX {
+        if skip.contains(&command) {
+            continue;
+        }
+        eprint!("\rtrying {:02x}...", command);
+        let n = match handle.transact(&[command], &mut response, timeout) {
+            Ok(n) => n,
+            Err(e) => {
+                // Most likely the probe took offense and reset, in which case
+                // this command is the interesting one.
+                eprintln!();
+                return Err(format!(
+                    "probe stopped responding after command {:02x}: {}",
+                    command, e
+                )
+                .into());
             }
-            0x82 => Ok((epoch, PollResult::Total(&mut response[2..1024]))),
-            _ => Err("unexpected poll response".into()),
+        };
+        if let Some(n) = n {
+            answered.push((command, response[..n.min(8)].to_vec(), n));
         }
     }
+    eprintln!("\r{} of 256 commands answered", answered.len());
+
+    let mut out = std::io::stdout().lock();
+    writeln!(out, "CMD  LEN   RESPONSE")?;
+    for (command, start, len) in answered {
+        writeln!(
+            out,
+            "{:02x}   {:<5} {} ...",
+            command,
+            len,
+            hex_bytes(&start)
+        )?;
+    }
+    Ok(())
 }
 
-pub enum PollResult<'a> {
-    /// No new traffic in buffer since last poll.
-    Empty,
-    /// New traffic has appeared in the buffer at offset `start` (inclusive)
-    /// through `end` (exclusive).
-    Incremental {
-        start: u16,
-        end: u16,
-        fragment: &'a mut [u8],
-    },
-    /// The buffer has filled up; here's the whole thing. If you've been polling
-    /// regularly, this will repeat data received in previous `Incremental`
-    /// messages.
-    Total(&'a mut [u8]),
+fn hex_bytes(bytes: &[u8]) -> String {
+    let hex: Vec<_> = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    hex.join(" ")
 }
 
-fn check_cmd(c: u8, expected: u8) -> Result<(), Box<dyn Error>> {
-    if c != expected {
-        Err("unexpected response".into())
+/// Parses a string of hex bytes, ignoring whitespace and colons between them.
+fn parse_hex(s: &str) -> Result<Vec<u8>, String> {
+    let digits: Vec<char> = s
+        .chars()
+        .filter(|c| !c.is_whitespace() && *c != ':')
+        .collect();
+    if digits.is_empty() || !digits.len().is_multiple_of(2) {
+        return Err(format!("`{}` isn't a whole number of hex bytes", s));
+    }
+    digits
+        .chunks(2)
+        .map(|pair| {
+            let pair: String = pair.iter().collect();
+            u8::from_str_radix(&pair, 16)
+                .map_err(|_| format!("`{}` isn't valid hex", s))
+        })
+        .collect()
+}
+
+fn parse_hex_byte(s: &str) -> Result<u8, String> {
+    u8::from_str_radix(s.trim_start_matches("0x"), 16)
+        .map_err(|_| format!("`{}` isn't a hex byte", s))
+}
+
+#[cfg(feature = "defmt")]
+/// Makes the sink for `--defmt`, with `--timestamps` if `timestamps` is
+/// given, holding the frequency of the timestamp clock, if known.
+fn defmt_sink<W: Write>(
+    elf: &[u8],
+    port: u8,
+    timestamps: Option<Option<u32>>,
+    out: W,
+) -> Result<lpc_cat::defmt::DefmtSink<W>, Box<dyn Error>> {
+    let color = atty::is(atty::Stream::Stdout);
+    let mut sink = lpc_cat::defmt::DefmtSink::new(elf, port, color, out)?;
+    if let Some(frequency) = timestamps {
+        sink.set_timestamps(frequency);
+    }
+    Ok(sink)
+}
+
+#[cfg(not(feature = "defmt"))]
+fn defmt_sink<W: Write>(
+    _elf: &[u8],
+    _port: u8,
+    _timestamps: Option<Option<u32>>,
+    _out: W,
+) -> Result<std::io::Sink, Box<dyn Error>> {
+    Err("this build of lpc-cat doesn't support defmt; rebuild with \
+         `--features defmt`"
+        .into())
+}
+
+#[cfg(feature = "tls")]
+fn tls_config(
+    cert: &std::path::Path,
+    key: &std::path::Path,
+) -> Result<std::sync::Arc<rustls::ServerConfig>, Box<dyn Error>> {
+    use rustls_pemfile::Item;
+
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(cert)?))?
+        .into_iter()
+        .map(rustls::Certificate)
+        .collect();
+    let key = rustls_pemfile::read_all(&mut BufReader::new(File::open(key)?))?
+        .into_iter()
+        .find_map(|item| match item {
+            Item::RSAKey(k) | Item::PKCS8Key(k) | Item::ECKey(k) => {
+                Some(rustls::PrivateKey(k))
+            }
+            _ => None,
+        })
+        .ok_or("no private key found in --tls-key file")?;
+    let config = rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+    Ok(std::sync::Arc::new(config))
+}
+
+/// Parses a length of time: a number of seconds, or of `ms`, `s`, `m` or `h`.
+fn parse_duration(s: &str) -> Result<Duration, String> {
+    let (n, unit) = match s.find(|c: char| c.is_ascii_alphabetic()) {
+        Some(i) => (&s[..i], &s[i..]),
+        None => (s, "s"),
+    };
+    let seconds = match unit {
+        "ms" => 0.001,
+        "s" => 1.0,
+        "m" => 60.0,
+        "h" => 3600.0,
+        _ => return Err(format!("unknown unit in `{}`", s)),
+    };
+    n.parse::<f64>()
+        .ok()
+        .filter(|n| n.is_finite() && *n > 0.0)
+        .map(|n| Duration::from_secs_f64(n * seconds))
+        .ok_or_else(|| format!("can't parse length of time `{}`", s))
+}
+
+/// Parses a byte count with an optional binary suffix: `K`, `M`, or `G`.
+fn parse_size(s: &str) -> Result<u64, String> {
+    let n = s.trim_end_matches(['B', 'b']);
+    let (digits, shift) = match n.char_indices().last() {
+        Some((i, 'K')) | Some((i, 'k')) => (&n[..i], 10),
+        Some((i, 'M')) | Some((i, 'm')) => (&n[..i], 20),
+        Some((i, 'G')) | Some((i, 'g')) => (&n[..i], 30),
+        _ => (n, 0),
+    };
+    digits
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(1 << shift))
+        .ok_or_else(|| format!("can't parse size `{}`", s))
+}
+
+/// Checks for `--version --verbose` (in either order), which clap doesn't
+/// know how to handle.
+fn wants_verbose_version(args: impl Iterator<Item = OsString>) -> bool {
+    let args: Vec<_> = args.skip(1).collect();
+    args.len() == 2
+        && args.iter().any(|a| a == "--version" || a == "-V")
+        && args.iter().any(|a| a == "--verbose")
+}
+
+/// Inserts the `cat` subcommand if the command line doesn't name one, so that
+/// `lpc-cat 3000000` keeps working.
+fn default_to_cat(
+    args: impl Iterator<Item = OsString>,
+) -> impl Iterator<Item = OsString> {
+    const SUBCOMMANDS: &[&str] = &[
+        "cat",
+        "list",
+        "record",
+        "replay",
+        "stat",
+        "serve",
+        "raw",
+        "help",
+        "-h",
+        "--help",
+        "-V",
+        "--version",
+    ];
+
+    let mut args = args.peekable();
+    let argv0 = args.next();
+    let needs_cat = match args.peek().and_then(|a| a.to_str()) {
+        Some(first) => !SUBCOMMANDS.contains(&first),
+        None => false,
+    };
+    argv0
+        .into_iter()
+        .chain(if needs_cat { Some("cat".into()) } else { None })
+        .chain(args)
+}
+
+/// Prints a table of matching probes and whether they offer SWO capture.
+/// Unless told which USB IDs to look for, this covers probes running any
+/// firmware we know, and any other NXP image.
+fn list(probe: &ProbeArgs, verbose: bool) -> Result<(), Box<dyn Error>> {
+    let api = hidapi::HidApi::new().map_err(lpc_cat::Error::from)?;
+    let ids = if probe.vid.is_none() && probe.pid.is_none() {
+        firmware::candidates(&api)
     } else {
-        Ok(())
+        vec![probe.vid_pid()?]
+    };
+    let selector = probe.selector();
+    let probes: Vec<_> = ids
+        .into_iter()
+        .flat_map(|(vid, pid)| select::enumerate(&api, vid, pid, &selector))
+        .collect();
+
+    let mut out = std::io::stdout().lock();
+    writeln!(
+        out,
+        "{:<24} {:<10} {:<24} {:<5} PATH",
+        "SERIAL", "PORT", "PRODUCT", "TRACE"
+    )?;
+    for p in &probes {
+        let trace = p.hid_path(Function::Trace);
+        writeln!(
+            out,
+            "{:<24} {:<10} {:<24} {:<5} {}",
+            p.serial.as_deref().unwrap_or("-"),
+            p.usb_port.as_deref().unwrap_or("-"),
+            p.product.as_deref().unwrap_or("-"),
+            if trace.is_some() { "yes" } else { "no" },
+            trace
+                .or_else(|| p
+                    .interfaces
+                    .iter()
+                    .find_map(|i| i.hid_path.as_deref()))
+                .unwrap_or("-"),
+        )?;
+        if verbose {
+            for i in &p.interfaces {
+                writeln!(
+                    out,
+                    "    interface {}: {:<9} {:<24} {}",
+                    i.number,
+                    i.function.to_string(),
+                    i.name.as_deref().unwrap_or("-"),
+                    i.hid_path.as_deref().unwrap_or("(not HID)"),
+                )?;
+            }
+        }
+    }
+    if probes.is_empty() {
+        eprintln!("no matching probes found");
     }
+    Ok(())
 }
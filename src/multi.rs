@@ -0,0 +1,127 @@
+//! Combining captures from several probes into one stream.
+//!
+//! Each chunk of the combined stream is tagged with the probe it came from
+//! (all integers little endian):
+//!
+//! ```text
+//! byte[0]    = length of the tag
+//! then       = tag (usually the probe's serial number), UTF-8
+//! then       = u8 kind: 0 for data, 1 for a gap
+//! then       = u32 length of the data (zero for gaps)
+//! then       = data
+//! ```
+
+use std::convert::TryFrom;
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Mutex};
+
+use crate::capture::Sink;
+
+const KIND_DATA: u8 = 0;
+const KIND_GAP: u8 = 1;
+
+/// Output shared by several captures, each of which writes to it through its
+/// own `Tagged` sink.
+pub struct Shared<W>(Arc<Mutex<W>>);
+
+impl<W: Write> Shared<W> {
+    pub fn new(inner: W) -> Self {
+        Self(Arc::new(Mutex::new(inner)))
+    }
+
+    /// Returns a sink that writes chunks tagged with `tag`, which must be
+    /// at most 255 bytes long.
+    pub fn tagged(&self, tag: &str) -> io::Result<Tagged<W>> {
+        if u8::try_from(tag.len()).is_err() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "tag too long",
+            ));
+        }
+        Ok(Tagged {
+            tag: tag.to_string(),
+            out: Arc::clone(&self.0),
+        })
+    }
+}
+
+/// Sink writing one capture's share of a combined stream.
+pub struct Tagged<W> {
+    tag: String,
+    out: Arc<Mutex<W>>,
+}
+
+impl<W: Write> Tagged<W> {
+    fn chunk(&mut self, kind: u8, data: &[u8]) -> io::Result<()> {
+        let len = u32::try_from(data.len()).map_err(|_| {
+            io::Error::new(io::ErrorKind::InvalidInput, "chunk too long")
+        })?;
+        let mut header = Vec::with_capacity(self.tag.len() + 6);
+        header.push(self.tag.len() as u8);
+        header.extend_from_slice(self.tag.as_bytes());
+        header.push(kind);
+        header.extend_from_slice(&len.to_le_bytes());
+
+        // A capture that panicked while holding the lock can't have left a
+        // chunk half written, since we only ever write whole ones.
+        let mut out = self.out.lock().unwrap_or_else(|e| e.into_inner());
+        out.write_all(&header)?;
+        out.write_all(data)?;
+        out.flush()
+    }
+}
+
+impl<W: Write> Sink for Tagged<W> {
+    fn data(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.chunk(KIND_DATA, bytes)
+    }
+
+    fn gap(&mut self) -> io::Result<()> {
+        self.chunk(KIND_GAP, &[])
+    }
+}
+
+/// A chunk of a combined stream.
+#[derive(Clone, Debug)]
+pub struct Chunk {
+    pub tag: String,
+    pub kind: ChunkKind,
+}
+
+#[derive(Clone, Debug)]
+pub enum ChunkKind {
+    Data(Vec<u8>),
+    Gap,
+}
+
+/// Reads the next chunk of a combined stream. Returns `Ok(None)` at the end
+/// of the stream.
+pub fn read_chunk(mut input: impl Read) -> io::Result<Option<Chunk>> {
+    let mut tag_len = [0];
+    match input.read_exact(&mut tag_len) {
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        r => r?,
+    }
+    let mut tag = vec![0; usize::from(tag_len[0])];
+    input.read_exact(&mut tag)?;
+    let tag = String::from_utf8(tag)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "bad tag"))?;
+
+    let mut header = [0; 5];
+    input.read_exact(&mut header)?;
+    let len = u32::from_le_bytes([header[1], header[2], header[3], header[4]]);
+    let mut data = vec![0; len as usize];
+    input.read_exact(&mut data)?;
+
+    let kind = match header[0] {
+        KIND_DATA => ChunkKind::Data(data),
+        KIND_GAP => ChunkKind::Gap,
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "unknown chunk kind",
+            ))
+        }
+    };
+    Ok(Some(Chunk { tag, kind }))
+}
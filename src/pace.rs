@@ -0,0 +1,94 @@
+//! Writing the stream out at the rate it came over the wire, rather than in
+//! bursts as the probe hands it over, for consumers that expect the timing
+//! of a real UART.
+//!
+//! Each fragment is written out at the SWO bit rate, taking 10 bits per
+//! byte as UART does with 8N1 framing, starting when it arrived (or when
+//! the fragment before it has been written, if that's later). Pauses in the
+//! stream longer than a poll interval are kept, so the output lags the wire
+//! by about a poll interval throughout. When replaying, fragments arrive
+//! when the recording says they did, so the original timing is kept there
+//! too.
+
+use std::io::{self, Write};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::capture::{Fragment, Sink};
+
+/// Bits on the wire for each byte of data: start bit, 8 data bits, and stop
+/// bit.
+const BITS_PER_BYTE: u64 = 10;
+
+/// How much to write at a time, so that there's a write about every this
+/// often rather than one per byte.
+const BATCH: Duration = Duration::from_millis(1);
+
+/// Sink writing data to `out` at the wire rate.
+pub struct Pace<W> {
+    out: W,
+    /// How long each byte took on the wire, once the bit rate is known.
+    byte_time: Option<Duration>,
+    /// When the next byte is due, if it follows on from the last.
+    next: Option<Instant>,
+    /// Whether we've warned about not knowing the bit rate.
+    warned: bool,
+}
+
+impl<W: Write> Pace<W> {
+    pub fn new(out: W) -> Self {
+        Self {
+            out,
+            byte_time: None,
+            next: None,
+            warned: false,
+        }
+    }
+
+    /// Writes `bytes`, which arrived at `received`, at the wire rate.
+    fn pace(&mut self, received: Instant, bytes: &[u8]) -> io::Result<()> {
+        let byte_time = match self.byte_time {
+            Some(byte_time) => byte_time,
+            None => {
+                if !self.warned {
+                    log::warn!("bit rate unknown, so not pacing output");
+                    self.warned = true;
+                }
+                return self.out.write_all(bytes);
+            }
+        };
+        let mut due = self.next.map_or(received, |next| next.max(received));
+        let batch = (BATCH.as_nanos() / byte_time.as_nanos()).max(1) as usize;
+        for chunk in bytes.chunks(batch) {
+            let now = Instant::now();
+            if due > now {
+                thread::sleep(due - now);
+            }
+            // Flushing, so that nothing on the way holds on to it.
+            self.out.write_all(chunk)?;
+            self.out.flush()?;
+            due += byte_time * chunk.len() as u32;
+        }
+        self.next = Some(due);
+        Ok(())
+    }
+}
+
+impl<W: Write> Sink for Pace<W> {
+    fn data(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.pace(Instant::now(), bytes)
+    }
+
+    fn fragment(&mut self, info: &Fragment, bytes: &[u8]) -> io::Result<()> {
+        self.pace(info.received, bytes)
+    }
+
+    fn bit_rate(&mut self, rate: u32) -> io::Result<()> {
+        if rate > 0 {
+            self.byte_time = Some(Duration::from_nanos(
+                BITS_PER_BYTE * 1_000_000_000 / u64::from(rate),
+            ));
+        }
+        Ok(())
+    }
+}
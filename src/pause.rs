@@ -0,0 +1,99 @@
+//! Pausing live output, so that someone watching can read what's on screen
+//! without stopping the capture or losing anything written elsewhere.
+
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// Writer that can be paused from another thread. While paused, output is
+/// held in memory, up to a limit, and written out on resuming; anything
+/// beyond the limit is discarded, with a note saying how much.
+///
+/// Clones share the same output and the same pause state.
+pub struct Pausable<W> {
+    inner: Arc<Mutex<Inner<W>>>,
+}
+
+struct Inner<W> {
+    out: W,
+    paused: bool,
+    held: Vec<u8>,
+    limit: usize,
+    discarded: u64,
+}
+
+impl<W: Write> Pausable<W> {
+    /// Writes to `out`, holding at most `limit` bytes while paused.
+    pub fn new(out: W, limit: usize) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(Inner {
+                out,
+                paused: false,
+                held: vec![],
+                limit,
+                discarded: 0,
+            })),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Inner<W>> {
+        // Whoever panicked can't have left the state inconsistent.
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.lock().paused
+    }
+
+    /// Holds output until `resume` is called.
+    pub fn pause(&self) {
+        self.lock().paused = true;
+    }
+
+    /// Writes out anything held while paused, and carries on as normal.
+    pub fn resume(&self) -> io::Result<()> {
+        let mut inner = self.lock();
+        inner.paused = false;
+        let held = std::mem::take(&mut inner.held);
+        inner.out.write_all(&held)?;
+        if inner.discarded > 0 {
+            let note = format!(
+                "-- {} bytes of output discarded while paused --\n",
+                inner.discarded
+            );
+            inner.discarded = 0;
+            inner.out.write_all(note.as_bytes())?;
+        }
+        inner.out.flush()
+    }
+}
+
+impl<W> Clone for Pausable<W> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: Arc::clone(&self.inner),
+        }
+    }
+}
+
+impl<W: Write> Write for Pausable<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut inner = self.lock();
+        if !inner.paused {
+            return inner.out.write(buf);
+        }
+        let room = inner.limit.saturating_sub(inner.held.len());
+        let n = room.min(buf.len());
+        inner.held.extend_from_slice(&buf[..n]);
+        inner.discarded += (buf.len() - n) as u64;
+        // Claim it all, so that the writer carries on.
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let mut inner = self.lock();
+        if inner.paused {
+            return Ok(());
+        }
+        inner.out.flush()
+    }
+}
@@ -0,0 +1,415 @@
+//! Showing the data on each ITM stimulus port in a way that suits it, for
+//! firmware that uses some ports for text and others for binary data.
+//!
+//! Each port's data is classified as text or binary from the first few bytes
+//! it sends, unless told otherwise. Text is shown a line at a time, and
+//! binary data as hex, each line prefixed with the port number:
+//!
+//! ```text
+//! [0] booting
+//! [3] 01 00 00 00 f4 01 00 00
+//! [0] ready
+//! ```
+//!
+//! Alternatively, each port can have a column of its own, so that a couple
+//! of ports can be watched side by side:
+//!
+//! ```text
+//! [0] booting              | [1] sensor 1: 3.3V
+//! [0] ready                |
+//!                          | [1] sensor 2: 5.0V
+//! ```
+//!
+//! Lines can also start with the wall clock time the last of their data was
+//! sent (see `timestamp`):
+//!
+//! ```text
+//! 2024-03-01T12:34:56.789012Z [0] booting
+//! ```
+
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::time::{Instant, SystemTime};
+
+use crate::capture::{Fragment, Sink};
+use crate::encoding::Encoding;
+use crate::itm;
+use crate::timestamp::{self, Clock, Correlator};
+
+/// What sort of data a port carries.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Kind {
+    Text,
+    Binary,
+}
+
+impl std::str::FromStr for Kind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Kind::Text),
+            "binary" => Ok(Kind::Binary),
+            _ => Err(format!("unknown port kind `{}`", s)),
+        }
+    }
+}
+
+/// Bytes to look at before deciding what a port carries, unless a line ends
+/// sooner.
+const SAMPLE_LEN: usize = 64;
+
+/// Bytes of binary data shown on each line.
+const HEX_WIDTH: usize = 16;
+
+/// Guesses what sort of data `sample` is. Text is UTF-8 (though the sample
+/// may end partway through a character) without control characters, other
+/// than whitespace and the escape that starts terminal color codes.
+pub fn classify(sample: &[u8]) -> Kind {
+    let valid = match std::str::from_utf8(sample) {
+        Ok(s) => s,
+        // Cut off at the end of the sample, not wrong.
+        Err(e) if e.error_len().is_none() => {
+            std::str::from_utf8(&sample[..e.valid_up_to()]).unwrap()
+        }
+        Err(_) => return Kind::Binary,
+    };
+    let text = valid
+        .chars()
+        .all(|c| !c.is_control() || matches!(c, '\n' | '\r' | '\t' | '\x1b'));
+    if text {
+        Kind::Text
+    } else {
+        Kind::Binary
+    }
+}
+
+/// Sink that decodes ITM, and writes the data on each stimulus port to one
+/// output, as text or hex according to what the port carries.
+pub struct Console<W: Write> {
+    itm: itm::Decoder,
+    out: W,
+    ports: Vec<Port>,
+    /// Ports to leave alone, because their data goes elsewhere.
+    ignored: Vec<u8>,
+    columns: Option<Columns>,
+    /// How to time lines, if they're to be timestamped.
+    timestamps: Option<Correlator>,
+    /// How text is encoded.
+    encoding: Encoding,
+}
+
+/// Separates columns.
+const COLUMN_SEPARATOR: &str = " | ";
+
+/// Width of a timestamp, and the space after it.
+const TIMESTAMP_WIDTH: usize = 28;
+
+/// Layout for showing ports side by side.
+struct Columns {
+    /// Width of the whole line, in characters.
+    line_width: usize,
+    count: usize,
+    /// Ports in the order they were given columns. Any beyond `count` share
+    /// the last column.
+    ports: Vec<u8>,
+}
+
+struct Port {
+    number: u8,
+    kind: Option<Kind>,
+    /// Data not yet shown: the sample, before the port is classified, then
+    /// any partial line.
+    pending: Vec<u8>,
+    /// Bytes of data shown so far.
+    shown: u64,
+    /// When data was sent, if lines are timestamped: each entry says that
+    /// the data before this offset (counting from the port's first byte)
+    /// and after the previous entry's was sent at this time.
+    times: VecDeque<(u64, SystemTime)>,
+}
+
+impl Port {
+    /// Returns when the data before `end` in `pending` finished being sent,
+    /// if known.
+    fn time(&self, end: usize) -> Option<SystemTime> {
+        let end = self.shown + end as u64;
+        self.times.iter().find(|(e, _)| *e >= end).map(|(_, t)| *t)
+    }
+
+    /// Takes the first `n` bytes of `pending`, which have been shown.
+    fn take(&mut self, n: usize) -> Vec<u8> {
+        self.shown += n as u64;
+        while self.times.front().is_some_and(|(e, _)| *e <= self.shown) {
+            self.times.pop_front();
+        }
+        self.pending.drain(..n).collect()
+    }
+}
+
+impl<W: Write> Console<W> {
+    pub fn new(out: W) -> Self {
+        Self {
+            itm: itm::Decoder::new(),
+            out,
+            ports: vec![],
+            ignored: vec![],
+            columns: None,
+            timestamps: None,
+            encoding: Encoding::Utf8,
+        }
+    }
+
+    /// Takes text to be in `encoding`, rather than UTF-8, both to show it
+    /// and to tell it from binary data.
+    pub fn set_encoding(&mut self, encoding: Encoding) {
+        self.encoding = encoding;
+    }
+
+    /// Starts each line with the wall clock time its data was sent, taken
+    /// from ITM local timestamps if `frequency` (of the timestamp clock, in
+    /// Hz) is given, and otherwise from when the data arrived (see
+    /// `timestamp`).
+    pub fn set_timestamps(&mut self, frequency: Option<u32>) {
+        self.timestamps = Some(Correlator::new(frequency));
+    }
+
+    /// Shows ports side by side, in `count` columns that fill a line
+    /// `width` characters wide, rather than one after the other. Ports get
+    /// columns in the order they first send something.
+    pub fn set_columns(&mut self, count: usize, width: usize) {
+        self.columns = Some(Columns {
+            line_width: width,
+            count: count.max(1),
+            ports: vec![],
+        });
+    }
+
+    /// Returns the width of each column, if there are columns.
+    fn column_width(&self) -> Option<usize> {
+        let columns = self.columns.as_ref()?;
+        let mut width = columns.line_width;
+        if self.timestamps.is_some() {
+            width = width.saturating_sub(TIMESTAMP_WIDTH);
+        }
+        let separators = COLUMN_SEPARATOR.len() * (columns.count - 1);
+        Some((width.saturating_sub(separators) / columns.count).max(8))
+    }
+
+    /// Writes a line of output for `port`, sent at `time` if known.
+    fn line(
+        &mut self,
+        port: u8,
+        time: Option<SystemTime>,
+        text: &str,
+    ) -> io::Result<()> {
+        let mut stamp = match (&self.timestamps, time) {
+            (Some(_), Some(time)) => timestamp::format(time) + " ",
+            (Some(_), None) => " ".repeat(TIMESTAMP_WIDTH),
+            (None, _) => String::new(),
+        };
+        let width = match self.column_width() {
+            Some(w) => w,
+            None => return writeln!(self.out, "{}[{}] {}", stamp, port, text),
+        };
+        let columns = self.columns.as_mut().unwrap();
+        let column = match columns.ports.iter().position(|&p| p == port) {
+            Some(i) => i,
+            None => {
+                columns.ports.push(port);
+                columns.ports.len() - 1
+            }
+        }
+        .min(columns.count - 1);
+
+        // Wrap long lines within the column, only timestamping the first
+        // row.
+        let text: Vec<char> = format!("[{}] {}", port, text).chars().collect();
+        let blank = " ".repeat(stamp.len());
+        for piece in text.chunks(width) {
+            let mut row = std::mem::replace(&mut stamp, blank.clone());
+            for _ in 0..column {
+                row.push_str(&" ".repeat(width));
+                row.push_str(COLUMN_SEPARATOR);
+            }
+            row.extend(piece);
+            writeln!(self.out, "{}", row)?;
+        }
+        Ok(())
+    }
+
+    /// Treats `port` as carrying `kind` of data, rather than guessing.
+    pub fn set_kind(&mut self, port: u8, kind: Kind) {
+        self.port(port).kind = Some(kind);
+    }
+
+    /// Doesn't show anything from `port`.
+    pub fn ignore(&mut self, port: u8) {
+        self.ignored.push(port);
+    }
+
+    fn port(&mut self, number: u8) -> &mut Port {
+        let i = match self.ports.iter().position(|p| p.number == number) {
+            Some(i) => i,
+            None => {
+                self.ports.push(Port {
+                    number,
+                    kind: None,
+                    pending: vec![],
+                    shown: 0,
+                    times: VecDeque::new(),
+                });
+                self.ports.len() - 1
+            }
+        };
+        &mut self.ports[i]
+    }
+
+    /// Writes out what's pending on every port. With `all`, that includes
+    /// partial lines of text, and samples too short to be sure about.
+    fn show(&mut self, all: bool) -> io::Result<()> {
+        // Keep hex within its column, allowing for the port number.
+        let hex_width = self.column_width().map_or(HEX_WIDTH, |w| {
+            (w.saturating_sub(5) / 3).clamp(1, HEX_WIDTH)
+        });
+        let encoding = self.encoding;
+        let kind_of = |sample: &[u8]| match encoding {
+            Encoding::Utf8 => classify(sample),
+            _ => classify(encoding.decode(sample).as_bytes()),
+        };
+        let mut lines = vec![];
+        for port in &mut self.ports {
+            let kind = match port.kind {
+                Some(kind) => kind,
+                None if all && !port.pending.is_empty() => {
+                    kind_of(&port.pending)
+                }
+                None => {
+                    let ready = port.pending.len() >= SAMPLE_LEN
+                        || port.pending.contains(&b'\n');
+                    if !ready {
+                        continue;
+                    }
+                    kind_of(&port.pending)
+                }
+            };
+            port.kind = Some(kind);
+            match kind {
+                Kind::Text => {
+                    let end = if all {
+                        port.pending.len()
+                    } else {
+                        match port.pending.iter().rposition(|&b| b == b'\n') {
+                            Some(i) => i + 1,
+                            None => continue,
+                        }
+                    };
+                    let mut shown = 0;
+                    for line in
+                        port.pending[..end].split_inclusive(|&b| b == b'\n')
+                    {
+                        shown += line.len();
+                        let line = line.strip_suffix(b"\n").unwrap_or(line);
+                        let line = line.strip_suffix(b"\r").unwrap_or(line);
+                        lines.push((
+                            port.number,
+                            port.time(shown),
+                            encoding.decode(line).into_owned(),
+                        ));
+                    }
+                    port.take(end);
+                }
+                Kind::Binary => {
+                    let mut shown = 0;
+                    for chunk in port.pending.chunks(hex_width) {
+                        shown += chunk.len();
+                        let hex: Vec<String> = chunk
+                            .iter()
+                            .map(|b| format!("{:02x}", b))
+                            .collect();
+                        lines.push((
+                            port.number,
+                            port.time(shown),
+                            hex.join(" "),
+                        ));
+                    }
+                    port.take(port.pending.len());
+                }
+            }
+        }
+        for (port, time, text) in lines {
+            self.line(port, time, &text)?;
+        }
+        self.out.flush()
+    }
+
+    /// Decodes `bytes`, which arrived at `received`, and shows what's ready.
+    fn decode(&mut self, received: Instant, bytes: &[u8]) -> io::Result<()> {
+        let mut packets = vec![];
+        self.itm.feed(bytes, |packet| match packet {
+            itm::Packet::Instrumentation { .. }
+            | itm::Packet::LocalTimestamp { .. } => packets.push(packet),
+            _ => (),
+        });
+        let times = self.timestamps.as_mut().map(|t| {
+            t.arrived(received);
+            t.times(&packets)
+        });
+        for (i, packet) in packets.into_iter().enumerate() {
+            let (port, payload) = match packet {
+                itm::Packet::Instrumentation { port, payload } => {
+                    (port, payload)
+                }
+                _ => continue,
+            };
+            if self.ignored.contains(&port) {
+                continue;
+            }
+            let port = self.port(port);
+            port.pending.extend_from_slice(&payload);
+            if let Some(times) = &times {
+                let end = port.shown + port.pending.len() as u64;
+                match port.times.back_mut() {
+                    Some((e, t)) if *t == times[i] => *e = end,
+                    _ => port.times.push_back((end, times[i])),
+                }
+            }
+        }
+        self.show(false)
+    }
+}
+
+impl<W: Write> Sink for Console<W> {
+    fn data(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.decode(Instant::now(), bytes)
+    }
+
+    fn fragment(&mut self, info: &Fragment, bytes: &[u8]) -> io::Result<()> {
+        self.decode(info.received, bytes)
+    }
+
+    fn gap(&mut self) -> io::Result<()> {
+        // Anything half-decoded is garbage now, and anything before the gap
+        // won't be finished.
+        self.itm.reset();
+        self.show(true)?;
+        if let Some(timestamps) = &mut self.timestamps {
+            timestamps.gap();
+        }
+        writeln!(self.out, "-- data may have been lost here --")?;
+        self.out.flush()
+    }
+
+    fn clock(&mut self, clock: Clock) -> io::Result<()> {
+        if let Some(timestamps) = &mut self.timestamps {
+            timestamps.set_clock(clock);
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write> Drop for Console<W> {
+    fn drop(&mut self) {
+        self.show(true).ok();
+    }
+}
@@ -0,0 +1,347 @@
+//! Statistical profiling from the DWT's PC samples, which the target sends
+//! over ITM without any changes to its code once PC sampling is enabled
+//! (`DWT_CTRL.PCSAMPLENA`, with `ITM_TCR.DWTENA`).
+//!
+//! Each sample is put down to the function containing it, using the symbol
+//! table of the target's ELF file, and the counts are written out when
+//! capture ends: as a table of the hottest functions, or as folded stacks
+//! for flame graph tools (e.g. `flamegraph.pl` or `inferno-flamegraph`).
+//! Samples only give the PC, not the stack above it, so each "stack" is one
+//! function deep. Samples taken while the core was asleep are counted as
+//! `[sleep]`, and those that fall outside every function as `[unknown]`.
+
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::io::{self, Write};
+
+use crate::capture::Sink;
+use crate::itm;
+
+/// DWT discriminator for PC samples.
+const PC_SAMPLE: u8 = 2;
+
+/// Names for samples that don't land in a function.
+const SLEEP: &str = "[sleep]";
+const UNKNOWN: &str = "[unknown]";
+
+/// How to write out the profile.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// Functions by number of samples, hottest first, with percentages.
+    Table,
+    /// `function count` lines, as flame graph tools take.
+    Folded,
+}
+
+impl Format {
+    /// Names of the formats, as accepted by `from_str`.
+    pub const NAMES: &'static [&'static str] = &["table", "folded"];
+}
+
+impl std::str::FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "table" => Ok(Format::Table),
+            "folded" => Ok(Format::Folded),
+            _ => Err(format!("unknown profile format `{}`", s)),
+        }
+    }
+}
+
+/// The functions in an ELF file's symbol table.
+pub struct Symbols {
+    /// Start address, end address (exclusive), and name, by start address.
+    functions: Vec<(u32, u32, String)>,
+}
+
+impl Symbols {
+    /// Reads the function symbols from the contents of a 32-bit
+    /// little-endian ELF file, as built for Cortex-M. Mangled Rust names are
+    /// demangled (the legacy scheme only).
+    pub fn from_elf(elf: &[u8]) -> io::Result<Self> {
+        let bad = |why: &str| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("ELF {}", why))
+        };
+        if elf.get(..4) != Some(b"\x7fELF") {
+            return Err(bad("magic number missing"));
+        }
+        // EI_CLASS and EI_DATA.
+        if elf.get(4..6) != Some(&[1, 1]) {
+            return Err(bad("isn't 32-bit little-endian"));
+        }
+        let u16_at = |at: usize| -> Option<u16> {
+            Some(u16::from_le_bytes(elf.get(at..at + 2)?.try_into().ok()?))
+        };
+        let u32_at = |at: usize| -> Option<u32> {
+            Some(u32::from_le_bytes(elf.get(at..at + 4)?.try_into().ok()?))
+        };
+        let truncated = || bad("is truncated");
+
+        let shoff = u32_at(0x20).ok_or_else(truncated)? as usize;
+        let shentsize = usize::from(u16_at(0x2e).ok_or_else(truncated)?);
+        let shnum = usize::from(u16_at(0x30).ok_or_else(truncated)?);
+        // Offset and size of section `i`, and its sh_type and sh_link.
+        let section = |i: usize| -> Option<(usize, usize, u32, usize)> {
+            let at = shoff + i * shentsize;
+            Some((
+                u32_at(at + 16)? as usize,
+                u32_at(at + 20)? as usize,
+                u32_at(at + 4)?,
+                u32_at(at + 24)? as usize,
+            ))
+        };
+
+        const SHT_SYMTAB: u32 = 2;
+        const STT_FUNC: u8 = 2;
+        const SYM_SIZE: usize = 16;
+        let mut functions = vec![];
+        for i in 0..shnum {
+            let (offset, size, kind, link) =
+                section(i).ok_or_else(truncated)?;
+            if kind != SHT_SYMTAB {
+                continue;
+            }
+            let (strings, strings_size, _, _) =
+                section(link).ok_or_else(truncated)?;
+            let strings = elf
+                .get(strings..strings + strings_size)
+                .ok_or_else(truncated)?;
+            let symbols =
+                elf.get(offset..offset + size).ok_or_else(truncated)?;
+            for sym in symbols.chunks_exact(SYM_SIZE) {
+                let field = |at: usize| {
+                    u32::from_le_bytes(sym[at..at + 4].try_into().unwrap())
+                };
+                if sym[12] & 0xf != STT_FUNC {
+                    continue;
+                }
+                let name = &strings[(field(0) as usize).min(strings.len())..];
+                let name =
+                    &name[..name.iter().position(|&b| b == 0).unwrap_or(0)];
+                if name.is_empty() {
+                    continue;
+                }
+                // The low bit of a Thumb function's address is set.
+                let start = field(4) & !1;
+                let end = start.saturating_add(field(8));
+                functions.push((
+                    start,
+                    end,
+                    demangle(&String::from_utf8_lossy(name)),
+                ));
+            }
+        }
+        if functions.is_empty() {
+            return Err(bad("has no function symbols (is it stripped?)"));
+        }
+        functions.sort();
+        // Symbols without a size run up to the next one.
+        for i in 0..functions.len() {
+            if functions[i].1 == functions[i].0 {
+                functions[i].1 =
+                    functions.get(i + 1).map_or(u32::MAX, |next| next.0);
+            }
+        }
+        Ok(Self { functions })
+    }
+
+    /// Returns the name of the function containing `pc`, if any.
+    pub fn lookup(&self, pc: u32) -> Option<&str> {
+        let i = self.functions.partition_point(|f| f.0 <= pc);
+        // Functions can overlap; take the closest that starts at or before
+        // `pc` and still covers it.
+        self.functions[..i]
+            .iter()
+            .rev()
+            .find(|f| pc < f.1)
+            .map(|f| f.2.as_str())
+    }
+}
+
+/// Demangles a Rust symbol name in the legacy scheme (`_ZN...E`), dropping
+/// the hash; other names come back unchanged.
+fn demangle(name: &str) -> String {
+    let mut rest = match name
+        .strip_prefix("_ZN")
+        .or_else(|| name.strip_prefix("__ZN"))
+    {
+        Some(rest) => rest,
+        None => return name.to_string(),
+    };
+    let mut parts = vec![];
+    while !rest.starts_with('E') {
+        let digits = rest.bytes().take_while(u8::is_ascii_digit).count();
+        let len: usize = match rest[..digits].parse() {
+            Ok(len) => len,
+            Err(_) => return name.to_string(),
+        };
+        match rest.get(digits..digits + len) {
+            Some(part) => parts.push(part),
+            None => return name.to_string(),
+        }
+        rest = &rest[digits + len..];
+    }
+    let is_hash = |p: &str| {
+        p.len() == 17
+            && p.starts_with('h')
+            && p[1..].bytes().all(|b| b.is_ascii_hexdigit())
+    };
+    if parts.last().is_some_and(|p| is_hash(p)) {
+        parts.pop();
+    }
+    let parts: Vec<String> = parts.iter().map(|p| unescape(p)).collect();
+    parts.join("::")
+}
+
+/// Undoes the escaping of punctuation in a legacy mangled name component.
+fn unescape(part: &str) -> String {
+    // Components that would start with an escape get an underscore first.
+    let part = match part.strip_prefix('_') {
+        Some(p) if p.starts_with('$') => p,
+        _ => part,
+    };
+    let mut out = String::new();
+    let mut rest = part;
+    while let Some(c) = rest.chars().next() {
+        if rest.starts_with("..") {
+            out.push_str("::");
+            rest = &rest[2..];
+            continue;
+        }
+        if c == '$' {
+            if let Some(end) = rest[1..].find('$') {
+                let code = &rest[1..end + 1];
+                let decoded = match code {
+                    "SP" => Some('@'),
+                    "BP" => Some('*'),
+                    "RF" => Some('&'),
+                    "LT" => Some('<'),
+                    "GT" => Some('>'),
+                    "LP" => Some('('),
+                    "RP" => Some(')'),
+                    "C" => Some(','),
+                    _ => code
+                        .strip_prefix('u')
+                        .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                        .and_then(char::from_u32),
+                };
+                if let Some(decoded) = decoded {
+                    out.push(decoded);
+                    rest = &rest[end + 2..];
+                    continue;
+                }
+            }
+        }
+        out.push(c);
+        rest = &rest[c.len_utf8()..];
+    }
+    out
+}
+
+/// Sink that counts PC samples by function, and writes the profile to `out`
+/// when dropped.
+pub struct Profile<W: Write> {
+    symbols: Symbols,
+    format: Format,
+    itm: itm::Decoder,
+    /// Samples by PC, and taken while asleep.
+    samples: HashMap<u32, u64>,
+    asleep: u64,
+    /// Overflow packets, each meaning the target dropped some samples.
+    overflows: u64,
+    out: W,
+}
+
+impl<W: Write> Profile<W> {
+    pub fn new(symbols: Symbols, format: Format, out: W) -> Self {
+        Self {
+            symbols,
+            format,
+            itm: itm::Decoder::new(),
+            samples: HashMap::new(),
+            asleep: 0,
+            overflows: 0,
+            out,
+        }
+    }
+
+    /// Returns the number of samples in each function, hottest first.
+    fn counts(&self) -> Vec<(&str, u64)> {
+        let mut counts: HashMap<&str, u64> = HashMap::new();
+        for (&pc, &n) in &self.samples {
+            *counts
+                .entry(self.symbols.lookup(pc).unwrap_or(UNKNOWN))
+                .or_default() += n;
+        }
+        if self.asleep > 0 {
+            counts.insert(SLEEP, self.asleep);
+        }
+        let mut counts: Vec<(&str, u64)> = counts.into_iter().collect();
+        counts.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+        counts
+    }
+
+    fn report(&mut self) -> io::Result<()> {
+        let counts = self.counts();
+        let mut out = Vec::new();
+        match self.format {
+            Format::Folded => {
+                for (name, n) in &counts {
+                    // Semicolons separate frames.
+                    writeln!(out, "{} {}", name.replace(';', ":"), n)?;
+                }
+            }
+            Format::Table => {
+                let total: u64 = counts.iter().map(|c| c.1).sum();
+                writeln!(out, "{:>10} {:>7}  function", "samples", "%")?;
+                for (name, n) in &counts {
+                    let percent = *n as f64 * 100.0 / total as f64;
+                    writeln!(out, "{:>10} {:>6.2}%  {}", n, percent, name)?;
+                }
+                writeln!(out, "{:>10} samples in total", total)?;
+                if self.overflows > 0 {
+                    writeln!(
+                        out,
+                        "{:>10} ITM overflows, each losing samples",
+                        self.overflows
+                    )?;
+                }
+            }
+        }
+        self.out.write_all(&out)?;
+        self.out.flush()
+    }
+}
+
+impl<W: Write> Sink for Profile<W> {
+    fn data(&mut self, bytes: &[u8]) -> io::Result<()> {
+        let (samples, asleep, overflows) =
+            (&mut self.samples, &mut self.asleep, &mut self.overflows);
+        self.itm.feed(bytes, |packet| match packet {
+            itm::Packet::Hardware {
+                discriminator: PC_SAMPLE,
+                payload,
+            } => match payload.len() {
+                4 => *samples.entry(payload.value()).or_default() += 1,
+                // A one-byte sample means the core was asleep.
+                _ => *asleep += 1,
+            },
+            itm::Packet::Overflow => *overflows += 1,
+            _ => (),
+        });
+        Ok(())
+    }
+
+    fn gap(&mut self) -> io::Result<()> {
+        self.itm.reset();
+        Ok(())
+    }
+}
+
+impl<W: Write> Drop for Profile<W> {
+    fn drop(&mut self) {
+        self.report().ok();
+    }
+}
@@ -0,0 +1,421 @@
+//! The trace interface's command set, as described in the README, with names
+//! for its magic numbers and functions to build commands and pick apart
+//! responses. None of this does any I/O; see `Handle` for that.
+//!
+//! All the names here are made up, since the protocol was reverse
+//! engineered.
+
+use std::convert::TryInto;
+
+use crate::Error;
+
+/// Size of every packet the probe sends, on the firmware we've studied;
+/// commands may be shorter. `Handle` uses this unless the transport finds
+/// out otherwise (see `Handle::response_size`).
+pub const PACKET_SIZE: usize = 1024;
+
+/// Size of the probe's capture buffer, which is what a flush response
+/// delivers.
+pub const CAPTURE_BUFFER_SIZE: usize = 1022;
+
+/// Sets the UART bit rate: `[SET_BIT_RATE, u32 rate]`. The response echoes
+/// the command and gives the achieved rate.
+pub const SET_BIT_RATE: u8 = 0x01;
+/// Asks for new data in the capture buffer: `[POLL]`. The response is an
+/// incremental or flush response, below.
+pub const POLL: u8 = 0x02;
+/// Sets up the UART: `[INIT_UART]`. The response echoes the command, then has
+/// four bytes we don't understand (zero in practice), then the maximum bit
+/// rate as a u32.
+pub const INIT_UART: u8 = 0x03;
+/// Starts a session: `[OHAI, mode]`. The response echoes the command and then
+/// `OHAI_ACK`.
+pub const OHAI: u8 = 0x1f;
+
+/// `OHAI` mode requesting UART SWO capture.
+pub const MODE_UART: u8 = 0xFF;
+/// `OHAI` mode that we guess requests Manchester SWO capture. This is the
+/// value CMSIS-DAP uses for Manchester in `DAP_SWO_Mode`; we've never seen
+/// the vendor tools send it.
+pub const MODE_MANCHESTER: u8 = 0x02;
+/// Second byte of the response to `OHAI`. We don't know what it means, but
+/// it's always this.
+pub const OHAI_ACK: u8 = 0x38;
+
+/// Poll response kind carrying data that's appeared in the capture buffer
+/// since the last poll: `[RESPONSE_INCREMENTAL, epoch, packed fill levels (3
+/// bytes), data]`.
+pub const RESPONSE_INCREMENTAL: u8 = 0x04;
+/// Poll response kind carrying the whole capture buffer once it's filled:
+/// `[RESPONSE_FLUSH, epoch, data (CAPTURE_BUFFER_SIZE bytes)]`.
+pub const RESPONSE_FLUSH: u8 = 0x82;
+/// Length of the header of an incremental response.
+const INCREMENTAL_HEADER: usize = 5;
+/// Length of the header of a flush response.
+const FLUSH_HEADER: usize = 2;
+
+/// Fill levels are packed as two 12-bit numbers.
+const FILL_BITS: u32 = 12;
+const FILL_MASK: u32 = (1 << FILL_BITS) - 1;
+
+/// Builds an `OHAI` command.
+pub fn encode_ohai(mode: u8) -> [u8; 2] {
+    [OHAI, mode]
+}
+
+/// Checks the response to `OHAI`.
+pub fn decode_ohai(response: &[u8]) -> Result<(), Error> {
+    check_cmd(response, OHAI)?;
+    if response.get(1) != Some(&OHAI_ACK) {
+        return Err(Error::Protocol {
+            command: OHAI,
+            reason: "unexpected ohai response",
+        });
+    }
+    Ok(())
+}
+
+/// Builds an `INIT_UART` command.
+pub fn encode_init_uart() -> [u8; 1] {
+    [INIT_UART]
+}
+
+/// Returns the maximum bit rate from the response to `INIT_UART`.
+pub fn decode_init_uart(response: &[u8]) -> Result<u32, Error> {
+    check_cmd(response, INIT_UART)?;
+    read_u32(response, 5, INIT_UART)
+}
+
+/// Builds a `SET_BIT_RATE` command.
+pub fn encode_set_bit_rate(rate: u32) -> [u8; 5] {
+    let mut req = [SET_BIT_RATE, 0, 0, 0, 0];
+    req[1..].copy_from_slice(&rate.to_le_bytes());
+    req
+}
+
+/// Returns the achieved bit rate from the response to `SET_BIT_RATE`.
+pub fn decode_set_bit_rate(response: &[u8]) -> Result<u32, Error> {
+    check_cmd(response, SET_BIT_RATE)?;
+    read_u32(response, 1, SET_BIT_RATE)
+}
+
+/// Builds a `POLL` command.
+pub fn encode_poll() -> [u8; 1] {
+    [POLL]
+}
+
+/// Packs the fill levels before and after an incremental response's data.
+/// Only the low 12 bits of each are kept.
+pub fn pack_fill_levels(start: u16, end: u16) -> [u8; 3] {
+    let packed = u32::from(start) & FILL_MASK
+        | (u32::from(end) & FILL_MASK) << FILL_BITS;
+    let bytes = packed.to_le_bytes();
+    [bytes[0], bytes[1], bytes[2]]
+}
+
+/// Unpacks the fill levels from an incremental response, as `(start, end)`.
+/// Returns `None` if there's no new data, which the probe signals with all
+/// zeros.
+pub fn unpack_fill_levels(packed: [u8; 3]) -> Option<(u16, u16)> {
+    let packed = u32::from_le_bytes([packed[0], packed[1], packed[2], 0]);
+    if packed == 0 {
+        return None;
+    }
+    Some((
+        (packed & FILL_MASK) as u16,
+        (packed >> FILL_BITS & FILL_MASK) as u16,
+    ))
+}
+
+pub enum PollResult<'a> {
+    /// No new traffic in buffer since last poll.
+    Empty,
+    /// New traffic has appeared in the buffer at offset `start` (inclusive)
+    /// through `end` (exclusive).
+    Incremental {
+        start: u16,
+        end: u16,
+        fragment: &'a mut [u8],
+    },
+    /// The buffer has filled up; here's the whole thing. If you've been polling
+    /// regularly, this will repeat data received in previous `Incremental`
+    /// messages.
+    Total(&'a mut [u8]),
+}
+
+/// Interprets a response to the poll command, given exactly the bytes
+/// received. Anything inconsistent with the number of bytes received is
+/// reported as `Error::Malformed`, rather than trusted.
+///
+/// Returns a tuple of `(epoch, poll_result)`.
+pub fn parse_poll(response: &mut [u8]) -> Result<(u8, PollResult<'_>), Error> {
+    let malformed = |response: &[u8], reason| Error::Malformed {
+        command: POLL,
+        reason,
+        report: response.to_vec(),
+    };
+
+    let (kind, epoch) = match response {
+        [kind, epoch, ..] => (*kind, *epoch),
+        _ => return Err(malformed(response, "response too short")),
+    };
+
+    match kind {
+        RESPONSE_INCREMENTAL => {
+            let packed = match response.get(2..INCREMENTAL_HEADER) {
+                Some(&[a, b, c]) => [a, b, c],
+                _ => return Err(malformed(response, "response too short")),
+            };
+            let (start, end) = match unpack_fill_levels(packed) {
+                Some(levels) => levels,
+                None => return Ok((epoch, PollResult::Empty)),
+            };
+
+            if end < start || usize::from(end) > CAPTURE_BUFFER_SIZE {
+                return Err(malformed(response, "invalid fill levels"));
+            }
+            let data = INCREMENTAL_HEADER
+                ..INCREMENTAL_HEADER + usize::from(end - start);
+            if response.len() < data.end {
+                return Err(malformed(
+                    response,
+                    "data shorter than fill levels",
+                ));
+            }
+            Ok((
+                epoch,
+                PollResult::Incremental {
+                    start,
+                    end,
+                    fragment: &mut response[data],
+                },
+            ))
+        }
+        RESPONSE_FLUSH => {
+            let data = FLUSH_HEADER..FLUSH_HEADER + CAPTURE_BUFFER_SIZE;
+            if response.len() < data.end {
+                return Err(malformed(response, "flush response too short"));
+            }
+            Ok((epoch, PollResult::Total(&mut response[data])))
+        }
+        _ => Err(malformed(response, "unexpected poll response")),
+    }
+}
+
+fn check_cmd(response: &[u8], expected: u8) -> Result<(), Error> {
+    if response.first() != Some(&expected) {
+        Err(Error::Protocol {
+            command: expected,
+            reason: "response is for a different command",
+        })
+    } else {
+        Ok(())
+    }
+}
+
+fn read_u32(response: &[u8], offset: usize, command: u8) -> Result<u32, Error> {
+    response
+        .get(offset..offset + 4)
+        .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+        .ok_or(Error::Protocol {
+            command,
+            reason: "response too short",
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds an incremental response carrying `data` between fill levels
+    /// `start` and `end`.
+    fn incremental(start: u16, end: u16, data: &[u8]) -> Vec<u8> {
+        let mut response = vec![RESPONSE_INCREMENTAL, 7];
+        response.extend_from_slice(&pack_fill_levels(start, end));
+        response.extend_from_slice(data);
+        response
+    }
+
+    /// Checks that `response` is rejected for `reason`, with the whole
+    /// response in the report.
+    fn assert_malformed(mut response: Vec<u8>, reason: &str) {
+        let expected = response.clone();
+        match parse_poll(&mut response) {
+            Err(Error::Malformed {
+                command,
+                reason: r,
+                report,
+            }) => {
+                assert_eq!(command, POLL);
+                assert_eq!(r, reason);
+                assert_eq!(report, expected);
+            }
+            Err(e) => panic!("expected {:?}, got {}", reason, e),
+            Ok(_) => panic!("expected {:?}, got a result", reason),
+        }
+    }
+
+    #[test]
+    fn ohai_round_trip() {
+        for &mode in &[MODE_UART, MODE_MANCHESTER] {
+            let mut response = encode_ohai(mode);
+            assert_eq!(response, [OHAI, mode]);
+            // The probe echoes the command, with its ack in place of the mode.
+            response[1] = OHAI_ACK;
+            decode_ohai(&response).unwrap();
+        }
+        assert!(decode_ohai(&encode_ohai(MODE_UART)).is_err());
+        assert!(decode_ohai(&[OHAI]).is_err());
+    }
+
+    #[test]
+    fn init_uart_round_trip() {
+        for &max in &[0, 1, 6_000_000, u32::MAX] {
+            let mut response = encode_init_uart().to_vec();
+            assert_eq!(response, [INIT_UART]);
+            response.extend_from_slice(&[0; 4]);
+            response.extend_from_slice(&max.to_le_bytes());
+            assert_eq!(decode_init_uart(&response).unwrap(), max);
+            assert!(decode_init_uart(&response[..8]).is_err());
+        }
+    }
+
+    #[test]
+    fn set_bit_rate_round_trip() {
+        for &rate in &[0, 1, 115_200, 0x1234_5678, u32::MAX] {
+            let command = encode_set_bit_rate(rate);
+            assert_eq!(command[0], SET_BIT_RATE);
+            // The response has the same layout as the command.
+            assert_eq!(decode_set_bit_rate(&command).unwrap(), rate);
+            assert!(decode_set_bit_rate(&command[..4]).is_err());
+        }
+    }
+
+    #[test]
+    fn decode_wrong_command() {
+        assert!(decode_ohai(&[INIT_UART, OHAI_ACK]).is_err());
+        assert!(decode_init_uart(&encode_set_bit_rate(0)).is_err());
+        assert!(decode_set_bit_rate(&[POLL, 0, 0, 0, 0]).is_err());
+        assert_eq!(encode_poll(), [POLL]);
+    }
+
+    #[test]
+    fn fill_levels_round_trip() {
+        let cases = [
+            (0, 1),
+            (1, 0),
+            (0, 0xFFF),
+            (0xFFF, 0),
+            (0xFFF, 0xFFF),
+            (0x123, 0x456),
+            (0xABC, 0x3FE),
+        ];
+        for &(start, end) in &cases {
+            let packed = pack_fill_levels(start, end);
+            assert_eq!(unpack_fill_levels(packed), Some((start, end)));
+        }
+        // All zeros means there's nothing new.
+        assert_eq!(pack_fill_levels(0, 0), [0; 3]);
+        assert_eq!(unpack_fill_levels([0; 3]), None);
+        // Only 12 bits of each are kept.
+        assert_eq!(
+            unpack_fill_levels(pack_fill_levels(0x1FFF, 0xF001)),
+            Some((0xFFF, 1))
+        );
+    }
+
+    #[test]
+    fn fill_levels_layout() {
+        // start in the low 12 bits, end in the next 12, little-endian.
+        assert_eq!(pack_fill_levels(0xFFF, 0), [0xFF, 0x0F, 0x00]);
+        assert_eq!(pack_fill_levels(0, 0xFFF), [0x00, 0xF0, 0xFF]);
+        assert_eq!(pack_fill_levels(0x123, 0x456), [0x23, 0x61, 0x45]);
+    }
+
+    #[test]
+    fn poll_empty() {
+        let mut response = incremental(0, 0, &[]);
+        match parse_poll(&mut response) {
+            Ok((7, PollResult::Empty)) => (),
+            _ => panic!("expected an empty result"),
+        }
+    }
+
+    #[test]
+    fn poll_incremental() {
+        let mut response = incremental(10, 13, b"abc");
+        // Anything past the fill levels is ignored.
+        response.extend_from_slice(&[0; 16]);
+        match parse_poll(&mut response) {
+            Ok((
+                7,
+                PollResult::Incremental {
+                    start,
+                    end,
+                    fragment,
+                },
+            )) => {
+                assert_eq!((start, end), (10, 13));
+                assert_eq!(fragment, b"abc");
+            }
+            _ => panic!("expected an incremental result"),
+        }
+    }
+
+    #[test]
+    fn poll_flush() {
+        let mut response = vec![RESPONSE_FLUSH, 3];
+        response.extend((0..CAPTURE_BUFFER_SIZE).map(|i| i as u8));
+        match parse_poll(&mut response) {
+            Ok((3, PollResult::Total(data))) => {
+                assert_eq!(data.len(), CAPTURE_BUFFER_SIZE);
+                assert_eq!(data[CAPTURE_BUFFER_SIZE - 1], 0xFD);
+            }
+            _ => panic!("expected a flush result"),
+        }
+    }
+
+    #[test]
+    fn poll_short_reads() {
+        assert_malformed(vec![], "response too short");
+        assert_malformed(vec![RESPONSE_INCREMENTAL], "response too short");
+        assert_malformed(
+            vec![RESPONSE_INCREMENTAL, 7, 1],
+            "response too short",
+        );
+        let mut flush = vec![RESPONSE_FLUSH, 3];
+        flush.resize(FLUSH_HEADER + CAPTURE_BUFFER_SIZE - 1, 0);
+        assert_malformed(flush, "flush response too short");
+    }
+
+    #[test]
+    fn poll_fill_levels_past_data() {
+        assert_malformed(
+            incremental(0, 4, b"abc"),
+            "data shorter than fill levels",
+        );
+        assert_malformed(
+            incremental(0, CAPTURE_BUFFER_SIZE as u16, &[]),
+            "data shorter than fill levels",
+        );
+    }
+
+    #[test]
+    fn poll_invalid_fill_levels() {
+        assert_malformed(incremental(5, 4, b"abc"), "invalid fill levels");
+        assert_malformed(
+            incremental(0, CAPTURE_BUFFER_SIZE as u16 + 1, &[0; 1100]),
+            "invalid fill levels",
+        );
+    }
+
+    #[test]
+    fn poll_bad_response_code() {
+        for &kind in &[0x00, POLL, RESPONSE_FLUSH + 1, 0xFF] {
+            assert_malformed(
+                vec![kind, 0, 0, 0, 0],
+                "unexpected poll response",
+            );
+        }
+    }
+}
@@ -0,0 +1,187 @@
+//! Python bindings for `embed::Capture`, as the `lpc_cat` extension module
+//! (build it with `maturin build --features python`).
+//!
+//! ```python
+//! import lpc_cat
+//!
+//! with lpc_cat.Capture.open(serial="...") as capture:
+//!     capture.set_bit_rate(2_000_000)
+//!     capture.start(queue_depth=8192)
+//!     while (data := capture.read(timeout=1.0)) is not None:
+//!         ...
+//!     print(capture.stats())
+//! ```
+//!
+//! Failures raise `lpc_cat.LpcCatError`, whose arguments are one of the
+//! `ffi::LPC_CAT_ERR_*` codes and a description. The GIL is released while
+//! waiting on the probe or for data.
+
+use std::time::Duration;
+
+use pyo3::create_exception;
+use pyo3::exceptions::PyException;
+use pyo3::prelude::*;
+use pyo3::types::{PyBytes, PyDict};
+
+use crate::capture::Stats;
+use crate::embed::Capture;
+use crate::select::Selector;
+use crate::{ffi, synthetic, Error};
+
+create_exception!(lpc_cat, LpcCatError, PyException);
+
+fn to_py(e: Error) -> PyErr {
+    LpcCatError::new_err((ffi::code(&e), e.to_string()))
+}
+
+fn invalid(why: &str) -> PyErr {
+    LpcCatError::new_err((ffi::LPC_CAT_ERR_INVALID, why.to_string()))
+}
+
+fn stats_dict(py: Python<'_>, s: Stats) -> PyResult<&PyDict> {
+    let d = PyDict::new(py);
+    d.set_item("bytes", s.bytes)?;
+    d.set_item("probe_gaps", s.probe_gaps)?;
+    d.set_item("overflows", s.overflows)?;
+    d.set_item("host_drops", s.host_drops)?;
+    d.set_item("host_dropped_bytes", s.host_dropped_bytes)?;
+    d.set_item("queue_high_water", s.queue_high_water)?;
+    d.set_item("reconnects", s.reconnects)?;
+    d.set_item("flushes", s.flushes)?;
+    d.set_item("gaps", s.gaps)?;
+    d.set_item("duration", s.duration.as_secs_f64())?;
+    Ok(d)
+}
+
+/// A capture; see `embed::Capture`.
+#[pyclass(name = "Capture")]
+struct PyCapture(Capture);
+
+#[pymethods]
+impl PyCapture {
+    /// Opens a probe, by USB IDs and serial number if given, or else the
+    /// first running firmware with a trace interface.
+    #[staticmethod]
+    #[pyo3(signature = (vid = None, pid = None, serial = None))]
+    fn open(
+        py: Python<'_>,
+        vid: Option<u16>,
+        pid: Option<u16>,
+        serial: Option<String>,
+    ) -> PyResult<Self> {
+        let selector = Selector {
+            serial,
+            ..Selector::default()
+        };
+        py.allow_threads(|| match (vid, pid) {
+            (None, None) => Capture::find(&selector),
+            (vid, pid) => Capture::open(
+                vid.unwrap_or(crate::firmware::NXP_VID),
+                pid.unwrap_or(0x0090),
+                &selector,
+            ),
+        })
+        .map(PyCapture)
+        .map_err(to_py)
+    }
+
+    /// Makes up data instead of capturing it, for testing without hardware.
+    #[staticmethod]
+    #[pyo3(signature = (pattern = "counter", rate = 1_000_000))]
+    fn synthetic(pattern: &str, rate: u64) -> PyResult<Self> {
+        if rate == 0 {
+            return Err(invalid("synthetic rate must be nonzero"));
+        }
+        let pattern = pattern.parse().map_err(|e: String| invalid(&e))?;
+        Ok(PyCapture(Capture::synthetic(synthetic::Config {
+            pattern,
+            rate,
+        })))
+    }
+
+    /// Sets the bit rate, before `start`, and returns the rate the probe
+    /// agreed to.
+    #[pyo3(signature = (rate, approximate = false))]
+    fn set_bit_rate(
+        &mut self,
+        py: Python<'_>,
+        rate: u32,
+        approximate: bool,
+    ) -> PyResult<u32> {
+        let capture = &mut self.0;
+        py.allow_threads(|| capture.set_bit_rate(rate, approximate))
+            .map_err(to_py)
+    }
+
+    /// Starts capture in the background, queueing up to `queue_depth`
+    /// chunks for `read` before dropping data.
+    #[pyo3(signature = (queue_depth = 4096))]
+    fn start(&mut self, queue_depth: usize) -> PyResult<()> {
+        if queue_depth == 0 {
+            return Err(invalid("queue depth must be nonzero"));
+        }
+        self.0.start(queue_depth).map_err(to_py)
+    }
+
+    /// Reads up to `size` bytes, waiting up to `timeout` seconds (or
+    /// forever, if `None`). Returns `b""` if nothing arrived in time, and
+    /// `None` once capture is over and everything has been read.
+    #[pyo3(signature = (size = 65536, timeout = None))]
+    fn read<'py>(
+        &mut self,
+        py: Python<'py>,
+        size: usize,
+        timeout: Option<f64>,
+    ) -> PyResult<Option<&'py PyBytes>> {
+        let timeout = timeout
+            .map(Duration::try_from_secs_f64)
+            .transpose()
+            .map_err(|_| invalid("timeout must be a nonnegative number"))?;
+        let capture = &mut self.0;
+        let mut buffer = vec![0; size];
+        let n = py
+            .allow_threads(|| capture.read(&mut buffer, timeout))
+            .map_err(to_py)?;
+        Ok(n.map(|n| PyBytes::new(py, &buffer[..n])))
+    }
+
+    /// Returns statistics about the capture so far, as a dict.
+    fn stats<'py>(&self, py: Python<'py>) -> PyResult<&'py PyDict> {
+        stats_dict(py, self.0.stats())
+    }
+
+    /// Stops capture, and returns the final statistics. Raises the error
+    /// that stopped capture early, if one did.
+    fn stop<'py>(&mut self, py: Python<'py>) -> PyResult<&'py PyDict> {
+        let capture = &mut self.0;
+        let stats = py.allow_threads(|| capture.stop()).map_err(to_py)?;
+        stats_dict(py, stats)
+    }
+
+    fn __enter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn __exit__(
+        &mut self,
+        py: Python<'_>,
+        exception: &PyAny,
+        _value: &PyAny,
+        _traceback: &PyAny,
+    ) -> PyResult<bool> {
+        let capture = &mut self.0;
+        let result = py.allow_threads(|| capture.stop());
+        // An exception on the way out is more interesting than ours.
+        if exception.is_none() {
+            result.map_err(to_py)?;
+        }
+        Ok(false)
+    }
+}
+
+#[pymodule]
+fn lpc_cat(py: Python<'_>, m: &PyModule) -> PyResult<()> {
+    m.add_class::<PyCapture>()?;
+    m.add("LpcCatError", py.get_type::<LpcCatError>())?;
+    Ok(())
+}
@@ -0,0 +1,109 @@
+//! Keeping the thread that polls the probe from being held up by everything
+//! else the machine is doing.
+//!
+//! The probe's buffer only holds a few milliseconds of trace at high bit
+//! rates, so a poll that's late because the scheduler ran something else
+//! first can be enough to lose data. Running the polling thread at a
+//! real-time priority, and keeping it on one core (ideally one that nothing
+//! else is pinned to), makes that much less likely on a loaded machine.
+//! What's possible depends on the platform, and usually on privileges: on
+//! Linux, real-time priorities need `CAP_SYS_NICE` or an `RLIMIT_RTPRIO`
+//! allowance, and only Linux lets us pin threads.
+
+use std::io;
+
+use crate::Error;
+
+/// How to schedule a thread.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Scheduling {
+    /// Real-time (`SCHED_FIFO`) priority to run at; on Linux, from 1 to 99.
+    /// If `None`, the thread keeps the usual time-sharing policy.
+    pub priority: Option<i32>,
+    /// CPU core to keep the thread on, numbered from 0.
+    pub core: Option<usize>,
+}
+
+impl Scheduling {
+    /// Applies these settings to the calling thread.
+    pub fn apply(&self) -> Result<(), Error> {
+        if let Some(priority) = self.priority {
+            set_priority(priority)?;
+        }
+        if let Some(core) = self.core {
+            pin(core)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+fn set_priority(priority: i32) -> Result<(), Error> {
+    // Safety: these only look up limits for the policy they're given.
+    let (min, max) = unsafe {
+        (
+            libc::sched_get_priority_min(libc::SCHED_FIFO),
+            libc::sched_get_priority_max(libc::SCHED_FIFO),
+        )
+    };
+    if priority < min || priority > max {
+        return Err(Error::Io(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("real-time priority must be from {} to {}", min, max),
+        )));
+    }
+    // `sched_param` has other fields on some platforms.
+    // Safety: it's a plain C struct, for which all zeros is a valid value.
+    let mut param: libc::sched_param = unsafe { std::mem::zeroed() };
+    param.sched_priority = priority;
+    // Safety: pthread_self is always a valid thread to pass, and
+    // pthread_setschedparam only reads the sched_param we pass it.
+    let r = unsafe {
+        libc::pthread_setschedparam(
+            libc::pthread_self(),
+            libc::SCHED_FIFO,
+            &param,
+        )
+    };
+    if r != 0 {
+        return Err(Error::Io(io::Error::from_raw_os_error(r)));
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn set_priority(_priority: i32) -> Result<(), Error> {
+    Err(Error::Unsupported("real-time priorities on this platform"))
+}
+
+#[cfg(target_os = "linux")]
+fn pin(core: usize) -> Result<(), Error> {
+    if core >= libc::CPU_SETSIZE as usize {
+        return Err(Error::Io(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("there's no core {}", core),
+        )));
+    }
+    // Safety: cpu_set_t is a plain C bitmask, for which all zeros is the
+    // empty set.
+    let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+    // Safety: `core` was checked to be within the set above.
+    unsafe { libc::CPU_SET(core, &mut set) };
+    // Thread ID 0 means the calling thread.
+    // Safety: sched_setaffinity only reads the set we pass it, and is told
+    // its size.
+    let r = unsafe {
+        libc::sched_setaffinity(0, std::mem::size_of_val(&set), &set)
+    };
+    if r != 0 {
+        return Err(Error::Io(io::Error::last_os_error()));
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn pin(_core: usize) -> Result<(), Error> {
+    Err(Error::Unsupported(
+        "pinning threads to a core on this platform",
+    ))
+}
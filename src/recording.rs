@@ -0,0 +1,871 @@
+//! Recordings of captures, which keep enough detail about how the data
+//! arrived to replay it later as if it were coming from the probe.
+//!
+//! A recording starts with the 8-byte magic `LPC-SWO` followed by a version
+//! byte (currently 1), and then holds a series of records (all integers
+//! little endian):
+//!
+//! ```text
+//! byte[0:3]   = u32 length of the rest of the record
+//! byte[4]     = kind: 0 for data, 1 for a gap (the probe or host lost data),
+//!               2 for information about the program that made the recording,
+//!               3 for the bit rate being captured, 4 for the probe's
+//!               buffer fill levels, 5 for decoded output, 6 for the wall
+//!               clock time, 7 for the session that made the recording
+//! byte[5:12]  = u64 microseconds since the recording started
+//! ```
+//!
+//! Data records continue with:
+//!
+//! ```text
+//! byte[13]    = capture epoch
+//! byte[14:15] = u16 offset in the probe's buffer where the data started
+//! byte[16:17] = u16 offset in the probe's buffer where the data ended
+//! byte[18+]   = data
+//! ```
+//!
+//! Gap records continue, if the cause of the gap is known, with a byte
+//! saying what it was: 0 for the probe's buffer overflowing, 1 for losing
+//! sync with the probe, 2 for the probe being disconnected, and 3 for the
+//! host not keeping up (see [`capture::GapCause`](crate::capture::GapCause)).
+//! Readers treat a gap record without one, or with one they don't
+//! recognize, as a gap of unknown cause.
+//!
+//! Information records continue with a UTF-8 description of the program, as
+//! given by [`version::Info::summary`](crate::version::Info::summary). Writers
+//! put one at the start of each recording.
+//!
+//! Bit rate records continue with the u32 bit rate. They're written when
+//! capture starts, and if the rate changes (say, because the probe was
+//! reconnected and agreed to a different rate).
+//!
+//! Fill level records continue with the capture epoch and the u16 start and
+//! end fill levels from a poll response, laid out like the header of a data
+//! record (see [`capture::FillLevels`](crate::capture::FillLevels)). They're
+//! only written if capture was asked to report them.
+//!
+//! Wall clock records continue with the u64 number of microseconds since
+//! the Unix epoch at the time of the record, so that the times of other
+//! records can be turned into wall clock time. Writers put one at the start
+//! of each recording, and another if they're told the time has changed
+//! (say, because what they're recording is itself a replay).
+//!
+//! Session records continue with the 16-byte ID of the run of the program
+//! that made the recording (see [`session`](crate::session)), to match it up
+//! with that run's logs and status. Writers put one at the start of each
+//! recording.
+//!
+//! Decoded output records hold what was shown while the recording was made
+//! (say, defmt log lines or per-port text), so that it can be seen again
+//! without whatever was needed to decode it. They continue with:
+//!
+//! ```text
+//! byte[13:20] = u64 offset in the data where the bytes that produced this
+//!               output started, counting the data in all data records
+//!               from the start of the recording
+//! byte[21:28] = u64 offset where they ended
+//! byte[29+]   = the output, as it was written
+//! ```
+//!
+//! Decoders hold on to partial messages, so output may well have been
+//! produced by data from earlier records than its range covers: the range
+//! is the data whose arrival produced the output, not all of the data the
+//! output came from. Output that didn't follow data (say, a note about a
+//! gap) has an empty range. See [`Annotated`].
+//!
+//! Readers skip records of kinds they don't understand.
+
+use std::convert::{TryFrom, TryInto};
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Mutex};
+use std::thread::sleep;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::capture::{FillLevels, Fragment, Gap, GapCause, Sink};
+use crate::session;
+use crate::timestamp::Clock;
+use crate::version;
+
+pub const MAGIC: [u8; 8] = *b"LPC-SWO\x01";
+
+const KIND_DATA: u8 = 0;
+const KIND_GAP: u8 = 1;
+const KIND_INFO: u8 = 2;
+const KIND_BIT_RATE: u8 = 3;
+const KIND_FILL_LEVELS: u8 = 4;
+const KIND_DECODED: u8 = 5;
+const KIND_WALL_CLOCK: u8 = 6;
+const KIND_SESSION: u8 = 7;
+const CAUSE_OVERFLOW: u8 = 0;
+const CAUSE_SYNC_LOST: u8 = 1;
+const CAUSE_DISCONNECTED: u8 = 2;
+const CAUSE_HOST_DROP: u8 = 3;
+/// Length of the fields common to all records, after the length itself.
+const COMMON_LEN: usize = 9;
+/// Length of the fields data records add before the data.
+const DATA_HEADER_LEN: usize = 5;
+/// Length of the fields decoded output records add before the output.
+const DECODED_HEADER_LEN: usize = 16;
+
+/// Sink that writes a recording.
+pub struct Writer<W: Write> {
+    inner: W,
+    started: Instant,
+    /// The wall clock time last recorded.
+    clock: Clock,
+    /// Bytes of data recorded so far.
+    offset: u64,
+}
+
+impl<W: Write> Writer<W> {
+    /// Starts a recording, writing the header to `inner`.
+    pub fn new(mut inner: W) -> io::Result<Self> {
+        inner.write_all(&MAGIC)?;
+        let started = Instant::now();
+        let clock = Clock::at(started, SystemTime::now());
+        let mut writer = Self {
+            inner,
+            started,
+            clock,
+            offset: 0,
+        };
+        let producer = version::info().summary();
+        writer.record(KIND_INFO, started, &[], producer.as_bytes())?;
+        writer.record(KIND_SESSION, started, &[], &session::id().0)?;
+        writer.wall_clock(started)?;
+        Ok(writer)
+    }
+
+    /// Records the wall clock time at `when`.
+    fn wall_clock(&mut self, when: Instant) -> io::Result<()> {
+        let wall = micros_since_epoch(self.clock.wall(when));
+        self.record(KIND_WALL_CLOCK, when, &[], &wall.to_le_bytes())
+    }
+
+    fn record(
+        &mut self,
+        kind: u8,
+        when: Instant,
+        header: &[u8],
+        data: &[u8],
+    ) -> io::Result<()> {
+        let len = u32::try_from(COMMON_LEN + header.len() + data.len())
+            .map_err(|_| invalid("record too long"))?;
+        let time = when.saturating_duration_since(self.started).as_micros();
+        self.inner.write_all(&len.to_le_bytes())?;
+        self.inner.write_all(&[kind])?;
+        self.inner.write_all(&(time as u64).to_le_bytes())?;
+        self.inner.write_all(header)?;
+        self.inner.write_all(data)
+    }
+
+    /// Records output decoded from the data, produced by the arrival of the
+    /// data from `start` to `end` (see the module documentation).
+    pub fn decoded(
+        &mut self,
+        start: u64,
+        end: u64,
+        output: &[u8],
+    ) -> io::Result<()> {
+        let mut header = [0; DECODED_HEADER_LEN];
+        header[..8].copy_from_slice(&start.to_le_bytes());
+        header[8..].copy_from_slice(&end.to_le_bytes());
+        self.record(KIND_DECODED, Instant::now(), &header, output)
+    }
+
+    /// Returns the number of bytes of data recorded so far.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Gets a reference to the underlying writer.
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    /// Gives back the underlying writer.
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W: Write> Sink for Writer<W> {
+    fn data(&mut self, bytes: &[u8]) -> io::Result<()> {
+        let info = Fragment {
+            received: Instant::now(),
+            epoch: 0,
+            start: 0,
+            end: 0,
+        };
+        self.fragment(&info, bytes)
+    }
+
+    fn fragment(&mut self, info: &Fragment, bytes: &[u8]) -> io::Result<()> {
+        let header = data_header(info.epoch, info.start, info.end);
+        self.record(KIND_DATA, info.received, &header, bytes)?;
+        self.offset += bytes.len() as u64;
+        Ok(())
+    }
+
+    fn gap(&mut self) -> io::Result<()> {
+        self.record(KIND_GAP, Instant::now(), &[], &[])
+    }
+
+    fn lost(&mut self, gap: &Gap) -> io::Result<()> {
+        let cause = match gap.cause {
+            GapCause::Overflow => CAUSE_OVERFLOW,
+            GapCause::SyncLost => CAUSE_SYNC_LOST,
+            GapCause::Disconnected => CAUSE_DISCONNECTED,
+            GapCause::HostDrop => CAUSE_HOST_DROP,
+        };
+        self.record(KIND_GAP, gap.noticed, &[], &[cause])
+    }
+
+    fn bit_rate(&mut self, rate: u32) -> io::Result<()> {
+        self.record(KIND_BIT_RATE, Instant::now(), &[], &rate.to_le_bytes())
+    }
+
+    fn fill_levels(&mut self, levels: &FillLevels) -> io::Result<()> {
+        let header = data_header(levels.epoch, levels.start, levels.end);
+        self.record(KIND_FILL_LEVELS, levels.received, &header, &[])
+    }
+
+    fn clock(&mut self, clock: Clock) -> io::Result<()> {
+        // Replays reset the clock all the time, to allow for their pacing;
+        // only bother recording real changes.
+        let now = Instant::now();
+        let (old, new) = (self.clock.wall(now), clock.wall(now));
+        let drift =
+            old.duration_since(new).or_else(|_| new.duration_since(old));
+        self.clock = clock;
+        if drift.is_ok_and(|d| d < Duration::from_millis(1)) {
+            return Ok(());
+        }
+        self.wall_clock(now)
+    }
+}
+
+/// Sink that records what it's given, like `Writer`, and passes it on to
+/// `inner`, also recording what `inner` writes through a `Transcript` as
+/// decoded output.
+pub struct Annotated<W: Write> {
+    writer: Writer<W>,
+    inner: Box<dyn Sink>,
+    transcript: Transcript,
+}
+
+impl<W: Write> Annotated<W> {
+    /// Records into `writer` what's given to `inner`, and what it writes
+    /// through (writers made by) `transcript`.
+    pub fn new(
+        writer: Writer<W>,
+        inner: Box<dyn Sink>,
+        transcript: Transcript,
+    ) -> Self {
+        Self {
+            writer,
+            inner,
+            transcript,
+        }
+    }
+
+    /// Records whatever `inner` has written since last time.
+    fn annotate(&mut self, start: u64) -> io::Result<()> {
+        let output = self.transcript.take();
+        if output.is_empty() {
+            return Ok(());
+        }
+        let end = self.writer.offset();
+        self.writer.decoded(start, end, &output)
+    }
+
+    /// Finishes off the recording, with anything `inner` writes as it's
+    /// dropped.
+    fn finish(&mut self) -> io::Result<()> {
+        drop(std::mem::replace(&mut self.inner, Box::new(io::sink())));
+        self.annotate(self.writer.offset())?;
+        self.writer.inner.flush()
+    }
+}
+
+impl<W: Write> Drop for Annotated<W> {
+    fn drop(&mut self) {
+        if let Err(e) = self.finish() {
+            log::warn!("can't finish recording: {}", e);
+        }
+    }
+}
+
+impl<W: Write> Sink for Annotated<W> {
+    fn data(&mut self, bytes: &[u8]) -> io::Result<()> {
+        let start = self.writer.offset();
+        self.writer.data(bytes)?;
+        self.inner.data(bytes)?;
+        self.annotate(start)
+    }
+
+    fn fragment(&mut self, info: &Fragment, bytes: &[u8]) -> io::Result<()> {
+        let start = self.writer.offset();
+        self.writer.fragment(info, bytes)?;
+        self.inner.fragment(info, bytes)?;
+        self.annotate(start)
+    }
+
+    fn gap(&mut self) -> io::Result<()> {
+        self.writer.gap()?;
+        self.inner.gap()?;
+        self.annotate(self.writer.offset())
+    }
+
+    fn lost(&mut self, gap: &Gap) -> io::Result<()> {
+        self.writer.lost(gap)?;
+        self.inner.lost(gap)?;
+        self.annotate(self.writer.offset())
+    }
+
+    fn bit_rate(&mut self, rate: u32) -> io::Result<()> {
+        self.writer.bit_rate(rate)?;
+        self.inner.bit_rate(rate)?;
+        self.annotate(self.writer.offset())
+    }
+
+    fn fill_levels(&mut self, levels: &FillLevels) -> io::Result<()> {
+        self.writer.fill_levels(levels)?;
+        self.inner.fill_levels(levels)?;
+        self.annotate(self.writer.offset())
+    }
+
+    fn clock(&mut self, clock: Clock) -> io::Result<()> {
+        self.writer.clock(clock)?;
+        self.inner.clock(clock)?;
+        self.annotate(self.writer.offset())
+    }
+}
+
+/// Collects what's written through the writers it makes, for `Annotated`.
+#[derive(Clone, Default)]
+pub struct Transcript(Arc<Mutex<Vec<u8>>>);
+
+impl Transcript {
+    /// Wraps `out` so that what's written to it is also collected here.
+    pub fn tee<O: Write>(&self, out: O) -> TranscriptWriter<O> {
+        TranscriptWriter {
+            out,
+            transcript: self.clone(),
+        }
+    }
+
+    /// Takes what's been collected so far.
+    fn take(&self) -> Vec<u8> {
+        std::mem::take(&mut *self.0.lock().unwrap_or_else(|e| e.into_inner()))
+    }
+}
+
+/// Writer that also collects what's written in a `Transcript`.
+pub struct TranscriptWriter<O> {
+    out: O,
+    transcript: Transcript,
+}
+
+impl<O: Write> Write for TranscriptWriter<O> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.out.write(buf)?;
+        self.transcript
+            .0
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .extend_from_slice(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+fn data_header(epoch: u8, start: u16, end: u16) -> [u8; DATA_HEADER_LEN] {
+    let mut header = [0; DATA_HEADER_LEN];
+    header[0] = epoch;
+    header[1..3].copy_from_slice(&start.to_le_bytes());
+    header[3..5].copy_from_slice(&end.to_le_bytes());
+    header
+}
+
+/// Something that happened during a recording.
+#[derive(Clone, Debug)]
+pub struct Record {
+    /// How long after the recording started it happened.
+    pub time: Duration,
+    pub kind: RecordKind,
+}
+
+#[derive(Clone, Debug)]
+pub enum RecordKind {
+    /// Data arrived from the probe.
+    Data {
+        epoch: u8,
+        start: u16,
+        end: u16,
+        data: Vec<u8>,
+    },
+    /// Data was lost, for this reason if it's known.
+    Gap(Option<GapCause>),
+    /// Describes the program that made the recording.
+    Info { producer: String },
+    /// The session that made the recording.
+    Session(session::Id),
+    /// The probe was capturing at this bit rate from here on.
+    BitRate(u32),
+    /// It was this wall clock time.
+    WallClock(SystemTime),
+    /// The probe reported these buffer fill levels.
+    FillLevels { epoch: u8, start: u16, end: u16 },
+    /// This output was decoded from the data, on the arrival of the data
+    /// from offset `start` to `end`.
+    Decoded {
+        start: u64,
+        end: u64,
+        output: Vec<u8>,
+    },
+}
+
+/// Reads records from a recording.
+pub struct Reader<R> {
+    inner: R,
+}
+
+impl<R: Read> Reader<R> {
+    /// Checks the header of a recording, and prepares to read its records.
+    pub fn new(mut inner: R) -> io::Result<Self> {
+        let mut magic = [0; 8];
+        inner.read_exact(&mut magic)?;
+        if magic[..7] != MAGIC[..7] {
+            return Err(invalid("not an lpc-cat recording"));
+        }
+        if magic[7] != MAGIC[7] {
+            return Err(invalid("unsupported recording version"));
+        }
+        Ok(Self { inner })
+    }
+
+    /// Reads the next record. Returns `Ok(None)` at the end of the recording.
+    ///
+    /// A recording that ends partway through a record (say, because the
+    /// program writing it was killed) is treated as ending just before it.
+    pub fn next_record(&mut self) -> io::Result<Option<Record>> {
+        loop {
+            let mut len = [0; 4];
+            match self.inner.read_exact(&mut len[..1]) {
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                    return Ok(None)
+                }
+                r => r?,
+            }
+            let mut body = vec![];
+            let complete =
+                self.inner.read_exact(&mut len[1..]).and_then(|_| {
+                    let len = u32::from_le_bytes(len) as usize;
+                    if len < COMMON_LEN {
+                        return Err(invalid("record too short"));
+                    }
+                    body.resize(len, 0);
+                    self.inner.read_exact(&mut body)
+                });
+            match complete {
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                    log::warn!("recording ends with a partial record");
+                    return Ok(None);
+                }
+                r => r?,
+            }
+
+            let mut time = [0; 8];
+            time.copy_from_slice(&body[1..COMMON_LEN]);
+            let time = Duration::from_micros(u64::from_le_bytes(time));
+            let kind = match body[0] {
+                KIND_DATA => {
+                    let header = body
+                        .get(COMMON_LEN..COMMON_LEN + DATA_HEADER_LEN)
+                        .ok_or_else(|| invalid("data record too short"))?;
+                    RecordKind::Data {
+                        epoch: header[0],
+                        start: u16::from_le_bytes([header[1], header[2]]),
+                        end: u16::from_le_bytes([header[3], header[4]]),
+                        data: body.split_off(COMMON_LEN + DATA_HEADER_LEN),
+                    }
+                }
+                KIND_GAP => RecordKind::Gap(match body.get(COMMON_LEN) {
+                    Some(&CAUSE_OVERFLOW) => Some(GapCause::Overflow),
+                    Some(&CAUSE_SYNC_LOST) => Some(GapCause::SyncLost),
+                    Some(&CAUSE_DISCONNECTED) => Some(GapCause::Disconnected),
+                    Some(&CAUSE_HOST_DROP) => Some(GapCause::HostDrop),
+                    _ => None,
+                }),
+                KIND_FILL_LEVELS => {
+                    let header = body
+                        .get(COMMON_LEN..COMMON_LEN + DATA_HEADER_LEN)
+                        .ok_or_else(|| {
+                            invalid("fill level record too short")
+                        })?;
+                    RecordKind::FillLevels {
+                        epoch: header[0],
+                        start: u16::from_le_bytes([header[1], header[2]]),
+                        end: u16::from_le_bytes([header[3], header[4]]),
+                    }
+                }
+                KIND_BIT_RATE => {
+                    let rate = body
+                        .get(COMMON_LEN..COMMON_LEN + 4)
+                        .ok_or_else(|| invalid("bit rate record too short"))?;
+                    RecordKind::BitRate(u32::from_le_bytes(
+                        rate.try_into().unwrap(),
+                    ))
+                }
+                KIND_DECODED => {
+                    let header = body
+                        .get(COMMON_LEN..COMMON_LEN + DECODED_HEADER_LEN)
+                        .ok_or_else(|| {
+                            invalid("decoded output record too short")
+                        })?;
+                    RecordKind::Decoded {
+                        start: u64::from_le_bytes(
+                            header[..8].try_into().unwrap(),
+                        ),
+                        end: u64::from_le_bytes(
+                            header[8..].try_into().unwrap(),
+                        ),
+                        output: body.split_off(COMMON_LEN + DECODED_HEADER_LEN),
+                    }
+                }
+                KIND_WALL_CLOCK => {
+                    let wall =
+                        body.get(COMMON_LEN..COMMON_LEN + 8).ok_or_else(
+                            || invalid("wall clock record too short"),
+                        )?;
+                    RecordKind::WallClock(
+                        UNIX_EPOCH
+                            + Duration::from_micros(u64::from_le_bytes(
+                                wall.try_into().unwrap(),
+                            )),
+                    )
+                }
+                KIND_SESSION => {
+                    let id = body
+                        .get(COMMON_LEN..COMMON_LEN + 16)
+                        .ok_or_else(|| invalid("session record too short"))?;
+                    RecordKind::Session(session::Id(id.try_into().unwrap()))
+                }
+                KIND_INFO => RecordKind::Info {
+                    producer: String::from_utf8_lossy(&body[COMMON_LEN..])
+                        .into_owned(),
+                },
+                other => {
+                    log::debug!("skipping record of unknown kind {}", other);
+                    continue;
+                }
+            };
+            return Ok(Some(Record { time, kind }));
+        }
+    }
+}
+
+/// Feeds a recording to `sink`. If `speed` is given, events are paced to
+/// happen at that multiple of the speed they were recorded at (so `1.0` is
+/// real time); otherwise they're delivered as fast as the sink will take
+/// them. Either way, if the recording gives the wall clock time, the sink is
+/// told the time of the original capture (see `Sink::clock`).
+pub fn replay(
+    mut recording: Reader<impl Read>,
+    speed: Option<f64>,
+    sink: &mut (impl Sink + ?Sized),
+) -> io::Result<()> {
+    let started = Instant::now();
+    // The wall clock time when the recording started, if it says.
+    let mut recorded: Option<SystemTime> = None;
+    while let Some(record) = recording.next_record()? {
+        let due = wait_for(started, speed, record.time);
+        if let RecordKind::WallClock(wall) = record.kind {
+            recorded = wall.checked_sub(record.time);
+        }
+        // Pacing stretches time (or does away with it), so the sink needs
+        // telling what time it was as of each record.
+        if let Some(recorded) = recorded {
+            sink.clock(Clock::at(due, recorded + record.time))?;
+        }
+        match record.kind {
+            RecordKind::Data {
+                epoch,
+                start,
+                end,
+                data,
+            } => {
+                let info = Fragment {
+                    received: due,
+                    epoch,
+                    start,
+                    end,
+                };
+                sink.fragment(&info, &data)?;
+            }
+            RecordKind::Gap(Some(cause)) => sink.lost(&Gap {
+                noticed: due,
+                cause,
+            })?,
+            RecordKind::Gap(None) => sink.gap()?,
+            RecordKind::BitRate(rate) => sink.bit_rate(rate)?,
+            RecordKind::FillLevels { epoch, start, end } => {
+                sink.fill_levels(&FillLevels {
+                    received: due,
+                    epoch,
+                    start,
+                    end,
+                })?;
+            }
+            RecordKind::Info { producer } => {
+                log::info!("recorded by {}", producer);
+            }
+            RecordKind::Session(id) => log::info!("recorded in session {}", id),
+            // The sink does its own decoding.
+            RecordKind::Decoded { .. } => (),
+            RecordKind::WallClock(_) => (),
+        }
+    }
+    Ok(())
+}
+
+/// Writes the decoded output stored in a recording to `out`, paced as for
+/// `replay`. Returns whether there was any.
+pub fn replay_decoded(
+    mut recording: Reader<impl Read>,
+    speed: Option<f64>,
+    out: &mut impl Write,
+) -> io::Result<bool> {
+    let started = Instant::now();
+    let mut any = false;
+    while let Some(record) = recording.next_record()? {
+        match record.kind {
+            RecordKind::Decoded { output, .. } => {
+                wait_for(started, speed, record.time);
+                out.write_all(&output)?;
+                out.flush()?;
+                any = true;
+            }
+            RecordKind::Info { producer } => {
+                log::info!("recorded by {}", producer);
+            }
+            RecordKind::Session(id) => log::info!("recorded in session {}", id),
+            _ => (),
+        }
+    }
+    Ok(any)
+}
+
+/// Waits until it's time to replay a record made at `time` into the
+/// recording, if replaying at `speed`, and returns that time.
+fn wait_for(started: Instant, speed: Option<f64>, time: Duration) -> Instant {
+    let due = match speed {
+        Some(speed) => started + time.div_f64(speed),
+        None => Instant::now(),
+    };
+    let now = Instant::now();
+    if due > now {
+        sleep(due - now);
+    }
+    due
+}
+
+fn micros_since_epoch(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |t| t.as_micros() as u64)
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Reads the records in `recording`, after those every recording starts
+    /// with.
+    fn read(recording: &[u8]) -> io::Result<Vec<RecordKind>> {
+        let mut reader = Reader::new(recording)?;
+        let mut records = vec![];
+        while let Some(record) = reader.next_record()? {
+            records.push(record.kind);
+        }
+        assert!(matches!(records[0], RecordKind::Info { .. }));
+        assert!(
+            matches!(records[1], RecordKind::Session(id) if id == session::id())
+        );
+        assert!(matches!(records[2], RecordKind::WallClock(_)));
+        Ok(records.split_off(3))
+    }
+
+    /// Builds a record by hand.
+    fn raw(kind: u8, rest: &[u8]) -> Vec<u8> {
+        let len = (COMMON_LEN + rest.len()) as u32;
+        let mut record = len.to_le_bytes().to_vec();
+        record.push(kind);
+        record.extend_from_slice(&[0; 8]);
+        record.extend_from_slice(rest);
+        record
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut writer = Writer::new(vec![]).unwrap();
+        let now = Instant::now();
+        let info = Fragment {
+            received: now,
+            epoch: 3,
+            start: 0x100,
+            end: 0x105,
+        };
+        writer.fragment(&info, b"hello").unwrap();
+        writer.data(b"!").unwrap();
+        writer.gap().unwrap();
+        let causes = [
+            GapCause::Overflow,
+            GapCause::SyncLost,
+            GapCause::Disconnected,
+            GapCause::HostDrop,
+        ];
+        for &cause in &causes {
+            writer
+                .lost(&Gap {
+                    noticed: now,
+                    cause,
+                })
+                .unwrap();
+        }
+        writer.bit_rate(3_000_000).unwrap();
+        let levels = FillLevels {
+            received: now,
+            epoch: 4,
+            start: 0x10,
+            end: 0x7ff,
+        };
+        writer.fill_levels(&levels).unwrap();
+        writer.decoded(0, 6, b"hello!\n").unwrap();
+        let wall = UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+        writer.clock(Clock::at(now, wall)).unwrap();
+        assert_eq!(writer.offset(), 6);
+
+        let mut records = read(&writer.into_inner()).unwrap().into_iter();
+        let mut next = || records.next().unwrap();
+        match next() {
+            RecordKind::Data {
+                epoch: 3,
+                start: 0x100,
+                end: 0x105,
+                data,
+            } => assert_eq!(data, b"hello"),
+            r => panic!("expected data, got {:?}", r),
+        }
+        match next() {
+            RecordKind::Data { data, .. } => assert_eq!(data, b"!"),
+            r => panic!("expected data, got {:?}", r),
+        }
+        assert!(matches!(next(), RecordKind::Gap(None)));
+        for &cause in &causes {
+            assert!(matches!(next(), RecordKind::Gap(Some(c)) if c == cause));
+        }
+        assert!(matches!(next(), RecordKind::BitRate(3_000_000)));
+        assert!(matches!(
+            next(),
+            RecordKind::FillLevels {
+                epoch: 4,
+                start: 0x10,
+                end: 0x7ff
+            }
+        ));
+        match next() {
+            RecordKind::Decoded {
+                start: 0,
+                end: 6,
+                output,
+            } => assert_eq!(output, b"hello!\n"),
+            r => panic!("expected decoded output, got {:?}", r),
+        }
+        match next() {
+            // The clock is read again as it's recorded.
+            RecordKind::WallClock(t) => {
+                let off = t.duration_since(wall).unwrap();
+                assert!(off < Duration::from_secs(10), "{:?}", off);
+            }
+            r => panic!("expected wall clock, got {:?}", r),
+        }
+        assert!(records.next().is_none());
+    }
+
+    #[test]
+    fn truncated() {
+        let mut writer = Writer::new(vec![]).unwrap();
+        writer.data(b"complete").unwrap();
+        let complete = writer.get_ref().len();
+        writer.data(b"partial").unwrap();
+        let recording = writer.into_inner();
+
+        // Cut off anywhere in the last record, including its length, the
+        // recording ends after the one before.
+        for len in complete + 1..recording.len() {
+            let records = read(&recording[..len]).unwrap();
+            assert_eq!(records.len(), 1, "cut at {}", len);
+            match &records[0] {
+                RecordKind::Data { data, .. } => assert_eq!(data, b"complete"),
+                r => panic!("expected data, got {:?}", r),
+            }
+        }
+
+        // Records too short for their kind are errors, though.
+        for &kind in &[
+            KIND_DATA,
+            KIND_BIT_RATE,
+            KIND_FILL_LEVELS,
+            KIND_DECODED,
+            KIND_WALL_CLOCK,
+            KIND_SESSION,
+        ] {
+            let mut recording = Writer::new(vec![]).unwrap().into_inner();
+            recording.extend(raw(kind, &[1, 2]));
+            let e = read(&recording).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidData, "kind {}", kind);
+        }
+        let mut recording = MAGIC.to_vec();
+        recording.extend_from_slice(&[1, 0, 0, 0, KIND_GAP]);
+        assert!(Reader::new(&recording[..]).unwrap().next_record().is_err());
+    }
+
+    #[test]
+    fn unknown_kinds() {
+        let mut writer = Writer::new(vec![]).unwrap();
+        writer.data(b"before").unwrap();
+        let mut recording = writer.into_inner();
+        recording.extend(raw(0xfe, b"from the future"));
+        recording.extend(raw(KIND_GAP, &[0xfe]));
+        recording.extend(raw(KIND_DATA, &[0, 0, 0, 0, 0, b'!']));
+
+        let records = read(&recording).unwrap();
+        assert_eq!(records.len(), 3);
+        assert!(
+            matches!(&records[0], RecordKind::Data { data, .. } if data == b"before")
+        );
+        // Gaps of unknown cause are still gaps.
+        assert!(matches!(records[1], RecordKind::Gap(None)));
+        assert!(
+            matches!(&records[2], RecordKind::Data { data, .. } if data == b"!")
+        );
+    }
+
+    #[test]
+    fn header() {
+        let mut recording = Writer::new(vec![]).unwrap().into_inner();
+        recording[7] = 2;
+        assert!(Reader::new(&recording[..]).is_err());
+        recording[0] = b'X';
+        assert!(Reader::new(&recording[..]).is_err());
+        assert!(Reader::new(&MAGIC[..4]).is_err());
+    }
+}
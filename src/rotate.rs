@@ -0,0 +1,182 @@
+//! Writing the raw stream to files that are rotated by size or age, for
+//! captures that run longer than anyone wants to keep in one file.
+//!
+//! The current file always has the name given; when it's rotated, it
+//! becomes `<name>.1`, the previous `<name>.1` becomes `<name>.2`, and so on,
+//! up to the number of files to keep. Each file starts with a 20-byte
+//! header (integers little endian), and then holds the raw stream:
+//!
+//! ```text
+//! byte[0:7]   = magic, `LPC-RAW` followed by a version byte (currently 1)
+//! byte[8:11]  = u32 bit rate being captured, or 0 if unknown
+//! byte[12:19] = u64 time the file was started, in microseconds since the
+//!               Unix epoch
+//! ```
+//!
+//! Files are only ever rotated between fragments, so data that arrived
+//! together stays together. A change of bit rate also starts a new file, so
+//! the header is right for everything in it.
+
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::capture::{Fragment, Sink};
+
+pub const MAGIC: [u8; 8] = *b"LPC-RAW\x01";
+
+/// Length of the header at the start of each file.
+pub const HEADER_LEN: usize = 20;
+
+/// Rotation options.
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// Name of the current file.
+    pub path: PathBuf,
+    /// Start a new file once the current one reaches this many bytes.
+    pub size: Option<u64>,
+    /// Start a new file once the current one is this old.
+    pub interval: Option<Duration>,
+    /// Keep at most this many files, including the current one.
+    pub count: usize,
+}
+
+/// Sink that writes the raw stream to rotating files. Gaps aren't recorded.
+pub struct Rotating {
+    config: Config,
+    current: Option<Current>,
+    bit_rate: Option<u32>,
+}
+
+struct Current {
+    file: BufWriter<File>,
+    written: u64,
+    started: Instant,
+}
+
+impl Rotating {
+    /// Prepares to write files as described by `config`. Nothing is written
+    /// until data arrives.
+    pub fn new(config: Config) -> Self {
+        Self {
+            config,
+            current: None,
+            bit_rate: None,
+        }
+    }
+
+    /// Returns the current file, starting a new one first if there isn't
+    /// one or it's due for rotation.
+    fn file(&mut self) -> io::Result<&mut Current> {
+        let due = match &self.current {
+            Some(current) => {
+                self.config.size.is_some_and(|s| current.written >= s)
+                    || self
+                        .config
+                        .interval
+                        .is_some_and(|i| current.started.elapsed() >= i)
+            }
+            None => true,
+        };
+        if due {
+            self.rotate()?;
+        }
+        Ok(self.current.as_mut().unwrap())
+    }
+
+    /// Moves the current file aside (if there is one, even from an earlier
+    /// run) and starts a new one.
+    fn rotate(&mut self) -> io::Result<()> {
+        if let Some(mut old) = self.current.take() {
+            old.file.flush()?;
+        }
+        if self.config.path.exists() {
+            self.shift()?;
+        }
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_micros() as u64);
+        let mut header = [0; HEADER_LEN];
+        header[..8].copy_from_slice(&MAGIC);
+        header[8..12]
+            .copy_from_slice(&self.bit_rate.unwrap_or(0).to_le_bytes());
+        header[12..].copy_from_slice(&now.to_le_bytes());
+
+        log::info!("writing to {}", self.config.path.display());
+        let mut file = BufWriter::new(File::create(&self.config.path)?);
+        file.write_all(&header)?;
+        self.current = Some(Current {
+            file,
+            written: HEADER_LEN as u64,
+            started: Instant::now(),
+        });
+        Ok(())
+    }
+
+    /// Renames `<path>` to `<path>.1`, `<path>.1` to `<path>.2` and so on,
+    /// deleting the oldest if there are too many.
+    fn shift(&self) -> io::Result<()> {
+        let path = &self.config.path;
+        let keep = self.config.count.max(1);
+        let oldest = match keep {
+            1 => path.clone(),
+            _ => numbered(path, keep - 1),
+        };
+        if oldest.exists() {
+            log::debug!("removing {}", oldest.display());
+            fs::remove_file(&oldest)?;
+        }
+        for n in (1..keep).rev() {
+            let from = if n == 1 {
+                path.clone()
+            } else {
+                numbered(path, n - 1)
+            };
+            if from.exists() {
+                fs::rename(&from, numbered(path, n))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Sink for Rotating {
+    fn data(&mut self, bytes: &[u8]) -> io::Result<()> {
+        let current = self.file()?;
+        current.file.write_all(bytes)?;
+        current.file.flush()?;
+        current.written += bytes.len() as u64;
+        Ok(())
+    }
+
+    fn fragment(&mut self, _info: &Fragment, bytes: &[u8]) -> io::Result<()> {
+        // Each fragment goes into one file, whatever its size.
+        self.data(bytes)
+    }
+
+    fn bit_rate(&mut self, rate: u32) -> io::Result<()> {
+        let changed = self.bit_rate.is_some_and(|r| r != rate);
+        self.bit_rate = Some(rate);
+        if changed && self.current.is_some() {
+            self.rotate()?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Rotating {
+    fn drop(&mut self) {
+        if let Some(current) = &mut self.current {
+            current.file.flush().ok();
+        }
+    }
+}
+
+/// Returns the name `path` has after being rotated `n` times.
+fn numbered(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", n));
+    PathBuf::from(name)
+}
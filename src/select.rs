@@ -0,0 +1,533 @@
+//! Choosing which debug probe to talk to.
+
+use std::fs;
+use std::path::Path;
+
+use crate::firmware;
+use crate::interfaces::{self, Function, Interface};
+
+/// Criteria for picking a probe out of the devices attached to the system.
+/// Each criterion that is `Some` must match.
+#[derive(Clone, Debug, Default)]
+pub struct Selector {
+    /// Exact USB serial number.
+    pub serial: Option<String>,
+    /// Physical USB port path, in the form Linux uses in sysfs: bus number,
+    /// then the port number at each hub level, e.g. `1-3.2`.
+    pub usb_port: Option<String>,
+    /// Arbitrary expression over the device's properties.
+    pub expr: Option<Expr>,
+}
+
+impl Selector {
+    pub fn matches(&self, device: &hidapi::DeviceInfo) -> bool {
+        if let Some(serial) = &self.serial {
+            if device.serial_number() != Some(serial) {
+                return false;
+            }
+        }
+        if let Some(port) = &self.usb_port {
+            if usb_port_path(device).as_deref() != Some(port) {
+                return false;
+            }
+        }
+        if let Some(expr) = &self.expr {
+            if !expr.matches(device) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// Works out the physical port path (e.g. `1-3.2`) of the USB device that
+/// provides a HID interface. Unlike the bus address, this stays the same when
+/// the device is unplugged and reattached to the same port.
+///
+/// This is currently only implemented on Linux, where it's recovered from
+/// sysfs. Returns `None` if it can't be determined.
+pub fn usb_port_path(device: &hidapi::DeviceInfo) -> Option<String> {
+    let path = device.path().to_str().ok()?;
+    if let Some(node) = path.strip_prefix("/dev/") {
+        // hidraw backend: the class device links back into the USB tree,
+        // through the interface (`1-3.2:1.4`) owning the node.
+        let link =
+            fs::canonicalize(Path::new("/sys/class/hidraw").join(node)).ok()?;
+        link.ancestors()
+            .filter_map(|p| p.file_name()?.to_str())
+            .find_map(|name| {
+                let (port, _config) = name.split_once(':')?;
+                if port.contains('-') {
+                    Some(port.to_string())
+                } else {
+                    None
+                }
+            })
+    } else {
+        // libusb backend: the path is `bus:address:interface` in hex, so go
+        // looking for the device with that bus and address.
+        let mut parts = path.split(':');
+        let bus = u8::from_str_radix(parts.next()?, 16).ok()?;
+        let addr = u8::from_str_radix(parts.next()?, 16).ok()?;
+        port_for_bus_address(bus, addr)
+    }
+}
+
+fn port_for_bus_address(bus: u8, addr: u8) -> Option<String> {
+    let read_num = |dir: &Path, attr: &str| -> Option<u8> {
+        fs::read_to_string(dir.join(attr)).ok()?.trim().parse().ok()
+    };
+    fs::read_dir("/sys/bus/usb/devices")
+        .ok()?
+        .filter_map(Result::ok)
+        .find(|entry| {
+            let dir = entry.path();
+            read_num(&dir, "busnum") == Some(bus)
+                && read_num(&dir, "devnum") == Some(addr)
+        })
+        .and_then(|entry| entry.file_name().into_string().ok())
+}
+
+/// Summary of one physical debug probe, which shows up as several HID
+/// interfaces.
+pub struct ProbeInfo {
+    pub serial: Option<String>,
+    pub usb_port: Option<String>,
+    pub product: Option<String>,
+    /// The probe's USB interfaces, in order of interface number.
+    pub interfaces: Vec<Interface>,
+}
+
+impl ProbeInfo {
+    /// Returns the HID path of the interface providing `function`, if the
+    /// probe has one.
+    pub fn hid_path(&self, function: Function) -> Option<&str> {
+        self.interfaces
+            .iter()
+            .filter(|i| i.function == function)
+            .find_map(|i| i.hid_path.as_deref())
+    }
+}
+
+/// Finds the probes with the given vid/pid having any HID interface that
+/// satisfies `selector`, grouping their interfaces together.
+pub fn enumerate(
+    api: &hidapi::HidApi,
+    vid: u16,
+    pid: u16,
+    selector: &Selector,
+) -> Vec<ProbeInfo> {
+    let mut probes: Vec<ProbeInfo> = vec![];
+    let mut hid_interfaces: Vec<Vec<(i32, String)>> = vec![];
+    let mut matched = vec![];
+    for d in api.device_list() {
+        if d.vendor_id() != vid || d.product_id() != pid {
+            continue;
+        }
+        let serial = d.serial_number().map(String::from);
+        let usb_port = usb_port_path(d);
+        // Interfaces of the same probe share a port path, if we can work it
+        // out. Otherwise, fall back to assuming serial numbers are unique.
+        let existing = probes.iter().position(|p| {
+            if usb_port.is_some() {
+                p.usb_port == usb_port
+            } else {
+                p.serial == serial
+            }
+        });
+        let index = existing.unwrap_or_else(|| {
+            probes.push(ProbeInfo {
+                serial,
+                usb_port,
+                product: d.product_string().map(String::from),
+                interfaces: vec![],
+            });
+            hid_interfaces.push(vec![]);
+            matched.push(false);
+            probes.len() - 1
+        });
+        hid_interfaces[index]
+            .push((d.interface_number(), d.path().to_string_lossy().into()));
+        matched[index] |= selector.matches(d);
+    }
+    probes
+        .into_iter()
+        .zip(hid_interfaces)
+        .zip(matched)
+        .filter(|(_, m)| *m)
+        .map(|((mut p, hid), _)| {
+            p.interfaces = interfaces::describe(
+                p.usb_port.as_deref(),
+                &hid,
+                firmware::layout(vid, pid),
+            );
+            p
+        })
+        .collect()
+}
+
+/// A boolean expression over device properties, e.g.
+/// `serial~a5f* && port=1-3.*`.
+///
+/// Terms have the form `key=pattern` (glob match, case-sensitive),
+/// `key~pattern` (glob match, ignoring case), or `key!=pattern` (doesn't
+/// match). Patterns may use `*` for any run of characters and `?` for any one
+/// character, and may be quoted with `'` or `"` if they contain spaces or
+/// operator characters. Terms can be combined with `&&`, `||`, `!`, and
+/// parentheses; `&&` binds tighter than `||`.
+///
+/// Keys are `serial`, `port` (USB port path), `path` (HID device path),
+/// `product`, `manufacturer`, `vid`, and `pid` (both four hex digits).
+///
+/// Expressions are parsed with `str::parse`.
+#[derive(Clone, Debug)]
+pub enum Expr {
+    Term { key: Key, op: Op, pattern: String },
+    Not(Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Key {
+    Serial,
+    Port,
+    Path,
+    Product,
+    Manufacturer,
+    Vid,
+    Pid,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Op {
+    Match,
+    MatchIgnoreCase,
+    NoMatch,
+}
+
+impl Expr {
+    pub fn matches(&self, device: &hidapi::DeviceInfo) -> bool {
+        self.matches_with(&|key| match key {
+            Key::Serial => device.serial_number().map(str::to_string),
+            Key::Port => usb_port_path(device),
+            Key::Path => device.path().to_str().ok().map(String::from),
+            Key::Product => device.product_string().map(String::from),
+            Key::Manufacturer => device.manufacturer_string().map(String::from),
+            Key::Vid => Some(format!("{:04x}", device.vendor_id())),
+            Key::Pid => Some(format!("{:04x}", device.product_id())),
+        })
+    }
+
+    /// Evaluates the expression against something other than a HID device,
+    /// such as a probe described in a configuration file, given the value
+    /// of each key (`None` if it's unknown, which matches no pattern).
+    pub fn matches_with(&self, value: &dyn Fn(Key) -> Option<String>) -> bool {
+        match self {
+            Expr::Term { key, op, pattern } => {
+                let matched = value(*key).is_some_and(|v| match op {
+                    Op::Match | Op::NoMatch => glob_match(pattern, &v),
+                    Op::MatchIgnoreCase => {
+                        glob_match(&pattern.to_lowercase(), &v.to_lowercase())
+                    }
+                });
+                matched != (*op == Op::NoMatch)
+            }
+            Expr::Not(e) => !e.matches_with(value),
+            Expr::And(a, b) => a.matches_with(value) && b.matches_with(value),
+            Expr::Or(a, b) => a.matches_with(value) || b.matches_with(value),
+        }
+    }
+}
+
+impl std::str::FromStr for Expr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let tokens = tokenize(s)?;
+        let mut parser = Parser {
+            tokens: &tokens,
+            pos: 0,
+        };
+        let expr = parser.or()?;
+        match parser.tokens.get(parser.pos) {
+            None => Ok(expr),
+            Some(t) => Err(format!("unexpected {:?} in selector", t)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Token {
+    Word(String),
+    Op(Op),
+    And,
+    Or,
+    Not,
+    Open,
+    Close,
+}
+
+fn tokenize(s: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = vec![];
+    let mut chars = s.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        match c {
+            _ if c.is_whitespace() => {
+                chars.next();
+            }
+            '(' | ')' | '=' | '~' => {
+                chars.next();
+                tokens.push(match c {
+                    '(' => Token::Open,
+                    ')' => Token::Close,
+                    '=' => Token::Op(Op::Match),
+                    _ => Token::Op(Op::MatchIgnoreCase),
+                });
+            }
+            '!' => {
+                chars.next();
+                if chars.peek() == Some(&'=') {
+                    chars.next();
+                    tokens.push(Token::Op(Op::NoMatch));
+                } else {
+                    tokens.push(Token::Not);
+                }
+            }
+            '&' | '|' => {
+                chars.next();
+                if chars.next() != Some(c) {
+                    return Err(format!("expected `{}{}` in selector", c, c));
+                }
+                tokens.push(if c == '&' { Token::And } else { Token::Or });
+            }
+            '\'' | '"' => {
+                chars.next();
+                let mut word = String::new();
+                loop {
+                    match chars.next() {
+                        Some(q) if q == c => break,
+                        Some(ch) => word.push(ch),
+                        None => return Err("unterminated quote".to_string()),
+                    }
+                }
+                tokens.push(Token::Word(word));
+            }
+            _ => {
+                let mut word = String::new();
+                while let Some(&ch) = chars.peek() {
+                    if ch.is_whitespace() || "()=~!&|'\"".contains(ch) {
+                        break;
+                    }
+                    word.push(ch);
+                    chars.next();
+                }
+                tokens.push(Token::Word(word));
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser<'a> {
+    tokens: &'a [Token],
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn next(&mut self) -> Option<&Token> {
+        let t = self.tokens.get(self.pos);
+        self.pos += 1;
+        t
+    }
+
+    fn eat(&mut self, t: &Token) -> bool {
+        if self.tokens.get(self.pos) == Some(t) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn or(&mut self) -> Result<Expr, String> {
+        let mut e = self.and()?;
+        while self.eat(&Token::Or) {
+            e = Expr::Or(Box::new(e), Box::new(self.and()?));
+        }
+        Ok(e)
+    }
+
+    fn and(&mut self) -> Result<Expr, String> {
+        let mut e = self.unary()?;
+        while self.eat(&Token::And) {
+            e = Expr::And(Box::new(e), Box::new(self.unary()?));
+        }
+        Ok(e)
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        if self.eat(&Token::Not) {
+            return Ok(Expr::Not(Box::new(self.unary()?)));
+        }
+        if self.eat(&Token::Open) {
+            let e = self.or()?;
+            if !self.eat(&Token::Close) {
+                return Err("missing `)` in selector".to_string());
+            }
+            return Ok(e);
+        }
+        let key = match self.next() {
+            Some(Token::Word(w)) => match w.as_str() {
+                "serial" => Key::Serial,
+                "port" => Key::Port,
+                "path" => Key::Path,
+                "product" => Key::Product,
+                "manufacturer" => Key::Manufacturer,
+                "vid" => Key::Vid,
+                "pid" => Key::Pid,
+                _ => return Err(format!("unknown selector key `{}`", w)),
+            },
+            t => return Err(format!("expected selector key, found {:?}", t)),
+        };
+        let op = match self.next() {
+            Some(Token::Op(op)) => *op,
+            t => {
+                return Err(format!(
+                    "expected `=`, `~`, or `!=`, found {:?}",
+                    t
+                ))
+            }
+        };
+        let pattern = match self.next() {
+            Some(Token::Word(w)) => w.clone(),
+            t => return Err(format!("expected pattern, found {:?}", t)),
+        };
+        Ok(Expr::Term { key, op, pattern })
+    }
+}
+
+/// Matches `text` against a glob `pattern`, where `*` matches any run of
+/// characters and `?` matches exactly one.
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let p: Vec<char> = pattern.chars().collect();
+    let t: Vec<char> = text.chars().collect();
+    let (mut pi, mut ti) = (0, 0);
+    // Position of the last `*` seen, and where in `text` it started matching.
+    let mut star: Option<(usize, usize)> = None;
+    while ti < t.len() {
+        if pi < p.len() && (p[pi] == '?' || p[pi] == t[ti]) {
+            pi += 1;
+            ti += 1;
+        } else if pi < p.len() && p[pi] == '*' {
+            star = Some((pi, ti));
+            pi += 1;
+        } else if let Some((sp, st)) = star {
+            // Let the last `*` swallow one more character and retry.
+            pi = sp + 1;
+            ti = st + 1;
+            star = Some((sp, st + 1));
+        } else {
+            return false;
+        }
+    }
+    p[pi..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Evaluates `expr` against a probe with the given serial number and
+    /// port, and made-up values for the other keys.
+    fn eval(expr: &str, serial: &str, port: &str) -> bool {
+        let expr: Expr = expr.parse().unwrap();
+        expr.matches_with(&|key| match key {
+            Key::Serial => Some(serial.to_string()),
+            Key::Port => Some(port.to_string()),
+            Key::Product => Some("LPC-LINK2 CMSIS-DAP V5.361".to_string()),
+            Key::Vid => Some("1fc9".to_string()),
+            Key::Pid => Some("0090".to_string()),
+            Key::Path | Key::Manufacturer => None,
+        })
+    }
+
+    #[test]
+    fn globs() {
+        assert!(glob_match("A5F*", "A5F00012"));
+        assert!(!glob_match("A5F*", "a5f00012"));
+        assert!(glob_match("1-3.*", "1-3.2"));
+        assert!(glob_match("1-3.*", "1-3."));
+        assert!(!glob_match("1-3.*", "1-3"));
+        assert!(!glob_match("1-3.*", "1-4.2"));
+        assert!(glob_match("1-?.2", "1-3.2"));
+        assert!(!glob_match("1-?.2", "1-.2"));
+        assert!(glob_match("*", ""));
+        assert!(glob_match("*a*b", "xaxab"));
+        assert!(!glob_match("*a*b", "xaxba"));
+        assert!(glob_match("", ""));
+        assert!(!glob_match("", "x"));
+    }
+
+    #[test]
+    fn terms() {
+        assert!(eval("serial=A5F*", "A5F00012", "1-3.2"));
+        assert!(!eval("serial=a5f*", "A5F00012", "1-3.2"));
+        assert!(eval("serial~a5f*", "A5F00012", "1-3.2"));
+        assert!(eval("serial!=a5f*", "A5F00012", "1-3.2"));
+        assert!(eval("port=1-3.*", "A5F00012", "1-3.2"));
+        assert!(eval("product='LPC-LINK2 CMSIS-DAP*'", "x", "1-3.2"));
+        assert!(eval("vid=1fc9 && pid=\"0090\"", "x", "1-3.2"));
+        // Unknown values match nothing, so their negation matches.
+        assert!(!eval("manufacturer=*", "x", "1-3.2"));
+        assert!(eval("manufacturer!=*", "x", "1-3.2"));
+    }
+
+    #[test]
+    fn precedence() {
+        // `&&` binds tighter than `||`: this is `a || (b && c)`.
+        let expr = "serial=A* || serial=B* && port=1-3.*";
+        assert!(eval(expr, "A1", "2-1"));
+        assert!(eval(expr, "B1", "1-3.2"));
+        assert!(!eval(expr, "B1", "2-1"));
+        // And `!` tighter than either.
+        let expr = "!serial=A* && port=1-3.*";
+        assert!(eval(expr, "B1", "1-3.2"));
+        assert!(!eval(expr, "A1", "1-3.2"));
+        assert!(!eval(expr, "B1", "2-1"));
+    }
+
+    #[test]
+    fn parentheses() {
+        let expr = "(serial=A* || serial=B*) && port=1-3.*";
+        assert!(eval(expr, "A1", "1-3.2"));
+        assert!(!eval(expr, "A1", "2-1"));
+        assert!(!eval(expr, "C1", "1-3.2"));
+        let expr = "!(serial=A* || port=2-*)";
+        assert!(eval(expr, "B1", "1-3.2"));
+        assert!(!eval(expr, "B1", "2-1"));
+        assert!(eval("((serial=A1))", "A1", "1-3.2"));
+    }
+
+    #[test]
+    fn malformed() {
+        for expr in &[
+            "",
+            "serial",
+            "serial=",
+            "=A*",
+            "serial=A* &&",
+            "serial=A* & port=1",
+            "serial=A* | port=1",
+            "(serial=A*",
+            "serial=A*)",
+            "serial=A* port=1",
+            "colour=red",
+            "serial='A5F",
+            "!",
+            "()",
+        ] {
+            assert!(expr.parse::<Expr>().is_err(), "{:?} parsed", expr);
+        }
+    }
+}
@@ -0,0 +1,579 @@
+//! TCP server sink, for feeding SWO to existing viewers over the network.
+//!
+//! By default this speaks the same (lack of) protocol as the SWO ports of
+//! OpenOCD and Orbuculum: once a client connects, it receives the raw SWO byte
+//! stream, with no framing or handshake. Anything the client sends is ignored.
+//!
+//! Optionally, the stream can be wrapped in the framing described in the
+//! `framing` module, so that clients can detect lost data. In that case the
+//! server can also keep a window of recent history, so that clients that
+//! reconnect after a short absence can pick up where they left off.
+//!
+//! Framing clients can also ask for the server's status rather than data, to
+//! see how capture is going without the load of receiving it.
+//!
+//! Access can be limited to certain networks and to clients that know a
+//! token (see `access`), and with the `tls` feature the server can speak TLS.
+
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::access;
+use crate::capture::{self, Progress, Sink};
+use crate::framing;
+use crate::telemetry;
+
+/// Number of chunks we'll queue for each client before we start dropping data
+/// on the floor for that client. This keeps one slow client from stalling the
+/// probe (and thus everyone else).
+const CLIENT_QUEUE_DEPTH: usize = 256;
+
+/// How long a client has to send its auth message and attach request (if
+/// it's expected to send them) after connecting.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Server options.
+#[derive(Clone, Debug, Default)]
+pub struct Config {
+    /// Send data wrapped in frames, as described in the `framing` module,
+    /// instead of raw.
+    pub framing: bool,
+    /// Number of bytes of recent data to keep for clients that ask to resume
+    /// from an earlier point. Only used with `framing`.
+    pub history_bytes: usize,
+    /// Most bytes per second to send to any one client. Data beyond this is
+    /// dropped for that client. Does not apply to history sent on attach.
+    pub client_rate: Option<u64>,
+    /// Most bytes per second to send to all clients together.
+    pub total_rate: Option<u64>,
+    /// Who may connect.
+    pub access: access::Policy,
+    /// How the capture feeding the server is going, for clients that ask
+    /// for the server's status. Only used with `framing`.
+    pub progress: Option<Arc<Progress>>,
+    /// Speak TLS with this configuration instead of plain TCP.
+    #[cfg(feature = "tls")]
+    pub tls: Option<Arc<rustls::ServerConfig>>,
+}
+
+/// Broadcasts SWO data to any number of connected TCP clients.
+///
+/// Writes to a `Server` never block on the network. Each client has its own
+/// queue and writer thread; if a client falls behind far enough to fill its
+/// queue, data is dropped for that client only. Likewise, data is dropped
+/// rather than delayed for clients that would exceed a bandwidth cap.
+pub struct Server {
+    shared: Arc<Mutex<Shared>>,
+    /// Sequence number of the next frame, if we're framing data.
+    framing: Option<u32>,
+    /// Number of bytes broadcast so far.
+    offset: u64,
+    /// Whether the probe has lost data since we last sent any.
+    pending_gap: bool,
+}
+
+/// State shared between the server and its client threads.
+struct Shared {
+    clients: Vec<Client>,
+    history: History,
+    /// Bandwidth available to all clients together.
+    total_rate: RateLimit,
+    /// Number of client threads still sending data.
+    sending: usize,
+    /// Whether the server has been closed, so new clients are turned away.
+    closed: bool,
+}
+
+struct Client {
+    peer: SocketAddr,
+    queue: SyncSender<Arc<[u8]>>,
+    /// Bytes dropped because the client couldn't keep up.
+    dropped: u64,
+    /// Bandwidth available to this client.
+    rate: RateLimit,
+    /// Bytes dropped because of bandwidth caps.
+    throttled: u64,
+}
+
+/// A token bucket allowing bursts of up to a second's worth of data.
+///
+/// Chunks can be bigger than the bucket (if the rate is low), so rather than
+/// wait for enough tokens for a whole chunk, we let the bucket go into debt
+/// and refuse further chunks until it's paid off.
+struct RateLimit {
+    /// Bytes per second, or `None` for no limit.
+    rate: Option<u64>,
+    available: i64,
+    refilled: Instant,
+}
+
+impl RateLimit {
+    fn new(rate: Option<u64>) -> Self {
+        Self {
+            rate,
+            available: rate.map_or(0, |r| r as i64),
+            refilled: Instant::now(),
+        }
+    }
+
+    /// Checks whether we can send more data now.
+    fn allows(&mut self, now: Instant) -> bool {
+        let rate = match self.rate {
+            Some(r) => r,
+            None => return true,
+        };
+        let elapsed = now.saturating_duration_since(self.refilled);
+        let earned = elapsed.as_micros() * u128::from(rate) / 1_000_000;
+        if earned > 0 {
+            let earned = earned.min(u128::from(rate)) as i64;
+            self.available = (self.available + earned).min(rate as i64);
+            self.refilled = now;
+        }
+        self.available > 0
+    }
+
+    /// Records that `n` bytes were sent.
+    fn take(&mut self, n: usize) {
+        if self.rate.is_some() {
+            self.available -= n as i64;
+        }
+    }
+}
+
+/// Recently sent frames.
+struct History {
+    limit: usize,
+    /// Total payload bytes in `entries`.
+    size: usize,
+    entries: VecDeque<HistoryEntry>,
+}
+
+struct HistoryEntry {
+    /// Offset and length of the payload, in the stream.
+    offset: u64,
+    len: usize,
+    received: Instant,
+    /// The encoded frames.
+    frames: Arc<[u8]>,
+}
+
+impl History {
+    fn push(&mut self, entry: HistoryEntry) {
+        if self.limit == 0 {
+            return;
+        }
+        self.size += entry.len;
+        self.entries.push_back(entry);
+        while self.size > self.limit {
+            match self.entries.pop_front() {
+                Some(old) => self.size -= old.len,
+                None => break,
+            }
+        }
+    }
+
+    /// Returns the frames a client asking for `start` should get before live
+    /// data.
+    fn since(&self, start: framing::Attach) -> Vec<Arc<[u8]>> {
+        let keep = |e: &HistoryEntry| match start {
+            framing::Attach::Live | framing::Attach::Status => false,
+            framing::Attach::Resume(offset) => e.offset + e.len as u64 > offset,
+            framing::Attach::Since(age) => e.received.elapsed() <= age,
+        };
+        self.entries
+            .iter()
+            .filter(|e| keep(e))
+            .map(|e| Arc::clone(&e.frames))
+            .collect()
+    }
+
+    /// Returns the offset of the oldest data we have, if any.
+    fn oldest(&self) -> Option<u64> {
+        self.entries.front().map(|e| e.offset)
+    }
+}
+
+impl Server {
+    /// Binds to `addr` and begins accepting clients in the background.
+    pub fn bind(addr: &str, config: Config) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        let local_addr = listener.local_addr()?;
+        let shared = Arc::new(Mutex::new(Shared {
+            clients: vec![],
+            history: History {
+                limit: if config.framing {
+                    config.history_bytes
+                } else {
+                    0
+                },
+                size: 0,
+                entries: VecDeque::new(),
+            },
+            total_rate: RateLimit::new(config.total_rate),
+            sending: 0,
+            closed: false,
+        }));
+
+        let framing = if config.framing { Some(0) } else { None };
+        let accept_shared = Arc::clone(&shared);
+        thread::spawn(move || accept_loop(listener, accept_shared, config));
+
+        log::info!("serving SWO on {}", local_addr);
+        Ok(Self {
+            shared,
+            framing,
+            offset: 0,
+            pending_gap: false,
+        })
+    }
+
+    /// Returns something that can close the server once it's no longer
+    /// needed, making sure clients get what's been sent to them.
+    pub fn closer(&self) -> Closer {
+        Closer {
+            shared: Arc::clone(&self.shared),
+        }
+    }
+
+    /// Queues `data` for delivery to every connected client.
+    pub fn broadcast(&mut self, data: &[u8]) {
+        if data.is_empty() {
+            return;
+        }
+        let chunk: Arc<[u8]> = match &mut self.framing {
+            Some(sequence) => {
+                let mut framed = vec![];
+                let mut offset = self.offset;
+                for payload in data.chunks(framing::MAX_PAYLOAD) {
+                    framing::encode(
+                        *sequence,
+                        offset,
+                        self.pending_gap,
+                        payload,
+                        &mut framed,
+                    );
+                    *sequence = sequence.wrapping_add(1);
+                    offset += payload.len() as u64;
+                    self.pending_gap = false;
+                }
+                framed.into()
+            }
+            None => data.into(),
+        };
+        let mut shared = self.shared.lock().unwrap();
+        if self.framing.is_some() {
+            shared.history.push(HistoryEntry {
+                offset: self.offset,
+                len: data.len(),
+                received: Instant::now(),
+                frames: Arc::clone(&chunk),
+            });
+        }
+        self.offset += data.len() as u64;
+        let now = Instant::now();
+        let Shared {
+            clients,
+            total_rate,
+            ..
+        } = &mut *shared;
+        clients.retain_mut(|c| {
+            // Check both caps before taking from either, so that data
+            // dropped by one isn't charged to the other.
+            let client_ok = c.rate.allows(now);
+            if !(client_ok && total_rate.allows(now)) {
+                if c.throttled == 0 {
+                    log::warn!(
+                        "client {} over bandwidth cap, dropping data",
+                        c.peer
+                    );
+                }
+                c.throttled += data.len() as u64;
+                return true;
+            }
+            match c.queue.try_send(Arc::clone(&chunk)) {
+                Ok(()) => {
+                    c.rate.take(chunk.len());
+                    total_rate.take(chunk.len());
+                    true
+                }
+                Err(TrySendError::Full(_)) => {
+                    if c.dropped == 0 {
+                        log::warn!(
+                            "client {} can't keep up, dropping data",
+                            c.peer
+                        );
+                    }
+                    c.dropped += data.len() as u64;
+                    true
+                }
+                Err(TrySendError::Disconnected(_)) => {
+                    log::info!(
+                        "client {} disconnected ({} bytes dropped, {} \
+                         throttled)",
+                        c.peer,
+                        c.dropped,
+                        c.throttled
+                    );
+                    false
+                }
+            }
+        });
+    }
+}
+
+impl Sink for Server {
+    fn data(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.broadcast(bytes);
+        Ok(())
+    }
+
+    fn gap(&mut self) -> io::Result<()> {
+        self.pending_gap = true;
+        Ok(())
+    }
+}
+
+fn accept_loop(
+    listener: TcpListener,
+    shared: Arc<Mutex<Shared>>,
+    config: Config,
+) {
+    let config = Arc::new(config);
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(s) => s,
+            Err(e) => {
+                log::warn!("accept failed: {}", e);
+                continue;
+            }
+        };
+        let peer = match stream.peer_addr() {
+            Ok(p) => p,
+            Err(_) => continue,
+        };
+        if !config.access.permits(peer.ip()) {
+            log::warn!("refused connection from {}", peer);
+            continue;
+        }
+        // SWO viewers want to see data as soon as it arrives.
+        stream.set_nodelay(true).ok();
+
+        log::info!("client {} connected", peer);
+        let shared = Arc::clone(&shared);
+        let config = Arc::clone(&config);
+        thread::spawn(move || {
+            let mut stream = match Conn::new(stream, &config) {
+                Ok(s) => s,
+                Err(e) => {
+                    log::warn!("can't set up connection to {}: {}", peer, e);
+                    return;
+                }
+            };
+            stream.tcp().set_read_timeout(Some(HANDSHAKE_TIMEOUT)).ok();
+            if let Err(e) = config.access.authenticate(&mut stream) {
+                log::warn!("client {} failed to authenticate: {}", peer, e);
+                return;
+            }
+            let start = if config.framing {
+                read_attach(&mut stream, peer)
+            } else {
+                framing::Attach::Live
+            };
+            stream.tcp().set_read_timeout(None).ok();
+            if start == framing::Attach::Status {
+                let status = status_json(config.progress.as_deref());
+                if let Err(e) = stream
+                    .write_all(status.as_bytes())
+                    .and_then(|_| stream.flush())
+                {
+                    log::debug!("can't send status to {}: {}", peer, e);
+                }
+                return;
+            }
+
+            // Registering the client and collecting its backlog under the
+            // same lock ensures it sees each frame exactly once.
+            let (queue, rx) = sync_channel(CLIENT_QUEUE_DEPTH);
+            let backlog = {
+                let mut shared = shared.lock().unwrap();
+                if shared.closed {
+                    log::info!("client {} connected too late", peer);
+                    return;
+                }
+                if let framing::Attach::Resume(offset) = start {
+                    match shared.history.oldest() {
+                        Some(oldest) if oldest <= offset => (),
+                        oldest => log::info!(
+                            "client {} asked to resume from offset {}, but \
+                             history starts at {:?}",
+                            peer,
+                            offset,
+                            oldest
+                        ),
+                    }
+                }
+                shared.clients.push(Client {
+                    peer,
+                    queue,
+                    dropped: 0,
+                    rate: RateLimit::new(config.client_rate),
+                    throttled: 0,
+                });
+                shared.sending += 1;
+                shared.history.since(start)
+            };
+            client_loop(stream, backlog, rx);
+            shared.lock().unwrap().sending -= 1;
+        });
+    }
+}
+
+/// Reads the attach request a framing client may send when it connects.
+fn read_attach(stream: &mut Conn, peer: SocketAddr) -> framing::Attach {
+    let mut req = [0; framing::ATTACH_LEN];
+    let attach = stream
+        .read_exact(&mut req)
+        .and_then(|_| framing::Attach::decode(&req));
+
+    match attach {
+        Ok(start) => {
+            log::debug!("client {} attaching: {:?}", peer, start);
+            start
+        }
+        Err(e) => {
+            log::debug!("no attach request from {}: {}", peer, e);
+            framing::Attach::Live
+        }
+    }
+}
+
+/// Describes how capture is going, as a line of JSON, for a client that
+/// asked for the server's status.
+fn status_json(progress: Option<&Progress>) -> String {
+    let progress = match progress {
+        Some(p) => p,
+        None => return "{}\n".to_string(),
+    };
+    let stats = progress.snapshot();
+    let recent: Vec<_> = capture::RECENT_SPANS
+        .iter()
+        .map(|&s| progress.recent(s))
+        .collect();
+    format!(
+        "{{\"bytes\":{},\"gaps\":{}{}}}\n",
+        stats.bytes,
+        stats.gaps,
+        telemetry::recent_json(&recent)
+    )
+}
+
+/// Closes a `Server`, from `Server::closer`.
+pub struct Closer {
+    shared: Arc<Mutex<Shared>>,
+}
+
+impl Closer {
+    /// Stops taking new clients, and waits until `deadline` for those
+    /// connected to be sent what's queued for them, then disconnects them.
+    /// Clients that are still being sent data at the deadline are cut off
+    /// when we exit.
+    pub fn close(self, deadline: Instant) {
+        {
+            let mut shared = self.shared.lock().unwrap();
+            shared.closed = true;
+            // Client threads finish once they've sent what's queued.
+            shared.clients.clear();
+        }
+        loop {
+            let sending = self.shared.lock().unwrap().sending;
+            if sending == 0 {
+                return;
+            }
+            if Instant::now() >= deadline {
+                log::warn!(
+                    "{} clients still being sent data, giving up on them",
+                    sending
+                );
+                return;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
+
+fn client_loop(
+    mut stream: Conn,
+    backlog: Vec<Arc<[u8]>>,
+    rx: Receiver<Arc<[u8]>>,
+) {
+    // Dropping `rx` on the way out (because the write failed) is what tells
+    // the broadcaster this client is gone.
+    for chunk in backlog.into_iter().chain(rx) {
+        if stream.write_all(&chunk).is_err() {
+            break;
+        }
+    }
+}
+
+/// A connection to a client, which may or may not be encrypted.
+enum Conn {
+    Plain(TcpStream),
+    #[cfg(feature = "tls")]
+    Tls(Box<rustls::StreamOwned<rustls::ServerConnection, TcpStream>>),
+}
+
+impl Conn {
+    #[cfg_attr(not(feature = "tls"), allow(unused_variables))]
+    fn new(stream: TcpStream, config: &Config) -> io::Result<Self> {
+        #[cfg(feature = "tls")]
+        if let Some(tls) = &config.tls {
+            let conn = rustls::ServerConnection::new(Arc::clone(tls))
+                .map_err(io::Error::other)?;
+            return Ok(Conn::Tls(Box::new(rustls::StreamOwned::new(
+                conn, stream,
+            ))));
+        }
+        Ok(Conn::Plain(stream))
+    }
+
+    fn tcp(&self) -> &TcpStream {
+        match self {
+            Conn::Plain(s) => s,
+            #[cfg(feature = "tls")]
+            Conn::Tls(s) => &s.sock,
+        }
+    }
+}
+
+impl Read for Conn {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Conn::Plain(s) => s.read(buf),
+            #[cfg(feature = "tls")]
+            Conn::Tls(s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for Conn {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Conn::Plain(s) => s.write(buf),
+            #[cfg(feature = "tls")]
+            Conn::Tls(s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Conn::Plain(s) => s.flush(),
+            #[cfg(feature = "tls")]
+            Conn::Tls(s) => s.flush(),
+        }
+    }
+}
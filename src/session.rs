@@ -0,0 +1,64 @@
+//! An ID for each run of the program, so that what it leaves behind (log
+//! lines, summaries, status logs, recordings) can be matched up afterwards,
+//! wherever it ended up.
+//!
+//! The ID is a random (version 4) UUID, made the first time it's asked for.
+
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
+use std::process;
+use std::sync::OnceLock;
+use std::time::SystemTime;
+
+/// A session ID.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Id(pub [u8; 16]);
+
+impl Id {
+    /// Makes a new random ID.
+    pub fn random() -> Self {
+        // `RandomState`'s keys come from the operating system's random
+        // number generator (once per thread; later ones are the same keys,
+        // counted up), which saves depending on a crate for it. The time and
+        // process ID are only there for good measure.
+        let half = |salt: u64| {
+            let mut hasher = RandomState::new().build_hasher();
+            hasher.write_u64(salt);
+            hasher.write_u32(process::id());
+            if let Ok(t) =
+                SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)
+            {
+                hasher.write_u128(t.as_nanos());
+            }
+            hasher.finish().to_le_bytes()
+        };
+        let mut bytes = [0; 16];
+        bytes[..8].copy_from_slice(&half(0));
+        bytes[8..].copy_from_slice(&half(1));
+        // Version 4, variant 1 (RFC 4122).
+        bytes[6] = (bytes[6] & 0x0f) | 0x40;
+        bytes[8] = (bytes[8] & 0x3f) | 0x80;
+        Id(bytes)
+    }
+}
+
+impl fmt::Display for Id {
+    /// Formats the ID the usual way, e.g.
+    /// `0f8fad5b-d9cb-469f-a165-70867728950e`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, b) in self.0.iter().enumerate() {
+            if let 4 | 6 | 8 | 10 = i {
+                f.write_str("-")?;
+            }
+            write!(f, "{:02x}", b)?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns the ID of this session.
+pub fn id() -> Id {
+    static ID: OnceLock<Id> = OnceLock::new();
+    *ID.get_or_init(Id::random)
+}
@@ -0,0 +1,169 @@
+//! Winding down a capture in a fixed order, so that the tail of the capture
+//! makes it all the way out before anything reports on it.
+//!
+//! The stages, in order, are:
+//!
+//! 1. `Polling`: the polling thread stops, and lets go of the probe.
+//! 2. `Draining`: whatever is still queued between the polling thread and
+//!    the sink is passed to the sink.
+//! 3. `Flushing`: sinks write out what they've held on to: buffered output,
+//!    half-finished lines, digests, tables and so on. Most do this when
+//!    they're dropped.
+//! 4. `Servers`: network clients are sent what's queued for them, and
+//!    disconnected.
+//! 5. `Reports`: summaries of the whole capture are written.
+//!
+//! `capture::run` goes through the first two, and whoever called it goes
+//! through the rest with the same `Shutdown` (see `capture::Config`). Each
+//! stage is logged as it starts, and each has a timeout. Stages spent
+//! waiting for other threads give up on them at the timeout, so that a
+//! stuck thread can't keep everything else from finishing; others can't be
+//! cut short, but are logged as stuck once they run over.
+
+use std::fmt;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// A stage of shutdown.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Stage {
+    Polling,
+    Draining,
+    Flushing,
+    Servers,
+    Reports,
+}
+
+impl Stage {
+    /// Returns how long the stage should take at most.
+    pub fn timeout(self) -> Duration {
+        Duration::from_secs(match self {
+            Stage::Polling => 2,
+            Stage::Draining => 10,
+            Stage::Flushing => 10,
+            Stage::Servers => 5,
+            Stage::Reports => 2,
+        })
+    }
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Stage::Polling => "stopping polling",
+            Stage::Draining => "draining the queue",
+            Stage::Flushing => "flushing sinks",
+            Stage::Servers => "stopping servers",
+            Stage::Reports => "writing reports",
+        })
+    }
+}
+
+/// Keeps track of shutdown as it goes through its `Stage`s. Clones share
+/// the same progress, so stages can be marked from different threads.
+#[derive(Clone, Debug, Default)]
+pub struct Shutdown(Arc<Mutex<State>>);
+
+#[derive(Debug, Default)]
+struct State {
+    /// The stage under way, and when it started.
+    current: Option<(Stage, Instant)>,
+    /// When the first stage started.
+    started: Option<Instant>,
+    /// Where to tell the watchdog about new stages, once it's running.
+    watchdog: Option<Sender<Option<(Stage, Instant)>>>,
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Moves on to `stage`, logging how long the last one took, and returns
+    /// when `stage` should be over by. Stages are expected to come in
+    /// order, but can be skipped.
+    pub fn stage(&self, stage: Stage) -> Instant {
+        let mut state = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        let now = Instant::now();
+        if let Some((last, started)) = state.current {
+            if stage < last {
+                log::warn!("shutdown: {} after {}", stage, last);
+            }
+            log::debug!("shutdown: {} took {:?}", last, now - started);
+        }
+        log::debug!("shutdown: {}", stage);
+        state.current = Some((stage, now));
+        state.started.get_or_insert(now);
+        let watchdog = state.watchdog.get_or_insert_with(|| {
+            let (tx, rx) = channel();
+            thread::spawn(move || watch(rx));
+            tx
+        });
+        watchdog.send(Some((stage, now))).ok();
+        now + stage.timeout()
+    }
+
+    /// Notes that shutdown is over, logging how long it took.
+    pub fn finish(&self) {
+        let mut state = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        let now = Instant::now();
+        if let Some((last, started)) = state.current.take() {
+            log::debug!("shutdown: {} took {:?}", last, now - started);
+        }
+        if let Some(started) = state.started.take() {
+            log::debug!("shutdown: done in {:?}", now - started);
+        }
+        if let Some(watchdog) = state.watchdog.take() {
+            watchdog.send(None).ok();
+        }
+    }
+}
+
+/// Warns about stages that run over their timeouts, as they're announced on
+/// `rx`, until told shutdown is over.
+fn watch(rx: Receiver<Option<(Stage, Instant)>>) {
+    let mut current: Option<(Stage, Instant)> = None;
+    loop {
+        let next = match current {
+            Some((stage, started)) => {
+                let deadline = started + stage.timeout();
+                let wait = deadline.saturating_duration_since(Instant::now());
+                match rx.recv_timeout(wait) {
+                    Ok(next) => next,
+                    Err(RecvTimeoutError::Timeout) => {
+                        log::warn!(
+                            "shutdown: still {} after {:?}",
+                            stage,
+                            stage.timeout()
+                        );
+                        None
+                    }
+                    Err(RecvTimeoutError::Disconnected) => return,
+                }
+            }
+            None => match rx.recv() {
+                Ok(next) => next,
+                Err(_) => return,
+            },
+        };
+        current = next;
+    }
+}
+
+/// Waits until `deadline` for `thread` to finish, and returns what it
+/// returned (or how it panicked), or gives the thread back if it's still
+/// going.
+pub fn join_by<T>(
+    thread: JoinHandle<T>,
+    deadline: Instant,
+) -> Result<thread::Result<T>, JoinHandle<T>> {
+    while !thread.is_finished() {
+        if Instant::now() >= deadline {
+            return Err(thread);
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    Ok(thread.join())
+}
@@ -0,0 +1,236 @@
+//! Long-running captures for qualifying probes and cables, which check that
+//! the stream adds up as it goes, and report on how it went.
+//!
+//! The checks are independent of the ones `capture` uses to find gaps, so
+//! they can catch mistakes there as well as misbehaving probes:
+//!
+//! - Between gaps, each fragment must pick up where the last one left off in
+//!   the probe's buffer, either in the same epoch, or at the start of the
+//!   next epoch once the buffer was full.
+//! - Each fragment must hold as many bytes as its offsets say.
+//! - Fill levels must make sense for the buffer.
+
+use std::io;
+use std::time::{Duration, Instant};
+
+use crate::capture::{FillLevels, Fragment, Gap, Sink, Stats};
+use crate::protocol::CAPTURE_BUFFER_SIZE;
+use crate::timestamp::Clock;
+
+/// Sink that checks the stream passing through it on its way to another,
+/// and writes checkpoint statistics to stderr every so often.
+///
+/// Checkpoints are only written when something arrives, so capture should
+/// pass on fill levels (see `capture::Config::fill_levels`) to make sure
+/// that happens even when the target is quiet.
+pub struct Soak {
+    inner: Box<dyn Sink>,
+    started: Instant,
+    interval: Duration,
+    next_checkpoint: Instant,
+    counts: Counts,
+    /// Epoch and end offset of the last fragment, unless there's been a gap
+    /// since.
+    last: Option<(u8, u16)>,
+}
+
+/// What a soak test has seen so far.
+#[derive(Clone, Debug, Default)]
+pub struct Counts {
+    pub bytes: u64,
+    pub fragments: u64,
+    pub gaps: u64,
+    pub violations: u64,
+    pub bit_rate_changes: u64,
+    bit_rate: Option<u32>,
+}
+
+impl Soak {
+    /// Passes the stream on to `inner`, writing a checkpoint every
+    /// `interval`.
+    pub fn new(inner: Box<dyn Sink>, interval: Duration) -> Self {
+        let started = Instant::now();
+        Self {
+            inner,
+            started,
+            interval,
+            next_checkpoint: started + interval,
+            counts: Counts::default(),
+            last: None,
+        }
+    }
+
+    /// Returns what's been seen so far.
+    pub fn counts(&self) -> &Counts {
+        &self.counts
+    }
+
+    /// Gives back the sink the stream was passed on to.
+    pub fn into_inner(self) -> Box<dyn Sink> {
+        self.inner
+    }
+
+    fn violation(&mut self, what: std::fmt::Arguments<'_>) {
+        self.counts.violations += 1;
+        log::warn!("soak: invariant violated: {}", what);
+    }
+
+    fn check_fragment(&mut self, info: &Fragment, len: usize) {
+        if info.end < info.start
+            || usize::from(info.end) > CAPTURE_BUFFER_SIZE
+            || usize::from(info.end - info.start) != len
+        {
+            self.violation(format_args!(
+                "fragment {:02x}:{:03x}-{:03x} holds {} bytes",
+                info.epoch, info.start, info.end, len
+            ));
+        }
+        if let Some((epoch, end)) = self.last {
+            let same_epoch = info.epoch == epoch && info.start == end;
+            let next_epoch = info.epoch == epoch.wrapping_add(1)
+                && info.start == 0
+                && usize::from(end) == CAPTURE_BUFFER_SIZE;
+            if !same_epoch && !next_epoch {
+                self.violation(format_args!(
+                    "fragment {:02x}:{:03x} doesn't follow {:02x}:{:03x} \
+                     and no gap was reported",
+                    info.epoch, info.start, epoch, end
+                ));
+            }
+        }
+        self.last = Some((info.epoch, info.end));
+    }
+
+    fn check_fill_levels(&mut self, levels: &FillLevels) {
+        if levels.end < levels.start
+            || usize::from(levels.end) > CAPTURE_BUFFER_SIZE
+        {
+            self.violation(format_args!(
+                "fill levels {:02x}:{:03x}-{:03x} are out of range",
+                levels.epoch, levels.start, levels.end
+            ));
+        }
+    }
+
+    /// Counts a gap, after which the next fragment can start anywhere.
+    fn note_gap(&mut self) {
+        self.counts.gaps += 1;
+        self.last = None;
+        self.maybe_checkpoint();
+    }
+
+    fn maybe_checkpoint(&mut self) {
+        let now = Instant::now();
+        if now < self.next_checkpoint {
+            return;
+        }
+        while self.next_checkpoint <= now {
+            self.next_checkpoint += self.interval;
+        }
+        let elapsed = now.duration_since(self.started);
+        eprintln!(
+            "soak: {}: {} bytes in {} fragments, {} gaps, {} violations",
+            format_elapsed(elapsed),
+            self.counts.bytes,
+            self.counts.fragments,
+            self.counts.gaps,
+            self.counts.violations,
+        );
+    }
+}
+
+impl Sink for Soak {
+    fn data(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.counts.bytes += bytes.len() as u64;
+        self.maybe_checkpoint();
+        self.inner.data(bytes)
+    }
+
+    fn fragment(&mut self, info: &Fragment, bytes: &[u8]) -> io::Result<()> {
+        self.check_fragment(info, bytes.len());
+        self.counts.bytes += bytes.len() as u64;
+        self.counts.fragments += 1;
+        self.maybe_checkpoint();
+        self.inner.fragment(info, bytes)
+    }
+
+    fn gap(&mut self) -> io::Result<()> {
+        self.note_gap();
+        self.inner.gap()
+    }
+
+    fn lost(&mut self, gap: &Gap) -> io::Result<()> {
+        self.note_gap();
+        self.inner.lost(gap)
+    }
+
+    fn bit_rate(&mut self, rate: u32) -> io::Result<()> {
+        if self.counts.bit_rate.is_some_and(|r| r != rate) {
+            self.counts.bit_rate_changes += 1;
+        }
+        self.counts.bit_rate = Some(rate);
+        self.inner.bit_rate(rate)
+    }
+
+    fn fill_levels(&mut self, levels: &FillLevels) -> io::Result<()> {
+        self.check_fill_levels(levels);
+        self.maybe_checkpoint();
+        self.inner.fill_levels(levels)
+    }
+
+    fn clock(&mut self, clock: Clock) -> io::Result<()> {
+        self.inner.clock(clock)
+    }
+}
+
+/// The outcome of a soak test.
+pub struct Verdict {
+    /// Everything that went wrong; empty if the test passed.
+    pub problems: Vec<String>,
+}
+
+impl Verdict {
+    /// Judges a soak test from what it saw, and the capture's statistics
+    /// (if it ended without error). Any lost data, reconnection or
+    /// violated invariant is a failure.
+    pub fn new(counts: &Counts, stats: Option<&Stats>) -> Self {
+        let mut problems = vec![];
+        let mut problem = |n: u64, what: &str| {
+            if n > 0 {
+                problems.push(format!("{} {}", n, what));
+            }
+        };
+        problem(counts.violations, "invariant violations");
+        problem(counts.gaps, "gaps in the data");
+        problem(counts.bit_rate_changes, "bit rate changes");
+        match stats {
+            Some(stats) => {
+                problem(stats.probe_gaps, "sync losses");
+                problem(stats.host_drops, "host-side drops");
+                problem(stats.reconnects, "reconnects");
+            }
+            None => problems.push("capture failed".to_string()),
+        }
+        Self { problems }
+    }
+
+    pub fn passed(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+impl std::fmt::Display for Verdict {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.passed() {
+            write!(f, "PASS")
+        } else {
+            write!(f, "FAIL: {}", self.problems.join(", "))
+        }
+    }
+}
+
+/// Formats a duration as hours, minutes and seconds.
+fn format_elapsed(d: Duration) -> String {
+    let s = d.as_secs();
+    format!("{}h{:02}m{:02}s", s / 3600, s / 60 % 60, s % 60)
+}
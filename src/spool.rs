@@ -0,0 +1,199 @@
+//! Rotating recordings, so that a long-running capture keeps its recent past
+//! on disk without filling it up.
+
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::capture::{FillLevels, Fragment, Gap, Sink};
+use crate::recording;
+use crate::timestamp::Clock;
+
+const PREFIX: &str = "swo-";
+const SUFFIX: &str = ".rec";
+
+type SpoolWriter = recording::Writer<Counting<BufWriter<File>>>;
+
+/// Spooling options.
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// Directory to write recordings into.
+    pub dir: PathBuf,
+    /// Start a new file once the current one reaches this many bytes.
+    pub file_size: u64,
+    /// Keep at most this many files, deleting the oldest.
+    pub files: usize,
+}
+
+/// Sink that writes recordings into a directory, starting a new file when
+/// the current one gets big and deleting the oldest ones as needed.
+///
+/// Files are named `swo-<unix time>-<sequence>.rec`, so that they sort in the
+/// order they were written. Files left over from earlier runs count towards
+/// the limit.
+///
+/// If writing fails, the spool reports it and stops, rather than failing the
+/// capture.
+pub struct Spool {
+    config: Config,
+    current: Option<SpoolWriter>,
+    sequence: u32,
+    failed: bool,
+    /// Bit rate to note at the start of each file, once we know it.
+    bit_rate: Option<u32>,
+}
+
+impl Spool {
+    pub fn new(config: Config) -> io::Result<Self> {
+        fs::create_dir_all(&config.dir)?;
+        let mut spool = Self {
+            config,
+            current: None,
+            sequence: 0,
+            failed: false,
+            bit_rate: None,
+        };
+        spool.rotate()?;
+        Ok(spool)
+    }
+
+    /// Starts a new file, and deletes old ones beyond the limit.
+    fn rotate(&mut self) -> io::Result<()> {
+        if let Some(old) = self.current.take() {
+            old.into_inner().inner.flush()?;
+        }
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        let name = format!("{}{}-{:04}{}", PREFIX, now, self.sequence, SUFFIX);
+        self.sequence = self.sequence.wrapping_add(1);
+        let path = self.config.dir.join(name);
+        log::info!("spooling to {}", path.display());
+        let file = BufWriter::new(File::create(&path)?);
+        let mut writer = recording::Writer::new(Counting {
+            inner: file,
+            count: 0,
+        })?;
+        if let Some(rate) = self.bit_rate {
+            writer.bit_rate(rate)?;
+        }
+        self.current = Some(writer);
+
+        let mut existing = spool_files(&self.config.dir)?;
+        existing.sort();
+        let excess = existing.len().saturating_sub(self.config.files.max(1));
+        for old in &existing[..excess] {
+            log::debug!("removing old spool file {}", old.display());
+            fs::remove_file(old)?;
+        }
+        Ok(())
+    }
+
+    /// Runs `f` on the current file, giving up on spooling if it fails.
+    fn with_current(
+        &mut self,
+        f: impl FnOnce(&mut SpoolWriter) -> io::Result<()>,
+    ) -> io::Result<()> {
+        if self.failed {
+            return Ok(());
+        }
+        if let Err(e) = self.write(f) {
+            log::warn!(
+                "spooling to {} failed, giving up: {}",
+                self.config.dir.display(),
+                e
+            );
+            self.failed = true;
+            self.current = None;
+        }
+        Ok(())
+    }
+
+    /// Runs `f` on the current file, rotating first if it's full.
+    fn write(
+        &mut self,
+        f: impl FnOnce(&mut SpoolWriter) -> io::Result<()>,
+    ) -> io::Result<()> {
+        let full = match &self.current {
+            Some(w) => w.get_ref().count >= self.config.file_size,
+            None => true,
+        };
+        if full {
+            self.rotate()?;
+        }
+        f(self.current.as_mut().unwrap())
+    }
+}
+
+impl Sink for Spool {
+    fn data(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.with_current(|w| w.data(bytes))
+    }
+
+    fn fragment(&mut self, info: &Fragment, bytes: &[u8]) -> io::Result<()> {
+        self.with_current(|w| w.fragment(info, bytes))
+    }
+
+    fn gap(&mut self) -> io::Result<()> {
+        self.with_current(|w| w.gap())
+    }
+
+    fn lost(&mut self, gap: &Gap) -> io::Result<()> {
+        self.with_current(|w| w.lost(gap))
+    }
+
+    fn bit_rate(&mut self, rate: u32) -> io::Result<()> {
+        self.bit_rate = Some(rate);
+        self.with_current(|w| w.bit_rate(rate))
+    }
+
+    fn fill_levels(&mut self, levels: &FillLevels) -> io::Result<()> {
+        self.with_current(|w| w.fill_levels(levels))
+    }
+
+    fn clock(&mut self, clock: Clock) -> io::Result<()> {
+        self.with_current(|w| w.clock(clock))
+    }
+}
+
+impl Drop for Spool {
+    fn drop(&mut self) {
+        if let Some(w) = self.current.take() {
+            w.into_inner().inner.flush().ok();
+        }
+    }
+}
+
+/// Lists the spool files in `dir`.
+fn spool_files(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut files = vec![];
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        if name.starts_with(PREFIX) && name.ends_with(SUFFIX) {
+            files.push(entry.path());
+        }
+    }
+    Ok(files)
+}
+
+/// Writer that counts the bytes written through it.
+struct Counting<W> {
+    inner: W,
+    count: u64,
+}
+
+impl<W: Write> Write for Counting<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.count += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
@@ -0,0 +1,227 @@
+//! Summaries of recordings, for triaging a pile of them without decoding
+//! each one.
+//!
+//! Stimulus ports are found by decoding the data as ITM, which is only
+//! meaningful if that's what the target was sending.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{self, Read};
+use std::time::{Duration, SystemTime};
+
+use crate::capture::GapCause;
+use crate::itm;
+use crate::recording::{Reader, RecordKind};
+use crate::session;
+use crate::timestamp;
+
+/// Widest bar drawn for throughput over time.
+const BAR_WIDTH: u64 = 40;
+
+/// Width of the labels on the left, and the lines they're on.
+const LABEL_WIDTH: usize = 20;
+const LINE_WIDTH: usize = 80;
+
+/// What's in a recording.
+#[derive(Clone, Debug, Default)]
+pub struct Summary {
+    /// The program that made the recording.
+    pub producer: Option<String>,
+    /// The session that made the recording, if it says.
+    pub session: Option<session::Id>,
+    /// Wall clock time when the recording started, if it says.
+    pub started: Option<SystemTime>,
+    /// Time from the start of the recording to its last record.
+    pub duration: Duration,
+    /// Bytes of data, and the number of records they arrived in.
+    pub bytes: u64,
+    pub fragments: u64,
+    /// Bit rates captured at, in the order they were used.
+    pub bit_rates: Vec<u32>,
+    /// Bytes that arrived in each of a number of equal slices of `duration`.
+    pub buckets: Vec<u64>,
+    /// Gaps, by cause, where known.
+    pub gaps: BTreeMap<Option<GapCause>, u64>,
+    /// Overflow packets in the ITM stream, meaning the target itself dropped
+    /// trace data.
+    pub itm_overflows: u64,
+    /// Bytes written to each ITM stimulus port.
+    pub ports: BTreeMap<u8, u64>,
+    /// Whether the recording holds decoded output (see `cat --record`).
+    pub decoded: bool,
+}
+
+impl Summary {
+    /// Reads `recording` to the end, splitting it into `buckets` slices of
+    /// time for throughput.
+    pub fn read(
+        mut recording: Reader<impl Read>,
+        buckets: usize,
+    ) -> io::Result<Self> {
+        let mut summary = Self::default();
+        let mut itm = itm::Decoder::new();
+        // When each piece of data arrived, and how much there was, so that
+        // it can be put into buckets once we know how long the recording is.
+        let mut arrivals = vec![];
+        while let Some(record) = recording.next_record()? {
+            summary.duration = summary.duration.max(record.time);
+            match record.kind {
+                RecordKind::Data { data, .. } => {
+                    summary.bytes += data.len() as u64;
+                    summary.fragments += 1;
+                    arrivals.push((record.time, data.len() as u64));
+                    let (ports, overflows) =
+                        (&mut summary.ports, &mut summary.itm_overflows);
+                    itm.feed(&data, |packet| match packet {
+                        itm::Packet::Instrumentation { port, payload } => {
+                            *ports.entry(port).or_default() +=
+                                payload.len() as u64;
+                        }
+                        itm::Packet::Overflow => *overflows += 1,
+                        _ => (),
+                    });
+                }
+                RecordKind::Gap(cause) => {
+                    *summary.gaps.entry(cause).or_default() += 1;
+                    itm.reset();
+                }
+                RecordKind::BitRate(rate) => summary.bit_rates.push(rate),
+                RecordKind::Session(id) => {
+                    summary.session.get_or_insert(id);
+                }
+                RecordKind::Info { producer } => {
+                    summary.producer.get_or_insert(producer);
+                }
+                RecordKind::WallClock(wall) => {
+                    if summary.started.is_none() {
+                        summary.started = wall.checked_sub(record.time);
+                    }
+                }
+                RecordKind::Decoded { .. } => summary.decoded = true,
+                RecordKind::FillLevels { .. } => (),
+            }
+        }
+
+        summary.buckets = vec![0; buckets.max(1)];
+        let n = summary.buckets.len() as u128;
+        let total = summary.duration.as_micros().max(1);
+        for (time, len) in arrivals {
+            let i = (time.as_micros() * n / total).min(n - 1);
+            summary.buckets[i as usize] += len;
+        }
+        Ok(summary)
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let secs = self.duration.as_secs_f64();
+        let rate = if secs > 0.0 {
+            self.bytes as f64 / secs
+        } else {
+            0.0
+        };
+        if let Some(producer) = &self.producer {
+            writeln!(f, "recorded by:        {}", producer)?;
+        }
+        if let Some(session) = self.session {
+            writeln!(f, "session:            {}", session)?;
+        }
+        if let Some(started) = self.started {
+            writeln!(f, "started:            {}", timestamp::format(started))?;
+        }
+        writeln!(f, "duration:           {:.1} s", secs)?;
+        writeln!(
+            f,
+            "bytes captured:     {} in {} fragments",
+            self.bytes, self.fragments
+        )?;
+        writeln!(f, "average throughput: {:.0} bytes/s", rate)?;
+        let rates: Vec<String> =
+            self.bit_rates.iter().map(|r| r.to_string()).collect();
+        match rates.is_empty() {
+            true => writeln!(f, "bit rate:           unknown")?,
+            false => writeln!(f, "bit rate:           {}", rates.join(", "))?,
+        }
+
+        let total: u64 = self.gaps.values().sum();
+        let causes: Vec<String> = self
+            .gaps
+            .iter()
+            .map(|(cause, n)| {
+                let cause = cause.map_or("unknown cause", GapCause::name);
+                format!("{} {}", n, cause)
+            })
+            .collect();
+        match total {
+            0 => writeln!(f, "gaps:               none")?,
+            _ => writeln!(
+                f,
+                "gaps:               {} ({})",
+                total,
+                causes.join(", ")
+            )?,
+        }
+        writeln!(f, "ITM overflows:      {}", self.itm_overflows)?;
+        let ports: Vec<String> = self
+            .ports
+            .iter()
+            .map(|(port, n)| format!("{} ({} bytes)", port, n))
+            .collect();
+        match ports.is_empty() {
+            true => writeln!(f, "stimulus ports:     none seen")?,
+            false => list(f, "stimulus ports:", &ports)?,
+        }
+        writeln!(
+            f,
+            "decoded output:     {}",
+            if self.decoded { "yes" } else { "no" }
+        )?;
+
+        if self.bytes == 0 {
+            return Ok(());
+        }
+        writeln!(f, "throughput over time (bytes/s):")?;
+        let slice = secs / self.buckets.len() as f64;
+        let most = self.buckets.iter().copied().max().unwrap_or(0).max(1);
+        for (i, &bytes) in self.buckets.iter().enumerate() {
+            let rate = if slice > 0.0 {
+                bytes as f64 / slice
+            } else {
+                0.0
+            };
+            let line = format!(
+                "  {:>9.3} s {:>10.0} {}",
+                i as f64 * slice,
+                rate,
+                "#".repeat((bytes * BAR_WIDTH / most) as usize)
+            );
+            writeln!(f, "{}", line.trim_end())?;
+        }
+        Ok(())
+    }
+}
+
+/// Writes `items` after `label`, separated by commas, wrapping lines to fit.
+fn list(
+    f: &mut fmt::Formatter<'_>,
+    label: &str,
+    items: &[String],
+) -> fmt::Result {
+    let mut line = format!("{:<width$}", label, width = LABEL_WIDTH);
+    for (i, item) in items.iter().enumerate() {
+        let item = match i + 1 == items.len() {
+            true => item.clone(),
+            false => format!("{},", item),
+        };
+        if line.len() > LABEL_WIDTH && line.len() + 1 + item.len() > LINE_WIDTH
+        {
+            writeln!(f, "{}", line)?;
+            line = " ".repeat(LABEL_WIDTH);
+        } else if line.len() > LABEL_WIDTH {
+            line.push(' ');
+        }
+        line.push_str(&item);
+    }
+    writeln!(f, "{}", line)
+}
@@ -0,0 +1,169 @@
+//! Adapter letting probe-rs based tools read SWO through the LPC-Link2's
+//! trace interface, which probe-rs's generic CMSIS-DAP support doesn't know
+//! about.
+//!
+//! probe-rs still does the target side of the setup (through its own
+//! CMSIS-DAP connection to the probe); this just takes care of capture.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use probe_rs::architecture::arm::{ArmError, SwoAccess, SwoConfig, SwoMode};
+
+use crate::capture::{self, Sink};
+use crate::protocol;
+use crate::select::Selector;
+use crate::{Error, Handle};
+
+/// Chunks of data that can be waiting to be read before we start dropping
+/// them. Each is at most about 1 KiB.
+const QUEUE_DEPTH: usize = 1024;
+
+/// The trace interface of an LPC-Link2, as a probe-rs `SwoAccess`.
+///
+/// The probe is opened when SWO is enabled, and captured from on a
+/// background thread until it's disabled, so that the probe's buffer doesn't
+/// overflow between reads.
+pub struct LpcLinkSwo {
+    vid: u16,
+    pid: u16,
+    selector: Selector,
+    capture: Option<Capture>,
+}
+
+struct Capture {
+    rx: Receiver<Vec<u8>>,
+    stop: Arc<AtomicBool>,
+    thread: JoinHandle<Result<(), Error>>,
+}
+
+impl LpcLinkSwo {
+    /// Prepares to capture from the probe with the given vid/pid that
+    /// satisfies `selector`. Nothing is opened until SWO is enabled.
+    pub fn new(vid: u16, pid: u16, selector: Selector) -> Self {
+        Self {
+            vid,
+            pid,
+            selector,
+            capture: None,
+        }
+    }
+
+    fn start(&mut self, config: &SwoConfig) -> Result<(), Error> {
+        self.stop()?;
+
+        let handle = Handle::open(self.vid, self.pid, &self.selector)?;
+        match config.mode() {
+            SwoMode::Uart => {
+                handle.ohai(protocol::MODE_UART)?;
+                handle.init_uart()?;
+                let actual = handle.set_bit_rate(config.baud())?;
+                if actual != config.baud() {
+                    return Err(Error::UnachievableBitRate {
+                        requested: config.baud(),
+                        closest: actual,
+                    });
+                }
+            }
+            SwoMode::Manchester => handle.init_manchester()?,
+        }
+
+        let (tx, rx) = sync_channel(QUEUE_DEPTH);
+        let stop = Arc::new(AtomicBool::new(false));
+        let capture_config = capture::Config {
+            stop: Some(Arc::clone(&stop)),
+            ..capture::Config::default()
+        };
+        let thread = thread::spawn(move || {
+            let mut sink = Forward(tx);
+            capture::run(handle, &capture_config, &mut sink).map(drop)
+        });
+        self.capture = Some(Capture { rx, stop, thread });
+        Ok(())
+    }
+
+    fn stop(&mut self) -> Result<(), Error> {
+        match self.capture.take() {
+            Some(capture) => {
+                capture.stop.store(true, Ordering::Relaxed);
+                drop(capture.rx);
+                match capture.thread.join().expect("capture thread panicked") {
+                    // That'll be us hanging up.
+                    Err(Error::Io(e)) if capture::is_closed(&e) => Ok(()),
+                    r => r,
+                }
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn read(&mut self, timeout: Duration) -> Result<Vec<u8>, Error> {
+        let capture = match &self.capture {
+            Some(c) => c,
+            None => return Ok(vec![]),
+        };
+        let mut data = match capture.rx.recv_timeout(timeout) {
+            Ok(data) => data,
+            Err(RecvTimeoutError::Timeout) => return Ok(vec![]),
+            Err(RecvTimeoutError::Disconnected) => {
+                // Capture stopped; find out why.
+                self.stop()?;
+                return Ok(vec![]);
+            }
+        };
+        // Hand over everything that's arrived, not just the first chunk.
+        data.extend(capture.rx.try_iter().flatten());
+        Ok(data)
+    }
+}
+
+impl SwoAccess for LpcLinkSwo {
+    fn enable_swo(&mut self, config: &SwoConfig) -> Result<(), ArmError> {
+        self.start(config).map_err(to_arm)
+    }
+
+    fn disable_swo(&mut self) -> Result<(), ArmError> {
+        self.stop().map_err(to_arm)
+    }
+
+    fn read_swo_timeout(
+        &mut self,
+        timeout: Duration,
+    ) -> Result<Vec<u8>, ArmError> {
+        self.read(timeout).map_err(to_arm)
+    }
+
+    fn swo_buffer_size(&mut self) -> Option<usize> {
+        Some(protocol::CAPTURE_BUFFER_SIZE)
+    }
+}
+
+impl Drop for LpcLinkSwo {
+    fn drop(&mut self) {
+        let _ = self.stop();
+    }
+}
+
+fn to_arm(e: Error) -> ArmError {
+    ArmError::Other(e.into())
+}
+
+/// Sink handing captured data to the reader.
+struct Forward(std::sync::mpsc::SyncSender<Vec<u8>>);
+
+impl Sink for Forward {
+    fn data(&mut self, bytes: &[u8]) -> std::io::Result<()> {
+        self.0.send(bytes.to_vec()).map_err(|_| {
+            std::io::Error::new(std::io::ErrorKind::BrokenPipe, "reader gone")
+        })
+    }
+
+    fn gap(&mut self) -> std::io::Result<()> {
+        // probe-rs has no way to hear about this.
+        log::warn!("SWO data lost");
+        Ok(())
+    }
+}
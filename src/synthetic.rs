@@ -0,0 +1,172 @@
+//! A stand-in for the probe that makes up data, for exercising everything
+//! downstream of it without hardware.
+//!
+//! The data is delivered the way the probe would deliver it: through a
+//! 1022-byte capture buffer, in incremental responses and a final flush per
+//! epoch. If the poller falls too far behind, data is lost and the epoch
+//! skips ahead, just as it would on the real thing.
+
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+use std::str::FromStr;
+use std::time::Instant;
+
+use crate::capture::Source;
+use crate::{Error, PollResult};
+
+/// Size of the probe's capture buffer.
+const BUFFER_LEN: usize = 1022;
+
+/// Bits each byte takes on the wire, as with UART SWO (start, 8 data, stop).
+const BITS_PER_BYTE: u128 = 10;
+
+/// What data to make up.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Pattern {
+    /// Bytes counting up from 0, wrapping around after 255.
+    Counter,
+    /// Numbered lines of text written to ITM stimulus port 0, one byte per
+    /// packet.
+    Itm,
+}
+
+impl FromStr for Pattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "counter" => Ok(Pattern::Counter),
+            "itm" => Ok(Pattern::Itm),
+            _ => Err(format!("unknown pattern `{}`", s)),
+        }
+    }
+}
+
+/// Synthetic data source options.
+#[derive(Copy, Clone, Debug)]
+pub struct Config {
+    pub pattern: Pattern,
+    /// Simulated SWO bit rate, in bits per second.
+    pub rate: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            pattern: Pattern::Counter,
+            rate: 1_000_000,
+        }
+    }
+}
+
+/// A source of made-up data that behaves like the probe.
+pub struct Synthetic {
+    config: Config,
+    started: Instant,
+    /// Bytes generated so far, whether or not they were delivered.
+    generated: u64,
+    /// Bytes of the current ITM line not yet generated.
+    pending: VecDeque<u8>,
+    /// Number of the next ITM line.
+    line: u64,
+    epoch: u8,
+    buffer: [u8; BUFFER_LEN],
+    fill: usize,
+}
+
+impl Synthetic {
+    /// # Panics
+    ///
+    /// If `config.rate` is zero.
+    pub fn new(config: Config) -> Self {
+        assert!(config.rate > 0);
+        Self {
+            config,
+            started: Instant::now(),
+            generated: 0,
+            pending: VecDeque::new(),
+            line: 0,
+            epoch: 1,
+            buffer: [0; BUFFER_LEN],
+            fill: 0,
+        }
+    }
+
+    fn next_byte(&mut self) -> u8 {
+        let b = match self.config.pattern {
+            Pattern::Counter => self.generated as u8,
+            Pattern::Itm => {
+                if self.pending.is_empty() {
+                    let text = format!("synthetic line {}\n", self.line);
+                    self.line += 1;
+                    for c in text.bytes() {
+                        // Port 0, one-byte payload.
+                        self.pending.push_back(0x01);
+                        self.pending.push_back(c);
+                    }
+                }
+                self.pending.pop_front().unwrap()
+            }
+        };
+        self.generated += 1;
+        b
+    }
+}
+
+impl Source for Synthetic {
+    fn poll<'a>(
+        &mut self,
+        buffer: &'a mut [u8],
+    ) -> Result<(u8, PollResult<'a>), Error> {
+        let elapsed = self.started.elapsed().as_micros();
+        let due = (elapsed * u128::from(self.config.rate)
+            / (BITS_PER_BYTE * 1_000_000)) as u64;
+        let mut backlog = (due - self.generated) as usize;
+
+        // More than a buffer's worth beyond what's already buffered wouldn't
+        // have fit in the probe, so it's lost.
+        if self.fill + backlog > 2 * BUFFER_LEN {
+            let lost = self.fill + backlog - BUFFER_LEN;
+            log::debug!("synthetic source dropping {} bytes", lost);
+            for _ in 0..backlog.saturating_sub(BUFFER_LEN) {
+                self.next_byte();
+            }
+            backlog = backlog.min(BUFFER_LEN);
+            self.epoch = self.epoch.wrapping_add(1);
+            self.fill = 0;
+        }
+
+        let n = backlog.min(BUFFER_LEN - self.fill);
+        if n == 0 {
+            return Ok((self.epoch, PollResult::Empty));
+        }
+        let start = self.fill;
+        for i in start..start + n {
+            self.buffer[i] = self.next_byte();
+        }
+        self.fill += n;
+
+        let epoch = self.epoch;
+        if self.fill == BUFFER_LEN {
+            self.epoch = self.epoch.wrapping_add(1);
+            self.fill = 0;
+            let out = &mut buffer[..BUFFER_LEN];
+            out.copy_from_slice(&self.buffer);
+            return Ok((epoch, PollResult::Total(out)));
+        }
+        let fragment = &mut buffer[..n];
+        fragment.copy_from_slice(&self.buffer[start..start + n]);
+        Ok((
+            epoch,
+            PollResult::Incremental {
+                start: start as u16,
+                end: (start + n) as u16,
+                fragment,
+            },
+        ))
+    }
+
+    fn bit_rate(&self) -> Option<u32> {
+        u32::try_from(self.config.rate).ok()
+    }
+}
@@ -0,0 +1,264 @@
+//! Machine-readable accounts of how a capture went.
+//!
+//! There are two of these, both with one JSON object per line, each with the
+//! time since the output started, in microseconds, the ID of the session
+//! (see [`session`](crate::session)), and what happened.
+//!
+//! `JsonLines` writes what the probe tells us about its capture buffer, for
+//! studying how its firmware manages it:
+//!
+//! ```text
+//! {"time_us":1234,"session":"...","event":"fill_levels","epoch":0,...}
+//! {"time_us":1240,"session":"...","event":"gap","cause":"overflow"}
+//! {"time_us":1250,"session":"...","event":"bit_rate","rate":3000000}
+//! ```
+//!
+//! Fill levels are as described by
+//! [`capture::FillLevels`](crate::capture::FillLevels). Gaps are included so
+//! that data loss can be lined up with what the buffer was doing at the time,
+//! with a `cause` (one of `overflow`, `sync_lost`, `disconnected` or
+//! `host_drop`) if it's known.
+//!
+//! `StatusLog` writes the `capture::Status` updates that are otherwise
+//! described on stderr, for harnesses that need to know whether a capture
+//! was lossless:
+//!
+//! ```text
+//! {"time_us":0,"event":"started","bit_rate":3000000}
+//! {"time_us":1000012,"event":"throughput","bytes":2951,"interval_us":1000012,
+//!  "rate_1s":2951,"gaps_1s":0,"rate_10s":2951,"gaps_10s":0,"rate_60s":2951,
+//!  "gaps_60s":0}
+//! {"time_us":1200345,"event":"sync_lost","epoch":3,"offset":156}
+//! {"time_us":1300003,"event":"overflow","epoch":5}
+//! {"time_us":1400000,"event":"host_drop"}
+//! {"time_us":1500000,"event":"disconnected","error":"..."}
+//! {"time_us":2500000,"event":"reconnected","bit_rate":3000000}
+//! {"time_us":2500001,"event":"bit_rate_changed","old":3000000,"new":2000000}
+//! {"time_us":9000000,"event":"ended","bytes":123456,"duration_us":9000000,
+//!  "flushes":2,"probe_gaps":2,"overflows":1,"host_drops":1,
+//!  "host_dropped_bytes":1022,"reconnects":1,"gaps":4}
+//! ```
+//!
+//! (The `session` field is left out of these lines, and the long ones are
+//! wrapped, but not in the output.) `bit_rate` is `null` if it isn't known.
+//! Throughput events also give the rate data was passed on, in bytes per
+//! second, and the number of gaps, over the last second, ten seconds and
+//! minute (see [`capture::Recent`](crate::capture::Recent)), for dashboards
+//! that want to show how capture is going now, rather than on average.
+//! If capture is cut short, there's an `aborted` event with the same fields
+//! as `ended`, and a `reason`, instead.
+
+use std::io::{self, Write};
+use std::sync::Mutex;
+use std::time::Instant;
+
+use crate::capture::{FillLevels, Gap, Recent, Sink, Status};
+use crate::session;
+
+/// Sink that writes fill levels and other events as JSON lines, ignoring the
+/// captured data itself.
+pub struct JsonLines<W: Write> {
+    inner: W,
+    started: Instant,
+}
+
+impl<W: Write> JsonLines<W> {
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            started: Instant::now(),
+        }
+    }
+
+    fn line(&mut self, when: Instant, rest: &str) -> io::Result<()> {
+        let time = when.saturating_duration_since(self.started).as_micros();
+        // Each line goes out in one write, so that a reader on the other end
+        // of a FIFO never sees half of one.
+        let line = format!(
+            "{{\"time_us\":{},\"session\":\"{}\",{}}}\n",
+            time,
+            session::id(),
+            rest
+        );
+        self.inner.write_all(line.as_bytes())?;
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> Sink for JsonLines<W> {
+    fn data(&mut self, _bytes: &[u8]) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn gap(&mut self) -> io::Result<()> {
+        self.line(Instant::now(), "\"event\":\"gap\"")
+    }
+
+    fn lost(&mut self, gap: &Gap) -> io::Result<()> {
+        self.line(
+            gap.noticed,
+            &format!("\"event\":\"gap\",\"cause\":\"{}\"", gap.cause.name()),
+        )
+    }
+
+    fn bit_rate(&mut self, rate: u32) -> io::Result<()> {
+        self.line(
+            Instant::now(),
+            &format!("\"event\":\"bit_rate\",\"rate\":{}", rate),
+        )
+    }
+
+    fn fill_levels(&mut self, levels: &FillLevels) -> io::Result<()> {
+        self.line(
+            levels.received,
+            &format!(
+                "\"event\":\"fill_levels\",\"epoch\":{},\"start\":{},\
+                 \"end\":{}",
+                levels.epoch, levels.start, levels.end
+            ),
+        )
+    }
+}
+
+/// Writes `capture::Status` updates as JSON lines. Use it through
+/// `capture::Observer`, e.g.
+/// `Observer::new(move |s| log.write(s))` for an `Arc<StatusLog<_>>`.
+pub struct StatusLog<W> {
+    inner: Mutex<W>,
+    started: Instant,
+}
+
+impl<W: Write> StatusLog<W> {
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner: Mutex::new(inner),
+            started: Instant::now(),
+        }
+    }
+
+    /// Writes a line for `status`. Since there's nobody to tell, failures
+    /// are only logged.
+    pub fn write(&self, status: &Status) {
+        let time = self.started.elapsed().as_micros();
+        let line = format!(
+            "{{\"time_us\":{},\"session\":\"{}\",{}}}\n",
+            time,
+            session::id(),
+            status_json(status)
+        );
+        let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        if let Err(e) =
+            inner.write_all(line.as_bytes()).and_then(|_| inner.flush())
+        {
+            log::debug!("can't write status: {}", e);
+        }
+    }
+}
+
+/// Describes `recent` as `rate_` and `gaps_` fields of a JSON object, each
+/// preceded by a comma.
+pub fn recent_json(recent: &[Recent]) -> String {
+    let mut json = String::new();
+    for r in recent {
+        let secs = r.span.as_secs();
+        json.push_str(&format!(
+            ",\"rate_{}s\":{:.0},\"gaps_{}s\":{}",
+            secs,
+            r.rate(),
+            secs,
+            r.gaps
+        ));
+    }
+    json
+}
+
+/// Describes `status` as the fields of a JSON object, without the braces.
+fn status_json(status: &Status) -> String {
+    let rate = |rate: Option<u32>| {
+        rate.map_or_else(|| "null".to_string(), |r| r.to_string())
+    };
+    match status {
+        Status::Started { bit_rate } => {
+            format!("\"event\":\"started\",\"bit_rate\":{}", rate(*bit_rate))
+        }
+        Status::Throughput {
+            bytes,
+            interval,
+            recent,
+        } => format!(
+            "\"event\":\"throughput\",\"bytes\":{},\"interval_us\":{}{}",
+            bytes,
+            interval.as_micros(),
+            recent_json(recent)
+        ),
+        Status::SyncLost { epoch, offset } => format!(
+            "\"event\":\"sync_lost\",\"epoch\":{},\"offset\":{}",
+            epoch, offset
+        ),
+        Status::Overflow { epoch } => {
+            format!("\"event\":\"overflow\",\"epoch\":{}", epoch)
+        }
+        Status::HostDrop => "\"event\":\"host_drop\"".to_string(),
+        Status::Disconnected { error } => format!(
+            "\"event\":\"disconnected\",\"error\":{}",
+            json_string(error)
+        ),
+        Status::Reconnected { bit_rate } => format!(
+            "\"event\":\"reconnected\",\"bit_rate\":{}",
+            rate(*bit_rate)
+        ),
+        Status::BitRateChanged { old, new } => format!(
+            "\"event\":\"bit_rate_changed\",\"old\":{},\"new\":{}",
+            old, new
+        ),
+        Status::Ended(stats) => format!(
+            "\"event\":\"ended\",\"bytes\":{},\"duration_us\":{},\
+             \"flushes\":{},\"probe_gaps\":{},\"overflows\":{},\
+             \"host_drops\":{},\"host_dropped_bytes\":{},\"reconnects\":{},\
+             \"gaps\":{}",
+            stats.bytes,
+            stats.duration.as_micros(),
+            stats.flushes,
+            stats.probe_gaps,
+            stats.overflows,
+            stats.host_drops,
+            stats.host_dropped_bytes,
+            stats.reconnects,
+            stats.gaps
+        ),
+        Status::Aborted { reason, stats } => format!(
+            "\"event\":\"aborted\",\"reason\":{},\"bytes\":{},\
+             \"duration_us\":{},\"flushes\":{},\"probe_gaps\":{},\
+             \"overflows\":{},\"host_drops\":{},\"host_dropped_bytes\":{},\
+             \"reconnects\":{},\"gaps\":{}",
+            json_string(reason),
+            stats.bytes,
+            stats.duration.as_micros(),
+            stats.flushes,
+            stats.probe_gaps,
+            stats.overflows,
+            stats.host_drops,
+            stats.host_dropped_bytes,
+            stats.reconnects,
+            stats.gaps
+        ),
+    }
+}
+
+/// Quotes `s` as a JSON string.
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => {
+                out.push_str(&format!("\\u{:04x}", c as u32))
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
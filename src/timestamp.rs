@@ -0,0 +1,184 @@
+//! Host wall clock times for captured data, for lining up what the target
+//! logged with what was going on elsewhere.
+//!
+//! Data is timed, at the least, by when it arrived from the probe, which is
+//! up to a poll interval (plus USB latency) after the target sent it. If the
+//! target sends ITM local timestamps, and the frequency of its timestamp
+//! clock is known, each packet is timed by the timestamp that follows it
+//! instead. Local timestamps only count cycles since the last one, so the
+//! count is tied to host time by when data arrives: a packet can't have been
+//! sent after it arrived, nor (unless the probe is falling behind) before
+//! the previous poll, and the tie is moved whenever a time falls outside
+//! those bounds. Times relative to each other are then as good as the
+//! timestamp clock, and absolute times are within a poll interval of the
+//! truth.
+
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::itm::Packet;
+
+/// Turns `Instant`s into wall clock time.
+#[derive(Copy, Clone, Debug)]
+pub struct Clock {
+    /// The same moment, as an `Instant` and as wall clock time.
+    instant: Instant,
+    wall: SystemTime,
+}
+
+impl Clock {
+    /// Returns a clock that starts now.
+    pub fn now() -> Self {
+        Self::at(Instant::now(), SystemTime::now())
+    }
+
+    /// Returns a clock on which `instant` is at `wall`; e.g. to give the
+    /// times of the original capture when replaying a recording.
+    pub fn at(instant: Instant, wall: SystemTime) -> Self {
+        Self { instant, wall }
+    }
+
+    /// Returns the wall clock time at `instant`.
+    pub fn wall(&self, instant: Instant) -> SystemTime {
+        if instant >= self.instant {
+            self.wall + (instant - self.instant)
+        } else {
+            self.wall - (self.instant - instant)
+        }
+    }
+}
+
+/// Ties ITM local timestamps to host time, as described in the module
+/// documentation.
+pub struct Correlator {
+    clock: Clock,
+    /// Frequency of the timestamp clock in Hz, if known.
+    frequency: Option<u32>,
+    /// Timestamp clock cycles counted so far.
+    cycles: u64,
+    /// A cycle count, and when we think it was reached.
+    anchor: Option<(u64, Instant)>,
+    /// When the data being decoded arrived, and the data before it.
+    received: Instant,
+    previous: Option<Instant>,
+}
+
+impl Correlator {
+    /// Times data by local timestamps, if `frequency` (of the timestamp
+    /// clock, in Hz) is given, and otherwise by when it arrived.
+    pub fn new(frequency: Option<u32>) -> Self {
+        Self {
+            clock: Clock::now(),
+            frequency,
+            cycles: 0,
+            anchor: None,
+            received: Instant::now(),
+            previous: None,
+        }
+    }
+
+    /// Changes the clock used to turn times into wall clock time.
+    pub fn set_clock(&mut self, clock: Clock) {
+        self.clock = clock;
+    }
+
+    /// Notes that the packets about to be timed arrived at `received`.
+    pub fn arrived(&mut self, received: Instant) {
+        if received != self.received {
+            self.previous = Some(self.received);
+            self.received = received;
+        }
+    }
+
+    /// Forgets how cycles relate to host time, after data was lost.
+    pub fn gap(&mut self) {
+        self.anchor = None;
+        self.previous = None;
+    }
+
+    /// Returns the time of each of `packets`, decoded from the data that
+    /// last `arrived`: the time given by the local timestamp that follows
+    /// it, or, for timestamps themselves and anything after the last one,
+    /// when the data arrived.
+    pub fn times(&mut self, packets: &[Packet]) -> Vec<SystemTime> {
+        let arrived = self.clock.wall(self.received);
+        let mut times = vec![arrived; packets.len()];
+        let mut next = arrived;
+        // Work out the times of the timestamps in order, since each counts
+        // from the last.
+        let stamps: Vec<Option<SystemTime>> = packets
+            .iter()
+            .map(|p| match p {
+                Packet::LocalTimestamp { delta, .. } => {
+                    Some(self.local(*delta))
+                }
+                _ => None,
+            })
+            .collect();
+        for (time, stamp) in times.iter_mut().zip(stamps).rev() {
+            match stamp {
+                Some(stamp) => next = stamp,
+                None => *time = next,
+            }
+        }
+        times
+    }
+
+    /// Counts a local timestamp of `delta` cycles, and returns its time.
+    fn local(&mut self, delta: u32) -> SystemTime {
+        self.cycles += u64::from(delta);
+        let frequency = match self.frequency {
+            Some(f) => f,
+            None => return self.clock.wall(self.received),
+        };
+        let estimate = match self.anchor {
+            Some((cycles, at)) => {
+                let elapsed = self.cycles - cycles;
+                let secs = elapsed / u64::from(frequency);
+                let nanos = (elapsed % u64::from(frequency)) * 1_000_000_000
+                    / u64::from(frequency);
+                at + Duration::new(secs, nanos as u32)
+            }
+            None => self.received,
+        };
+        let estimate = match self.previous {
+            Some(previous) if estimate < previous => previous,
+            _ => estimate.min(self.received),
+        };
+        self.anchor = Some((self.cycles, estimate));
+        self.clock.wall(estimate)
+    }
+}
+
+/// Formats `time` as in RFC 3339, in UTC, to the microsecond, e.g.
+/// `2024-03-01T12:34:56.789012Z`.
+pub fn format(time: SystemTime) -> String {
+    let since = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since.as_secs();
+    let (year, month, day) = civil(secs / 86400);
+    let secs = secs % 86400;
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:06}Z",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60,
+        since.subsec_micros()
+    )
+}
+
+/// Returns the year, month and day `days` after 1970-01-01, using Howard
+/// Hinnant's `civil_from_days`.
+fn civil(days: u64) -> (u64, u64, u64) {
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z % 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    (year, month, day)
+}
@@ -0,0 +1,145 @@
+//! Setting up a Cortex-M target to emit SWO, by programming its debug
+//! registers through the probe's CMSIS-DAP interface.
+//!
+//! This covers the architectural registers (DEMCR, the TPIU, the ITM, and the
+//! DWT), which are the same on all ARMv7-M and ARMv8-M parts. Many parts also
+//! have vendor-specific registers gating the trace clock or the SWO pin; of
+//! those, only STM32's `DBGMCU_CR` is handled here.
+
+use crate::dap::Dap;
+use crate::{Error, SwoProtocol};
+
+/// Debug Exception and Monitor Control Register, and its global trace enable.
+const DEMCR: u32 = 0xE000_EDFC;
+const DEMCR_TRCENA: u32 = 1 << 24;
+
+const TPIU_CSPSR: u32 = 0xE004_0004;
+const TPIU_ACPR: u32 = 0xE004_0010;
+const TPIU_SPPR: u32 = 0xE004_00F0;
+const TPIU_FFCR: u32 = 0xE004_0304;
+/// `SPPR` values for the two SWO encodings.
+const SPPR_MANCHESTER: u32 = 1;
+const SPPR_NRZ: u32 = 2;
+/// `FFCR.TrigIn`, which is all we set: with `EnFCont` clear, the formatter
+/// stays disabled, since SWO carries only ITM/DWT data.
+const FFCR_TRIGIN: u32 = 1 << 8;
+
+const ITM_TER: u32 = 0xE000_0E00;
+const ITM_TPR: u32 = 0xE000_0E40;
+const ITM_TCR: u32 = 0xE000_0E80;
+const ITM_LAR: u32 = 0xE000_0FB0;
+const LAR_UNLOCK: u32 = 0xC5AC_CE55;
+/// `TCR` enabling the ITM, synchronization packets, and forwarding of DWT
+/// packets, with trace bus ID 1.
+const TCR_ENABLE: u32 = 1 << 16 | 1 << 3 | 1 << 2 | 1;
+
+/// `DWT_CTRL`, and the bits that make it generate synchronization packets:
+/// the cycle counter, and a tap on its bit 24.
+const DWT_CTRL: u32 = 0xE000_1000;
+const DWT_SYNC: u32 = 0b01 << 10 | 1;
+
+/// STM32's `DBGMCU_CR`, and the bit that connects the trace pins, with
+/// `TRACE_MODE` (the following two bits) left at zero for asynchronous SWO.
+const STM32_DBGMCU_CR: u32 = 0xE004_2004;
+const STM32_TRACE_IOEN: u32 = 1 << 5;
+const STM32_TRACE_MODE: u32 = 0b11 << 6;
+
+/// How to set up the target's trace output.
+#[derive(Copy, Clone, Debug)]
+pub struct Config {
+    /// Frequency of the target's trace clock (usually the CPU clock), in Hz.
+    pub cpu_freq: u32,
+    /// SWO bit rate to produce, in bits per second.
+    pub bitrate: u32,
+    pub protocol: SwoProtocol,
+    /// Mask of ITM stimulus ports to enable; bit N enables port N.
+    pub ports: u32,
+    /// Also enable the trace pins in STM32's `DBGMCU_CR`.
+    pub stm32_dbgmcu: bool,
+}
+
+impl Config {
+    /// Works out the TPIU prescaler for the requested bit rate, returning it
+    /// along with the bit rate it actually gives.
+    pub fn prescaler(&self) -> Result<(u32, u32), Error> {
+        if self.bitrate == 0 || self.bitrate > self.cpu_freq {
+            return Err(Error::Target("SWO bit rate must be below CPU clock"));
+        }
+        let divisor = (self.cpu_freq + self.bitrate / 2) / self.bitrate;
+        // ACPR holds a 13-bit prescaler on most parts.
+        let prescaler = (divisor - 1).min(0x1FFF);
+        Ok((prescaler, self.cpu_freq / (prescaler + 1)))
+    }
+}
+
+/// Returns the SWO bit rates a target with a `cpu_freq` Hz trace clock can
+/// produce at or below `ceiling`, fastest first, along with the prescalers
+/// that give them.
+pub fn rates(cpu_freq: u32, ceiling: u32) -> impl Iterator<Item = (u32, u32)> {
+    let first = match ceiling {
+        0 => 0x2000,
+        _ => cpu_freq.div_ceil(ceiling).max(1) - 1,
+    };
+    (first..=0x1FFF)
+        .map(move |prescaler| (prescaler, cpu_freq / (prescaler + 1)))
+}
+
+/// Programs the target attached to `dap` to emit SWO as described by
+/// `config`.
+///
+/// The CPU clock and bit rate have to divide nearly evenly for UART SWO to
+/// work; if the rate the target will actually produce is more than 3% off,
+/// this returns an error instead of setting things up to produce garbage.
+pub fn configure(dap: &Dap, config: &Config) -> Result<(), Error> {
+    let (prescaler, actual) = config.prescaler()?;
+    let error = (f64::from(actual) / f64::from(config.bitrate) - 1.0).abs();
+    if config.protocol == SwoProtocol::Uart && error > 0.03 {
+        return Err(Error::Target(
+            "CPU clock can't be divided down to the SWO bit rate",
+        ));
+    }
+    log::info!(
+        "target SWO: prescaler {}, {} bit/s, ports {:#010x}",
+        prescaler,
+        actual,
+        config.ports
+    );
+
+    if config.stm32_dbgmcu {
+        let cr = dap.read_word(STM32_DBGMCU_CR)?;
+        dap.write_word(
+            STM32_DBGMCU_CR,
+            cr & !STM32_TRACE_MODE | STM32_TRACE_IOEN,
+        )?;
+    }
+
+    let demcr = dap.read_word(DEMCR)?;
+    dap.write_word(DEMCR, demcr | DEMCR_TRCENA)?;
+
+    dap.write_word(TPIU_CSPSR, 1)?;
+    dap.write_word(TPIU_ACPR, prescaler)?;
+    dap.write_word(
+        TPIU_SPPR,
+        match config.protocol {
+            SwoProtocol::Uart => SPPR_NRZ,
+            SwoProtocol::Manchester => SPPR_MANCHESTER,
+        },
+    )?;
+    dap.write_word(TPIU_FFCR, FFCR_TRIGIN)?;
+
+    let dwt = dap.read_word(DWT_CTRL)?;
+    dap.write_word(DWT_CTRL, dwt | DWT_SYNC)?;
+
+    dap.write_word(ITM_LAR, LAR_UNLOCK)?;
+    dap.write_word(ITM_TCR, TCR_ENABLE)?;
+    // Let unprivileged code use all ports too.
+    dap.write_word(ITM_TPR, 0)?;
+    dap.write_word(ITM_TER, config.ports)?;
+
+    // If the target ignored us (say, because the ITM is locked or powered
+    // down), better to say so now than to capture nothing.
+    if dap.read_word(ITM_TCR)? & 1 == 0 {
+        return Err(Error::Target("ITM didn't enable"));
+    }
+    Ok(())
+}
@@ -0,0 +1,84 @@
+//! Ways of moving commands and responses between us and the probe's trace
+//! interface, so that `Handle` doesn't need to care which is in use.
+
+use std::convert::TryFrom;
+use std::time::Duration;
+
+use crate::Error;
+
+/// A connection to the trace interface that can send a command and read a
+/// response, each a single USB transaction.
+pub trait Transport: Send {
+    /// Sends `data` as one packet.
+    fn write(&self, data: &[u8]) -> Result<(), Error>;
+
+    /// Reads one packet into `buffer`, returning its length. Waits for up to
+    /// `timeout`, returning 0 if nothing arrives, or forever if `None`.
+    fn read(
+        &self,
+        buffer: &mut [u8],
+        timeout: Option<Duration>,
+    ) -> Result<usize, Error>;
+
+    /// Returns the probe's USB serial number, if it has one.
+    fn serial(&self) -> Result<Option<String>, Error>;
+
+    /// Returns the size of the packets the probe sends, if the transport
+    /// can find out (e.g. from the interface's report descriptor).
+    fn report_size(&self) -> Option<usize> {
+        None
+    }
+}
+
+impl Transport for hidapi::HidDevice {
+    fn write(&self, data: &[u8]) -> Result<(), Error> {
+        hidapi::HidDevice::write(self, data)?;
+        Ok(())
+    }
+
+    fn read(
+        &self,
+        buffer: &mut [u8],
+        timeout: Option<Duration>,
+    ) -> Result<usize, Error> {
+        Ok(match timeout {
+            Some(timeout) => {
+                let ms = i32::try_from(timeout.as_millis()).unwrap_or(i32::MAX);
+                self.read_timeout(buffer, ms)?
+            }
+            None => hidapi::HidDevice::read(self, buffer)?,
+        })
+    }
+
+    fn serial(&self) -> Result<Option<String>, Error> {
+        Ok(self.get_serial_number_string()?)
+    }
+}
+
+/// Which transport to use.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Default)]
+pub enum Kind {
+    /// HID reports, through hidapi and the OS's HID driver.
+    #[default]
+    Hid,
+    /// USB transfers straight to the interface's endpoints through libusb,
+    /// bypassing the HID driver (see `usb`). Requires the `bulk` feature.
+    Bulk,
+}
+
+impl Kind {
+    /// Names accepted by `from_str`.
+    pub const NAMES: &'static [&'static str] = &["hid", "bulk"];
+}
+
+impl std::str::FromStr for Kind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "hid" => Ok(Kind::Hid),
+            "bulk" => Ok(Kind::Bulk),
+            _ => Err(format!("unknown transport `{}`", s)),
+        }
+    }
+}
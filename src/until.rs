@@ -0,0 +1,172 @@
+//! Ending a capture on its own, for scripts: once a number of bytes has been
+//! captured, once a length of time has passed, or once a pattern turns up in
+//! the stream.
+//!
+//! Conditions are checked against the raw stream, before any decoding, and
+//! capture is stopped through the same flag as Ctrl-C (`Config::stop`), so
+//! that it ends cleanly. Data captured after the condition was met (from the
+//! rest of the chunk it was met in, or polls already under way) isn't
+//! passed on.
+
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::thread;
+use std::time::Duration;
+
+use crate::capture::{FillLevels, Fragment, Gap, Sink};
+use crate::timestamp::Clock;
+use crate::CAPTURE_BUFFER_SIZE;
+
+/// When to stop.
+#[derive(Clone, Debug, Default)]
+pub struct Conditions {
+    /// Stop once this many bytes have been captured.
+    pub max_bytes: Option<u64>,
+    /// Stop once capture has gone on this long.
+    pub duration: Option<Duration>,
+    /// Stop once the stream contains this.
+    pub pattern: Option<Vec<u8>>,
+}
+
+/// Which condition stopped capture.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Reason {
+    MaxBytes,
+    Duration,
+    Pattern,
+}
+
+/// Sink that checks `Conditions` against the stream on its way to `inner`.
+pub struct Until<'a> {
+    inner: &'a mut dyn Sink,
+    conditions: Conditions,
+    stop: Arc<AtomicBool>,
+    reason: Arc<OnceLock<Reason>>,
+    bytes: u64,
+    /// The end of the stream so far, in case the pattern spans chunks.
+    tail: Vec<u8>,
+}
+
+impl<'a> Until<'a> {
+    /// Passes the stream on to `inner`, setting `stop` once one of
+    /// `conditions` is met. The clock for `Conditions::duration` starts now.
+    pub fn new(
+        inner: &'a mut dyn Sink,
+        conditions: Conditions,
+        stop: Arc<AtomicBool>,
+    ) -> Self {
+        let reason = Arc::new(OnceLock::new());
+        if let Some(duration) = conditions.duration {
+            let (reason, stop) = (Arc::clone(&reason), Arc::clone(&stop));
+            thread::spawn(move || {
+                thread::sleep(duration);
+                if reason.set(Reason::Duration).is_ok() {
+                    stop.store(true, Ordering::Relaxed);
+                }
+            });
+        }
+        Self {
+            inner,
+            conditions,
+            stop,
+            reason,
+            bytes: 0,
+            tail: vec![],
+        }
+    }
+
+    /// Returns a handle saying which condition stopped capture, if one did.
+    pub fn reason(&self) -> Arc<OnceLock<Reason>> {
+        Arc::clone(&self.reason)
+    }
+
+    /// Returns how much of `bytes` to pass on, noting if a condition is met
+    /// within them.
+    fn check(&mut self, bytes: &[u8]) -> usize {
+        if self.reason.get().is_some() {
+            return 0;
+        }
+        let mut keep = bytes.len();
+        let mut met = None;
+        if let Some(pattern) = self.conditions.pattern.as_deref() {
+            let start = self.tail.len();
+            self.tail.extend_from_slice(bytes);
+            if let Some(i) = find(&self.tail, pattern) {
+                keep = (i + pattern.len()).saturating_sub(start);
+                met = Some(Reason::Pattern);
+            }
+            let excess = (self.tail.len() + 1).saturating_sub(pattern.len());
+            self.tail.drain(..excess);
+        }
+        if let Some(max) = self.conditions.max_bytes {
+            let left = max.saturating_sub(self.bytes);
+            if left <= keep as u64 {
+                keep = left as usize;
+                met = met.or(Some(Reason::MaxBytes));
+            }
+        }
+        self.bytes += keep as u64;
+        if let Some(reason) = met {
+            if self.reason.set(reason).is_ok() {
+                self.stop.store(true, Ordering::Relaxed);
+            }
+        }
+        keep
+    }
+}
+
+/// Returns where `needle` first appears in `haystack`.
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    if needle.is_empty() {
+        return Some(0);
+    }
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+impl Sink for Until<'_> {
+    fn data(&mut self, bytes: &[u8]) -> io::Result<()> {
+        match self.check(bytes) {
+            0 => Ok(()),
+            n => self.inner.data(&bytes[..n]),
+        }
+    }
+
+    fn fragment(&mut self, info: &Fragment, bytes: &[u8]) -> io::Result<()> {
+        match self.check(bytes) {
+            0 => Ok(()),
+            n if n == bytes.len() => self.inner.fragment(info, bytes),
+            n => {
+                // The fragment now ends sooner in the probe's buffer too.
+                let end = (usize::from(info.start) + n) % CAPTURE_BUFFER_SIZE;
+                let info = Fragment {
+                    end: end as u16,
+                    ..*info
+                };
+                self.inner.fragment(&info, &bytes[..n])
+            }
+        }
+    }
+
+    fn gap(&mut self) -> io::Result<()> {
+        self.tail.clear();
+        self.inner.gap()
+    }
+
+    fn lost(&mut self, gap: &Gap) -> io::Result<()> {
+        self.tail.clear();
+        self.inner.lost(gap)
+    }
+
+    fn bit_rate(&mut self, rate: u32) -> io::Result<()> {
+        self.inner.bit_rate(rate)
+    }
+
+    fn fill_levels(&mut self, levels: &FillLevels) -> io::Result<()> {
+        self.inner.fill_levels(levels)
+    }
+
+    fn clock(&mut self, clock: Clock) -> io::Result<()> {
+        self.inner.clock(clock)
+    }
+}
@@ -0,0 +1,286 @@
+//! Talking to the trace interface with USB transfers through libusb, rather
+//! than as HID reports.
+//!
+//! The HID path goes through the OS's HID driver, which reads one report per
+//! call, and on some systems queues or copies them along the way; that
+//! limits how fast the capture buffer can be drained at high bit rates. Here
+//! we claim the interface ourselves (detaching the HID driver where the OS
+//! lets us) and transfer straight to and from its endpoints.
+//!
+//! The firmware we've studied gives the trace interface interrupt endpoints
+//! (see the README), though the vendor tools appear to drain it with bulk
+//! transfers. We use bulk transfers if the interface has bulk endpoints, and
+//! interrupt transfers otherwise; the packets are the same either way.
+
+use std::time::Duration;
+
+use rusb::{Direction, GlobalContext, TransferType, UsbContext};
+
+use crate::firmware;
+use crate::interfaces::{self, Function};
+use crate::transport::Transport;
+use crate::Error;
+
+/// How long to wait for a transfer that should complete promptly.
+const TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The trace interface of a probe, claimed through libusb.
+pub struct Usb {
+    handle: rusb::DeviceHandle<GlobalContext>,
+    interface: u8,
+    ep_in: u8,
+    ep_out: u8,
+    transfer: TransferType,
+    serial: Option<String>,
+    /// Size of the interface's input reports, from its report descriptor.
+    report_size: Option<usize>,
+}
+
+impl Usb {
+    /// Opens the probe with the given vid/pid, plugged into `usb_port` (see
+    /// `select::usb_port_path`) or with serial number `serial`, whichever is
+    /// given (the port, if both are). If neither is, the first probe found
+    /// is used.
+    pub fn open(
+        vid: u16,
+        pid: u16,
+        usb_port: Option<&str>,
+        serial: Option<&str>,
+    ) -> Result<Self, Error> {
+        for device in rusb::devices()?.iter() {
+            let desc = device.device_descriptor()?;
+            if desc.vendor_id() != vid || desc.product_id() != pid {
+                continue;
+            }
+            if let Some(port) = usb_port {
+                if port_path(&device).as_deref() != Some(port) {
+                    continue;
+                }
+            }
+            let handle = device.open()?;
+            let device_serial = handle
+                .read_serial_number_string_ascii(&desc)
+                .ok()
+                .filter(|s| !s.is_empty());
+            if usb_port.is_none()
+                && serial.is_some()
+                && device_serial.as_deref() != serial
+            {
+                continue;
+            }
+            return Self::claim(handle, device_serial);
+        }
+        Err(Error::DeviceNotFound)
+    }
+
+    /// Finds and claims the trace interface of an opened probe.
+    fn claim(
+        handle: rusb::DeviceHandle<GlobalContext>,
+        serial: Option<String>,
+    ) -> Result<Self, Error> {
+        let device = handle.device();
+        let ids = device.device_descriptor()?;
+        let layout = firmware::layout(ids.vendor_id(), ids.product_id());
+        let config = device.active_config_descriptor()?;
+        let mut found = None;
+        for interface in config.interfaces() {
+            for desc in interface.descriptors() {
+                let name = desc
+                    .description_string_index()
+                    .and_then(|i| handle.read_string_descriptor_ascii(i).ok());
+                let function = match &name {
+                    Some(name) => interfaces::classify(Some(name), None),
+                    None => interfaces::default_function(
+                        layout,
+                        i32::from(desc.interface_number()),
+                    ),
+                };
+                if function != Function::Trace {
+                    continue;
+                }
+                let mut ep_in = None;
+                let mut ep_out = None;
+                let mut transfer = TransferType::Interrupt;
+                for ep in desc.endpoint_descriptors() {
+                    match ep.transfer_type() {
+                        TransferType::Bulk | TransferType::Interrupt => (),
+                        _ => continue,
+                    }
+                    transfer = ep.transfer_type();
+                    match ep.direction() {
+                        Direction::In => ep_in = Some(ep.address()),
+                        Direction::Out => ep_out = Some(ep.address()),
+                    }
+                }
+                if let (Some(ep_in), Some(ep_out)) = (ep_in, ep_out) {
+                    found = Some((
+                        desc.interface_number(),
+                        ep_in,
+                        ep_out,
+                        transfer,
+                    ));
+                }
+            }
+        }
+        let (interface, ep_in, ep_out, transfer) =
+            found.ok_or(Error::Unsupported("trace interface over libusb"))?;
+
+        // Not every OS lets us (or needs us to) take the interface from its
+        // HID driver; claiming it will fail if it matters.
+        if let Err(e) = handle.set_auto_detach_kernel_driver(true) {
+            log::debug!("can't detach kernel driver: {}", e);
+        }
+        handle.claim_interface(interface)?;
+        let report_size = read_report_descriptor(&handle, interface)
+            .as_deref()
+            .and_then(input_report_size);
+        log::debug!("input report size {:?}", report_size);
+        log::info!(
+            "claimed trace interface {} ({:?} endpoints {:02x}/{:02x})",
+            interface,
+            transfer,
+            ep_in,
+            ep_out
+        );
+        Ok(Self {
+            handle,
+            interface,
+            ep_in,
+            ep_out,
+            transfer,
+            serial,
+            report_size,
+        })
+    }
+}
+
+impl Transport for Usb {
+    fn write(&self, data: &[u8]) -> Result<(), Error> {
+        // A timeout here is an error like any other.
+        match self.transfer {
+            TransferType::Bulk => {
+                self.handle.write_bulk(self.ep_out, data, TIMEOUT)?
+            }
+            _ => self.handle.write_interrupt(self.ep_out, data, TIMEOUT)?,
+        };
+        Ok(())
+    }
+
+    fn read(
+        &self,
+        buffer: &mut [u8],
+        timeout: Option<Duration>,
+    ) -> Result<usize, Error> {
+        // Not waiting forever without a timeout, as HID does, since a
+        // transfer that never completes would hold us up for good.
+        let wait = timeout.unwrap_or(TIMEOUT);
+        let result = match self.transfer {
+            TransferType::Bulk => {
+                self.handle.read_bulk(self.ep_in, buffer, wait)
+            }
+            _ => self.handle.read_interrupt(self.ep_in, buffer, wait),
+        };
+        match result {
+            Ok(n) => Ok(n),
+            Err(rusb::Error::Timeout) if timeout.is_some() => Ok(0),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn serial(&self) -> Result<Option<String>, Error> {
+        Ok(self.serial.clone())
+    }
+
+    fn report_size(&self) -> Option<usize> {
+        self.report_size
+    }
+}
+
+impl Drop for Usb {
+    fn drop(&mut self) {
+        self.handle.release_interface(self.interface).ok();
+    }
+}
+
+/// Asks for the HID report descriptor of `interface`, if it has one.
+fn read_report_descriptor(
+    handle: &rusb::DeviceHandle<GlobalContext>,
+    interface: u8,
+) -> Option<Vec<u8>> {
+    // GET_DESCRIPTOR, standard request to an interface, for a report
+    // descriptor (type 0x22). They're rarely more than a few hundred bytes.
+    let mut descriptor = vec![0; 4096];
+    let n = handle
+        .read_control(
+            rusb::request_type(
+                Direction::In,
+                rusb::RequestType::Standard,
+                rusb::Recipient::Interface,
+            ),
+            rusb::constants::LIBUSB_REQUEST_GET_DESCRIPTOR,
+            0x22 << 8,
+            u16::from(interface),
+            &mut descriptor,
+            TIMEOUT,
+        )
+        .map_err(|e| log::debug!("can't read report descriptor: {}", e))
+        .ok()?;
+    descriptor.truncate(n);
+    Some(descriptor)
+}
+
+/// Returns the size in bytes of the largest input report described by a
+/// HID report descriptor, including the report ID if there is one.
+fn input_report_size(descriptor: &[u8]) -> Option<usize> {
+    // Bits of input in each report, by report ID.
+    let mut reports: Vec<(u8, usize)> = vec![];
+    let (mut id, mut size, mut count) = (0u8, 0usize, 0usize);
+    let mut rest = descriptor;
+    while let Some((&prefix, tail)) = rest.split_first() {
+        if prefix == 0xfe {
+            // Long item: size, tag, then data. Nothing we need.
+            let len = usize::from(*tail.first()?);
+            rest = tail.get(2 + len..)?;
+            continue;
+        }
+        let len = match prefix & 0x03 {
+            3 => 4,
+            n => usize::from(n),
+        };
+        let data = tail.get(..len)?;
+        rest = &tail[len..];
+        let value = data
+            .iter()
+            .rev()
+            .fold(0usize, |v, &b| v << 8 | usize::from(b));
+        // Tag and type, leaving out the size.
+        match prefix & 0xfc {
+            // Input (main item).
+            0x80 => match reports.iter_mut().find(|(i, _)| *i == id) {
+                Some((_, bits)) => *bits += size * count,
+                None => reports.push((id, size * count)),
+            },
+            // Report Size, Report ID and Report Count (global items).
+            0x74 => size = value,
+            0x84 => id = value as u8,
+            0x94 => count = value,
+            _ => (),
+        }
+    }
+    reports
+        .iter()
+        .map(|&(id, bits)| bits.div_ceil(8) + usize::from(id != 0))
+        .max()
+        .filter(|&n| n > 0)
+}
+
+/// Returns the port path of `device` in the form `select::usb_port_path`
+/// uses, e.g. `1-3.2`.
+fn port_path<T: UsbContext>(device: &rusb::Device<T>) -> Option<String> {
+    let ports = device.port_numbers().ok()?;
+    if ports.is_empty() {
+        return None;
+    }
+    let ports: Vec<String> = ports.iter().map(u8::to_string).collect();
+    Some(format!("{}-{}", device.bus_number(), ports.join(".")))
+}
@@ -0,0 +1,82 @@
+//! Which implementation this is, in enough detail for bug reports.
+
+use std::fmt;
+
+use crate::protocol;
+
+/// Details of this build of the crate.
+#[derive(Copy, Clone, Debug)]
+pub struct Info {
+    /// Crate version.
+    pub version: &'static str,
+    /// Abbreviated hash of the git commit we were built from, with `-dirty`
+    /// appended if there were uncommitted changes, if known.
+    pub git_hash: Option<&'static str>,
+    /// Optional cargo features that were enabled.
+    pub features: &'static [&'static str],
+    /// Probe commands we know how to send, and what we call them.
+    pub commands: &'static [(u8, &'static str)],
+}
+
+const FEATURES: &[&str] = &[
+    #[cfg(feature = "bulk")]
+    "bulk",
+    #[cfg(feature = "client")]
+    "client",
+    #[cfg(feature = "defmt")]
+    "defmt",
+    #[cfg(feature = "ffi")]
+    "ffi",
+    #[cfg(feature = "probe-rs")]
+    "probe-rs",
+    #[cfg(feature = "python")]
+    "python",
+    #[cfg(feature = "tls")]
+    "tls",
+    #[cfg(feature = "tokio")]
+    "tokio",
+];
+
+const COMMANDS: &[(u8, &str)] = &[
+    (protocol::SET_BIT_RATE, "configure SWO bit rate"),
+    (protocol::POLL, "poll capture buffer"),
+    (protocol::INIT_UART, "initialize UART"),
+    (protocol::OHAI, "ohai"),
+];
+
+/// Returns details of this build.
+pub fn info() -> Info {
+    Info {
+        version: env!("CARGO_PKG_VERSION"),
+        git_hash: option_env!("LPC_CAT_GIT_HASH"),
+        features: FEATURES,
+        commands: COMMANDS,
+    }
+}
+
+impl Info {
+    /// One-line summary, e.g. `lpc-cat 0.1.0 (0123456789ab)`.
+    pub fn summary(&self) -> String {
+        match self.git_hash {
+            Some(hash) => format!("lpc-cat {} ({})", self.version, hash),
+            None => format!("lpc-cat {}", self.version),
+        }
+    }
+}
+
+impl fmt::Display for Info {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}", self.summary())?;
+        writeln!(f, "commit: {}", self.git_hash.unwrap_or("unknown"))?;
+        if self.features.is_empty() {
+            writeln!(f, "features: (none)")?;
+        } else {
+            writeln!(f, "features: {}", self.features.join(", "))?;
+        }
+        writeln!(f, "probe commands:")?;
+        for (cmd, name) in self.commands {
+            writeln!(f, "  {:02x} {}", cmd, name)?;
+        }
+        Ok(())
+    }
+}
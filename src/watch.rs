@@ -0,0 +1,274 @@
+//! Splitting a capture into a file per boot of the target, for loops of
+//! flashing the target and seeing what it does.
+//!
+//! The probe can't tell us when the target resets, so a restart is worked
+//! out from the stream: while the target is held in reset or being flashed,
+//! SWO goes quiet, so data arriving after a long enough silence is taken to
+//! be from a new boot. So is data after the probe was reconnected, since a
+//! probe that shares a cable with the debugger may go away while the target
+//! is flashed. A target that's quiet for long stretches while it runs will
+//! look like it restarted, so the silence should be longer than that.
+//!
+//! Each boot's data goes to files as `rotate` writes them, named for the
+//! boot: `trace.bin` becomes `trace-boot1.bin`, `trace-boot2.bin` and so on.
+//! `Watch::boots` keeps track of the boots, so that a table of them can be
+//! written when capture ends, with why the target reset, if its firmware
+//! said so: the first few kilobytes of each boot are searched for a line
+//! with `reset reason` or `reset cause` in it (in any case, and with `_` or
+//! `-` for the space), and what follows on that line is taken to be the
+//! reason. That only works if the firmware's text appears as such in the
+//! raw stream, so not if it's framed as ITM.
+
+use std::ffi::OsString;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::capture::{Fragment, Gap, GapCause, Sink};
+use crate::rotate::{self, Rotating};
+use crate::timestamp::{self, Clock};
+
+/// How much of the start of each boot to search for a reset reason.
+const HEAD_LEN: usize = 4096;
+
+/// What comes before a reset reason, in lower case.
+const REASON_KEYS: &[&str] = &["reset reason", "reset cause"];
+
+/// Longest reset reason kept; firmware that goes on at length is cut short.
+const REASON_LEN: usize = 40;
+
+/// What happened in one boot of the target.
+#[derive(Clone, Debug)]
+pub struct Boot {
+    /// Counting from 1.
+    pub index: u32,
+    /// When its first data arrived.
+    pub started: SystemTime,
+    /// From when its first data arrived to its last.
+    pub duration: Duration,
+    /// Bytes captured.
+    pub bytes: u64,
+    /// Why the target reset, as the firmware put it.
+    pub reset_reason: Option<String>,
+}
+
+/// The boots seen by a `Watch`, shared with it, so they can be looked at
+/// during capture or once the `Watch` is gone. Displays as a table.
+#[derive(Clone, Debug, Default)]
+pub struct Boots(Arc<Mutex<Vec<Boot>>>);
+
+impl Boots {
+    /// Returns the boots so far, the current one last.
+    pub fn get(&self) -> Vec<Boot> {
+        self.lock().clone()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Vec<Boot>> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl fmt::Display for Boots {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", Table(&self.lock()))
+    }
+}
+
+/// Sink that writes the raw stream to a new set of files each time the
+/// target restarts.
+pub struct Watch {
+    /// Rotation for each boot's files, with the path they're named after.
+    config: rotate::Config,
+    /// How long the stream has to be quiet before a restart.
+    quiet: Duration,
+    /// Files for the current boot.
+    files: Option<Rotating>,
+    /// Boots so far, the current one last.
+    boots: Boots,
+    /// The start of the current boot, while searching it for a reset
+    /// reason.
+    head: Vec<u8>,
+    clock: Clock,
+    bit_rate: Option<u32>,
+    /// When the first data of the current boot arrived, and the last data.
+    first: Option<Instant>,
+    last: Option<Instant>,
+    /// Whether the probe was reconnected since then.
+    reconnected: bool,
+}
+
+impl Watch {
+    /// Prepares to write files as described by `config`, for each boot.
+    /// Nothing is written until data arrives.
+    pub fn new(config: rotate::Config, quiet: Duration) -> Self {
+        Self {
+            config,
+            quiet,
+            files: None,
+            boots: Boots::default(),
+            head: vec![],
+            clock: Clock::now(),
+            bit_rate: None,
+            first: None,
+            last: None,
+            reconnected: false,
+        }
+    }
+
+    /// Returns the boots, as they are so far and as they go on to be.
+    pub fn boots(&self) -> Boots {
+        self.boots.clone()
+    }
+
+    /// Returns the files for `bytes`, which arrived at `received`, starting
+    /// on a new boot first if the target seems to have restarted.
+    fn files(
+        &mut self,
+        received: Instant,
+        bytes: &[u8],
+    ) -> io::Result<&mut Rotating> {
+        let restarted = self.reconnected
+            || self.last.is_some_and(|last| {
+                received.saturating_duration_since(last) >= self.quiet
+            });
+        self.last = Some(received);
+        self.reconnected = false;
+        if self.files.is_none() || restarted {
+            // Finish the last boot's files before starting the next.
+            self.files = None;
+            let mut boots = self.boots.lock();
+            let index = boots.len() as u32 + 1;
+            if index > 1 {
+                log::info!("target restarted; this is boot {}", index);
+            }
+            boots.push(Boot {
+                index,
+                started: self.clock.wall(received),
+                duration: Duration::ZERO,
+                bytes: 0,
+                reset_reason: None,
+            });
+            drop(boots);
+            self.head.clear();
+            self.first = Some(received);
+            let mut files = Rotating::new(rotate::Config {
+                path: boot_path(&self.config.path, index),
+                ..self.config.clone()
+            });
+            if let Some(rate) = self.bit_rate {
+                files.bit_rate(rate)?;
+            }
+            self.files = Some(files);
+        }
+
+        let mut boots = self.boots.lock();
+        let boot = boots.last_mut().unwrap();
+        if let Some(first) = self.first {
+            boot.duration = received.saturating_duration_since(first);
+        }
+        boot.bytes += bytes.len() as u64;
+        if boot.reset_reason.is_none() && self.head.len() < HEAD_LEN {
+            let n = bytes.len().min(HEAD_LEN - self.head.len());
+            self.head.extend_from_slice(&bytes[..n]);
+            boot.reset_reason = reset_reason(&self.head);
+            if boot.reset_reason.is_some() {
+                log::info!(
+                    "boot {} reset reason: {}",
+                    boot.index,
+                    boot.reset_reason.as_deref().unwrap_or_default()
+                );
+            }
+        }
+        Ok(self.files.as_mut().unwrap())
+    }
+}
+
+/// Finds a reset reason in the complete lines of `head`.
+fn reset_reason(head: &[u8]) -> Option<String> {
+    let complete = head.iter().rposition(|&b| b == b'\n')?;
+    head[..complete].split(|&b| b == b'\n').find_map(|line| {
+        let line = String::from_utf8_lossy(line);
+        // Same length as `line`, so offsets carry over.
+        let lower = line.to_ascii_lowercase().replace(['_', '-'], " ");
+        let (at, key) = REASON_KEYS
+            .iter()
+            .find_map(|key| Some((lower.find(key)?, key)))?;
+        let reason = line[at + key.len()..]
+            .trim_start()
+            .trim_start_matches([':', '='])
+            .trim();
+        match reason.is_empty() {
+            true => None,
+            false => Some(reason.chars().take(REASON_LEN).collect()),
+        }
+    })
+}
+
+/// The table of boots written at the end.
+struct Table<'a>(&'a [Boot]);
+
+impl fmt::Display for Table<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{:>5}  {:<27}  {:>10}  {:>12}  reset reason",
+            "boot", "started", "duration", "bytes"
+        )?;
+        for boot in self.0 {
+            let duration = format!("{:.1} s", boot.duration.as_secs_f64());
+            writeln!(
+                f,
+                "{:>5}  {:<27}  {:>10}  {:>12}  {}",
+                boot.index,
+                timestamp::format(boot.started),
+                duration,
+                boot.bytes,
+                boot.reset_reason.as_deref().unwrap_or("-")
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns the name of the file for `boot`, after `path`.
+fn boot_path(path: &Path, boot: u32) -> PathBuf {
+    let mut name = OsString::from(path.file_stem().unwrap_or_default());
+    name.push(format!("-boot{}", boot));
+    if let Some(extension) = path.extension() {
+        name.push(".");
+        name.push(extension);
+    }
+    path.with_file_name(name)
+}
+
+impl Sink for Watch {
+    fn data(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.files(Instant::now(), bytes)?.data(bytes)
+    }
+
+    fn fragment(&mut self, info: &Fragment, bytes: &[u8]) -> io::Result<()> {
+        self.files(info.received, bytes)?.fragment(info, bytes)
+    }
+
+    fn lost(&mut self, gap: &Gap) -> io::Result<()> {
+        if gap.cause == GapCause::Disconnected {
+            self.reconnected = true;
+        }
+        Ok(())
+    }
+
+    fn bit_rate(&mut self, rate: u32) -> io::Result<()> {
+        self.bit_rate = Some(rate);
+        match &mut self.files {
+            Some(files) => files.bit_rate(rate),
+            None => Ok(()),
+        }
+    }
+
+    fn clock(&mut self, clock: Clock) -> io::Result<()> {
+        self.clock = clock;
+        Ok(())
+    }
+}